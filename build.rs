@@ -0,0 +1,44 @@
+use std::env;
+use std::process::Command;
+
+/// The LLVM major version whose C API `src/native.rs` declares.
+const LLVM_MAJOR: &str = "14";
+
+/// Links against the LLVM shared library for the `llvm` feature, as reported by
+/// `llvm-config`, or by the program named in `LLVM_CONFIG`, which must be for
+/// `LLVM_MAJOR`.
+fn main() {
+    println!("cargo:rerun-if-env-changed=LLVM_CONFIG");
+    if env::var_os("CARGO_FEATURE_LLVM").is_none() {
+        return;
+    }
+    let llvm_config = env::var("LLVM_CONFIG").unwrap_or_else(|_| "llvm-config".to_string());
+    let query = |args: &[&str]| {
+        let output = Command::new(&llvm_config)
+            .args(args)
+            .output()
+            .unwrap_or_else(|e| panic!("could not run {}: {}", llvm_config, e));
+        if !output.status.success() {
+            panic!("{} {} failed", llvm_config, args.join(" "));
+        }
+        String::from_utf8(output.stdout).expect("llvm-config prints UTF-8")
+    };
+    let version = query(&["--version"]);
+    if version.trim().split('.').next() != Some(LLVM_MAJOR) {
+        panic!(
+            "the llvm feature needs LLVM {}, but {} is for LLVM {}",
+            LLVM_MAJOR,
+            llvm_config,
+            version.trim()
+        );
+    }
+    println!(
+        "cargo:rustc-link-search=native={}",
+        query(&["--libdir"]).trim()
+    );
+    for lib in query(&["--libs", "--link-shared"]).split_whitespace() {
+        if let Some(name) = lib.strip_prefix("-l") {
+            println!("cargo:rustc-link-lib=dylib={}", name);
+        }
+    }
+}
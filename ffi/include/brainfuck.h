@@ -0,0 +1,38 @@
+#ifndef BRAINFUCK_H
+#define BRAINFUCK_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Brainfuck Brainfuck;
+
+/* Parses `len` bytes of source. Returns NULL on failure; see bf_last_error. */
+Brainfuck *bf_parse(const uint8_t *source, size_t len);
+
+/* Runs on stdin/stdout. Returns 0 on success and -1 on failure. */
+int bf_exec(const Brainfuck *bf);
+
+/* Runs with in-memory I/O. Writes at most `output_cap` bytes to `output` and
+ * stores the total number of bytes produced in `output_len`.
+ * Returns 0 on success and -1 on failure. */
+int bf_exec_buffer(const Brainfuck *bf,
+                   const uint8_t *input, size_t input_len,
+                   uint8_t *output, size_t output_cap, size_t *output_len);
+
+/* Frees a program returned by bf_parse. NULL is ignored. */
+void bf_free(Brainfuck *bf);
+
+/* The most recent error on this thread, or NULL. Valid until the next failure. */
+const char *bf_last_error(void);
+
+void bf_clear_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
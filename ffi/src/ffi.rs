@@ -0,0 +1,166 @@
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::io::{self, Write};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use std::slice;
+
+use brainfuck::Brainfuck;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error<E: ToString>(e: E) {
+    let message = CString::new(e.to_string()).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Parses `len` bytes of brainfuck source. Returns null on failure, with the
+/// reason available from `bf_last_error`.
+///
+/// # Safety
+///
+/// `source` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn bf_parse(source: *const u8, len: usize) -> *mut Brainfuck {
+    if source.is_null() {
+        set_last_error("source is null");
+        return ptr::null_mut();
+    }
+    let source = slice::from_raw_parts(source, len);
+    match Brainfuck::builder().parse_bytes(source, "<ffi>", "") {
+        Ok(bf) => Box::into_raw(Box::new(bf)),
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Runs a program on stdin and stdout. Returns 0 on success and -1 on failure.
+///
+/// # Safety
+///
+/// `bf` must be null or a live pointer returned by `bf_parse`.
+#[no_mangle]
+pub unsafe extern "C" fn bf_exec(bf: *const Brainfuck) -> c_int {
+    let bf = match bf.as_ref() {
+        Some(bf) => bf,
+        None => {
+            set_last_error("program is null");
+            return -1;
+        }
+    };
+    match bf.exec() {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Writes into a buffer of fixed size, dropping whatever does not fit but
+/// counting every byte, so the program's full output length can be reported
+/// without holding on to it.
+struct CappedWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Write for CappedWriter<'a> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if let Some(room) = self.buf.get_mut(self.len..) {
+            let fits = room.len().min(data.len());
+            room[..fits].copy_from_slice(&data[..fits]);
+        }
+        self.len = self.len.saturating_add(data.len());
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs a program reading from `input` and writing at most `output_cap` bytes to
+/// `output`. The number of bytes the program wrote is stored in `output_len`,
+/// even if it exceeds `output_cap`. Returns 0 on success and -1 on failure.
+///
+/// # Safety
+///
+/// `bf` must be null or a live pointer returned by `bf_parse`. `input` must be
+/// null or point to `input_len` readable bytes, `output` must be null or point to
+/// `output_cap` writable bytes, and `output_len` must be null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn bf_exec_buffer(
+    bf: *const Brainfuck,
+    input: *const u8,
+    input_len: usize,
+    output: *mut u8,
+    output_cap: usize,
+    output_len: *mut usize,
+) -> c_int {
+    let bf = match bf.as_ref() {
+        Some(bf) => bf,
+        None => {
+            set_last_error("program is null");
+            return -1;
+        }
+    };
+    let input = if input.is_null() {
+        &[][..]
+    } else {
+        slice::from_raw_parts(input, input_len)
+    };
+
+    let buf = if output.is_null() {
+        &mut [][..]
+    } else {
+        slice::from_raw_parts_mut(output, output_cap)
+    };
+
+    let mut written = CappedWriter { buf, len: 0 };
+    let result = bf.exec_with(input, &mut written);
+
+    if !output_len.is_null() {
+        *output_len = written.len;
+    }
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Frees a program returned by `bf_parse`. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `bf` must be null or a pointer returned by `bf_parse` that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn bf_free(bf: *mut Brainfuck) {
+    if !bf.is_null() {
+        drop(Box::from_raw(bf));
+    }
+}
+
+/// The message for the most recent failure on this thread, or null if there has
+/// been none. The string remains valid until the next failing call on this thread.
+#[no_mangle]
+pub extern "C" fn bf_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match *last.borrow() {
+        Some(ref message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Clears the message returned by `bf_last_error`.
+#[no_mangle]
+pub extern "C" fn bf_clear_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
@@ -0,0 +1,10 @@
+//! Bindings for embedding the interpreter: a C ABI (see `include/brainfuck.h`)
+//! and, with the `wasm` feature, wasm-bindgen exports for the browser.
+
+extern crate brainfuck;
+#[cfg(feature = "wasm")]
+extern crate wasm_bindgen;
+
+pub mod ffi;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+
+use wasm_bindgen::prelude::*;
+
+use brainfuck::{Brainfuck, Io, StateSnapshot};
+
+/// Feeds input from a queue and collects output, decoding bytes as Latin-1 like
+/// the command line interpreter does.
+struct StringIo<'a> {
+    input: &'a mut VecDeque<u8>,
+    output: &'a mut String,
+}
+
+impl<'a> Io for StringIo<'a> {
+    fn output(&mut self, value: u8) {
+        self.output.push(value as char);
+    }
+
+    fn input(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+}
+
+/// A parsed program.
+#[wasm_bindgen]
+pub struct Program {
+    bf: Brainfuck,
+}
+
+#[wasm_bindgen]
+impl Program {
+    /// Runs the program to completion on `input`, returning everything it wrote.
+    pub fn run(&self, input: &str) -> Result<String, JsValue> {
+        let mut input = input.bytes().collect();
+        let mut output = String::new();
+        self.bf
+            .exec_with_io(&mut StringIo {
+                input: &mut input,
+                output: &mut output,
+            })
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+        Ok(output)
+    }
+
+    /// Starts a new run that can be advanced a few steps at a time.
+    pub fn stepper(&self) -> Stepper {
+        let snapshot = self.bf.interpreter(brainfuck::NullIo).snapshot();
+        Stepper {
+            bf: self.bf.clone(),
+            snapshot,
+            input: VecDeque::new(),
+            output: String::new(),
+        }
+    }
+}
+
+#[wasm_bindgen]
+pub fn parse(source: &str) -> Result<Program, JsValue> {
+    source
+        .parse()
+        .map(|bf| Program { bf })
+        .map_err(|e: brainfuck::ParseError| JsValue::from_str(&e.to_string()))
+}
+
+/// A paused run of a program, for playgrounds that visualize execution.
+#[wasm_bindgen]
+pub struct Stepper {
+    bf: Brainfuck,
+    snapshot: StateSnapshot,
+    input: VecDeque<u8>,
+    output: String,
+}
+
+#[wasm_bindgen]
+impl Stepper {
+    /// Queues bytes for the program's `,` instructions.
+    pub fn push_input(&mut self, input: &str) {
+        self.input.extend(input.bytes());
+    }
+
+    /// Executes up to `count` nodes, returning whether the program has finished.
+    pub fn step(&mut self, count: u32) -> Result<bool, JsValue> {
+        let io = StringIo {
+            input: &mut self.input,
+            output: &mut self.output,
+        };
+        let mut interpreter = self.bf.interpreter(io);
+        interpreter
+            .restore(&self.snapshot)
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+        let mut result = Ok(());
+        for _ in 0..count {
+            if interpreter.is_finished() {
+                break;
+            }
+            if let Err(e) = interpreter.step() {
+                result = Err(JsValue::from_str(&e.to_string()));
+                break;
+            }
+        }
+        self.snapshot = interpreter.snapshot();
+        result.map(|_| interpreter.is_finished())
+    }
+
+    /// Returns and clears the output written since the last call.
+    pub fn take_output(&mut self) -> String {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.snapshot.ptr
+    }
+
+    /// Copies up to `len` cells of the tape starting at `start`.
+    pub fn tape(&self, start: usize, len: usize) -> Vec<u32> {
+        let start = start.min(self.snapshot.mem.len());
+        let end = start.saturating_add(len).min(self.snapshot.mem.len());
+        self.snapshot.mem[start..end].to_vec()
+    }
+}
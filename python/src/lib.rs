@@ -0,0 +1,57 @@
+//! The `brainfuck_rs` Python module.
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+
+/// A parsed program.
+#[pyclass(name = "Program", frozen)]
+struct Program {
+    bf: brainfuck::Brainfuck,
+}
+
+#[pymethods]
+impl Program {
+    /// Runs the program with no input, returning its output.
+    fn run(&self) -> PyResult<Vec<u8>> {
+        self.run_with_input(&[])
+    }
+
+    /// Runs the program reading `,` from `input`, returning its output.
+    fn run_with_input(&self, input: &[u8]) -> PyResult<Vec<u8>> {
+        let mut output = Vec::new();
+        self.bf
+            .exec_with(input, &mut output)
+            .map_err(|e| PyRuntimeError::new_err(e.to_string()))?;
+        Ok(output)
+    }
+}
+
+/// Parses brainfuck source, raising `ValueError` if it is malformed.
+#[pyfunction]
+fn parse(source: &str) -> PyResult<Program> {
+    source
+        .parse()
+        .map(|bf| Program { bf })
+        .map_err(|e: brainfuck::ParseError| PyValueError::new_err(e.to_string()))
+}
+
+/// Parses and runs `source` with no input, returning its output.
+#[pyfunction]
+fn run(source: &str) -> PyResult<Vec<u8>> {
+    parse(source)?.run()
+}
+
+/// Parses and runs `source` reading `,` from `input`, returning its output.
+#[pyfunction]
+fn run_with_input(source: &str, input: &[u8]) -> PyResult<Vec<u8>> {
+    parse(source)?.run_with_input(input)
+}
+
+#[pymodule]
+fn brainfuck_rs(m: &Bound<PyModule>) -> PyResult<()> {
+    m.add_class::<Program>()?;
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    m.add_function(wrap_pyfunction!(run, m)?)?;
+    m.add_function(wrap_pyfunction!(run_with_input, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use brainfuck::{Brainfuck, ExecError};
+use interpreter::{Events, ExecEvent, ExecStats};
+
+impl Brainfuck {
+    /// Runs the program reading `,` from `input` and writing `.` to `output`,
+    /// waiting on them instead of blocking the thread. Computation between I/O
+    /// operations runs without yielding to the executor.
+    pub fn exec_async<R, W>(&self, input: R, output: W) -> ExecFuture<'_, R, W>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        ExecFuture {
+            events: self.events(),
+            input,
+            output,
+            pending_output: None,
+            needs_input: false,
+        }
+    }
+}
+
+/// The future returned by `Brainfuck::exec_async`.
+pub struct ExecFuture<'p, R, W> {
+    events: Events<'p>,
+    input: R,
+    output: W,
+    pending_output: Option<u8>,
+    needs_input: bool,
+}
+
+impl<'p, R, W> Future for ExecFuture<'p, R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    type Output = Result<ExecStats, ExecError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        loop {
+            if let Some(value) = this.pending_output {
+                match Pin::new(&mut this.output).poll_write(cx, &[value]) {
+                    Poll::Ready(_) => this.pending_output = None,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            if this.needs_input {
+                let mut buf = [0];
+                let mut read_buf = ReadBuf::new(&mut buf);
+                match Pin::new(&mut this.input).poll_read(cx, &mut read_buf) {
+                    Poll::Ready(Ok(())) if read_buf.filled().len() == 1 => {
+                        let value = read_buf.filled()[0];
+                        this.events.provide_input(value)
+                    }
+                    Poll::Ready(_) => this.events.provide_eof(),
+                    Poll::Pending => return Poll::Pending,
+                }
+                this.needs_input = false;
+            }
+
+            match this.events.next() {
+                Some(Ok(ExecEvent::Output(value))) => this.pending_output = Some(value),
+                Some(Ok(ExecEvent::NeedsInput)) => this.needs_input = true,
+                Some(Ok(_)) => (),
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                None => {
+                    if Pin::new(&mut this.output).poll_flush(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    let stats = this.events.interpreter().stats().clone();
+                    return Poll::Ready(Ok(stats));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use brainfuck::Brainfuck;
+use c::CSource;
+use error::BrainfuckError;
+use llvm::{LlvmIr, Target};
+#[cfg(feature = "llvm")]
+use native::LlvmObject;
+use rust::RustSource;
+use wasm::{WasmModule, WasmText};
+
+/// The output of a `Backend`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Artifact {
+    /// Source code or assembly, such as LLVM IR or C.
+    Text(String),
+    /// An object file, module or executable.
+    Binary(Vec<u8>),
+}
+
+impl Artifact {
+    pub fn as_bytes(&self) -> &[u8] {
+        match *self {
+            Artifact::Text(ref text) => text.as_bytes(),
+            Artifact::Binary(ref bytes) => bytes,
+        }
+    }
+}
+
+/// A code generator that lowers an optimized program to some target. The
+/// program's tree is available through `Brainfuck::ast`, and its tape layout
+/// through `Brainfuck::mem_size` and `Brainfuck::start_ptr`.
+pub trait Backend {
+    /// The name used to select this backend, such as `llvm-ir`.
+    fn name(&self) -> &'static str;
+
+    fn lower(&self, program: &Brainfuck) -> Result<Artifact, BrainfuckError>;
+}
+
+/// Every built-in backend.
+pub static BACKENDS: &[&(dyn Backend + Sync)] = &[
+    &LlvmIr {
+        target: Target::X86_64Linux,
+    },
+    #[cfg(feature = "llvm")]
+    &LlvmObject {
+        target: Target::X86_64Linux,
+    },
+    &CSource,
+    &RustSource,
+    &WasmModule,
+    &WasmText,
+];
+
+/// Looks up a built-in backend by name.
+pub fn find_backend(name: &str) -> Option<&'static (dyn Backend + Sync)> {
+    BACKENDS
+        .iter()
+        .cloned()
+        .find(|backend| backend.name() == name)
+}
+
+/// How many levels deep the text backends indent nested code. Deeper code is
+/// indented no further, so the output of deeply nested programs stays
+/// proportional to their size.
+pub(crate) const MAX_INDENT: usize = 32;
@@ -1,536 +1,3549 @@
-extern crate libc;
+use alloc::collections::vec_deque::Iter;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::str::{self, FromStr};
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(feature = "std")]
+use std::io::{self, BufWriter, IsTerminal, Read, StdoutLock, Write};
 
-use std::collections::VecDeque;
-use std::io::Read;
+use backend::{Artifact, Backend};
+use error::BrainfuckError;
+use frontend::{Classic, Extensions, Frontend, Symbol};
+use golf;
+use interpreter::{EventIo, Events, ExecStats, Hooks, Interpreter, Limits};
+use llvm::{LlvmIr, Target};
+use visit::{Visitor, VisitorMut};
 
-pub struct Brainfuck<'a> {
-    program: Program<'a>,
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+/// A parsed and optimized program. Programs are `Send + Sync`, so one can be
+/// shared behind an `Arc` and run from several threads, each with its own
+/// `Interpreter` state.
+#[derive(Clone)]
+pub struct Brainfuck {
+    pub(crate) program: Program,
 }
 
-impl<'a> Brainfuck<'a> {
+impl Brainfuck {
+    pub fn builder() -> BrainfuckBuilder {
+        BrainfuckBuilder::new()
+    }
+
+    #[cfg(feature = "std")]
+    pub fn parse<T>(reader: T, file_name: &str, directory: &str) -> Result<Brainfuck, ParseError>
+    where
+        T: Read,
+    {
+        Self::builder().parse(reader, file_name, directory)
+    }
+
+    /// Parses an in-memory program, attributed to a synthetic `<memory>` source.
+    pub fn parse_str(source: &str) -> Result<Brainfuck, ParseError> {
+        Self::builder().parse_str(source)
+    }
+
+    #[cfg(feature = "std")]
+    pub fn exec(&self) -> Result<(), ExecError> {
+        self.exec_stats().map(|_| ())
+    }
+
+    /// Runs the program on stdin and stdout, returning execution statistics.
+    #[cfg(feature = "std")]
+    pub fn exec_stats(&self) -> Result<ExecStats, ExecError> {
+        self.exec_stats_with_io(&mut StdIo::new())
+    }
+
+    /// Runs the program reading `,` from `input` and writing `.` to `output`.
+    #[cfg(feature = "std")]
+    pub fn exec_with<R, W>(&self, input: R, output: W) -> Result<(), ExecError>
+    where
+        R: Read,
+        W: Write,
+    {
+        let mut io = ReadWriteIo::new(input, output);
+        let result = self.exec_with_io(&mut io);
+        let _ = io.output.flush();
+        result
+    }
+
+    pub fn exec_with_io<I>(&self, io: &mut I) -> Result<(), ExecError>
+    where
+        I: Io,
+    {
+        self.exec_stats_with_io(io).map(|_| ())
+    }
+
+    pub fn exec_stats_with_io<I>(&self, io: &mut I) -> Result<ExecStats, ExecError>
+    where
+        I: Io,
+    {
+        self.interpreter(io).run().cloned()
+    }
+
+    /// Runs the program, reporting each step to `hooks`.
+    pub fn exec_with_hooks<I, H>(&self, io: &mut I, hooks: &mut H) -> Result<ExecStats, ExecError>
+    where
+        I: Io,
+        H: Hooks,
+    {
+        self.interpreter(io).with_hooks(hooks).run().cloned()
+    }
+
+    /// Creates an interpreter that can run the program one step at a time.
+    pub fn interpreter<I>(&self, io: I) -> Interpreter<'_, I>
+    where
+        I: Io,
+    {
+        Interpreter::new(&self.program, io)
+    }
+
+    /// Runs the program as an iterator of events, leaving all I/O to the caller.
+    pub fn events(&self) -> Events<'_> {
+        Events::new(self.interpreter(EventIo::new()))
+    }
+
+    /// Runs a program that never reads input to completion within `limits`, and
+    /// returns one that just writes the same output and, for `exit_cell`, leaves
+    /// the same exit status. Returns `None` for programs that read input or use
+    /// `?` without a seed.
+    pub fn precompute(&self, limits: &Limits) -> Result<Option<Brainfuck>, ExecError> {
+        let ast = &self.program.ast;
+        if ast.uses(Node::reads_input)
+            || (self.program.seed.is_none() && ast.uses(|node| matches!(*node, Node::Random(_))))
+        {
+            return Ok(None);
+        }
+        let mut io = CaptureIo(Vec::new());
+        let exit = {
+            let mut interpreter = self.interpreter(&mut io);
+            interpreter.run_limited(limits)?;
+            interpreter.tape().get(interpreter.ptr()).cloned()
+        };
+        let mut nodes = VecDeque::new();
+        if let (Some(first), Some(last)) = (ast.nodes.front(), ast.nodes.back()) {
+            let mut d = first.debug_info().clone();
+            d.span = d.span.to(last.debug_info().span);
+            if !io.0.is_empty() {
+                nodes.push_back(Node::Write(io.0, d.clone()));
+            }
+            if self.program.exit_cell {
+                nodes.push_back(Node::Set(exit.unwrap_or(0), d));
+            }
+        }
+        let mut program = self.program.clone();
+        program.ast = Ast { nodes };
+        Ok(Some(Brainfuck { program }))
+    }
+
+    /// Generates LLVM IR for x86-64 Linux using the `LlvmIr` backend.
+    pub fn gen_ir(&self) -> Result<String, BrainfuckError> {
+        self.gen_ir_for(Target::default())
+    }
+
+    /// Generates LLVM IR for `target` using the `LlvmIr` backend.
+    pub fn gen_ir_for(&self, target: Target) -> Result<String, BrainfuckError> {
+        match (LlvmIr { target }).lower(self)? {
+            Artifact::Text(ir) => Ok(ir),
+            Artifact::Binary(_) => unreachable!("the LLVM IR backend always produces text"),
+        }
+    }
+
+    /// The number of cells on the tape.
+    pub fn mem_size(&self) -> usize {
+        self.program.mem_size
+    }
+
+    /// The cell the pointer starts on.
+    pub fn start_ptr(&self) -> usize {
+        self.program.start_ptr
+    }
+
+    pub fn tape_mode(&self) -> TapeMode {
+        self.program.tape_mode
+    }
+
+    pub fn cell_size(&self) -> CellSize {
+        self.program.cell_size
+    }
+
+    pub fn signed_cells(&self) -> bool {
+        self.program.signed_cells
+    }
+
+    pub fn eof(&self) -> EofMode {
+        self.program.eof
+    }
+
+    pub fn strict(&self) -> bool {
+        self.program.strict
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.program.seed
+    }
+
+    pub fn exit_cell(&self) -> bool {
+        self.program.exit_cell
+    }
+
+    pub fn max_mem(&self) -> Option<usize> {
+        self.program.max_mem
+    }
+
+    /// The bytes loaded into the tape before running, and the cell they start at.
+    pub fn tape_init(&self) -> (usize, &[u8]) {
+        (self.program.tape_init_offset, &self.program.tape_init)
+    }
+
+    /// The optimized program tree.
+    pub fn ast(&self) -> &Ast {
+        &self.program.ast
+    }
+
+    /// Allows external tools to transform the optimized program in place.
+    pub fn ast_mut(&mut self) -> &mut Ast {
+        &mut self.program.ast
+    }
+}
+
+impl FromStr for Brainfuck {
+    type Err = ParseError;
+
+    fn from_str(source: &str) -> Result<Brainfuck, ParseError> {
+        Self::parse_str(source)
+    }
+}
+
+/// Configures how programs are parsed, optimized and executed.
+#[derive(Clone)]
+pub struct BrainfuckBuilder {
+    mem_size: usize,
+    start_ptr: Option<usize>,
+    tape_mode: TapeMode,
+    cell_size: CellSize,
+    signed_cells: bool,
+    eof: EofMode,
+    strict: bool,
+    seed: Option<u64>,
+    exit_cell: bool,
+    tape_init: Vec<u8>,
+    tape_init_offset: Option<usize>,
+    max_mem: Option<usize>,
+    passes: Vec<&'static (dyn Pass + Sync)>,
+    frontend: &'static (dyn Frontend + Sync),
+    extensions: Extensions,
+}
+
+impl BrainfuckBuilder {
+    pub fn new() -> BrainfuckBuilder {
+        BrainfuckBuilder {
+            mem_size: 100000,
+            start_ptr: None,
+            tape_mode: TapeMode::Fixed,
+            cell_size: CellSize::U8,
+            signed_cells: false,
+            eof: EofMode::MinusOne,
+            strict: false,
+            seed: None,
+            exit_cell: false,
+            tape_init: Vec::new(),
+            tape_init_offset: None,
+            max_mem: None,
+            passes: DEFAULT_PASSES.to_vec(),
+            frontend: &Classic,
+            extensions: Extensions::default(),
+        }
+    }
+
+    /// Sets the number of cells on the tape.
+    pub fn mem_size(mut self, mem_size: usize) -> BrainfuckBuilder {
+        self.mem_size = mem_size;
+        self
+    }
+
+    /// Sets the initial cell index, defaulting to the middle of a fixed or wrapping
+    /// tape and the start of a growing one.
+    pub fn start_ptr(mut self, start_ptr: usize) -> BrainfuckBuilder {
+        self.start_ptr = Some(start_ptr);
+        self
+    }
+
+    /// Sets what happens when the pointer moves past the end of the tape.
+    pub fn tape_mode(mut self, tape_mode: TapeMode) -> BrainfuckBuilder {
+        self.tape_mode = tape_mode;
+        self
+    }
+
+    /// Sets the width of each tape cell, 8 bits by default.
+    pub fn cell_size(mut self, cell_size: CellSize) -> BrainfuckBuilder {
+        self.cell_size = cell_size;
+        self
+    }
+
+    /// Treats cells as two's complement signed numbers when they are read as
+    /// values. Arithmetic still wraps and `[` still tests for zero.
+    pub fn signed_cells(mut self, signed_cells: bool) -> BrainfuckBuilder {
+        self.signed_cells = signed_cells;
+        self
+    }
+
+    /// Sets what `,` stores at end of input, `EofMode::MinusOne` by default.
+    pub fn eof(mut self, eof: EofMode) -> BrainfuckBuilder {
+        self.eof = eof;
+        self
+    }
+
+    /// Makes incrementing a cell past its largest value, or decrementing it below
+    /// its smallest, an error instead of wrapping around.
+    pub fn strict(mut self, strict: bool) -> BrainfuckBuilder {
+        self.strict = strict;
+        self
+    }
+
+    /// Seeds the generator behind the `?` extension, making runs repeatable.
+    /// Unseeded programs draw a fresh seed each run where the platform allows.
+    pub fn seed(mut self, seed: u64) -> BrainfuckBuilder {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Makes compiled programs exit with the low byte of the cell the pointer is
+    /// on when they halt, rather than 0.
+    pub fn exit_cell(mut self, exit_cell: bool) -> BrainfuckBuilder {
+        self.exit_cell = exit_cell;
+        self
+    }
+
+    /// Loads `data` into the tape before running, one byte per cell, starting at
+    /// the initial cell. Bytes past the end of a fixed or wrapping tape are dropped.
+    pub fn tape_init(mut self, data: &[u8]) -> BrainfuckBuilder {
+        self.tape_init = data.to_vec();
+        self
+    }
+
+    /// Sets the cell `tape_init` data starts at, instead of the initial cell.
+    pub fn tape_init_offset(mut self, offset: usize) -> BrainfuckBuilder {
+        self.tape_init_offset = Some(offset);
+        self
+    }
+
+    /// Caps how many bytes a growing tape may occupy, making growth past it an
+    /// error. Has no effect on fixed or wrapping tapes.
+    pub fn max_mem(mut self, bytes: usize) -> BrainfuckBuilder {
+        self.max_mem = Some(bytes);
+        self
+    }
+
+    /// Replaces the optimization passes run after parsing.
+    pub fn passes(mut self, passes: &[&'static (dyn Pass + Sync)]) -> BrainfuckBuilder {
+        self.passes = passes.to_vec();
+        self
+    }
+
+    /// Sets the dialect source is read in, `Classic` by default.
+    pub fn frontend(mut self, frontend: &'static (dyn Frontend + Sync)) -> BrainfuckBuilder {
+        self.frontend = frontend;
+        self
+    }
+
+    /// Enables commands beyond the standard eight.
+    pub fn extensions(mut self, extensions: Extensions) -> BrainfuckBuilder {
+        self.extensions = extensions;
+        self
+    }
+
+    #[cfg(feature = "std")]
     pub fn parse<T>(
-        reader: T,
-        file_name: &'a str,
-        directory: &'a str,
-    ) -> Result<Brainfuck<'a>, ParseError<'a>>
+        &self,
+        mut reader: T,
+        file_name: &str,
+        directory: &str,
+    ) -> Result<Brainfuck, ParseError>
     where
         T: Read,
     {
-        let mut symbols = VecDeque::new();
-        let mut line = 1;
-        let mut column = 1;
-        for byte in reader.bytes() {
-            let debug = DebugInfo {
-                directory: directory,
-                file: file_name,
-                line: line,
-                column: column,
+        let mut source = Vec::new();
+        let _ = reader.read_to_end(&mut source);
+        self.parse_bytes(&source, file_name, directory)
+    }
+
+    pub fn parse_bytes(
+        &self,
+        source: &[u8],
+        file_name: &str,
+        directory: &str,
+    ) -> Result<Brainfuck, ParseError> {
+        let file_name: Arc<str> = file_name.into();
+        let directory: Arc<str> = directory.into();
+
+        let symbols = self
+            .frontend
+            .tokenize(source)
+            .into_iter()
+            .filter(|&(symbol, _)| self.extensions.allows(symbol))
+            .map(|(symbol, span)| {
+                let debug = DebugInfo {
+                    directory: directory.clone(),
+                    file: file_name.clone(),
+                    span,
+                };
+                (symbol, debug)
+            })
+            .collect();
+
+        let ast = Ast::parse(symbols);
+
+        let context = self.pass_context();
+        ast.map(|mut x| {
+            for &pass in &self.passes {
+                x.run_pass(pass, &context);
+            }
+            Brainfuck {
+                program: Program {
+                    ast: x,
+                    mem_size: self.mem_size,
+                    start_ptr: self.resolved_start_ptr(),
+                    tape_mode: self.tape_mode,
+                    cell_size: self.cell_size,
+                    signed_cells: self.signed_cells,
+                    eof: self.eof,
+                    strict: self.strict,
+                    seed: self.seed,
+                    exit_cell: self.exit_cell,
+                    tape_init: self.tape_init.clone(),
+                    max_mem: self.max_mem,
+                    tape_init_offset: self
+                        .tape_init_offset
+                        .unwrap_or_else(|| self.resolved_start_ptr()),
+                },
+            }
+        })
+    }
+
+    /// Parses an in-memory program, attributed to a synthetic `<memory>` source.
+    pub fn parse_str(&self, source: &str) -> Result<Brainfuck, ParseError> {
+        self.parse_bytes(source.as_bytes(), "<memory>", "")
+    }
+
+    /// Runs a single pass over `ast`, assuming what this builder's settings allow,
+    /// for tools that inspect the tree between passes.
+    pub fn run_pass(&self, ast: &mut Ast, pass: &dyn Pass) {
+        ast.run_pass(pass, &self.pass_context());
+    }
+
+    /// Works out which values each cell may hold at each point of `ast`,
+    /// assuming what this builder's settings allow.
+    pub fn analyze(&self, ast: &Ast) -> ValueRanges {
+        value_ranges(&ast.nodes, &self.pass_context())
+    }
+
+    /// Rewrites `ast`, parsed with no passes, as shorter brainfuck that does the
+    /// same under this builder's settings, trying at most `budget` loops for each
+    /// run of `+-<>`. In strict mode, a program that overflows may report another
+    /// cell first. Returns `None` if `ast` holds nodes only passes make.
+    pub fn golf(&self, ast: &Ast, budget: usize) -> Option<String> {
+        golf::golf(&ast.nodes, &self.pass_context(), budget)
+    }
+
+    /// Identifies a source file and this configuration, for validating caches.
+    pub fn fingerprint(&self, source: &[u8]) -> u64 {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        let mut feed = |bytes: &[u8]| {
+            for &b in bytes {
+                hash ^= u64::from(b);
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        };
+        feed(source);
+        feed(self.frontend.name().as_bytes());
+        for pass in &self.passes {
+            feed(&[0]);
+            feed(pass.name().as_bytes());
+        }
+        feed(&(self.mem_size as u64).to_le_bytes());
+        feed(&(self.resolved_start_ptr() as u64).to_le_bytes());
+        feed(&[
+            self.tape_mode as u8,
+            self.cell_size.bits() as u8,
+            self.signed_cells as u8,
+            self.eof as u8,
+            self.strict as u8,
+            self.extensions.bits(),
+            self.exit_cell as u8,
+        ]);
+        if let Some(seed) = self.seed {
+            feed(&seed.to_le_bytes());
+        }
+        if let Some(max_mem) = self.max_mem {
+            feed(&(max_mem as u64).to_le_bytes());
+        }
+        if !self.tape_init.is_empty() {
+            let offset = self
+                .tape_init_offset
+                .unwrap_or_else(|| self.resolved_start_ptr());
+            feed(&(offset as u64).to_le_bytes());
+            feed(&(self.tape_init.len() as u64).to_le_bytes());
+            feed(&self.tape_init);
+        }
+        hash
+    }
+
+    /// What the passes may assume about how the program will run.
+    fn pass_context(&self) -> PassContext {
+        let start_ptr = self.resolved_start_ptr();
+        let start_cell = start_ptr
+            .checked_sub(self.tape_init_offset.unwrap_or(start_ptr))
+            .and_then(|i| self.tape_init.get(i))
+            .map_or(0, |&b| b);
+        PassContext {
+            zeroed_start: start_cell == 0,
+            zeroed_tape: self.tape_init.iter().all(|&b| b == 0),
+            wrap_len: if self.tape_mode == TapeMode::Wrap {
+                Some(self.mem_size)
+            } else {
+                None
+            },
+            mem_size: self.mem_size,
+            start_ptr,
+            strict: self.strict,
+            cell_size: self.cell_size,
+            signed_cells: self.signed_cells,
+        }
+    }
+
+    /// A growing tape extends in both directions, so it can start at cell 0;
+    /// otherwise start in the middle to leave room on either side.
+    fn resolved_start_ptr(&self) -> usize {
+        match (self.start_ptr, self.tape_mode) {
+            (Some(start_ptr), _) => start_ptr,
+            (None, TapeMode::Grow) => 0,
+            (None, _) => self.mem_size / 2,
+        }
+    }
+}
+
+impl Default for BrainfuckBuilder {
+    fn default() -> BrainfuckBuilder {
+        BrainfuckBuilder::new()
+    }
+}
+
+/// The byte-level I/O the interpreter performs for `.` and `,`.
+pub trait Io {
+    fn output(&mut self, value: u8);
+
+    /// Reads the next byte, or `None` at end of input.
+    fn input(&mut self) -> Option<u8>;
+
+    /// Whether `input` can answer without waiting. `Interpreter::run_for` stops
+    /// with `RunOutcome::NeedsInput` rather than call `input` while this is false.
+    fn input_ready(&mut self) -> bool {
+        true
+    }
+
+    /// Called for the `#` extension; prints the dump to stderr by default.
+    fn debug(&mut self, _dump: &DebugDump) {
+        #[cfg(feature = "std")]
+        eprintln!("{}", _dump);
+    }
+}
+
+impl<I: Io + ?Sized> Io for &mut I {
+    fn output(&mut self, value: u8) {
+        (**self).output(value)
+    }
+
+    fn input(&mut self) -> Option<u8> {
+        (**self).input()
+    }
+
+    fn input_ready(&mut self) -> bool {
+        (**self).input_ready()
+    }
+
+    fn debug(&mut self, dump: &DebugDump) {
+        (**self).debug(dump)
+    }
+}
+
+/// The pointer and the cells around it, reported by the `#` extension.
+#[derive(Debug, Clone)]
+pub struct DebugDump<'a> {
+    pub location: &'a DebugInfo,
+    pub ptr: usize,
+    /// The index of the first cell in `cells`.
+    pub first_cell: usize,
+    /// Cell values, read as signed if the program uses signed cells.
+    pub cells: Vec<i64>,
+}
+
+impl<'a> fmt::Display for DebugDump<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: ptr {}, cells from {}:",
+            self.location, self.ptr, self.first_cell
+        )?;
+        for (i, value) in self.cells.iter().enumerate() {
+            if self.first_cell + i == self.ptr {
+                write!(f, " [{}]", value)?;
+            } else {
+                write!(f, " {}", value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How `StdIo` turns output bytes into text.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputEncoding {
+    /// Each byte is one character, so bytes from 0x80 up become two UTF-8 bytes.
+    Latin1,
+    /// Bytes are decoded as UTF-8, replacing invalid sequences with U+FFFD.
+    Utf8,
+    /// Bytes are written exactly, so programs can produce binary data.
+    Raw,
+}
+
+/// Writes to stdout and reads from stdin. Output is buffered, and flushed before
+/// each read and when the `StdIo` is dropped. Stdout stays locked meanwhile.
+///
+/// Output is written raw when stdout is not a terminal, and as Latin-1 text
+/// otherwise.
+#[cfg(feature = "std")]
+pub struct StdIo {
+    out: BufWriter<StdoutLock<'static>>,
+    buffered: bool,
+    encoding: OutputEncoding,
+    /// The start of a UTF-8 sequence still waiting for its remaining bytes.
+    partial: Vec<u8>,
+}
+
+#[cfg(feature = "std")]
+impl StdIo {
+    pub fn new() -> StdIo {
+        let stdout = io::stdout();
+        let encoding = if stdout.is_terminal() {
+            OutputEncoding::Latin1
+        } else {
+            OutputEncoding::Raw
+        };
+        StdIo {
+            out: BufWriter::new(stdout.lock()),
+            buffered: true,
+            encoding,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Flushes after every byte, for output that must appear immediately.
+    pub fn unbuffered() -> StdIo {
+        let mut io = StdIo::new();
+        io.buffered = false;
+        io
+    }
+
+    /// Sets how output bytes are written.
+    pub fn encoding(mut self, encoding: OutputEncoding) -> StdIo {
+        self.encoding = encoding;
+        self
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+
+    /// Writes whatever prefix of `partial` decodes, leaving an incomplete
+    /// sequence at the end for the next byte to finish.
+    fn write_utf8(&mut self) {
+        loop {
+            let (valid, invalid) = match str::from_utf8(&self.partial) {
+                Ok(_) => (self.partial.len(), None),
+                Err(e) => (e.valid_up_to(), e.error_len()),
             };
-            if byte.is_err() {
-                break;
+            let _ = self.out.write_all(&self.partial[..valid]);
+            match invalid {
+                Some(len) => {
+                    let _ = self.out.write_all(REPLACEMENT.as_bytes());
+                    self.partial.drain(..valid + len);
+                }
+                None => {
+                    self.partial.drain(..valid);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+const REPLACEMENT: &str = "\u{fffd}";
+
+#[cfg(feature = "std")]
+impl Drop for StdIo {
+    /// A sequence cut short by the end of the program is invalid.
+    fn drop(&mut self) {
+        if !self.partial.is_empty() {
+            let _ = self.out.write_all(REPLACEMENT.as_bytes());
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdIo {
+    fn default() -> StdIo {
+        StdIo::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Io for StdIo {
+    fn output(&mut self, value: u8) {
+        match self.encoding {
+            OutputEncoding::Latin1 => {
+                let mut buf = [0; 4];
+                let _ = self
+                    .out
+                    .write_all((value as char).encode_utf8(&mut buf).as_bytes());
+            }
+            OutputEncoding::Utf8 => {
+                self.partial.push(value);
+                self.write_utf8();
+            }
+            OutputEncoding::Raw => {
+                let _ = self.out.write_all(&[value]);
+            }
+        }
+        if !self.buffered {
+            self.flush();
+        }
+    }
+
+    fn input(&mut self) -> Option<u8> {
+        self.flush();
+        let mut buf = [0];
+        match io::stdin().read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            _ => None,
+        }
+    }
+}
+
+/// Adapts a reader and writer pair, writing each output byte verbatim.
+#[cfg(feature = "std")]
+pub struct ReadWriteIo<R, W> {
+    input: R,
+    output: W,
+}
+
+#[cfg(feature = "std")]
+impl<R: Read, W: Write> ReadWriteIo<R, W> {
+    pub fn new(input: R, output: W) -> ReadWriteIo<R, W> {
+        ReadWriteIo { input, output }
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.input, self.output)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: Read, W: Write> Io for ReadWriteIo<R, W> {
+    fn output(&mut self, value: u8) {
+        let _ = self.output.write_all(&[value]);
+    }
+
+    fn input(&mut self) -> Option<u8> {
+        let mut buf = [0];
+        match self.input.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            _ => None,
+        }
+    }
+}
+
+/// Discards all output and behaves as if stdin is always at EOF.
+pub struct NullIo;
+
+impl Io for NullIo {
+    fn output(&mut self, _value: u8) {}
+
+    fn input(&mut self) -> Option<u8> {
+        None
+    }
+}
+
+/// Collects output for `Brainfuck::precompute`, ignoring `#`.
+struct CaptureIo(Vec<u8>);
+
+impl Io for CaptureIo {
+    fn output(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn input(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn debug(&mut self, _dump: &DebugDump) {}
+}
+
+/// An optimization pass over the parsed program. Passes run one after another,
+/// each rebuilding the whole tree, so any of them can be run, skipped or
+/// reordered on its own.
+pub trait Pass {
+    /// The name used to select this pass, such as `coalesce`.
+    fn name(&self) -> &'static str;
+
+    /// Moves `nodes` into `opt_nodes`, rewritten however the pass sees fit.
+    fn run(
+        &self,
+        opt_nodes: &mut VecDeque<Node>,
+        nodes: &mut VecDeque<Node>,
+        context: &PassContext,
+    );
+}
+
+/// A built-in pass, implemented by a function.
+struct BuiltinPass {
+    name: &'static str,
+    run: fn(&mut VecDeque<Node>, &mut VecDeque<Node>, &PassContext),
+}
+
+impl Pass for BuiltinPass {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(
+        &self,
+        opt_nodes: &mut VecDeque<Node>,
+        nodes: &mut VecDeque<Node>,
+        context: &PassContext,
+    ) {
+        (self.run)(opt_nodes, nodes, context)
+    }
+}
+
+/// What the builder's settings let passes assume about the program.
+pub struct PassContext {
+    /// Whether the pointer starts on a zero cell.
+    pub(crate) zeroed_start: bool,
+    /// Whether every cell starts at zero.
+    pub(crate) zeroed_tape: bool,
+    /// The tape length, if the tape wraps.
+    pub(crate) wrap_len: Option<usize>,
+    mem_size: usize,
+    start_ptr: usize,
+    pub(crate) strict: bool,
+    pub(crate) cell_size: CellSize,
+    signed_cells: bool,
+}
+
+impl PassContext {
+    /// Whether a cell `offset` cells from the pointer may be the current cell.
+    pub fn aliases_current(&self, offset: isize) -> bool {
+        match self.wrap_len {
+            Some(len) => offset.rem_euclid(len as isize) == 0,
+            None => offset == 0,
+        }
+    }
+
+    /// Whether adding `delta` to a cell holding `raw` stays within the range
+    /// strict mode allows.
+    fn fits(&self, raw: u32, delta: i32) -> bool {
+        let value = self.cell_size.value(raw, self.signed_cells) + i64::from(delta);
+        value >= self.cell_size.min_value(self.signed_cells)
+            && value <= self.cell_size.max_value(self.signed_cells)
+    }
+
+    /// Where `pos` cells from the starting cell lands, wrapping on a wrapping
+    /// tape.
+    fn wrap(&self, pos: isize) -> isize {
+        match self.wrap_len {
+            Some(len) => pos.rem_euclid(len as isize),
+            None => pos,
+        }
+    }
+
+    /// Whether the cell `pos` cells from the starting cell is on the tape the
+    /// program starts with.
+    pub(crate) fn on_start_tape(&self, pos: isize) -> bool {
+        let cell = (self.start_ptr as isize).wrapping_add(pos);
+        self.wrap_len.is_some() || (cell >= 0 && (cell as usize) < self.mem_size)
+    }
+}
+
+/// Every built-in pass, in the order the default pipeline runs them.
+pub static PASSES: &[&(dyn Pass + Sync)] = &[
+    &BuiltinPass {
+        name: "coalesce",
+        run: coalesce,
+    },
+    &BuiltinPass {
+        name: "clear-loops",
+        run: clear_loops,
+    },
+    &BuiltinPass {
+        name: "mul-loops",
+        run: mul_loops,
+    },
+    &BuiltinPass {
+        name: "nested-mul",
+        run: nested_mul,
+    },
+    &BuiltinPass {
+        name: "scan-loops",
+        run: scan_loops,
+    },
+    &BuiltinPass {
+        name: "offsets",
+        run: offsets,
+    },
+    &BuiltinPass {
+        name: "const-fold",
+        run: const_fold,
+    },
+    &BuiltinPass {
+        name: "peephole",
+        run: peephole,
+    },
+    &BuiltinPass {
+        name: "unroll",
+        run: unroll,
+    },
+    &BuiltinPass {
+        name: "dead-code",
+        run: dead_code,
+    },
+    &BuiltinPass {
+        name: "if-blocks",
+        run: if_blocks,
+    },
+    &BuiltinPass {
+        name: "hoist",
+        run: hoist,
+    },
+];
+
+/// The passes run by `Brainfuck::parse`.
+pub static DEFAULT_PASSES: &[&(dyn Pass + Sync)] = PASSES;
+
+/// Looks up a built-in pass by name.
+pub fn find_pass(name: &str) -> Option<&'static (dyn Pass + Sync)> {
+    PASSES.iter().cloned().find(|pass| pass.name() == name)
+}
+
+/// The passes run at an optimization level: none at 0, run-length merging at 1,
+/// loop rewriting and offsets at 2, and everything at 3.
+pub fn opt_level_passes(level: u32) -> Option<Vec<&'static (dyn Pass + Sync)>> {
+    let names: &[&str] = match level {
+        0 => &[],
+        1 => &["coalesce"],
+        2 => &[
+            "coalesce",
+            "clear-loops",
+            "mul-loops",
+            "nested-mul",
+            "scan-loops",
+            "offsets",
+        ],
+        3 => return Some(PASSES.to_vec()),
+        _ => return None,
+    };
+    Some(names.iter().filter_map(|&name| find_pass(name)).collect())
+}
+
+/// A range of bytes in the source, with the line and column where it starts.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub byte_offset: usize,
+    pub len: usize,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Span {
+    /// The smallest span covering both `self` and `other`, usually from the
+    /// start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        let end = (self.byte_offset + self.len).max(other.byte_offset + other.len);
+        let start = if other.byte_offset < self.byte_offset {
+            other
+        } else {
+            self
+        };
+        Span {
+            len: end - start.byte_offset,
+            ..start
+        }
+    }
+
+    /// Renders the line of `source` the span starts on, with the span underlined.
+    pub fn render(&self, source: &[u8]) -> String {
+        let start = self.byte_offset.min(source.len());
+        let line_start = source[..start]
+            .iter()
+            .rposition(|&b| b == b'\n')
+            .map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(source.len(), |i| start + i);
+        let text = String::from_utf8_lossy(&source[line_start..line_end]);
+        let text = text.trim_end_matches('\r');
+
+        let number = format!("{}", self.line);
+        let gutter = " ".repeat(number.len());
+        let underline = self.len.min(line_end - start).max(1);
+        // Spans of merged nodes can run onto later lines.
+        let end = (self.byte_offset + self.len).min(source.len());
+        let lines = source[start.min(end)..end]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count();
+        let rest = if lines > 0 {
+            format!(" ...through line {}", self.line as usize + lines)
+        } else {
+            String::new()
+        };
+        format!(
+            "{} |\n{} | {}\n{} | {}{}{}\n",
+            gutter,
+            number,
+            text,
+            gutter,
+            " ".repeat(start - line_start),
+            "^".repeat(underline),
+            rest
+        )
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub struct DebugInfo {
+    pub directory: Arc<str>,
+    pub file: Arc<str>,
+    pub span: Span,
+}
+
+impl fmt::Display for DebugInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.span.line, self.span.column)
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    UnmatchedLoop(DebugInfo),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::UnmatchedLoop(ref d) => write!(f, "unmatched loop --> {}", d),
+        }
+    }
+}
+
+impl ParseError {
+    pub fn span(&self) -> Span {
+        match *self {
+            ParseError::UnmatchedLoop(ref d) => d.span,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for ParseError {}
+
+/// A single operation in the parsed program, tagged with where it came from.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone)]
+pub enum Node {
+    /// Runs the body while the current cell is nonzero.
+    Loop(VecDeque<Node>, DebugInfo),
+    /// Moves the pointer by a signed number of cells, to the right if positive.
+    Move(isize, DebugInfo),
+    /// Adds a signed value to the cell at the offset from the pointer, leaving the
+    /// pointer where it is. `+` and `-` parse to offset 0 and the optimizer
+    /// merges and folds them; in strict mode only while no overflow is hidden.
+    Add(isize, i32, DebugInfo),
+    Output(DebugInfo),
+    Input(DebugInfo),
+    /// Reports the pointer and nearby cells through `Io::debug`.
+    Debug(DebugInfo),
+    /// Stores a pseudo-random byte in the current cell.
+    Random(DebugInfo),
+    /// Writes the current cell in decimal, followed by a newline.
+    PrintNumber(DebugInfo),
+    /// Reads a decimal number into the current cell, skipping leading whitespace
+    /// and consuming the byte that ends it.
+    ReadNumber(DebugInfo),
+    /// Stores a value in the current cell; produced by the optimizer for loops
+    /// like `[-]`, and in strict mode only where the loop cannot overflow.
+    Set(u32, DebugInfo),
+    /// Adds the current cell times the factor to the cell at the offset, leaving
+    /// the current cell alone; produced by the optimizer for loops like `[->+<]`.
+    /// Neither cell is touched if the current cell is zero.
+    MulAdd(isize, u32, DebugInfo),
+    /// Adds the current cell times the cell at the second offset times the
+    /// factor to the cell at the first offset; produced by the optimizer for
+    /// nested loops like `[>[->+>+<<]>>[-<<+>>]<<<-]`. Neither cell is touched
+    /// if the current cell is zero.
+    Product(isize, isize, u32, DebugInfo),
+    /// Moves the pointer by the step until it lands on a zero cell; produced by
+    /// the optimizer for loops like `[>]` and `[<<]`.
+    Scan(isize, DebugInfo),
+    /// Writes these bytes; produced by the optimizer for `.` on cells whose
+    /// values it knows.
+    Write(Vec<u8>, DebugInfo),
+    /// Runs the body once if the current cell is nonzero; produced by the
+    /// optimizer for loops whose bodies always clear the cell, like `[>+<[-]]`.
+    If(VecDeque<Node>, DebugInfo),
+}
+
+impl Node {
+    pub fn debug_info(&self) -> &DebugInfo {
+        match *self {
+            Node::Loop(_, ref d)
+            | Node::Move(_, ref d)
+            | Node::Add(_, _, ref d)
+            | Node::Output(ref d)
+            | Node::Input(ref d)
+            | Node::Debug(ref d)
+            | Node::Random(ref d)
+            | Node::PrintNumber(ref d)
+            | Node::ReadNumber(ref d)
+            | Node::Set(_, ref d)
+            | Node::MulAdd(_, _, ref d)
+            | Node::Product(_, _, _, ref d)
+            | Node::Scan(_, ref d)
+            | Node::Write(_, ref d)
+            | Node::If(_, ref d) => d,
+        }
+    }
+
+    /// Whether executing this node reads from `Io::input`.
+    pub fn reads_input(&self) -> bool {
+        matches!(*self, Node::Input(_) | Node::ReadNumber(_))
+    }
+
+    /// Whether executing this node writes to `Io::output`.
+    pub fn writes_output(&self) -> bool {
+        matches!(
+            *self,
+            Node::Output(_) | Node::PrintNumber(_) | Node::Write(..)
+        )
+    }
+
+    /// The source covered by this node; a loop spans from its `[` to its `]`.
+    pub fn span(&self) -> Span {
+        self.debug_info().span
+    }
+}
+
+/// The cells a loop body can reach relative to the cell it starts on, as the
+/// lowest and highest offsets, if the body is straight-line code that ends back
+/// on that cell. Every iteration of such a loop stays within the same cells, so
+/// they only need checking once, when the loop is entered.
+pub(crate) fn balanced_span(body: &VecDeque<Node>) -> Option<(isize, isize)> {
+    let mut pos = 0isize;
+    let (mut lo, mut hi) = (0, 0);
+    for node in body {
+        let reached = match *node {
+            Node::Move(v, _) => {
+                pos = pos.checked_add(v)?;
+                pos
+            }
+            Node::Add(offset, _, _) | Node::MulAdd(offset, _, _) => pos.checked_add(offset)?,
+            Node::Product(offset, source, _, _) => {
+                let source = pos.checked_add(source)?;
+                lo = lo.min(source);
+                hi = hi.max(source);
+                pos.checked_add(offset)?
+            }
+            Node::Loop(..) | Node::If(..) | Node::Scan(..) => return None,
+            _ => pos,
+        };
+        lo = lo.min(reached);
+        hi = hi.max(reached);
+    }
+    if pos == 0 {
+        Some((lo, hi))
+    } else {
+        None
+    }
+}
+
+/// Whether any node in `nodes`, including those in loop bodies, matches
+/// `wanted`. Bodies are searched with an explicit stack, so nesting depth is
+/// limited by memory rather than the call stack.
+pub(crate) fn uses<F: Fn(&Node) -> bool>(nodes: &VecDeque<Node>, wanted: F) -> bool {
+    let mut levels = vec![nodes.iter()];
+    while let Some(nodes) = levels.last_mut() {
+        match nodes.next() {
+            Some(&Node::Loop(ref body, _)) | Some(&Node::If(ref body, _)) => {
+                levels.push(body.iter())
+            }
+            Some(node) if wanted(node) => return true,
+            Some(_) => (),
+            None => {
+                levels.pop();
+            }
+        }
+    }
+    false
+}
+
+/// The program as a tree of nodes, with loops holding their bodies.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct Ast {
+    pub nodes: VecDeque<Node>,
+}
+
+impl Ast {
+    pub fn visit<V: Visitor>(&self, visitor: &mut V) {
+        visitor.visit_nodes(&self.nodes);
+    }
+
+    pub fn visit_mut<V: VisitorMut>(&mut self, visitor: &mut V) {
+        visitor.visit_nodes_mut(&mut self.nodes);
+    }
+
+    /// Whether any node, including those in loop bodies, matches `wanted`.
+    pub(crate) fn uses<F: Fn(&Node) -> bool>(&self, wanted: F) -> bool {
+        uses(&self.nodes, wanted)
+    }
+
+    /// Builds the tree with an explicit stack of open loops, so nesting depth is
+    /// limited by memory rather than the call stack.
+    fn parse(symbols: VecDeque<(Symbol, DebugInfo)>) -> Result<Ast, ParseError> {
+        let mut ast = Ast {
+            nodes: VecDeque::new(),
+        };
+        // The enclosing levels of each open loop, and the loop's `[`.
+        let mut open: Vec<(Ast, DebugInfo)> = Vec::new();
+
+        for (symbol, d) in symbols {
+            let node = match symbol {
+                Symbol::OpenBlock => {
+                    let outer = mem::replace(
+                        &mut ast,
+                        Ast {
+                            nodes: VecDeque::new(),
+                        },
+                    );
+                    open.push((outer, d));
+                    continue;
+                }
+                Symbol::CloseBlock => match open.pop() {
+                    Some((outer, mut open_d)) => {
+                        open_d.span = open_d.span.to(d.span);
+                        let mut body = mem::replace(&mut ast, outer);
+                        Node::Loop(mem::take(&mut body.nodes), open_d)
+                    }
+                    None => return Err(ParseError::UnmatchedLoop(d)),
+                },
+                Symbol::IncPtr => Node::Move(1, d),
+                Symbol::DecPtr => Node::Move(-1, d),
+                Symbol::Increment => Node::Add(0, 1, d),
+                Symbol::Decrement => Node::Add(0, -1, d),
+                Symbol::Output => Node::Output(d),
+                Symbol::Input => Node::Input(d),
+                Symbol::Debug => Node::Debug(d),
+                Symbol::Random => Node::Random(d),
+                Symbol::PrintNumber => Node::PrintNumber(d),
+                Symbol::ReadNumber => Node::ReadNumber(d),
+            };
+            ast.nodes.push_back(node);
+        }
+
+        match open.pop() {
+            Some((_, d)) => Err(ParseError::UnmatchedLoop(d)),
+            None => Ok(ast),
+        }
+    }
+
+    fn run_pass(&mut self, pass: &dyn Pass, context: &PassContext) {
+        let mut opt_nodes = VecDeque::new();
+        pass.run(&mut opt_nodes, &mut self.nodes, context);
+        self.nodes = opt_nodes;
+    }
+}
+
+impl Clone for Ast {
+    /// Copies loop bodies with an explicit stack, as `drop` does.
+    fn clone(&self) -> Ast {
+        // The rest of each enclosing level, its copy so far, and the block entered.
+        let mut outer: Vec<(Iter<'_, Node>, VecDeque<Node>, Block)> = Vec::new();
+        let mut nodes = self.nodes.iter();
+        let mut copy = VecDeque::new();
+        loop {
+            let node = match nodes.next() {
+                Some(node) => node,
+                None => match outer.pop() {
+                    Some((rest, enclosing, block)) => {
+                        let body = mem::replace(&mut copy, enclosing);
+                        copy.push_back(block.rebuild(body));
+                        nodes = rest;
+                        continue;
+                    }
+                    None => return Ast { nodes: copy },
+                },
+            };
+            let (body, block) = match *node {
+                Node::Loop(ref body, ref d) => (body, Block::Loop(d.clone())),
+                Node::If(ref body, ref d) => (body, Block::If(d.clone())),
+                _ => {
+                    copy.push_back(node.clone());
+                    continue;
+                }
+            };
+            let rest = mem::replace(&mut nodes, body.iter());
+            outer.push((rest, mem::take(&mut copy), block));
+        }
+    }
+}
+
+impl Drop for Ast {
+    /// Detaches loop bodies before dropping them, so deeply nested programs do
+    /// not overflow the stack.
+    fn drop(&mut self) {
+        let mut levels = vec![mem::take(&mut self.nodes)];
+        while let Some(mut nodes) = levels.pop() {
+            for node in nodes.drain(..) {
+                if let Node::Loop(body, _) | Node::If(body, _) = node {
+                    levels.push(body);
+                }
+            }
+        }
+    }
+}
+
+/// Merges runs of identical pointer and cell adjustments into a single node.
+fn coalesce(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, context: &PassContext) {
+    // The rest of each enclosing level, its output so far, and the block entered.
+    let mut outer: Vec<(VecDeque<Node>, VecDeque<Node>, Block)> = Vec::new();
+    let mut nodes = mem::take(nodes);
+    let mut output = VecDeque::new();
+    loop {
+        let node = match nodes.pop_front() {
+            Some(node) => node,
+            None => match outer.pop() {
+                Some((rest, enclosing, block)) => {
+                    let body = mem::replace(&mut output, enclosing);
+                    output.push_back(block.rebuild(body));
+                    nodes = rest;
+                    continue;
+                }
+                None => break,
+            },
+        };
+        match node {
+            Node::Loop(body, d) => {
+                let block = Block::Loop(d);
+                outer.push((
+                    mem::replace(&mut nodes, body),
+                    mem::take(&mut output),
+                    block,
+                ));
+            }
+            Node::If(body, d) => {
+                let block = Block::If(d);
+                outer.push((
+                    mem::replace(&mut nodes, body),
+                    mem::take(&mut output),
+                    block,
+                ));
+            }
+            // Like `Add` below, mixed runs like `>><` cancel out.
+            Node::Move(v, mut d) => {
+                let mut value = v;
+                while let Some(&Node::Move(v, ref next)) = nodes.front() {
+                    value = value.wrapping_add(v);
+                    d.span = d.span.to(next.span);
+                    nodes.pop_front();
+                }
+                if value != 0 {
+                    output.push_back(Node::Move(value, d));
+                }
+            }
+            // Mixed runs like `++-` cancel out, and runs that cancel completely
+            // are dropped. Strict mode must still see each overflow, so there
+            // only runs of one sign that stay within the cell's range merge.
+            Node::Add(offset, v, mut d) => {
+                let mut value = v;
+                let range = i64::from(context.cell_size.mask()).min(i64::from(i32::MAX));
+                while let Some(&Node::Add(o, v, ref next)) = nodes.front() {
+                    if o != offset {
+                        break;
+                    }
+                    if context.strict
+                        && ((value < 0) != (v < 0)
+                            || (i64::from(value) + i64::from(v)).abs() > range)
+                    {
+                        break;
+                    }
+                    value = value.wrapping_add(v);
+                    d.span = d.span.to(next.span);
+                    nodes.pop_front();
+                }
+                if value != 0 {
+                    output.push_back(Node::Add(offset, value, d));
+                }
+            }
+            Node::Input(d) => {
+                output.push_back(Node::Input(d));
+            }
+            Node::Output(d) => {
+                output.push_back(Node::Output(d));
+            }
+            Node::Debug(d) => {
+                output.push_back(Node::Debug(d));
+            }
+            Node::Random(d) => {
+                output.push_back(Node::Random(d));
+            }
+            Node::PrintNumber(d) => {
+                output.push_back(Node::PrintNumber(d));
+            }
+            Node::ReadNumber(d) => {
+                output.push_back(Node::ReadNumber(d));
+            }
+            Node::Set(v, d) => {
+                output.push_back(Node::Set(v, d));
+            }
+            Node::MulAdd(offset, factor, d) => {
+                output.push_back(Node::MulAdd(offset, factor, d));
+            }
+            Node::Product(offset, source, factor, d) => {
+                output.push_back(Node::Product(offset, source, factor, d));
+            }
+            Node::Scan(step, d) => {
+                output.push_back(Node::Scan(step, d));
+            }
+            Node::Write(bytes, d) => {
+                output.push_back(Node::Write(bytes, d));
+            }
+        }
+    }
+    opt_nodes.append(&mut output);
+}
+
+/// Replaces loops that step the current cell by one and add multiples of it to
+/// nearby cells, returning to where they started, such as `[->+>++<<]`, with a
+/// `MulAdd` for each nearby cell followed by `Set(0)`.
+fn mul_loops(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, context: &PassContext) {
+    rewrite_loops(opt_nodes, nodes, |body, d, output| {
+        // Strict mode needs the body's `Add`s to check for overflow.
+        if context.strict {
+            return output.push_back(Node::Loop(body, d));
+        }
+        // The total change to each cell, by offset from the starting cell.
+        let mut deltas: Vec<(isize, u32)> = Vec::new();
+        let mut offset = 0isize;
+        for node in &body {
+            let (at, delta) = match *node {
+                Node::Move(v, _) => {
+                    offset = offset.wrapping_add(v);
+                    continue;
+                }
+                Node::Add(o, v, _) => (offset.wrapping_add(o), v as u32),
+                _ => return output.push_back(Node::Loop(body, d)),
+            };
+            // On a wrapping tape a target may be the loop's own cell.
+            if at != 0 && context.aliases_current(at) {
+                return output.push_back(Node::Loop(body, d));
+            }
+            match deltas.iter_mut().find(|&&mut (o, _)| o == at) {
+                Some(&mut (_, ref mut total)) => *total = total.wrapping_add(delta),
+                None => deltas.push((at, delta)),
+            }
+        }
+        let step = deltas
+            .iter()
+            .find(|&&(o, _)| o == 0)
+            .map_or(0, |&(_, delta)| delta);
+        if offset != 0 || (step != 1 && step != u32::MAX) {
+            return output.push_back(Node::Loop(body, d));
+        }
+        // Stepping down by one runs the body once per unit of the cell; stepping
+        // up runs it once per unit of the cell's negation.
+        for (offset, delta) in deltas {
+            if offset != 0 && delta != 0 {
+                let factor = if step == 1 {
+                    delta.wrapping_neg()
+                } else {
+                    delta
+                };
+                output.push_back(Node::MulAdd(offset, factor, d.clone()));
+            }
+        }
+        output.push_back(Node::Set(0, d));
+    });
+}
+
+/// Replaces loops that step the current cell by one and, on each run, add
+/// multiples of cells they leave alone to other cells, such as the nested
+/// multiplication `[>[->+>+<<]>>[-<<+>>]<<<-]`. The first run is kept, inside
+/// an `If`, since it may start with temporaries the body then clears; the rest
+/// become a `Product` or `MulAdd` for each addition. Needs `mul-loops` to have
+/// rewritten the inner loops first.
+fn nested_mul(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, context: &PassContext) {
+    rewrite_loops(
+        opt_nodes,
+        nodes,
+        |mut body, d, output| match reduce_nested_mul(&body, &d, context) {
+            Some(mut reduced) => {
+                body.append(&mut reduced);
+                output.push_back(Node::If(body, d));
+            }
+            None => output.push_back(Node::Loop(body, d)),
+        },
+    );
+}
+
+/// A cell's value after one run of a loop body, as a constant plus multiples
+/// of the values cells held when the run started, all wrapped to the cell width.
+#[derive(Clone, PartialEq)]
+struct Linear {
+    constant: u32,
+    terms: BTreeMap<isize, u32>,
+}
+
+impl Linear {
+    fn cell(offset: isize) -> Linear {
+        let mut terms = BTreeMap::new();
+        terms.insert(offset, 1);
+        Linear { constant: 0, terms }
+    }
+
+    fn constant(constant: u32) -> Linear {
+        Linear {
+            constant,
+            terms: BTreeMap::new(),
+        }
+    }
+
+    fn coefficient(&self, offset: isize) -> u32 {
+        self.terms.get(&offset).map_or(0, |&c| c)
+    }
+
+    /// Adds `other` times `factor`.
+    fn add(&mut self, other: &Linear, factor: u32, mask: u32) {
+        self.constant = self
+            .constant
+            .wrapping_add(other.constant.wrapping_mul(factor))
+            & mask;
+        for (&offset, &c) in &other.terms {
+            let sum = self
+                .coefficient(offset)
+                .wrapping_add(c.wrapping_mul(factor))
+                & mask;
+            if sum == 0 {
+                self.terms.remove(&offset);
+            } else {
+                self.terms.insert(offset, sum);
+            }
+        }
+    }
+}
+
+/// What one run of a loop `body` leaves in each cell it writes, keyed by wrapped
+/// offset, when the cells in `start` begin with those constants. `None` unless
+/// the body is straight-line arithmetic that ends where it began.
+fn run_linear(
+    body: &VecDeque<Node>,
+    start: &BTreeMap<isize, u32>,
+    context: &PassContext,
+) -> Option<BTreeMap<isize, Linear>> {
+    let mask = context.cell_size.mask();
+    let mut cells: BTreeMap<isize, Linear> = BTreeMap::new();
+    let value = |cells: &BTreeMap<isize, Linear>, at: isize| match cells.get(&at) {
+        Some(cell) => cell.clone(),
+        None => match start.get(&at) {
+            Some(&v) => Linear::constant(v),
+            None => Linear::cell(at),
+        },
+    };
+    let mut pos = 0isize;
+    for node in body {
+        match *node {
+            Node::Move(v, _) => pos = pos.wrapping_add(v),
+            Node::Add(offset, v, _) => {
+                let at = context.wrap(pos.wrapping_add(offset));
+                let mut cell = value(&cells, at);
+                cell.constant = cell.constant.wrapping_add(v as u32) & mask;
+                cells.insert(at, cell);
+            }
+            Node::Set(v, _) => {
+                cells.insert(context.wrap(pos), Linear::constant(v & mask));
+            }
+            Node::MulAdd(offset, factor, _) => {
+                let source = value(&cells, context.wrap(pos));
+                let at = context.wrap(pos.wrapping_add(offset));
+                let mut cell = value(&cells, at);
+                cell.add(&source, factor, mask);
+                cells.insert(at, cell);
+            }
+            _ => return None,
+        }
+    }
+    if pos != 0 {
+        return None;
+    }
+    for (&at, &v) in start {
+        cells.entry(at).or_insert_with(|| Linear::constant(v));
+    }
+    Some(cells)
+}
+
+/// The `nested-mul` rewrite of every run but the first of a loop with `body`,
+/// if it fits.
+fn reduce_nested_mul(
+    body: &VecDeque<Node>,
+    d: &DebugInfo,
+    context: &PassContext,
+) -> Option<VecDeque<Node>> {
+    // Strict mode needs the body's `Add`s to check for overflow.
+    if context.strict {
+        return None;
+    }
+    let mask = context.cell_size.mask();
+    // Cells each run sets to a constant, which later runs start with.
+    let resets: BTreeMap<isize, u32> = run_linear(body, &BTreeMap::new(), context)?
+        .into_iter()
+        .filter(|&(at, ref cell)| at != 0 && cell.terms.is_empty())
+        .map(|(at, cell)| (at, cell.constant))
+        .collect();
+    let cells = run_linear(body, &resets, context)?;
+    let counter = cells.get(&0)?;
+    if counter.terms.len() != 1 || counter.coefficient(0) != 1 {
+        return None;
+    }
+    // Stepping down by one runs the body once per unit of the cell; stepping
+    // up runs it once per unit of the cell's negation.
+    let sign = match counter.constant {
+        1 => mask,
+        step if step == mask => 1,
+        _ => return None,
+    };
+    let unchanged = |at: isize| match cells.get(&at) {
+        Some(cell) => at != 0 && *cell == Linear::cell(at),
+        None => true,
+    };
+    let mut reduced = VecDeque::new();
+    for (&at, cell) in &cells {
+        if at == 0 || unchanged(at) || resets.contains_key(&at) {
+            continue;
+        }
+        // Each run adds the same amount, made of cells no run changes.
+        if cell.coefficient(at) != 1 {
+            return None;
+        }
+        if cell.constant != 0 {
+            let factor = cell.constant.wrapping_mul(sign) & mask;
+            reduced.push_back(Node::MulAdd(at, factor, d.clone()));
+        }
+        for (&source, &c) in &cell.terms {
+            if source == at {
+                continue;
+            }
+            if !unchanged(source) {
+                return None;
+            }
+            let factor = c.wrapping_mul(sign) & mask;
+            reduced.push_back(Node::Product(at, source, factor, d.clone()));
+        }
+    }
+    // Plain multiplication loops are left to `mul-loops`.
+    if !reduced
+        .iter()
+        .any(|node| matches!(*node, Node::Product(..)))
+    {
+        return None;
+    }
+    reduced.push_back(Node::Set(0, d.clone()));
+    Some(reduced)
+}
+
+/// A node with a body, as remembered while the body is being rebuilt.
+enum Block {
+    Loop(DebugInfo),
+    If(DebugInfo),
+}
+
+impl Block {
+    fn rebuild(self, body: VecDeque<Node>) -> Node {
+        match self {
+            Block::Loop(d) => Node::Loop(body, d),
+            Block::If(d) => Node::If(body, d),
+        }
+    }
+}
+
+/// Rebuilds `nodes` into `opt_nodes`, handing each loop to `rewrite` once its
+/// body has been rebuilt, innermost loops first. `rewrite` pushes whatever
+/// should replace the loop. The bodies of `If` nodes are rebuilt the same way,
+/// but the `If` itself is kept.
+fn rewrite_loops<F>(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, mut rewrite: F)
+where
+    F: FnMut(VecDeque<Node>, DebugInfo, &mut VecDeque<Node>),
+{
+    rewrite_indexed_loops(opt_nodes, nodes, |_, body, d, output| {
+        rewrite(body, d, output)
+    });
+}
+
+/// Like `rewrite_loops`, but also hands `rewrite` the index of each loop,
+/// counting nodes in the order a depth-first walk meets them, as
+/// `ValueRanges` does.
+fn rewrite_indexed_loops<F>(
+    opt_nodes: &mut VecDeque<Node>,
+    nodes: &mut VecDeque<Node>,
+    mut rewrite: F,
+) where
+    F: FnMut(usize, VecDeque<Node>, DebugInfo, &mut VecDeque<Node>),
+{
+    // The rest of each enclosing level, its output so far, and the block entered
+    // with its index.
+    let mut outer: Vec<(VecDeque<Node>, VecDeque<Node>, Block, usize)> = Vec::new();
+    let mut nodes = mem::take(nodes);
+    let mut output = VecDeque::new();
+    let mut index = 0;
+    loop {
+        let node = match nodes.pop_front() {
+            Some(node) => node,
+            None => match outer.pop() {
+                Some((rest, enclosing, block, block_index)) => {
+                    let body = mem::replace(&mut output, enclosing);
+                    match block {
+                        Block::Loop(d) => rewrite(block_index, body, d, &mut output),
+                        block => output.push_back(block.rebuild(body)),
+                    }
+                    nodes = rest;
+                    continue;
+                }
+                None => break,
+            },
+        };
+        index += 1;
+        let (body, block) = match node {
+            Node::Loop(body, d) => (body, Block::Loop(d)),
+            Node::If(body, d) => (body, Block::If(d)),
+            node => {
+                output.push_back(node);
+                continue;
+            }
+        };
+        outer.push((
+            mem::replace(&mut nodes, body),
+            mem::take(&mut output),
+            block,
+            index - 1,
+        ));
+    }
+    opt_nodes.append(&mut output);
+}
+
+/// Replaces loops that only step the current cell by an odd amount, such as
+/// `[-]` and `[+]`, with `Set(0)`; any odd step reaches zero eventually. In
+/// strict mode only `[-]` on unsigned cells is replaced, as the others can
+/// overflow on the way.
+fn clear_loops(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, context: &PassContext) {
+    rewrite_loops(opt_nodes, nodes, |body, d, output| {
+        let clears = body.len() == 1
+            && match body[0] {
+                Node::Add(0, -1, _) if context.strict => !context.signed_cells,
+                Node::Add(0, v, _) => v & 1 == 1 && !context.strict,
+                _ => false,
+            };
+        output.push_back(if clears {
+            Node::Set(0, d)
+        } else {
+            Node::Loop(body, d)
+        });
+    });
+}
+
+/// Replaces loops that only move the pointer, such as `[>]` and `[<<]`, with
+/// `Scan`.
+fn scan_loops(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, _: &PassContext) {
+    rewrite_loops(opt_nodes, nodes, |body, d, output| {
+        let step = match (body.len(), body.front()) {
+            (1, Some(&Node::Move(v, _))) => v,
+            _ => 0,
+        };
+        output.push_back(if step != 0 {
+            Node::Scan(step, d)
+        } else {
+            Node::Loop(body, d)
+        });
+    });
+}
+
+/// Folds pointer moves into the offsets of the `Add` nodes that follow them,
+/// moving the pointer once at the end of each straight-line run instead.
+fn offsets(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, _: &PassContext) {
+    let mut output = VecDeque::new();
+    rewrite_loops(&mut output, nodes, |body, d, output| {
+        output.push_back(Node::Loop(fold_offsets(body), d));
+    });
+    opt_nodes.append(&mut fold_offsets(output));
+}
+
+/// The `offsets` pass for a single level of the program.
+fn fold_offsets(nodes: VecDeque<Node>) -> VecDeque<Node> {
+    let mut output = VecDeque::new();
+    // How far the pointer should have moved, and the moves that got it there.
+    let mut pending: Option<(isize, DebugInfo)> = None;
+    for node in nodes {
+        let offset = pending.as_ref().map_or(0, |&(offset, _)| offset);
+        match node {
+            Node::Move(v, mut d) => {
+                if let Some((_, ref moved)) = pending {
+                    d.span = moved.span.to(d.span);
+                }
+                pending = Some((offset.wrapping_add(v), d));
+            }
+            // The moves share the blame if the cell turns out to be off the tape.
+            Node::Add(o, v, mut d) if offset != 0 => {
+                if let Some((_, ref moved)) = pending {
+                    d.span = moved.span.to(d.span);
+                }
+                output.push_back(Node::Add(offset.wrapping_add(o), v, d))
+            }
+            node => {
+                flush_move(&mut output, pending.take());
+                output.push_back(node);
+            }
+        }
+    }
+    flush_move(&mut output, pending);
+    output
+}
+
+/// Pushes the pointer move left over from `fold_offsets`, if it goes anywhere.
+fn flush_move(output: &mut VecDeque<Node>, pending: Option<(isize, DebugInfo)>) {
+    match pending {
+        Some((offset, d)) if offset != 0 => output.push_back(Node::Move(offset, d)),
+        _ => (),
+    }
+}
+
+/// Tracks the values of cells through the program, turning `Add` on a known
+/// cell into `Set`, `MulAdd` from a known cell into `Add`, and `.` of a known
+/// cell into `Write`. Cells a loop never writes keep their values inside and
+/// after it, so reads of them are folded out of inner loops as well.
+fn const_fold(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, context: &PassContext) {
+    let ranges = value_ranges(nodes, context);
+    let mut cells = ranges.cells.into_iter();
+    let mut writes = block_writes(nodes).into_iter();
+    // The rest of each enclosing level, its output so far, the block entered,
+    // and what is known once the block is done.
+    let mut outer: Vec<(VecDeque<Node>, VecDeque<Node>, Block, KnownCells)> = Vec::new();
+    let mut nodes = mem::take(nodes);
+    let mut output = VecDeque::new();
+    let mut known = KnownCells::new(context, context.zeroed_tape);
+    loop {
+        match nodes.pop_front() {
+            Some(node @ Node::Loop(..)) | Some(node @ Node::If(..)) => {
+                cells.next();
+                let written = writes.next().expect("every block has its writes");
+                let mut after = known.clone();
+                after.forget(&written);
+                let (body, block) = match node {
+                    Node::Loop(body, d) => {
+                        known = after.clone();
+                        (body, Block::Loop(d))
+                    }
+                    Node::If(body, d) => (body, Block::If(d)),
+                    _ => unreachable!(),
+                };
+                after.set(0, Some(0));
+                outer.push((
+                    mem::replace(&mut nodes, body),
+                    mem::take(&mut output),
+                    block,
+                    after,
+                ));
+            }
+            Some(node) => {
+                let cell = cells.next().expect("every node has a range");
+                fold_constant(node, cell, &mut known, &mut output);
+            }
+            None => match outer.pop() {
+                Some((rest, enclosing, block, after)) => {
+                    let body = mem::replace(&mut output, enclosing);
+                    output.push_back(block.rebuild(body));
+                    known = after;
+                    nodes = rest;
+                }
+                None => break,
+            },
+        }
+    }
+    opt_nodes.append(&mut output);
+}
+
+/// The cells each `Loop` and `If` in `nodes` may write, in the order they
+/// start, as offsets from the pointer when the block is entered. A block that
+/// can leave the pointer somewhere else writes unknown cells, giving `None`.
+fn block_writes(nodes: &VecDeque<Node>) -> Vec<Option<BTreeSet<isize>>> {
+    let mut writes = Vec::new();
+    // The enclosing levels, each with the index of its block in `writes`, its
+    // pointer position and the cells written so far.
+    let mut outer = Vec::new();
+    let mut iter = nodes.iter();
+    let mut index = 0;
+    let mut pos: isize = 0;
+    let mut written: Option<BTreeSet<isize>> = Some(BTreeSet::new());
+    loop {
+        let cell = match iter.next() {
+            Some(&Node::Loop(ref body, _)) | Some(&Node::If(ref body, _)) => {
+                writes.push(None);
+                let level = (
+                    mem::replace(&mut iter, body.iter()),
+                    mem::replace(&mut index, writes.len() - 1),
+                    mem::replace(&mut pos, 0),
+                    written.replace(BTreeSet::new()),
+                );
+                outer.push(level);
+                continue;
+            }
+            Some(&Node::Move(v, _)) => {
+                pos = pos.wrapping_add(v);
+                continue;
+            }
+            Some(&Node::Add(offset, _, _))
+            | Some(&Node::MulAdd(offset, _, _))
+            | Some(&Node::Product(offset, _, _, _)) => pos.wrapping_add(offset),
+            Some(&Node::Set(..))
+            | Some(&Node::Input(_))
+            | Some(&Node::Random(_))
+            | Some(&Node::ReadNumber(_)) => pos,
+            Some(&Node::Scan(..)) => {
+                written = None;
+                continue;
+            }
+            Some(_) => continue,
+            None => match outer.pop() {
+                Some((rest, enclosing, enclosing_pos, enclosing_written)) => {
+                    let block = if pos == 0 { written } else { None };
+                    writes[index] = block.clone();
+                    iter = rest;
+                    index = enclosing;
+                    pos = enclosing_pos;
+                    written = match (enclosing_written, block) {
+                        (Some(mut cells), Some(block)) => {
+                            cells.extend(block.iter().map(|&cell| cell.wrapping_add(pos)));
+                            Some(cells)
+                        }
+                        _ => None,
+                    };
+                    continue;
+                }
+                None => break,
+            },
+        };
+        if let Some(ref mut written) = written {
+            written.insert(cell);
+        }
+    }
+    writes
+}
+
+/// The cell values `const_fold` knows, before masking to the cell width.
+#[derive(Clone)]
+struct KnownCells<'c> {
+    context: &'c PassContext,
+    /// Values by position, where `None` marks a cell known to be unknown.
+    cells: BTreeMap<isize, Option<i32>>,
+    /// Where the pointer is, relative to where it was when tracking started.
+    pos: isize,
+    /// Whether tracking started at the beginning of a zeroed tape, making every
+    /// cell not in `cells` zero.
+    zeroed: bool,
+}
+
+impl<'c> KnownCells<'c> {
+    fn new(context: &'c PassContext, zeroed: bool) -> KnownCells<'c> {
+        KnownCells {
+            context,
+            cells: BTreeMap::new(),
+            pos: 0,
+            zeroed,
+        }
+    }
+
+    /// The position of the cell `offset` cells from the pointer.
+    fn key(&self, offset: isize) -> isize {
+        self.context.wrap(self.pos.wrapping_add(offset))
+    }
+
+    fn get(&self, offset: isize) -> Option<i32> {
+        let key = self.key(offset);
+        if let Some(&value) = self.cells.get(&key) {
+            return value;
+        }
+        // Only trust zeroes on the starting tape, so folding never hides an out
+        // of bounds access.
+        if self.zeroed && self.context.on_start_tape(key) {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    fn set(&mut self, offset: isize, value: Option<i32>) {
+        let key = self.key(offset);
+        self.cells.insert(key, value);
+    }
+
+    /// Whether the cell `offset` cells from `pos` is surely on the tape, so
+    /// accessing it cannot fail.
+    fn in_bounds(&self, pos: isize, offset: isize) -> bool {
+        // Tracking from the start of the tape is the only way to know where the
+        // pointer really is.
+        self.context.wrap_len.is_some()
+            || (self.zeroed && self.context.on_start_tape(pos.wrapping_add(offset)))
+    }
+
+    /// Forgets the cells in `written`, or every cell if it is `None`.
+    fn forget(&mut self, written: &Option<BTreeSet<isize>>) {
+        match *written {
+            Some(ref written) => {
+                for &offset in written {
+                    self.set(offset, None);
+                }
+            }
+            None => {
+                self.cells.clear();
+                self.zeroed = false;
+            }
+        }
+    }
+
+    /// Learns what running `node` does to the cells.
+    fn update(&mut self, node: &Node) {
+        match *node {
+            Node::Add(offset, v, _) => {
+                let value = self.get(offset).map(|c| c.wrapping_add(v));
+                self.set(offset, value);
+            }
+            Node::Set(v, _) => self.set(0, Some(v as i32)),
+            Node::MulAdd(offset, factor, _) => {
+                let value = match (self.get(0), self.get(offset)) {
+                    (Some(0), target) => target,
+                    (Some(c), Some(t)) => {
+                        Some(t.wrapping_add((c as u32).wrapping_mul(factor) as i32))
+                    }
+                    _ => None,
+                };
+                self.set(offset, value);
+            }
+            Node::Product(offset, source, factor, _) => {
+                let value = match (self.get(0), self.get(source), self.get(offset)) {
+                    (Some(0), _, target) | (_, Some(0), target) => target,
+                    (Some(c), Some(s), Some(t)) => {
+                        let product = (c as u32).wrapping_mul(s as u32).wrapping_mul(factor);
+                        Some(t.wrapping_add(product as i32))
+                    }
+                    _ => None,
+                };
+                self.set(offset, value);
+            }
+            Node::Move(v, _) => self.pos = self.pos.wrapping_add(v),
+            // Only the current cell is known after a loop: it is zero.
+            Node::Loop(..) | Node::If(..) | Node::Scan(..) => {
+                self.cells.clear();
+                self.zeroed = false;
+                self.set(0, Some(0));
+            }
+            Node::Input(_) | Node::Random(_) | Node::ReadNumber(_) => self.set(0, None),
+            Node::Output(_) | Node::Write(..) | Node::Debug(_) | Node::PrintNumber(_) => (),
+        }
+    }
+}
+
+/// Folds `node` into `output` for `const_fold`, learning what it does. `cell`
+/// is what the current cell may hold before it runs.
+fn fold_constant(
+    node: Node,
+    cell: ValueRange,
+    known: &mut KnownCells,
+    output: &mut VecDeque<Node>,
+) {
+    let context = known.context;
+    let node = match node {
+        // Strict mode needs the `Add` to check for overflow, unless it cannot.
+        Node::Add(0, v, d) if context.strict => match cell.value() {
+            Some(c) if context.fits(c, v) => Node::Set(c.wrapping_add(v as u32), d),
+            _ => Node::Add(0, v, d),
+        },
+        Node::Add(0, v, d) => match known.get(0) {
+            Some(c) => Node::Set(c.wrapping_add(v) as u32, d),
+            None => Node::Add(0, v, d),
+        },
+        Node::Set(v, _) if known.get(0) == Some(v as i32) => return,
+        Node::MulAdd(..) if known.get(0) == Some(0) => return,
+        Node::MulAdd(offset, factor, d) if !context.strict => match known.get(0) {
+            Some(c) => Node::Add(offset, (c as u32).wrapping_mul(factor) as i32, d),
+            None => Node::MulAdd(offset, factor, d),
+        },
+        Node::Product(..) if known.get(0) == Some(0) => return,
+        Node::Product(offset, source, factor, d) => match known.get(source) {
+            Some(s) => {
+                let factor = (s as u32).wrapping_mul(factor);
+                return fold_constant(Node::MulAdd(offset, factor, d), cell, known, output);
+            }
+            None => Node::Product(offset, source, factor, d),
+        },
+        // Every cell width writes its low byte.
+        Node::Output(d) => match known.get(0) {
+            Some(c) => return push_write(output, &[c as u8], d, known),
+            None => Node::Output(d),
+        },
+        Node::Write(bytes, d) => return push_write(output, &bytes, d, known),
+        node => node,
+    };
+    known.update(&node);
+    output.push_back(node);
+}
+
+/// Appends `bytes` to the last `Write` in `output`, or starts a new one. The
+/// bytes move back past nodes that can neither fail nor be seen, such as the
+/// stores setting up the next character, so a run of constant output becomes
+/// one `Write`.
+fn push_write(output: &mut VecDeque<Node>, bytes: &[u8], d: DebugInfo, known: &KnownCells) {
+    // Where the pointer was before each node, walking back from the end.
+    let mut pos = known.pos;
+    for node in output.iter_mut().rev() {
+        match *node {
+            Node::Write(ref mut written, ref mut last) => {
+                written.extend_from_slice(bytes);
+                last.span = last.span.to(d.span);
+                return;
+            }
+            Node::Move(v, _) => pos = pos.wrapping_sub(v),
+            // Strict mode may report an overflow.
+            Node::Add(offset, _, _) if !known.context.strict && known.in_bounds(pos, offset) => (),
+            Node::MulAdd(offset, _, _)
+                if known.in_bounds(pos, offset) && known.in_bounds(pos, 0) => {}
+            Node::Product(offset, source, _, _)
+                if known.in_bounds(pos, offset)
+                    && known.in_bounds(pos, source)
+                    && known.in_bounds(pos, 0) => {}
+            Node::Set(..) if known.in_bounds(pos, 0) => (),
+            _ => break,
+        }
+    }
+    output.push_back(Node::Write(bytes.to_vec(), d));
+}
+
+/// Removes stores to the current cell that are overwritten straight away, such
+/// as the `Set(3)` in `Set(3) Set(0)` or an `Add` followed by `Set`, and folds
+/// an `Add` into the `Set` before it.
+fn peephole(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, context: &PassContext) {
+    let mut output = VecDeque::new();
+    rewrite_loops(&mut output, nodes, |body, d, output| {
+        output.push_back(Node::Loop(fold_stores(body, context), d));
+    });
+    opt_nodes.append(&mut fold_stores(output, context));
+}
+
+/// The `peephole` pass for one level of the program.
+fn fold_stores(nodes: VecDeque<Node>, context: &PassContext) -> VecDeque<Node> {
+    let mut output: VecDeque<Node> = VecDeque::new();
+    for node in nodes {
+        match node {
+            Node::Set(v, mut d) => {
+                // Strict mode needs an overwritten `Add` to check for overflow.
+                while let Some(&Node::Set(..)) | Some(&Node::Add(0, _, _)) = output.back() {
+                    if context.strict && matches!(output.back(), Some(&Node::Add(..))) {
+                        break;
+                    }
+                    let popped = output.pop_back().expect("output has a node");
+                    d.span = popped.debug_info().span.to(d.span);
+                }
+                output.push_back(Node::Set(v, d));
+            }
+            Node::Add(0, v, d) if !context.strict => match output.back_mut() {
+                Some(&mut Node::Set(ref mut value, ref mut set)) => {
+                    *value = value.wrapping_add(v as u32);
+                    set.span = set.span.to(d.span);
+                }
+                _ => output.push_back(Node::Add(0, v, d)),
+            },
+            node => output.push_back(node),
+        }
+    }
+    output
+}
+
+/// The most iterations `unroll` will unroll.
+const UNROLL_MAX_TRIPS: u32 = 16;
+
+/// The most nodes `unroll` will turn a single loop into.
+const UNROLL_MAX_NODES: usize = 64;
+
+/// Replaces small loops that run a known number of times with copies of their
+/// body: loops whose counter has a known value and whose straight-line body
+/// returns to the counter and decrements it by one, such as `[>.<-]`.
+fn unroll(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, context: &PassContext) {
+    let mut output = VecDeque::new();
+    rewrite_loops(&mut output, nodes, |body, d, output| {
+        let known = KnownCells::new(context, false);
+        output.push_back(Node::Loop(unroll_level(body, known), d));
+    });
+    let known = KnownCells::new(context, context.zeroed_tape);
+    opt_nodes.append(&mut unroll_level(output, known));
+}
+
+/// The `unroll` pass for one level of the program. Loop bodies must already
+/// have been unrolled.
+fn unroll_level(nodes: VecDeque<Node>, mut known: KnownCells) -> VecDeque<Node> {
+    let mut output = VecDeque::new();
+    for node in nodes {
+        if let Node::Loop(ref body, _) = node {
+            let trips = match known.get(0) {
+                Some(trips) if trips >= 0 && trips as u32 <= UNROLL_MAX_TRIPS => trips as usize,
+                _ => usize::MAX,
+            };
+            if trips.saturating_mul(body.len()) <= UNROLL_MAX_NODES
+                && counts_down(body, known.context)
+            {
+                for _ in 0..trips {
+                    for node in body {
+                        known.update(node);
+                        output.push_back(node.clone());
+                    }
+                }
+                continue;
+            }
+        }
+        known.update(&node);
+        output.push_back(node);
+    }
+    output
+}
+
+/// Whether a loop body is straight-line code that ends where it started, having
+/// decremented the starting cell by exactly one.
+fn counts_down(body: &VecDeque<Node>, context: &PassContext) -> bool {
+    let mut pos = 0isize;
+    let mut step = 0i32;
+    for node in body {
+        match *node {
+            Node::Move(v, _) => pos = pos.wrapping_add(v),
+            Node::Add(offset, v, _) => {
+                if context.aliases_current(pos.wrapping_add(offset)) {
+                    step = step.wrapping_add(v);
+                }
+            }
+            Node::MulAdd(offset, _, _) | Node::Product(offset, _, _, _) => {
+                if context.aliases_current(pos.wrapping_add(offset)) {
+                    return false;
+                }
+            }
+            Node::Set(..) | Node::Input(_) | Node::Random(_) | Node::ReadNumber(_) => {
+                if context.aliases_current(pos) {
+                    return false;
+                }
+            }
+            Node::Output(_) | Node::Write(..) | Node::Debug(_) | Node::PrintNumber(_) => (),
+            Node::Loop(..) | Node::If(..) | Node::Scan(..) => return false,
+        }
+    }
+    pos == 0 && step == -1
+}
+
+/// Removes code that can never run: loops, scans and other nodes that do
+/// nothing when the current cell must be zero, such as a loop right after
+/// another loop, and whatever follows a loop that never exits.
+fn dead_code(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, context: &PassContext) {
+    let ranges = value_ranges(nodes, context);
+    // The rest of each enclosing level, its output so far, the block entered,
+    // and how many nodes were dropped after it.
+    let mut outer: Vec<(VecDeque<Node>, VecDeque<Node>, Block, usize)> = Vec::new();
+    let mut nodes = mem::take(nodes);
+    let mut output = VecDeque::new();
+    let mut index = 0;
+    loop {
+        let node = match nodes.pop_front() {
+            Some(node) => node,
+            None => match outer.pop() {
+                Some((rest, enclosing, block, dropped)) => {
+                    let body = mem::replace(&mut output, enclosing);
+                    output.push_back(block.rebuild(body));
+                    nodes = rest;
+                    index += dropped;
+                    continue;
+                }
+                None => break,
+            },
+        };
+        let cell = ranges.cell(index);
+        index += 1;
+        let (body, block) = match node {
+            Node::Loop(..)
+            | Node::If(..)
+            | Node::Scan(..)
+            | Node::MulAdd(..)
+            | Node::Product(..)
+            | Node::Set(0, _)
+                if cell.is_zero() =>
+            {
+                index += tree_size(&node) - 1;
+                continue;
+            }
+            Node::Loop(body, d) => (body, Block::Loop(d)),
+            Node::If(body, d) => (body, Block::If(d)),
+            node => {
+                output.push_back(node);
+                continue;
+            }
+        };
+        let mut dropped = 0;
+        let endless = cell.is_nonzero() && body.iter().all(|n| keeps_cell(n, context));
+        if matches!(block, Block::Loop(_)) && endless {
+            dropped = nodes.iter().map(tree_size).sum();
+            nodes.clear();
+        }
+        outer.push((
+            mem::replace(&mut nodes, body),
+            mem::take(&mut output),
+            block,
+            dropped,
+        ));
+    }
+    opt_nodes.append(&mut output);
+}
+
+/// How many nodes `node` is made of, counting those in its body.
+fn tree_size(node: &Node) -> usize {
+    let mut size = 0;
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        size += 1;
+        if let Node::Loop(ref body, _) | Node::If(ref body, _) = *node {
+            stack.extend(body.iter());
+        }
+    }
+    size
+}
+
+/// Whether `node` leaves both the pointer and the current cell alone.
+fn keeps_cell(node: &Node, context: &PassContext) -> bool {
+    match *node {
+        Node::Add(offset, _, _) | Node::MulAdd(offset, _, _) | Node::Product(offset, _, _, _) => {
+            !context.aliases_current(offset)
+        }
+        Node::Output(_) | Node::Debug(_) | Node::PrintNumber(_) => true,
+        _ => false,
+    }
+}
+
+/// Replaces loops that can run at most once with `If`: loops whose bodies
+/// always end back on the starting cell after setting it to zero, or that the
+/// value ranges show always leave it zero. Runs late, as the passes before it
+/// leave the bodies of `If` nodes as they are.
+fn if_blocks(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, context: &PassContext) {
+    let ranges = value_ranges(nodes, context);
+    rewrite_indexed_loops(opt_nodes, nodes, |index, body, d, output| {
+        output.push_back(if runs_once(&body, context) || ranges.runs_once(index) {
+            Node::If(body, d)
+        } else {
+            Node::Loop(body, d)
+        });
+    });
+}
+
+/// Whether a loop body ends where it started, on a cell it set to zero and did
+/// not write again.
+fn runs_once(body: &VecDeque<Node>, context: &PassContext) -> bool {
+    let mut pos = 0isize;
+    let mut zeroed = false;
+    for node in body {
+        match *node {
+            Node::Move(v, _) => pos = pos.wrapping_add(v),
+            Node::Set(v, _) => {
+                if context.aliases_current(pos) {
+                    zeroed = v == 0;
+                }
+            }
+            Node::Add(offset, _, _)
+            | Node::MulAdd(offset, _, _)
+            | Node::Product(offset, _, _, _) => {
+                if context.aliases_current(pos.wrapping_add(offset)) {
+                    zeroed = false;
+                }
             }
-            let byte = byte.unwrap();
-            match byte {
-                b'>' => symbols.push_back(Symbol::IncPtr(debug)),
-                b'<' => symbols.push_back(Symbol::DecPtr(debug)),
-                b'+' => symbols.push_back(Symbol::Increment(debug)),
-                b'-' => symbols.push_back(Symbol::Decrement(debug)),
-                b'.' => symbols.push_back(Symbol::Output(debug)),
-                b',' => symbols.push_back(Symbol::Input(debug)),
-                b'[' => symbols.push_back(Symbol::OpenBlock(debug)),
-                b']' => symbols.push_back(Symbol::CloseBlock(debug)),
-                b'\n' => {
-                    line += 1;
-                    column = 0;
+            Node::Input(_) | Node::Random(_) | Node::ReadNumber(_) => {
+                if context.aliases_current(pos) {
+                    zeroed = false;
                 }
-                _ => (),
             }
-            column += 1;
+            Node::Output(_) | Node::Write(..) | Node::Debug(_) | Node::PrintNumber(_) => (),
+            // Every `If` ends where it started, but may write anywhere it reaches.
+            Node::If(..) => zeroed = false,
+            Node::Loop(..) | Node::Scan(..) => return false,
         }
+    }
+    zeroed && context.aliases_current(pos)
+}
 
-        let ast = Ast::parse(symbols);
+/// Hoists loop-invariant stores out of inner loops. In a straight-line body that
+/// ends back on its starting cell, a `Set` of another cell that the body only
+/// reads afterwards stores the same value every iteration, so it runs once
+/// before the loop instead, inside an `If` so it still needs the loop to run.
+fn hoist(opt_nodes: &mut VecDeque<Node>, nodes: &mut VecDeque<Node>, context: &PassContext) {
+    rewrite_loops(opt_nodes, nodes, |body, d, output| {
+        output.push_back(hoist_stores(body, d, context));
+    });
+}
 
-        ast.map(|mut x| {
-            x.optimize();
-            Brainfuck {
-                program: Program::new(x, 100000),
+/// The `hoist` pass for a single loop.
+fn hoist_stores(body: VecDeque<Node>, d: DebugInfo, context: &PassContext) -> Node {
+    let has_debug = body.iter().any(|node| matches!(*node, Node::Debug(_)));
+    if has_debug || balanced_span(&body).is_none() {
+        return Node::Loop(body, d);
+    }
+    // By cell, the index and position of the `Set` that first touches it, or
+    // `None` if the first touch is a read or something else writes it.
+    let mut stores: BTreeMap<isize, Option<(usize, isize)>> = BTreeMap::new();
+    let mut pos = 0isize;
+    for (index, node) in body.iter().enumerate() {
+        let (reads, written) = match *node {
+            Node::Move(v, _) => {
+                pos = pos.wrapping_add(v);
+                continue;
             }
-        })
+            Node::Set(..) => {
+                let cell = context.wrap(pos);
+                let store = if stores.contains_key(&cell) {
+                    None
+                } else {
+                    Some((index, pos))
+                };
+                stores.insert(cell, store);
+                continue;
+            }
+            Node::Add(offset, _, _) => ([None, None], Some(pos.wrapping_add(offset))),
+            Node::MulAdd(offset, _, _) => ([Some(pos), None], Some(pos.wrapping_add(offset))),
+            Node::Product(offset, source, _, _) => (
+                [Some(pos), Some(pos.wrapping_add(source))],
+                Some(pos.wrapping_add(offset)),
+            ),
+            Node::Output(_) | Node::PrintNumber(_) => ([Some(pos), None], None),
+            Node::Input(_) | Node::Random(_) | Node::ReadNumber(_) => ([None, None], Some(pos)),
+            _ => continue,
+        };
+        for &cell in reads.iter().flatten() {
+            stores.entry(context.wrap(cell)).or_insert(None);
+        }
+        if let Some(cell) = written {
+            stores.insert(context.wrap(cell), None);
+        }
     }
-
-    pub fn exec(&'a self) -> Result<(), ExecError<'a>> {
-        self.program.exec()
+    let mut hoisted: Vec<(usize, isize)> = stores
+        .into_iter()
+        .filter(|&(cell, _)| !context.aliases_current(cell))
+        .filter_map(|(_, store)| store)
+        .collect();
+    if hoisted.is_empty() {
+        return Node::Loop(body, d);
     }
+    hoisted.sort_unstable();
 
-    pub fn gen_ir(&self) -> String {
-        self.program.gen_ir()
+    let mut before = VecDeque::new();
+    let mut rest: VecDeque<Node> = VecDeque::new();
+    let mut at = 0isize;
+    let mut next = hoisted.iter().peekable();
+    for (index, node) in body.into_iter().enumerate() {
+        if let Some(&(_, pos)) = next.next_if(|&&(hoist, _)| hoist == index) {
+            let store = node.debug_info().clone();
+            if pos != at {
+                before.push_back(Node::Move(pos.wrapping_sub(at), store.clone()));
+            }
+            before.push_back(node);
+            at = pos;
+            continue;
+        }
+        // Moves either side of a hoisted store now run back to back.
+        if let Node::Move(v, ref moved) = node {
+            if let Some(&mut Node::Move(ref mut total, ref mut last)) = rest.back_mut() {
+                *total = total.wrapping_add(v);
+                last.span = last.span.to(moved.span);
+                continue;
+            }
+        }
+        rest.push_back(node);
     }
+    rest.retain(|node| !matches!(*node, Node::Move(0, _)));
+    if at != 0 {
+        let back = before
+            .back()
+            .expect("a store was hoisted")
+            .debug_info()
+            .clone();
+        before.push_back(Node::Move(at.wrapping_neg(), back));
+    }
+    before.push_back(Node::Loop(rest, d.clone()));
+    Node::If(before, d)
 }
 
-#[derive(Debug)]
-enum Symbol<'a> {
-    IncPtr(DebugInfo<'a>),
-    DecPtr(DebugInfo<'a>),
-    Increment(DebugInfo<'a>),
-    Decrement(DebugInfo<'a>),
-    Output(DebugInfo<'a>),
-    Input(DebugInfo<'a>),
-    OpenBlock(DebugInfo<'a>),
-    CloseBlock(DebugInfo<'a>),
+/// The values a cell may hold, from `lo` to `hi`, as raw cell values before any
+/// sign is applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueRange {
+    pub lo: u32,
+    pub hi: u32,
 }
 
-#[derive(Debug)]
-pub struct DebugInfo<'a> {
-    pub directory: &'a str,
-    pub file: &'a str,
-    pub line: u32,
-    pub column: u32,
+impl ValueRange {
+    pub fn exact(value: u32) -> ValueRange {
+        ValueRange {
+            lo: value,
+            hi: value,
+        }
+    }
+
+    /// Every value a cell of width `mask` can hold.
+    pub fn full(mask: u32) -> ValueRange {
+        ValueRange { lo: 0, hi: mask }
+    }
+
+    /// The only value the cell can hold, if there is just one.
+    pub fn value(self) -> Option<u32> {
+        if self.lo == self.hi {
+            Some(self.lo)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.hi == 0
+    }
+
+    pub fn is_nonzero(self) -> bool {
+        self.lo > 0
+    }
+
+    fn union(self, other: ValueRange) -> ValueRange {
+        ValueRange {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+        }
+    }
+
+    /// Adds `delta` to every value, wrapping at the cell width `mask`. A range
+    /// that wraps only in part becomes the full range.
+    fn add(self, delta: u32, mask: u32) -> ValueRange {
+        let modulus = u64::from(mask) + 1;
+        let delta = u64::from(delta) % modulus;
+        let (lo, hi) = (u64::from(self.lo) + delta, u64::from(self.hi) + delta);
+        if hi < modulus {
+            ValueRange {
+                lo: lo as u32,
+                hi: hi as u32,
+            }
+        } else if lo >= modulus {
+            ValueRange {
+                lo: (lo - modulus) as u32,
+                hi: (hi - modulus) as u32,
+            }
+        } else {
+            ValueRange::full(mask)
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum ParseError<'a> {
-    UnmatchedLoop(DebugInfo<'a>),
+impl fmt::Display for ValueRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value() {
+            Some(value) => write!(f, "{}", value),
+            None => write!(f, "{}..={}", self.lo, self.hi),
+        }
+    }
 }
 
-#[derive(Debug)]
-enum ParseResult<'a> {
-    UnmatchedLoop(DebugInfo<'a>),
-    CloseLoop(DebugInfo<'a>),
-    Ok,
-    Eof,
+/// What `BrainfuckBuilder::analyze` found out about the values of cells.
+/// Nodes are numbered in the order a depth-first walk of the tree meets them.
+#[derive(Debug, Clone)]
+pub struct ValueRanges {
+    /// What the current cell may hold before each node runs.
+    cells: Vec<ValueRange>,
+    /// The loops whose first iteration always leaves the current cell zero.
+    once: BTreeSet<usize>,
 }
 
-#[derive(Debug)]
-enum Node<'a> {
-    Loop(VecDeque<Node<'a>>, DebugInfo<'a>),
-    IncPtr(usize, DebugInfo<'a>),
-    DecPtr(usize, DebugInfo<'a>),
-    Increment(u8, DebugInfo<'a>),
-    Decrement(u8, DebugInfo<'a>),
-    Output(DebugInfo<'a>),
-    Input(DebugInfo<'a>),
+impl ValueRanges {
+    /// The values the current cell may hold when node `index` is about to run.
+    pub fn cell(&self, index: usize) -> ValueRange {
+        self.cells[index]
+    }
+
+    /// Whether node `index` is a loop that never runs more than once.
+    pub fn runs_once(&self, index: usize) -> bool {
+        self.once.contains(&index)
+    }
 }
 
-#[derive(Debug)]
-struct Ast<'a> {
-    nodes: VecDeque<Node<'a>>,
+/// The cell values `value_ranges` knows at one point of the program.
+#[derive(Clone)]
+struct CellRanges<'c> {
+    context: &'c PassContext,
+    /// Ranges by position, like `KnownCells`.
+    cells: BTreeMap<isize, ValueRange>,
+    pos: isize,
+    /// Whether cells not in `cells` are zero if they are on the starting tape.
+    zeroed: bool,
 }
 
-impl<'a> Ast<'a> {
-    fn parse(mut symbols: VecDeque<Symbol<'a>>) -> Result<Ast<'a>, ParseError<'a>> {
-        let mut ast = Ast {
-            nodes: VecDeque::new(),
-        };
+impl<'c> CellRanges<'c> {
+    fn new(context: &'c PassContext, zeroed: bool) -> CellRanges<'c> {
+        CellRanges {
+            context,
+            cells: BTreeMap::new(),
+            pos: 0,
+            zeroed,
+        }
+    }
 
-        while {
-            match Self::add_node(&mut ast.nodes, &mut symbols) {
-                ParseResult::Ok => true,
-                ParseResult::UnmatchedLoop(d) => return Err(ParseError::UnmatchedLoop(d)),
-                ParseResult::CloseLoop(d) => return Err(ParseError::UnmatchedLoop(d)),
-                ParseResult::Eof => false,
-            }
-        } {}
+    fn get(&self, offset: isize) -> ValueRange {
+        self.at(self.context.wrap(self.pos.wrapping_add(offset)))
+    }
 
-        Ok(ast)
+    /// The range of the cell at position `key`.
+    fn at(&self, key: isize) -> ValueRange {
+        match self.cells.get(&key) {
+            Some(&range) => range,
+            None if self.zeroed && self.context.on_start_tape(key) => ValueRange::exact(0),
+            None => ValueRange::full(self.context.cell_size.mask()),
+        }
     }
 
-    fn add_node(
-        nodes: &mut VecDeque<Node<'a>>,
-        symbols: &mut VecDeque<Symbol<'a>>,
-    ) -> ParseResult<'a> {
-        let symbol = symbols.pop_front();
+    fn set(&mut self, offset: isize, range: ValueRange) {
+        let key = self.context.wrap(self.pos.wrapping_add(offset));
+        self.cells.insert(key, range);
+    }
 
-        match symbol {
-            Some(Symbol::OpenBlock(d)) => {
-                let mut loop_body = VecDeque::new();
-                while {
-                    match Self::add_node(&mut loop_body, symbols) {
-                        ParseResult::UnmatchedLoop(d) => {
-                            return ParseResult::UnmatchedLoop(d);
-                        }
-                        ParseResult::CloseLoop(_) => false,
-                        ParseResult::Ok => true,
-                        ParseResult::Eof => {
-                            return ParseResult::UnmatchedLoop(d);
-                        }
-                    }
-                } {}
-                nodes.push_back(Node::Loop(loop_body, d));
-                ParseResult::Ok
-            }
-            Some(Symbol::CloseBlock(d)) => ParseResult::CloseLoop(d),
-            Some(Symbol::IncPtr(d)) => {
-                nodes.push_back(Node::IncPtr(1, d));
-                ParseResult::Ok
+    /// Forgets the cells in `written`, or every cell if it is `None`.
+    fn forget(&mut self, written: &Option<BTreeSet<isize>>) {
+        let full = ValueRange::full(self.context.cell_size.mask());
+        match *written {
+            Some(ref written) => {
+                for &offset in written {
+                    self.set(offset, full);
+                }
             }
-            Some(Symbol::DecPtr(d)) => {
-                nodes.push_back(Node::DecPtr(1, d));
-                ParseResult::Ok
+            None => {
+                self.cells.clear();
+                self.zeroed = false;
             }
-            Some(Symbol::Increment(d)) => {
-                nodes.push_back(Node::Increment(1, d));
-                ParseResult::Ok
+        }
+    }
+
+    /// Narrows the current cell to the values that enter a loop body.
+    fn enter(&mut self) {
+        let cell = self.get(0);
+        if !cell.is_zero() {
+            self.set(
+                0,
+                ValueRange {
+                    lo: cell.lo.max(1),
+                    ..cell
+                },
+            );
+        }
+    }
+
+    /// Widens every cell to also allow what `other`, which must be at the same
+    /// position, allows.
+    fn join(&mut self, other: &CellRanges) {
+        let keys: BTreeSet<isize> = self
+            .cells
+            .keys()
+            .chain(other.cells.keys())
+            .cloned()
+            .collect();
+        for key in keys {
+            let range = self.at(key).union(other.at(key));
+            self.cells.insert(key, range);
+        }
+        self.zeroed = self.zeroed && other.zeroed;
+    }
+
+    /// Learns what running `node`, other than a `Loop` or `If`, does to the
+    /// cells.
+    fn update(&mut self, node: &Node) {
+        let mask = self.context.cell_size.mask();
+        match *node {
+            Node::Move(v, _) => self.pos = self.pos.wrapping_add(v),
+            Node::Add(offset, v, _) => {
+                let range = self.get(offset).add(v as u32, mask);
+                self.set(offset, range);
             }
-            Some(Symbol::Decrement(d)) => {
-                nodes.push_back(Node::Decrement(1, d));
-                ParseResult::Ok
+            Node::Set(v, _) => self.set(0, ValueRange::exact(v & mask)),
+            Node::MulAdd(offset, factor, _) => {
+                let source = self.get(0);
+                if !source.is_zero() {
+                    let range = match source.value() {
+                        Some(c) => self.get(offset).add(c.wrapping_mul(factor), mask),
+                        None => ValueRange::full(mask),
+                    };
+                    self.set(offset, range);
+                }
             }
-            Some(Symbol::Output(d)) => {
-                nodes.push_back(Node::Output(d));
-                ParseResult::Ok
+            Node::Product(offset, source, factor, _) => {
+                let (current, source) = (self.get(0), self.get(source));
+                if !current.is_zero() && !source.is_zero() {
+                    let range = match (current.value(), source.value()) {
+                        (Some(c), Some(s)) => self
+                            .get(offset)
+                            .add(c.wrapping_mul(s).wrapping_mul(factor), mask),
+                        _ => ValueRange::full(mask),
+                    };
+                    self.set(offset, range);
+                }
             }
-            Some(Symbol::Input(d)) => {
-                nodes.push_back(Node::Input(d));
-                ParseResult::Ok
+            Node::Random(_) => self.set(0, ValueRange { lo: 0, hi: 0xff }),
+            Node::Input(_) | Node::ReadNumber(_) => self.set(0, ValueRange::full(mask)),
+            Node::Scan(..) => {
+                self.forget(&None);
+                self.set(0, ValueRange::exact(0));
             }
-            None => ParseResult::Eof,
+            Node::Loop(..)
+            | Node::If(..)
+            | Node::Output(_)
+            | Node::Write(..)
+            | Node::Debug(_)
+            | Node::PrintNumber(_) => (),
         }
     }
 
-    fn optimize(&mut self) {
-        let mut opt_nodes = VecDeque::new();
-        Self::optimize_nodes(&mut opt_nodes, &mut self.nodes);
-        self.nodes = opt_nodes;
+    /// What is known after a block that runs its body at most once, given what
+    /// is known at the end of the body.
+    fn after_once(mut self, end: CellRanges<'c>) -> CellRanges<'c> {
+        let cell = self.get(0);
+        if cell.is_zero() {
+            return self;
+        }
+        if end.pos != self.pos {
+            self.forget(&None);
+            return self;
+        }
+        if cell.is_nonzero() {
+            return end;
+        }
+        self.set(0, ValueRange::exact(0));
+        self.join(&end);
+        self
     }
 
-    fn optimize_nodes(opt_nodes: &mut VecDeque<Node<'a>>, nodes: &mut VecDeque<Node<'a>>) {
-        while let Some(node) = nodes.pop_front() {
-            match node {
-                Node::Loop(mut n, d) => {
-                    let mut loop_body = VecDeque::new();
-                    Self::optimize_nodes(&mut loop_body, &mut n);
-                    opt_nodes.push_back(Node::Loop(loop_body, d));
-                }
-                Node::IncPtr(v, d) => {
-                    let mut value = v;
-                    while let Some(&Node::IncPtr(v, _)) = nodes.front() {
-                        value = value.wrapping_add(v);
-                        nodes.pop_front();
-                    }
-                    opt_nodes.push_back(Node::IncPtr(value, d));
-                }
-                Node::DecPtr(v, d) => {
-                    let mut value = v;
-                    while let Some(&Node::DecPtr(v, _)) = nodes.front() {
-                        value = value.wrapping_add(v);
-                        nodes.pop_front();
-                    }
-                    opt_nodes.push_back(Node::DecPtr(value, d));
-                }
-                Node::Increment(v, d) => {
-                    let mut value = v;
-                    while let Some(&Node::Increment(v, _)) = nodes.front() {
-                        value = value.wrapping_add(v);
-                        nodes.pop_front();
-                    }
-                    opt_nodes.push_back(Node::Increment(value, d));
-                }
-                Node::Decrement(v, d) => {
-                    let mut value = v;
-                    while let Some(&Node::Decrement(v, _)) = nodes.front() {
-                        value = value.wrapping_add(v);
-                        nodes.pop_front();
-                    }
-                    opt_nodes.push_back(Node::Decrement(value, d));
-                }
-                Node::Input(d) => {
-                    opt_nodes.push_back(Node::Input(d));
+    /// What is known after a loop that writes `written`.
+    fn after_loop(mut self, written: &Option<BTreeSet<isize>>) -> CellRanges<'c> {
+        if !self.get(0).is_zero() {
+            self.forget(written);
+            self.set(0, ValueRange::exact(0));
+        }
+        self
+    }
+}
+
+/// How `value_ranges` is walking a block.
+#[derive(Clone, Copy, PartialEq)]
+enum Walk {
+    If,
+    Loop,
+    /// Following only the first iteration of a loop, to see whether it is the
+    /// last.
+    FirstIteration,
+}
+
+/// A block `value_ranges` has entered.
+struct Entered<'n, 'c> {
+    /// The rest of the enclosing level.
+    rest: Iter<'n, Node>,
+    body: &'n VecDeque<Node>,
+    /// The index of the block among all nodes, and among blocks only.
+    index: usize,
+    block: usize,
+    /// What was known before the block.
+    before: CellRanges<'c>,
+    walk: Walk,
+}
+
+/// Finds the values each cell may hold at each point of `nodes`. A loop is
+/// summed up by the cells it may write, except that a loop with no loops inside
+/// is first followed through one iteration, to see whether it always ends there.
+/// So each node is walked at most twice.
+fn value_ranges(nodes: &VecDeque<Node>, context: &PassContext) -> ValueRanges {
+    let writes = block_writes(nodes);
+    let loop_free = loop_free_blocks(nodes);
+    let mut ranges = ValueRanges {
+        cells: Vec::new(),
+        once: BTreeSet::new(),
+    };
+    let mut cells = CellRanges::new(context, context.zeroed_tape);
+    if context.zeroed_start {
+        cells.set(0, ValueRange::exact(0));
+    }
+    let mut outer: Vec<Entered> = Vec::new();
+    let mut iter = nodes.iter();
+    let mut block = 0;
+    let mut first_iteration = false;
+    loop {
+        let node = match iter.next() {
+            Some(node) => node,
+            None => match outer.pop() {
+                Some(Entered {
+                    rest,
+                    body,
+                    index,
+                    block: block_index,
+                    before,
+                    walk,
+                }) => {
+                    let end = mem::replace(&mut cells, CellRanges::new(context, false));
+                    let written = &writes[block_index];
+                    iter = rest;
+                    cells = match walk {
+                        Walk::If => before.after_once(end),
+                        Walk::Loop => before.after_loop(written),
+                        Walk::FirstIteration => {
+                            first_iteration = false;
+                            if end.pos == before.pos && end.get(0).is_zero() {
+                                ranges.once.insert(index);
+                                before.after_once(end)
+                            } else {
+                                // The loop may go round again, so walk it again
+                                // as any loop.
+                                ranges.cells.truncate(index + 1);
+                                block = block_index + 1;
+                                let mut entry = before.clone();
+                                entry.forget(written);
+                                entry.enter();
+                                outer.push(Entered {
+                                    rest: mem::replace(&mut iter, body.iter()),
+                                    body,
+                                    index,
+                                    block: block_index,
+                                    before,
+                                    walk: Walk::Loop,
+                                });
+                                entry
+                            }
+                        }
+                    };
+                    continue;
                 }
-                Node::Output(d) => {
-                    opt_nodes.push_back(Node::Output(d));
+                None => break,
+            },
+        };
+        let index = ranges.cells.len();
+        ranges.cells.push(cells.get(0));
+        let (body, walk) = match *node {
+            Node::If(ref body, _) => (body, Walk::If),
+            Node::Loop(ref body, _) if !first_iteration && loop_free[block] => {
+                (body, Walk::FirstIteration)
+            }
+            Node::Loop(ref body, _) => (body, Walk::Loop),
+            _ => {
+                cells.update(node);
+                continue;
+            }
+        };
+        let mut entry = cells.clone();
+        if walk == Walk::Loop {
+            entry.forget(&writes[block]);
+        }
+        entry.enter();
+        first_iteration |= walk == Walk::FirstIteration;
+        let before = mem::replace(&mut cells, entry);
+        outer.push(Entered {
+            rest: mem::replace(&mut iter, body.iter()),
+            body,
+            index,
+            block,
+            before,
+            walk,
+        });
+        block += 1;
+    }
+    ranges
+}
+
+/// Whether the body of each `Loop` or `If` in `nodes`, in the order of
+/// `block_writes`, holds no loops or scans, however deep.
+fn loop_free_blocks(nodes: &VecDeque<Node>) -> Vec<bool> {
+    let mut free = Vec::new();
+    // An iterator over each level entered, and the index of its block.
+    let mut levels = vec![(nodes.iter(), None)];
+    while !levels.is_empty() {
+        let last = levels.len() - 1;
+        let node = match levels[last].0.next() {
+            Some(node) => node,
+            None => {
+                let (_, block) = levels.pop().expect("levels is not empty");
+                // A block holding a loop or scan makes its enclosing blocks
+                // hold one too.
+                if let (Some(block), Some(&(_, Some(outer)))) = (block, levels.last()) {
+                    free[outer] = free[outer] && free[block];
                 }
+                continue;
+            }
+        };
+        if let Some(outer) = levels[last].1 {
+            if let Node::Loop(..) | Node::Scan(..) = *node {
+                free[outer] = false;
             }
         }
+        if let Node::Loop(ref body, _) | Node::If(ref body, _) = *node {
+            levels.push((body.iter(), Some(free.len())));
+            free.push(true);
+        }
     }
+    free
 }
 
-struct Program<'a> {
-    ast: Ast<'a>,
-    mem_size: usize,
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Clone)]
+pub(crate) struct Program {
+    pub(crate) ast: Ast,
+    pub(crate) mem_size: usize,
+    pub(crate) start_ptr: usize,
+    pub(crate) tape_mode: TapeMode,
+    pub(crate) cell_size: CellSize,
+    pub(crate) signed_cells: bool,
+    pub(crate) eof: EofMode,
+    pub(crate) strict: bool,
+    pub(crate) seed: Option<u64>,
+    pub(crate) exit_cell: bool,
+    pub(crate) tape_init: Vec<u8>,
+    pub(crate) tape_init_offset: usize,
+    pub(crate) max_mem: Option<usize>,
 }
 
-struct ProgramState {
-    ptr: usize,
-    mem: Vec<u8>,
-    mem_size: usize,
+impl Program {
+    /// How many cells a growing tape adds beyond the one the pointer reached.
+    pub(crate) const GROW_CHUNK: usize = 64 * 1024;
+
+    /// Reported by compiled programs that exceed their memory limit.
+    pub(crate) const MEM_LIMIT_MESSAGE: &'static str = "error: tape memory limit exceeded\n";
+
+    /// The `tape_init` bytes that land on the tape.
+    pub(crate) fn tape_init_data(&self) -> &[u8] {
+        let data = &self.tape_init[..];
+        if self.tape_mode == TapeMode::Grow {
+            return data;
+        }
+        let room = self.mem_size.saturating_sub(self.tape_init_offset);
+        &data[..data.len().min(room)]
+    }
+}
+
+/// The xorshift64 state for `seed`, which must never be zero.
+pub(crate) fn rng_state(seed: u64) -> u64 {
+    seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1
+}
+
+/// Advances a xorshift64 state, returning the next random byte.
+pub(crate) fn next_random(state: &mut u64) -> u8 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    (*state >> 56) as u8
+}
+
+/// The width of each tape cell; cell arithmetic wraps around at this width.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellSize {
+    U8,
+    U16,
+    U32,
 }
 
-impl ProgramState {
-    fn new(mem_size: usize) -> ProgramState {
-        let mut mem = Vec::with_capacity(mem_size);
-        for _ in 0..mem_size {
-            mem.push(0);
+impl CellSize {
+    pub fn from_bits(bits: u32) -> Option<CellSize> {
+        match bits {
+            8 => Some(CellSize::U8),
+            16 => Some(CellSize::U16),
+            32 => Some(CellSize::U32),
+            _ => None,
         }
+    }
 
-        ProgramState {
-            ptr: mem_size / 2,
-            mem: mem,
-            mem_size: mem_size,
+    pub fn bits(self) -> u32 {
+        match self {
+            CellSize::U8 => 8,
+            CellSize::U16 => 16,
+            CellSize::U32 => 32,
         }
     }
 
-    fn is_oob(&self) -> bool {
-        self.ptr >= self.mem_size
+    pub fn bytes(self) -> usize {
+        self.bits() as usize / 8
     }
-}
 
-struct IrState {
-    next_label: i32,
-}
+    /// The largest value a cell can hold.
+    pub fn mask(self) -> u32 {
+        u32::MAX >> (32 - self.bits())
+    }
+
+    /// The smallest value a cell holds, as read by `value`.
+    pub fn min_value(self, signed: bool) -> i64 {
+        if signed {
+            -(1 << (self.bits() - 1))
+        } else {
+            0
+        }
+    }
 
-impl IrState {
-    fn new() -> IrState {
-        IrState { next_label: 0 }
+    /// The largest value a cell holds, as read by `value`.
+    pub fn max_value(self, signed: bool) -> i64 {
+        if signed {
+            (1 << (self.bits() - 1)) - 1
+        } else {
+            i64::from(self.mask())
+        }
     }
 
-    fn ident(&mut self) -> String {
-        self.next_label += 1;
-        format!("%i{}", self.next_label)
+    /// Reads a raw cell as a number, sign-extending it if `signed`.
+    pub fn value(self, raw: u32, signed: bool) -> i64 {
+        if signed {
+            let shift = 32 - self.bits();
+            i64::from(((raw << shift) as i32) >> shift)
+        } else {
+            i64::from(raw)
+        }
     }
 
-    fn label(&mut self) -> String {
-        self.next_label += 1;
-        format!("l{}", self.next_label)
+    pub(crate) fn ir_type(self) -> &'static str {
+        match self {
+            CellSize::U8 => "i8",
+            CellSize::U16 => "i16",
+            CellSize::U32 => "i32",
+        }
     }
 }
 
-pub enum ExecError<'a> {
-    OutOfBounds(&'a DebugInfo<'a>),
+/// What `,` stores once input is exhausted.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EofMode {
+    Zero,
+    /// All bits set, like C's `getchar` returning -1.
+    MinusOne,
+    /// The cell keeps its previous value.
+    Unchanged,
 }
 
-impl<'a> Program<'a> {
-    fn new(ast: Ast<'a>, mem_size: usize) -> Program<'a> {
-        let prog = Program {
-            ast: ast,
-            mem_size: mem_size,
-        };
+/// What happens when the pointer moves past the end of the tape.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapeMode {
+    /// Accessing a cell off either end raises `ExecError::OutOfBounds`.
+    Fixed,
+    /// The tape extends whenever the pointer walks past either end. Growing to
+    /// the left shifts existing cells, so indices reported by the interpreter
+    /// can change as the program runs.
+    Grow,
+    /// The pointer wraps around modulo the tape length, so it never leaves the tape.
+    Wrap,
+}
+
+#[derive(Debug)]
+pub enum ExecError {
+    OutOfBounds(DebugInfo),
+    /// A cell was incremented past its largest value in strict mode.
+    Overflow(DebugInfo),
+    /// A cell was decremented below its smallest value in strict mode.
+    Underflow(DebugInfo),
+    /// A run exceeded its `Limits`, while executing the loop or node given.
+    LimitExceeded(DebugInfo),
+    /// A run tried to write more than `Limits::max_output` bytes.
+    OutputLimitExceeded(DebugInfo),
+    /// A growing tape needed more than its `max_mem` bytes.
+    MemoryLimitExceeded(DebugInfo),
+    /// A run was stopped through `Limits::interrupt`.
+    Interrupted(DebugInfo),
+}
 
-        prog
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecError::OutOfBounds(ref d) => write!(f, "out of bounds access --> {}", d),
+            ExecError::Overflow(ref d) => write!(f, "cell overflow --> {}", d),
+            ExecError::Underflow(ref d) => write!(f, "cell underflow --> {}", d),
+            ExecError::LimitExceeded(ref d) => write!(f, "execution limit exceeded --> {}", d),
+            ExecError::OutputLimitExceeded(ref d) => write!(f, "output limit exceeded --> {}", d),
+            ExecError::MemoryLimitExceeded(ref d) => {
+                write!(f, "tape memory limit exceeded --> {}", d)
+            }
+            ExecError::Interrupted(ref d) => write!(f, "interrupted --> {}", d),
+        }
     }
+}
 
-    fn exec(&'a self) -> Result<(), ExecError<'a>> {
-        let mut state = ProgramState::new(self.mem_size);
-        Self::exec_nodes(&mut state, &self.ast.nodes)
+impl ExecError {
+    pub fn span(&self) -> Span {
+        match *self {
+            ExecError::OutOfBounds(ref d)
+            | ExecError::Overflow(ref d)
+            | ExecError::Underflow(ref d)
+            | ExecError::LimitExceeded(ref d)
+            | ExecError::OutputLimitExceeded(ref d)
+            | ExecError::MemoryLimitExceeded(ref d)
+            | ExecError::Interrupted(ref d) => d.span,
+        }
     }
+}
 
-    fn exec_nodes(
-        state: &mut ProgramState,
-        nodes: &'a VecDeque<Node<'a>>,
-    ) -> Result<(), ExecError<'a>> {
-        for node in nodes {
-            match node {
-                &Node::IncPtr(v, _) => state.ptr = state.ptr.wrapping_add(v),
-                &Node::DecPtr(v, _) => state.ptr = state.ptr.wrapping_sub(v),
-                &Node::Increment(v, ref d) => {
-                    if state.is_oob() {
-                        return Err(ExecError::OutOfBounds(d));
-                    }
-                    let val = state.mem[state.ptr].wrapping_add(v);
-                    state.mem[state.ptr] = val;
-                }
-                &Node::Decrement(v, ref d) => {
-                    if state.is_oob() {
-                        return Err(ExecError::OutOfBounds(d));
-                    }
-                    let val = state.mem[state.ptr].wrapping_sub(v);
-                    state.mem[state.ptr] = val;
-                }
-                &Node::Output(ref d) => {
-                    if state.is_oob() {
-                        return Err(ExecError::OutOfBounds(d));
-                    }
-                    print!("{}", state.mem[state.ptr] as char)
-                }
-                &Node::Input(ref d) => {
-                    let val = unsafe { libc::getchar() };
-                    if state.is_oob() {
-                        return Err(ExecError::OutOfBounds(d));
-                    }
-                    state.mem[state.ptr] = val as u8;
-                }
-                &Node::Loop(ref nodes, ref d) => {
-                    if state.is_oob() {
-                        return Err(ExecError::OutOfBounds(d));
-                    }
-                    while state.mem[state.ptr] != 0 {
-                        match Self::exec_nodes(state, nodes) {
-                            Err(e) => return Err(e),
-                            _ => (),
-                        }
-                        if state.is_oob() {
-                            return Err(ExecError::OutOfBounds(d));
-                        }
-                    }
+#[cfg(feature = "std")]
+impl Error for ExecError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `source` with the passes of each optimization level in turn,
+    /// returning the output or error of each run.
+    fn run_levels(builder: &BrainfuckBuilder, source: &str) -> Vec<Result<Vec<u8>, ExecError>> {
+        (0..=3)
+            .map(|level| {
+                let passes = opt_level_passes(level).expect("levels 0 to 3 exist");
+                let program = builder.clone().passes(&passes).parse_str(source).unwrap();
+                let mut io = CaptureIo(Vec::new());
+                program.exec_with_io(&mut io).map(|()| io.0)
+            })
+            .collect()
+    }
+
+    /// Parses `source` with just the named passes, describing the tree they
+    /// leave like `Add(0, 2) Loop(Move(1))`.
+    fn optimize(builder: &BrainfuckBuilder, passes: &[&str], source: &str) -> String {
+        let passes: Vec<_> = passes
+            .iter()
+            .map(|&name| find_pass(name).unwrap())
+            .collect();
+        let program = builder.clone().passes(&passes).parse_str(source).unwrap();
+        describe(&program.ast().nodes)
+    }
+
+    fn describe(nodes: &VecDeque<Node>) -> String {
+        let nodes: Vec<String> = nodes
+            .iter()
+            .map(|node| match *node {
+                Node::Loop(ref body, _) => format!("Loop({})", describe(body)),
+                Node::If(ref body, _) => format!("If({})", describe(body)),
+                Node::Move(v, _) => format!("Move({})", v),
+                Node::Add(offset, v, _) => format!("Add({}, {})", offset, v),
+                Node::Set(v, _) => format!("Set({})", v),
+                Node::MulAdd(offset, factor, _) => format!("MulAdd({}, {})", offset, factor),
+                Node::Product(offset, source, factor, _) => {
+                    format!("Product({}, {}, {})", offset, source, factor)
                 }
+                Node::Scan(step, _) => format!("Scan({})", step),
+                Node::Write(ref bytes, _) => format!("Write({:?})", bytes),
+                Node::Output(_) => "Output".into(),
+                Node::Input(_) => "Input".into(),
+                Node::Debug(_) => "Debug".into(),
+                Node::Random(_) => "Random".into(),
+                Node::PrintNumber(_) => "PrintNumber".into(),
+                Node::ReadNumber(_) => "ReadNumber".into(),
+            })
+            .collect();
+        nodes.join(" ")
+    }
+
+    #[test]
+    fn coalesce_merges_runs() {
+        let builder = Brainfuck::builder();
+        let passes = &["coalesce"];
+        assert_eq!(optimize(&builder, passes, "+++--"), "Add(0, 1)");
+        assert_eq!(optimize(&builder, passes, "+-><"), "");
+        assert_eq!(
+            optimize(&builder, passes, "[++>>>]+.+"),
+            "Loop(Add(0, 2) Move(3)) Add(0, 1) Output Add(0, 1)"
+        );
+        // Strict mode keeps apart changes whose sum would hide an overflow.
+        let strict = builder.strict(true);
+        assert_eq!(optimize(&strict, passes, "++--"), "Add(0, 2) Add(0, -2)");
+        let source = "+".repeat(300);
+        assert_eq!(optimize(&strict, passes, &source), "Add(0, 255) Add(0, 45)");
+    }
+
+    #[test]
+    fn strict_coalesced_runs_overflow() {
+        let builder = Brainfuck::builder().strict(true);
+        for result in run_levels(&builder, "-+") {
+            assert!(
+                matches!(result, Err(ExecError::Underflow(_))),
+                "{:?}",
+                result
+            );
+        }
+        let source = format!("{}-", "+".repeat(256));
+        for result in run_levels(&builder, &source) {
+            assert!(
+                matches!(result, Err(ExecError::Overflow(_))),
+                "{:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn clear_loops_match_odd_steps() {
+        let builder = Brainfuck::builder();
+        let passes = &["coalesce", "clear-loops"];
+        assert_eq!(
+            optimize(&builder, passes, "[-][+][---][--]"),
+            "Set(0) Set(0) Set(0) Loop(Add(0, -2))"
+        );
+        assert_eq!(
+            optimize(&builder, passes, "[->]"),
+            "Loop(Add(0, -1) Move(1))"
+        );
+        // Odd steps reach zero whatever the cell width, wrapping on the way.
+        for &cell_size in &[CellSize::U8, CellSize::U16, CellSize::U32] {
+            let builder = builder.clone().cell_size(cell_size);
+            assert_eq!(optimize(&builder, passes, "[---]"), "Set(0)");
+        }
+        for &cell_size in &[CellSize::U8, CellSize::U16] {
+            let builder = builder.clone().cell_size(cell_size);
+            for result in run_levels(&builder, "+[---]+.") {
+                assert_eq!(result.unwrap(), [1]);
             }
         }
+    }
 
-        Ok(())
+    #[test]
+    fn mul_loops_match_balanced_unit_steps() {
+        let builder = Brainfuck::builder();
+        let passes = &["coalesce", "mul-loops"];
+        assert_eq!(
+            optimize(&builder, passes, "[->+>++<<]"),
+            "MulAdd(1, 1) MulAdd(2, 2) Set(0)"
+        );
+        assert_eq!(optimize(&builder, passes, "[>+<-]"), "MulAdd(1, 1) Set(0)");
+        assert_eq!(
+            optimize(&builder, passes, "[-[->+<]]"),
+            "Loop(Add(0, -1) MulAdd(1, 1) Set(0))"
+        );
+        for source in &["[-->+<]", "[->+]", "[->.<]"] {
+            let tree = optimize(&builder, passes, source);
+            assert!(!tree.contains("MulAdd"), "{} => {}", source, tree);
+        }
+        assert_eq!(
+            optimize(&builder.clone().strict(true), passes, "[->+<]"),
+            "Loop(Add(0, -1) Move(1) Add(0, 1) Move(-1))"
+        );
+        // On a tape of two cells, two cells over is the loop's own cell.
+        let wrapping = builder.tape_mode(TapeMode::Wrap).mem_size(2);
+        assert_eq!(
+            optimize(&wrapping, passes, "[->>+<<]"),
+            "Loop(Add(0, -1) Move(2) Add(0, 1) Move(-2))"
+        );
+    }
+
+    #[test]
+    fn mul_loops_count_up_to_the_cell_width() {
+        // Stepping up from -1 runs the body once, adding -2 at the cell width.
+        let extensions = Extensions {
+            numeric_io: true,
+            ..Extensions::default()
+        };
+        let builder = Brainfuck::builder().extensions(extensions);
+        let cases = [
+            (CellSize::U8, false, "254\n"),
+            (CellSize::U8, true, "-2\n"),
+            (CellSize::U16, false, "65534\n"),
+            (CellSize::U32, false, "4294967294\n"),
+        ];
+        for &(cell_size, signed, expected) in &cases {
+            let builder = builder.clone().cell_size(cell_size).signed_cells(signed);
+            for result in run_levels(&builder, "-[+>--<]>:") {
+                assert_eq!(result.unwrap(), expected.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn nested_mul_matches_nested_multiplication() {
+        let builder = Brainfuck::builder();
+        let passes = &["coalesce", "mul-loops", "nested-mul"];
+        let source = "[>[->+>+<<]>>[-<<+>>]<<<-]";
+        let tree = optimize(&builder, passes, source);
+        assert!(tree.starts_with("If("), "{}", tree);
+        assert!(tree.ends_with("Product(2, 1, 1) Set(0))"), "{}", tree);
+        let tree = optimize(&builder.clone().strict(true), passes, source);
+        assert!(!tree.contains("Product"), "{}", tree);
+        let source = format!("+++>++++<{}>>.", source);
+        for result in run_levels(&builder, &source) {
+            assert_eq!(result.unwrap(), [12]);
+        }
     }
 
-    fn gen_ir(&self) -> String {
-        let mut ir = String::new();
-        let mut ir_state = IrState::new();
-        let prelude = format!(
-            r"
-@mem = private global [{} x i8] zeroinitializer
-define void @_start() {{
-    %ptr = alloca i64
-    store atomic volatile i64 {}, i64* %ptr monotonic, align 1",
-            self.mem_size,
-            self.mem_size / 2
+    #[test]
+    fn scan_loops_match_moves() {
+        let builder = Brainfuck::builder();
+        let passes = &["coalesce", "scan-loops"];
+        assert_eq!(
+            optimize(&builder, passes, "[>][<<][>+]"),
+            "Scan(1) Scan(-2) Loop(Move(1) Add(0, 1))"
         );
-        ir.push_str(&prelude);
-        self.gen_ir_nodes(&mut ir, &mut ir_state, &self.ast.nodes);
-        let epilogue = format!(
-            r#"
-    call i64 asm sideeffect "syscall", "=r,{{rax}},{{rdi}}"(i64 60, i64 0)
-    ret void
-}}"#
+    }
+
+    #[test]
+    fn offsets_fold_moves_into_adds() {
+        let builder = Brainfuck::builder();
+        let passes = &["coalesce", "offsets"];
+        assert_eq!(
+            optimize(&builder, passes, ">+>++<.[>+<-]"),
+            "Add(1, 1) Add(2, 2) Move(1) Output Loop(Add(1, 1) Add(0, -1))"
         );
-        ir.push_str(&epilogue);
-        ir
-    }
-
-    fn gen_ir_nodes(&self, ir: &mut String, state: &mut IrState, nodes: &VecDeque<Node>) {
-        for node in nodes {
-            match node {
-                &Node::IncPtr(v, _) => {
-                    let i0 = state.ident();
-                    let i1 = state.ident();
-                    let r = format!(
-                        r"
-    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Increment Pointer
-    {mem_ptr} = add i64 {ptr}, {value}
-    store atomic i64 {mem_ptr}, i64* %ptr monotonic, align 1",
-                        ptr = i0,
-                        mem_ptr = i1,
-                        value = v
-                    );
-                    ir.push_str(&r);
-                }
-                &Node::DecPtr(v, _) => {
-                    let i0 = state.ident();
-                    let i1 = state.ident();
-                    let r = format!(
-                        r"
-    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Decrement Pointer
-    {mem_ptr} = sub i64 {ptr}, {value}
-    store atomic i64 {mem_ptr}, i64* %ptr monotonic, align 1",
-                        ptr = i0,
-                        mem_ptr = i1,
-                        value = v
-                    );
-                    ir.push_str(&r);
-                }
-                &Node::Increment(v, _) => {
-                    let i0 = state.ident();
-                    let i1 = state.ident();
-                    let i2 = state.ident();
-                    let i3 = state.ident();
-                    let r = format!(
-                        r"
-    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Increment
-    {mem_ptr} = getelementptr [{mem_size} x i8], [{mem_size} x i8]* @mem, i64 0, i64 {ptr}
-    {mem_val} = load atomic volatile i8, i8* {mem_ptr} monotonic, align 1
-    {new_mem_val} = add i8 {mem_val}, {value}
-    store atomic volatile i8 {new_mem_val}, i8* {mem_ptr} monotonic, align 1",
-                        mem_size = self.mem_size,
-                        ptr = i0,
-                        mem_ptr = i1,
-                        mem_val = i2,
-                        new_mem_val = i3,
-                        value = v
-                    );
-                    ir.push_str(&r);
-                }
-                &Node::Decrement(v, _) => {
-                    let i0 = state.ident();
-                    let i1 = state.ident();
-                    let i2 = state.ident();
-                    let i3 = state.ident();
-                    let r = format!(
-                        r"
-    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Decrement
-    {mem_ptr} = getelementptr [{mem_size} x i8], [{mem_size} x i8]* @mem, i64 0, i64 {ptr}
-    {mem_val} = load atomic volatile i8, i8* {mem_ptr} monotonic, align 1
-    {new_mem_val} = sub i8 {mem_val}, {value}
-    store atomic volatile i8 {new_mem_val}, i8* {mem_ptr} monotonic, align 1",
-                        mem_size = self.mem_size,
-                        ptr = i0,
-                        mem_ptr = i1,
-                        mem_val = i2,
-                        new_mem_val = i3,
-                        value = v
-                    );
-                    ir.push_str(&r);
-                }
-                &Node::Output(_) => {
-                    let i0 = state.ident();
-                    let i1 = state.ident();
-                    let r = format!(
-                        r#"
-    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Output
-    {mem_ptr} = getelementptr [{mem_size} x i8], [{mem_size} x i8]* @mem, i64 0, i64 {ptr}
-    call i64 asm sideeffect "syscall", "=r,{{rax}},{{rdi}},{{rsi}},{{rdx}}"(i64 1, i64 1, i8* {mem_ptr}, i64 1)"#,
-                        mem_size = self.mem_size,
-                        ptr = i0,
-                        mem_ptr = i1,
-                    );
-                    ir.push_str(&r);
-                }
-                &Node::Input(_) => {
-                    let i0 = state.ident();
-                    let i1 = state.ident();
-                    let r = format!(
-                        r#"
-    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Input
-    {mem_ptr} = getelementptr [{mem_size} x i8], [{mem_size} x i8]* @mem, i64 0, i64 {ptr}
-    call i64 asm sideeffect "syscall", "=r,{{rax}},{{rdi}},{{rsi}},{{rdx}}"(i64 0, i64 0, i8* {mem_ptr}, i64 1)"#,
-                        mem_size = self.mem_size,
-                        ptr = i0,
-                        mem_ptr = i1,
-                    );
-                    ir.push_str(&r);
-                }
-                &Node::Loop(ref nodes, _) => {
-                    let i0 = state.ident();
-                    let i1 = state.ident();
-                    let i2 = state.ident();
-                    let i3 = state.ident();
-                    let header = state.label();
-                    let body = state.label();
-                    let end = state.label();
-                    let r = format!(
-                        r"
-    br label %{header} ; Loop
-{header}:
-    {ptr} = load atomic i64, i64* %ptr monotonic, align 1
-    {mem_ptr} = getelementptr [{mem_size} x i8], [{mem_size} x i8]* @mem, i64 0, i64 {ptr}
-    {mem_val} = load atomic volatile i8, i8* {mem_ptr} monotonic, align 1
-    {comp} = icmp eq i8 0, {mem_val}
-    br i1 {comp}, label %{end}, label %{body}
-{body}:",
-                        ptr = i0,
-                        mem_size = self.mem_size,
-                        mem_ptr = i1,
-                        mem_val = i2,
-                        comp = i3,
-                        header = header,
-                        body = body,
-                        end = end
-                    );
-                    ir.push_str(&r);
-                    self.gen_ir_nodes(ir, state, nodes);
-                    let r = format!(
-                        r"
-    br label %{header}
-{end}:",
-                        header = header,
-                        end = end
-                    );
-                    ir.push_str(&r);
-                }
+    }
+
+    #[test]
+    fn const_fold_tracks_known_cells() {
+        let builder = Brainfuck::builder();
+        let passes = &["coalesce", "const-fold"];
+        assert_eq!(
+            optimize(&builder, passes, "+++.>++.<,."),
+            "Set(3) Write([3, 2]) Move(1) Set(2) Move(-1) Input Output"
+        );
+        // Only cells known to start at zero are folded.
+        let initialized = builder.clone().tape_init(&[5]);
+        assert_eq!(optimize(&initialized, passes, "+."), "Add(0, 1) Output");
+        for result in run_levels(&initialized, "+.") {
+            assert_eq!(result.unwrap(), [6]);
+        }
+        // In strict mode an `Add` that may overflow stays to report it.
+        let strict = builder.strict(true);
+        let source = "+".repeat(128);
+        assert_eq!(optimize(&strict, passes, &source), "Set(128)");
+        assert_eq!(optimize(&strict, passes, "-"), "Add(0, -1)");
+        let signed = strict.signed_cells(true);
+        assert_eq!(optimize(&signed, passes, &source), "Add(0, 128)");
+    }
+
+    #[test]
+    fn peephole_drops_overwritten_stores() {
+        let builder = Brainfuck::builder();
+        let passes = &["coalesce", "clear-loops", "peephole"];
+        assert_eq!(
+            optimize(&builder, passes, "+++[-]>[-]+++"),
+            "Set(0) Move(1) Set(3)"
+        );
+        assert_eq!(
+            optimize(&builder.strict(true), passes, "+++[-]>[-]+++"),
+            "Add(0, 3) Set(0) Move(1) Set(0) Add(0, 3)"
+        );
+    }
+
+    #[test]
+    fn unroll_copies_short_counted_loops() {
+        let builder = Brainfuck::builder();
+        let passes = &["coalesce", "unroll"];
+        assert_eq!(
+            optimize(&builder, passes, "++[>.<-]"),
+            "Add(0, 2) Move(1) Output Move(-1) Add(0, -1) Move(1) Output Move(-1) Add(0, -1)"
+        );
+        let many = format!("{}[>.<-]", "+".repeat(UNROLL_MAX_TRIPS as usize + 1));
+        assert!(optimize(&builder, passes, &many).contains("Loop("));
+        assert!(optimize(&builder, passes, ",[>.<-]").contains("Loop("));
+        assert!(optimize(&builder, passes, "++[>.<--]").contains("Loop("));
+    }
+
+    #[test]
+    fn dead_code_drops_unreachable_nodes() {
+        let builder = Brainfuck::builder();
+        let passes = &["coalesce", "dead-code"];
+        assert_eq!(
+            optimize(&builder, passes, "[>+<].+[.]+."),
+            "Output Add(0, 1) Loop(Output)"
+        );
+        assert_eq!(
+            optimize(&builder, passes, "+[-][+]."),
+            "Add(0, 1) Loop(Add(0, -1)) Output"
+        );
+        let initialized = builder.tape_init(&[1]);
+        assert_eq!(optimize(&initialized, passes, "[-]"), "Loop(Add(0, -1))");
+    }
+
+    #[test]
+    fn hoist_moves_invariant_stores_out_of_loops() {
+        let builder = Brainfuck::builder();
+        let passes = &["coalesce", "clear-loops", "hoist"];
+        assert_eq!(
+            optimize(&builder, passes, "[->[-]>>[-].<<<]"),
+            "If(Move(1) Set(0) Move(2) Set(0) Move(-3) Loop(Add(0, -1) Move(3) Output Move(-3)))"
+        );
+        // Cells read before the store, or written again, differ between
+        // iterations, as does the loop's own cell.
+        for source in &["[->.[-]<]", "[->[-]+<]", "[->,[-]<]", "[-[-]+]"] {
+            let tree = optimize(&builder, passes, source);
+            assert!(!tree.contains("If"), "{} => {}", source, tree);
+        }
+        let wrapping = builder.clone().tape_mode(TapeMode::Wrap).mem_size(1);
+        assert_eq!(
+            optimize(&wrapping, passes, "[->[-]<]"),
+            "Loop(Add(0, -1) Move(1) Set(0) Move(-1))"
+        );
+        // The store still only happens if the loop runs.
+        for result in run_levels(&builder, ">+<[>[-]<-]>.<+++[>[-]<-]>.") {
+            assert_eq!(result.unwrap(), [1, 0]);
+        }
+    }
+
+    #[test]
+    fn if_blocks_match_loops_that_run_once() {
+        let builder = Brainfuck::builder();
+        let passes = &["coalesce", "clear-loops", "if-blocks"];
+        assert_eq!(
+            optimize(&builder, passes, "+[>+<[-]]"),
+            "Add(0, 1) If(Move(1) Add(0, 1) Move(-1) Set(0))"
+        );
+        // Value ranges show a cell holding one is only counted down once.
+        assert_eq!(
+            optimize(&builder, passes, "+[>+<-]"),
+            "Add(0, 1) If(Move(1) Add(0, 1) Move(-1) Add(0, -1))"
+        );
+        assert_eq!(
+            optimize(&builder, passes, ",[>+<-]"),
+            "Input Loop(Move(1) Add(0, 1) Move(-1) Add(0, -1))"
+        );
+        // The body clears the cell it ends on, which is not where it started.
+        assert_eq!(
+            optimize(&builder, passes, ",[>[-]]"),
+            "Input Loop(Move(1) Set(0))"
+        );
+    }
+
+    #[test]
+    fn strict_mul_loop_overflows() {
+        let source = format!("+++>{}<[->+<]>.", "+".repeat(254));
+        let builder = Brainfuck::builder().strict(true);
+        for result in run_levels(&builder, &source) {
+            assert!(
+                matches!(result, Err(ExecError::Overflow(_))),
+                "{:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn strict_mul_loop_underflows_signed() {
+        let builder = Brainfuck::builder().strict(true).signed_cells(true);
+        for result in run_levels(&builder, "-[->+<]") {
+            assert!(
+                matches!(result, Err(ExecError::Underflow(_))),
+                "{:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn strict_clear_loop_overflows() {
+        let builder = Brainfuck::builder().strict(true);
+        for result in run_levels(&builder, "+[+]") {
+            assert!(
+                matches!(result, Err(ExecError::Overflow(_))),
+                "{:?}",
+                result
+            );
+        }
+        let builder = builder.signed_cells(true);
+        for result in run_levels(&builder, "-[-]") {
+            assert!(
+                matches!(result, Err(ExecError::Underflow(_))),
+                "{:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn strict_clear_loop_is_set_when_it_cannot_overflow() {
+        let builder = Brainfuck::builder()
+            .strict(true)
+            .passes(&[find_pass("clear-loops").unwrap()]);
+        let program = builder.parse_str("[-]").unwrap();
+        assert!(matches!(program.ast().nodes[0], Node::Set(0, _)));
+        for source in &["[+]", "[---]"] {
+            let program = builder.parse_str(source).unwrap();
+            assert!(matches!(program.ast().nodes[0], Node::Loop(..)));
+        }
+        let program = builder.clone().signed_cells(true).parse_str("[-]").unwrap();
+        assert!(matches!(program.ast().nodes[0], Node::Loop(..)));
+        for result in run_levels(&Brainfuck::builder().strict(true), "+++[-]+.") {
+            assert_eq!(result.unwrap(), [1]);
+        }
+    }
+
+    #[test]
+    fn instruction_limit_stops_empty_loops() {
+        let limits = Limits {
+            max_instructions: Some(100),
+            ..Limits::default()
+        };
+        for level in 0..=3 {
+            let passes = opt_level_passes(level).unwrap();
+            for source in &["+[]", "+[<>]", "+[-+]>"] {
+                let program = Brainfuck::builder()
+                    .passes(&passes)
+                    .parse_str(source)
+                    .unwrap();
+                let mut interpreter = program.interpreter(NullIo);
+                let result = interpreter.run_limited(&limits);
+                assert!(matches!(result, Err(ExecError::LimitExceeded(_))));
+                assert_eq!(interpreter.stats().instructions, 100);
+            }
+        }
+    }
+
+    #[test]
+    fn instruction_limit_stops_inside_fused_nodes() {
+        // Between them, levels 1 and 2 give every pair of nodes the interpreter
+        // runs as one superinstruction.
+        for level in 1..=2 {
+            let passes = opt_level_passes(level).unwrap();
+            let program = Brainfuck::builder()
+                .passes(&passes)
+                .parse_str("++>+++[->++<]>[-]>+>+>+[->+<]<.")
+                .unwrap();
+            let total = program.interpreter(NullIo).run().unwrap().instructions;
+            let mut stepped = program.interpreter(NullIo);
+            while !stepped.is_finished() {
+                stepped.step().unwrap();
+            }
+            assert_eq!(stepped.stats().instructions, total);
+            for max in 0..total {
+                let limits = Limits {
+                    max_instructions: Some(max),
+                    ..Limits::default()
+                };
+                let mut interpreter = program.interpreter(NullIo);
+                let result = interpreter.run_limited(&limits);
+                assert!(matches!(result, Err(ExecError::LimitExceeded(_))));
+                assert_eq!(interpreter.stats().instructions, max);
             }
         }
     }
@@ -0,0 +1,317 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+
+use backend::{Artifact, Backend, MAX_INDENT};
+use brainfuck::{rng_state, Brainfuck, CellSize, EofMode, Node, Program, TapeMode};
+use error::BrainfuckError;
+
+/// Generates portable C that reads and writes through `getchar` and `putchar`,
+/// for any hosted C99 compiler.
+pub struct CSource;
+
+impl Backend for CSource {
+    fn name(&self) -> &'static str {
+        "c"
+    }
+
+    fn lower(&self, program: &Brainfuck) -> Result<Artifact, BrainfuckError> {
+        if program.program.strict {
+            return Err(BrainfuckError::Backend(
+                "strict overflow checking is only supported by the interpreter".into(),
+            ));
+        }
+        if program
+            .program
+            .ast
+            .uses(|node| matches!(*node, Node::PrintNumber(_) | Node::ReadNumber(_)))
+        {
+            return Err(BrainfuckError::Backend(
+                "numeric I/O is only supported by the interpreter".into(),
+            ));
+        }
+        Ok(Artifact::Text(gen_c(&program.program)))
+    }
+}
+
+/// Finds the cell at index `i` on a growing tape, reallocating it to take in
+/// `i` and `Program::GROW_CHUNK` cells beyond if needed.
+const GROW_TAPE: &str = r#"
+static cell *tape;
+/* The indices of the first cell on the tape and one past the last. */
+static long long lo, hi;
+
+static cell *at(long long i) {
+    if (i < lo || i >= hi) {
+        long long new_lo = i < lo ? i - CHUNK : lo;
+        long long new_hi = i >= hi ? i + 1 + CHUNK : hi;
+        cell *grown;
+        if (LIMIT) {
+            if ((i < lo ? hi - i : i + 1 - lo) > LIMIT) {
+                fputs("MESSAGE", stderr);
+                exit(1);
+            }
+            if (new_hi - new_lo > LIMIT) {
+                if (i < lo)
+                    new_lo = hi - LIMIT;
+                else
+                    new_hi = lo + LIMIT;
+            }
+        }
+        grown = calloc((size_t)(new_hi - new_lo), sizeof(cell));
+        if (!grown) {
+            fputs("error: out of memory\n", stderr);
+            exit(1);
+        }
+        if (tape)
+            memcpy(grown + (lo - new_lo), tape, (size_t)(hi - lo) * sizeof(cell));
+        free(tape);
+        tape = grown;
+        lo = new_lo;
+        hi = new_hi;
+    }
+    return &tape[i - lo];
+}
+"#;
+
+/// One xorshift64 step, keeping the top byte, as the interpreter does.
+const NEXT_RANDOM: &str = r"
+static cell next_random(void) {
+    rng ^= rng << 13;
+    rng ^= rng >> 7;
+    rng ^= rng << 17;
+    return (cell)(rng >> 56);
+}
+";
+
+fn c_type(cell_size: CellSize) -> &'static str {
+    match cell_size {
+        CellSize::U8 => "uint8_t",
+        CellSize::U16 => "uint16_t",
+        CellSize::U32 => "uint32_t",
+    }
+}
+
+fn gen_c(program: &Program) -> String {
+    let grow = program.tape_mode == TapeMode::Grow;
+    let random = program.ast.uses(|node| matches!(*node, Node::Random(_)));
+    let mut c = String::from("#include <stdint.h>\n#include <stdio.h>\n");
+    if grow {
+        c.push_str("#include <stdlib.h>\n#include <string.h>\n");
+    }
+    if random && program.seed.is_none() {
+        c.push_str("#include <time.h>\n");
+    }
+    c.push_str(&format!("\ntypedef {} cell;\n", c_type(program.cell_size)));
+    if grow {
+        let limit = program
+            .max_mem
+            .map_or(0, |bytes| (bytes / program.cell_size.bytes()).max(1));
+        c.push_str(
+            &GROW_TAPE
+                .replace("CHUNK", &format!("{}", Program::GROW_CHUNK))
+                .replace("LIMIT", &format!("{}LL", limit))
+                .replace("MESSAGE", &Program::MEM_LIMIT_MESSAGE.replace('\n', "\\n")),
+        );
+    } else {
+        c.push_str(&format!("\nstatic cell mem[{}];\n", program.mem_size));
+    }
+    let data = program.tape_init_data();
+    if !data.is_empty() {
+        c.push_str("\nstatic const unsigned char tape_init[] = {");
+        for (i, b) in data.iter().enumerate() {
+            c.push_str(if i % 12 == 0 { "\n    " } else { " " });
+            c.push_str(&format!("{},", b));
+        }
+        c.push_str("\n};\n");
+    }
+    if random {
+        match program.seed {
+            Some(seed) => c.push_str(&format!(
+                "\nstatic uint64_t rng = {}ULL;\n",
+                rng_state(seed)
+            )),
+            None => c.push_str("\nstatic uint64_t rng;\n"),
+        }
+        c.push_str(NEXT_RANDOM);
+    }
+
+    c.push_str("\nint main(void) {\n");
+    if grow {
+        c.push_str(&format!(
+            "    long long p = {};\n    hi = {};\n    tape = calloc({}, sizeof(cell));\n",
+            program.start_ptr, program.mem_size, program.mem_size
+        ));
+    } else {
+        c.push_str(&format!("    size_t p = {};\n", program.start_ptr));
+    }
+    if !data.is_empty() {
+        c.push_str(&format!(
+            "    for (size_t i = 0; i < sizeof tape_init; i++)\n        {} = tape_init[i];\n",
+            cell_at(program, &format!("{} + i", program.tape_init_offset))
+        ));
+    }
+    if random && program.seed.is_none() {
+        c.push_str("    rng = (uint64_t)time(NULL) * 0x9e3779b97f4a7c15ULL | 1;\n");
+    }
+    gen_c_nodes(program, &mut c, &program.ast.nodes);
+    if program.exit_cell {
+        c.push_str(&format!("    return {} & 255;\n}}\n", cell(program, 0)));
+    } else {
+        c.push_str("    return 0;\n}\n");
+    }
+    c
+}
+
+/// The cell at index `index`, as an lvalue.
+fn cell_at(program: &Program, index: &str) -> String {
+    match program.tape_mode {
+        TapeMode::Fixed | TapeMode::Wrap => format!("mem[{}]", index),
+        TapeMode::Grow => format!("*at({})", index),
+    }
+}
+
+/// `p` plus `offset`, modulo the tape length on a wrapping tape.
+fn index(program: &Program, offset: isize) -> String {
+    if program.tape_mode == TapeMode::Wrap {
+        let offset = offset.rem_euclid(program.mem_size as isize);
+        if offset == 0 {
+            return "p".into();
+        }
+        return format!("(p + {}) % {}", offset, program.mem_size);
+    }
+    match offset {
+        0 => "p".into(),
+        offset if offset < 0 => format!("p - {}", offset.unsigned_abs()),
+        offset => format!("p + {}", offset),
+    }
+}
+
+/// The cell `offset` cells from the pointer, as an lvalue.
+fn cell(program: &Program, offset: isize) -> String {
+    cell_at(program, &index(program, offset))
+}
+
+/// Moves the pointer `offset` cells.
+fn seek(program: &Program, offset: isize) -> String {
+    if program.tape_mode == TapeMode::Wrap {
+        return format!("p = {};", index(program, offset));
+    }
+    if offset < 0 {
+        format!("p -= {};", offset.unsigned_abs())
+    } else {
+        format!("p += {};", offset)
+    }
+}
+
+/// A string literal holding `data`, escaping everything but printable ASCII.
+fn c_string(data: &[u8]) -> String {
+    let mut c = String::from("\"");
+    for &b in data {
+        match b {
+            // `?` is escaped too, so no run of them reads as a trigraph.
+            b'"' | b'\\' | b'?' => c.push_str(&format!("\\{:03o}", b)),
+            b' '..=b'~' => c.push(b as char),
+            _ => c.push_str(&format!("\\{:03o}", b)),
+        }
+    }
+    c.push('"');
+    c
+}
+
+/// Emits `nodes` inside `main`, walking loop bodies with an explicit stack so
+/// nesting depth is limited by memory rather than the call stack.
+fn gen_c_nodes(program: &Program, c: &mut String, nodes: &VecDeque<Node>) {
+    let mask = program.cell_size.mask();
+    // An iterator over each level entered, each indented a step further.
+    let mut levels = vec![nodes.iter()];
+    while let Some(nodes) = levels.last_mut() {
+        let node = match nodes.next() {
+            Some(node) => node,
+            None => {
+                levels.pop();
+                if !levels.is_empty() {
+                    indent(c, levels.len());
+                    c.push_str("}\n");
+                }
+                continue;
+            }
+        };
+        indent(c, levels.len());
+        match *node {
+            Node::Move(v, _) => c.push_str(&seek(program, v)),
+            Node::Add(offset, v, _) => c.push_str(&format!(
+                "{} += {};",
+                cell(program, offset),
+                v as u32 & mask
+            )),
+            Node::Set(v, _) => c.push_str(&format!("{} = {};", cell(program, 0), v & mask)),
+            // Multiplying as `uint32_t` keeps narrow cells from overflowing `int`.
+            Node::MulAdd(offset, factor, _) => c.push_str(&format!(
+                "if ({cur}) {target} += (uint32_t){cur} * {factor}u;",
+                cur = cell(program, 0),
+                target = cell(program, offset),
+                factor = factor & mask
+            )),
+            Node::Product(offset, source, factor, _) => c.push_str(&format!(
+                "if ({cur}) {target} += (uint32_t){cur} * {source} * {factor}u;",
+                cur = cell(program, 0),
+                target = cell(program, offset),
+                source = cell(program, source),
+                factor = factor & mask
+            )),
+            Node::Scan(step, _) => c.push_str(&format!(
+                "while ({}) {}",
+                cell(program, 0),
+                seek(program, step)
+            )),
+            Node::Output(_) => {
+                c.push_str(&format!("putchar((unsigned char){});", cell(program, 0)))
+            }
+            Node::Input(_) => {
+                let stored = match program.eof {
+                    EofMode::Zero => "c == EOF ? 0 : c".into(),
+                    EofMode::MinusOne => format!("c == EOF ? {}u : (unsigned)c", mask),
+                    EofMode::Unchanged => "c".into(),
+                };
+                let guard = if program.eof == EofMode::Unchanged {
+                    "if (c != EOF) "
+                } else {
+                    ""
+                };
+                c.push_str(&format!(
+                    "{{ int c = getchar(); {}{} = {}; }}",
+                    guard,
+                    cell(program, 0),
+                    stored
+                ));
+            }
+            Node::Write(ref bytes, _) => c.push_str(&format!(
+                "fwrite({}, 1, {}, stdout);",
+                c_string(bytes),
+                bytes.len()
+            )),
+            Node::Random(_) => c.push_str(&format!("{} = next_random();", cell(program, 0))),
+            // Compiled programs have nowhere to report to, so `#` is ignored.
+            Node::Debug(_) => c.push_str("/* # */"),
+            Node::PrintNumber(_) | Node::ReadNumber(_) => {
+                unreachable!("numeric I/O is rejected by `CSource::lower`")
+            }
+            Node::Loop(ref body, _) | Node::If(ref body, _) => {
+                let keyword = match *node {
+                    Node::Loop(..) => "while",
+                    _ => "if",
+                };
+                c.push_str(&format!("{} ({}) {{\n", keyword, cell(program, 0)));
+                levels.push(body.iter());
+                continue;
+            }
+        }
+        c.push('\n');
+    }
+}
+
+fn indent(c: &mut String, depth: usize) {
+    for _ in 0..depth.min(MAX_INDENT) {
+        c.push_str("    ");
+    }
+}
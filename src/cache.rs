@@ -0,0 +1,398 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::Arc;
+
+use brainfuck::{Ast, Brainfuck, CellSize, DebugInfo, EofMode, Node, Program, Span, TapeMode};
+use interpreter::{ExecStats, StateSnapshot};
+
+impl Brainfuck {
+    /// Writes the optimized program to `writer`, tagged with `fingerprint`.
+    pub fn write_cache<W>(&self, mut writer: W, fingerprint: u64) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(CACHE_MAGIC);
+        bytes.extend_from_slice(&fingerprint.to_le_bytes());
+        bytes.extend_from_slice(&(self.program.mem_size as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.program.start_ptr as u64).to_le_bytes());
+        bytes.push(self.program.tape_mode as u8);
+        bytes.push(self.program.cell_size.bits() as u8);
+        bytes.push(self.program.signed_cells as u8);
+        bytes.push(self.program.eof as u8);
+        bytes.push(self.program.strict as u8);
+        match self.program.seed {
+            Some(seed) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&seed.to_le_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes.push(self.program.exit_cell as u8);
+        match self.program.max_mem {
+            Some(max_mem) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&(max_mem as u64).to_le_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes.extend_from_slice(&(self.program.tape_init_offset as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.program.tape_init.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.program.tape_init);
+        encode_nodes(&mut bytes, &self.program.ast.nodes);
+        writer.write_all(&bytes)
+    }
+
+    /// Loads a program previously written by `write_cache`. Returns `None` if the
+    /// cache is unreadable or was written for a different `fingerprint`.
+    pub fn read_cache<R>(
+        mut reader: R,
+        file_name: &str,
+        directory: &str,
+        fingerprint: u64,
+    ) -> Option<Brainfuck>
+    where
+        R: Read,
+    {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).ok()?;
+        let mut cache = CacheReader {
+            bytes: &bytes,
+            pos: 0,
+            file_name: file_name.into(),
+            directory: directory.into(),
+        };
+
+        if cache.take(CACHE_MAGIC.len())? != CACHE_MAGIC || cache.u64()? != fingerprint {
+            return None;
+        }
+        let mem_size = cache.u64()? as usize;
+        let start_ptr = cache.u64()? as usize;
+        let tape_mode = match cache.u8()? {
+            0 => TapeMode::Fixed,
+            1 => TapeMode::Grow,
+            2 => TapeMode::Wrap,
+            _ => return None,
+        };
+        let cell_size = CellSize::from_bits(u32::from(cache.u8()?))?;
+        let signed_cells = cache.bool()?;
+        let eof = match cache.u8()? {
+            0 => EofMode::Zero,
+            1 => EofMode::MinusOne,
+            2 => EofMode::Unchanged,
+            _ => return None,
+        };
+        let strict = cache.bool()?;
+        let seed = if cache.bool()? {
+            Some(cache.u64()?)
+        } else {
+            None
+        };
+        let exit_cell = cache.bool()?;
+        let max_mem = if cache.bool()? {
+            Some(cache.u64()? as usize)
+        } else {
+            None
+        };
+        let tape_init_offset = cache.u64()? as usize;
+        let tape_init_len = cache.u64()? as usize;
+        let tape_init = cache.take(tape_init_len)?.to_vec();
+        let nodes = cache.nodes()?;
+        if cache.pos != bytes.len() {
+            return None;
+        }
+
+        Some(Brainfuck {
+            program: Program {
+                ast: Ast { nodes },
+                mem_size,
+                start_ptr,
+                tape_mode,
+                cell_size,
+                signed_cells,
+                eof,
+                strict,
+                seed,
+                exit_cell,
+                tape_init,
+                tape_init_offset,
+                max_mem,
+            },
+        })
+    }
+}
+
+impl StateSnapshot {
+    /// Writes the snapshot to `writer`, tagged with the `fingerprint` of the
+    /// program it was taken from.
+    pub fn write_checkpoint<W>(&self, mut writer: W, fingerprint: u64) -> io::Result<()>
+    where
+        W: Write,
+    {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(CHECKPOINT_MAGIC);
+        bytes.extend_from_slice(&fingerprint.to_le_bytes());
+        bytes.extend_from_slice(&(self.ptr as u64).to_le_bytes());
+        bytes.extend_from_slice(&(self.pc.len() as u64).to_le_bytes());
+        for &index in &self.pc {
+            bytes.extend_from_slice(&(index as u64).to_le_bytes());
+        }
+        for &count in &[
+            self.stats.instructions,
+            self.stats.loop_iterations,
+            self.stats.lowest_cell as u64,
+            self.stats.highest_cell as u64,
+            self.stats.bytes_read,
+            self.stats.bytes_written,
+        ] {
+            bytes.extend_from_slice(&count.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.mem.len() as u64).to_le_bytes());
+        for &cell in &self.mem {
+            bytes.extend_from_slice(&cell.to_le_bytes());
+        }
+        writer.write_all(&bytes)
+    }
+
+    /// Loads a snapshot written by `write_checkpoint`. Returns `None` if it is
+    /// unreadable or was taken from a program with a different `fingerprint`.
+    pub fn read_checkpoint<R>(mut reader: R, fingerprint: u64) -> Option<StateSnapshot>
+    where
+        R: Read,
+    {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).ok()?;
+        let mut checkpoint = CacheReader {
+            bytes: &bytes,
+            pos: 0,
+            file_name: "".into(),
+            directory: "".into(),
+        };
+
+        if checkpoint.take(CHECKPOINT_MAGIC.len())? != CHECKPOINT_MAGIC
+            || checkpoint.u64()? != fingerprint
+        {
+            return None;
+        }
+        let ptr = checkpoint.u64()? as usize;
+        let depth = checkpoint.u64()? as usize;
+        let pc = (0..depth)
+            .map(|_| checkpoint.u64().map(|index| index as usize))
+            .collect::<Option<Vec<usize>>>()?;
+        let stats = ExecStats {
+            instructions: checkpoint.u64()?,
+            loop_iterations: checkpoint.u64()?,
+            lowest_cell: checkpoint.u64()? as usize,
+            highest_cell: checkpoint.u64()? as usize,
+            bytes_read: checkpoint.u64()?,
+            bytes_written: checkpoint.u64()?,
+        };
+        let cells = checkpoint.u64()? as usize;
+        let mem = (0..cells)
+            .map(|_| checkpoint.u32())
+            .collect::<Option<Vec<u32>>>()?;
+        if checkpoint.pos != bytes.len() {
+            return None;
+        }
+
+        Some(StateSnapshot {
+            ptr,
+            mem,
+            pc,
+            stats,
+        })
+    }
+}
+
+const CACHE_MAGIC: &[u8] = b"BFC\x14";
+const CHECKPOINT_MAGIC: &[u8] = b"BFS\x01";
+
+const TAG_END: u8 = 0;
+const TAG_LOOP: u8 = 1;
+const TAG_MOVE: u8 = 2;
+const TAG_OUTPUT: u8 = 6;
+const TAG_INPUT: u8 = 7;
+const TAG_DEBUG: u8 = 8;
+const TAG_RANDOM: u8 = 9;
+const TAG_PRINT_NUMBER: u8 = 10;
+const TAG_READ_NUMBER: u8 = 11;
+const TAG_SET: u8 = 12;
+const TAG_MUL_ADD: u8 = 13;
+const TAG_SCAN: u8 = 14;
+const TAG_ADD: u8 = 15;
+const TAG_WRITE: u8 = 16;
+const TAG_IF: u8 = 17;
+const TAG_PRODUCT: u8 = 18;
+
+/// Encodes `nodes`, walking loop bodies with an explicit stack so nesting depth
+/// is limited by memory rather than the call stack.
+fn encode_nodes(bytes: &mut Vec<u8>, nodes: &VecDeque<Node>) {
+    let mut levels = vec![nodes.iter()];
+    while let Some(nodes) = levels.last_mut() {
+        let node = match nodes.next() {
+            Some(node) => node,
+            None => {
+                levels.pop();
+                if !levels.is_empty() {
+                    bytes.push(TAG_END);
+                }
+                continue;
+            }
+        };
+        let (tag, d) = match *node {
+            Node::Loop(_, ref d) => (TAG_LOOP, d),
+            Node::Move(_, ref d) => (TAG_MOVE, d),
+            Node::Output(ref d) => (TAG_OUTPUT, d),
+            Node::Input(ref d) => (TAG_INPUT, d),
+            Node::Debug(ref d) => (TAG_DEBUG, d),
+            Node::Random(ref d) => (TAG_RANDOM, d),
+            Node::PrintNumber(ref d) => (TAG_PRINT_NUMBER, d),
+            Node::ReadNumber(ref d) => (TAG_READ_NUMBER, d),
+            Node::Set(_, ref d) => (TAG_SET, d),
+            Node::MulAdd(_, _, ref d) => (TAG_MUL_ADD, d),
+            Node::Product(_, _, _, ref d) => (TAG_PRODUCT, d),
+            Node::Scan(_, ref d) => (TAG_SCAN, d),
+            Node::Write(_, ref d) => (TAG_WRITE, d),
+            Node::Add(_, _, ref d) => (TAG_ADD, d),
+            Node::If(_, ref d) => (TAG_IF, d),
+        };
+        bytes.push(tag);
+        bytes.extend_from_slice(&(d.span.byte_offset as u64).to_le_bytes());
+        bytes.extend_from_slice(&(d.span.len as u64).to_le_bytes());
+        bytes.extend_from_slice(&d.span.line.to_le_bytes());
+        bytes.extend_from_slice(&d.span.column.to_le_bytes());
+        match *node {
+            Node::Loop(ref body, _) | Node::If(ref body, _) => levels.push(body.iter()),
+            Node::Move(v, _) => bytes.extend_from_slice(&(v as i64).to_le_bytes()),
+            Node::Set(v, _) => bytes.extend_from_slice(&v.to_le_bytes()),
+            Node::MulAdd(offset, factor, _) => {
+                bytes.extend_from_slice(&(offset as i64).to_le_bytes());
+                bytes.extend_from_slice(&factor.to_le_bytes());
+            }
+            Node::Product(offset, source, factor, _) => {
+                bytes.extend_from_slice(&(offset as i64).to_le_bytes());
+                bytes.extend_from_slice(&(source as i64).to_le_bytes());
+                bytes.extend_from_slice(&factor.to_le_bytes());
+            }
+            Node::Add(offset, v, _) => {
+                bytes.extend_from_slice(&(offset as i64).to_le_bytes());
+                bytes.extend_from_slice(&v.to_le_bytes());
+            }
+            Node::Scan(step, _) => bytes.extend_from_slice(&(step as i64).to_le_bytes()),
+            Node::Write(ref written, _) => {
+                bytes.extend_from_slice(&(written.len() as u64).to_le_bytes());
+                bytes.extend_from_slice(written);
+            }
+            Node::Output(_)
+            | Node::Input(_)
+            | Node::Debug(_)
+            | Node::Random(_)
+            | Node::PrintNumber(_)
+            | Node::ReadNumber(_) => (),
+        }
+    }
+}
+
+struct CacheReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    file_name: Arc<str>,
+    directory: Arc<str>,
+}
+
+impl<'a> CacheReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        match self.u8()? {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Some(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Some(u64::from_le_bytes(buf))
+    }
+
+    /// Decodes the program's nodes, with an explicit stack of open loop bodies
+    /// so nesting depth is limited by memory rather than the call stack.
+    fn nodes(&mut self) -> Option<VecDeque<Node>> {
+        // The enclosing levels of each open body, and the tag and location of
+        // the node holding it.
+        let mut outer: Vec<(VecDeque<Node>, u8, DebugInfo)> = Vec::new();
+        let mut nodes = VecDeque::new();
+        loop {
+            let tag = match self.u8() {
+                Some(TAG_END) => {
+                    let (enclosing, tag, d) = outer.pop()?;
+                    let body = mem::replace(&mut nodes, enclosing);
+                    nodes.push_back(match tag {
+                        TAG_LOOP => Node::Loop(body, d),
+                        _ => Node::If(body, d),
+                    });
+                    continue;
+                }
+                None if outer.is_empty() => return Some(nodes),
+                Some(tag) => tag,
+                None => return None,
+            };
+            let d = DebugInfo {
+                directory: self.directory.clone(),
+                file: self.file_name.clone(),
+                span: Span {
+                    byte_offset: self.u64()? as usize,
+                    len: self.u64()? as usize,
+                    line: self.u32()?,
+                    column: self.u32()?,
+                },
+            };
+            let node = match tag {
+                TAG_LOOP | TAG_IF => {
+                    outer.push((mem::take(&mut nodes), tag, d));
+                    continue;
+                }
+                TAG_MOVE => Node::Move(self.u64()? as i64 as isize, d),
+                TAG_OUTPUT => Node::Output(d),
+                TAG_INPUT => Node::Input(d),
+                TAG_DEBUG => Node::Debug(d),
+                TAG_RANDOM => Node::Random(d),
+                TAG_PRINT_NUMBER => Node::PrintNumber(d),
+                TAG_READ_NUMBER => Node::ReadNumber(d),
+                TAG_SET => Node::Set(self.u32()?, d),
+                TAG_MUL_ADD => Node::MulAdd(self.u64()? as i64 as isize, self.u32()?, d),
+                TAG_PRODUCT => Node::Product(
+                    self.u64()? as i64 as isize,
+                    self.u64()? as i64 as isize,
+                    self.u32()?,
+                    d,
+                ),
+                TAG_SCAN => Node::Scan(self.u64()? as i64 as isize, d),
+                TAG_WRITE => {
+                    let len = self.u64()? as usize;
+                    Node::Write(self.take(len)?.to_vec(), d)
+                }
+                TAG_ADD => Node::Add(self.u64()? as i64 as isize, self.u32()? as i32, d),
+                _ => return None,
+            };
+            nodes.push_back(node);
+        }
+    }
+}
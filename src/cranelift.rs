@@ -0,0 +1,453 @@
+use std::collections::VecDeque;
+use std::mem;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags, Type, Value};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Linkage, Module};
+
+use brainfuck::{Brainfuck, CellSize, DebugInfo, EofMode, Io, Node, Program, TapeMode};
+use error::BrainfuckError;
+use jit::{self, jit_input, jit_output, jit_random, jit_write, Entry, Image};
+use ExecError;
+
+/// A program compiled to native code in-process with Cranelift, ready to run
+/// at close to the speed of a compiled binary without an LLVM toolchain. It
+/// supports fixed and wrapping tapes, and ignores `#` as compiled programs do.
+pub struct CraneliftJit {
+    /// `None` only while being dropped.
+    module: Option<JITModule>,
+    entry: Entry,
+    image: Image,
+}
+
+impl CraneliftJit {
+    pub fn compile(program: &Brainfuck) -> Result<CraneliftJit, BrainfuckError> {
+        let program = &program.program;
+        jit::check(program)?;
+        let backend = |e: &dyn std::fmt::Display| BrainfuckError::Backend(e.to_string());
+
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").map_err(|e| backend(&e))?;
+        let isa = cranelift_native::builder()
+            .map_err(|e| backend(&e))?
+            .finish(settings::Flags::new(flags))
+            .map_err(|e| backend(&e))?;
+        let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+        builder.symbol("jit_output", jit_output as *const u8);
+        builder.symbol("jit_input", jit_input as *const u8);
+        builder.symbol("jit_random", jit_random as *const u8);
+        builder.symbol("jit_write", jit_write as *const u8);
+        let mut module = JITModule::new(builder);
+
+        let mut lowering = Lowering::new(&mut module, program)?;
+        let mut ctx = module.make_context();
+        let mut builder_ctx = FunctionBuilderContext::new();
+        ctx.func.signature = lowering.entry_signature.clone();
+        {
+            let mut fb = FunctionBuilder::new(&mut ctx.func, &mut builder_ctx);
+            lowering.lower(&mut module, &mut fb, &program.ast.nodes);
+            fb.finalize();
+        }
+        let entry_id = lowering.entry;
+        module
+            .define_function(entry_id, &mut ctx)
+            .map_err(|e| backend(&e))?;
+        module.clear_context(&mut ctx);
+        module.finalize_definitions().map_err(|e| backend(&e))?;
+        let code = module.get_finalized_function(entry_id);
+        Ok(CraneliftJit {
+            module: Some(module),
+            entry: unsafe { mem::transmute::<*const u8, Entry>(code) },
+            image: lowering.image,
+        })
+    }
+
+    /// Runs the program to completion, returning the value of the cell the
+    /// pointer ends on.
+    pub fn run(&self, io: &mut dyn Io) -> Result<u32, ExecError> {
+        unsafe { self.image.run(self.entry, io) }
+    }
+}
+
+impl Drop for CraneliftJit {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // Nothing can call into the code once the program is dropped.
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// The state of lowering one program to a Cranelift function.
+struct Lowering<'p> {
+    program: &'p Program,
+    entry: FuncId,
+    entry_signature: cranelift_codegen::ir::Signature,
+    output: FuncId,
+    input: FuncId,
+    random: FuncId,
+    write: FuncId,
+    pointer: Type,
+    cell: Type,
+    image: Image,
+}
+
+/// A block being lowered, and how to leave it once its nodes are done.
+enum Close {
+    Program,
+    Loop { header: Block, exit: Block },
+    If { exit: Block },
+}
+
+/// The values every node needs while lowering.
+struct Frame {
+    base: Value,
+    runtime: Value,
+    ptr: Variable,
+    /// Returns its parameter: a fault code.
+    fault: Block,
+}
+
+impl<'p> Lowering<'p> {
+    fn new(module: &mut JITModule, program: &'p Program) -> Result<Lowering<'p>, BrainfuckError> {
+        let declare = |module: &mut JITModule, name, linkage, signature| {
+            module
+                .declare_function(name, linkage, signature)
+                .map_err(|e| BrainfuckError::Backend(e.to_string()))
+        };
+        let pointer = module.target_config().pointer_type();
+        let mut entry_signature = module.make_signature();
+        entry_signature.params.push(AbiParam::new(pointer));
+        entry_signature.params.push(AbiParam::new(pointer));
+        entry_signature.returns.push(AbiParam::new(types::I64));
+        let entry = declare(module, "run", Linkage::Local, &entry_signature)?;
+
+        let mut signature = module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(types::I32));
+        let output = declare(module, "jit_output", Linkage::Import, &signature)?;
+        let mut signature = module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.returns.push(AbiParam::new(types::I32));
+        let input = declare(module, "jit_input", Linkage::Import, &signature)?;
+        let random = declare(module, "jit_random", Linkage::Import, &signature)?;
+        let mut signature = module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(pointer));
+        let write = declare(module, "jit_write", Linkage::Import, &signature)?;
+
+        let cell = match program.cell_size {
+            CellSize::U8 => types::I8,
+            CellSize::U16 => types::I16,
+            CellSize::U32 => types::I32,
+        };
+        Ok(Lowering {
+            program,
+            entry,
+            entry_signature,
+            output,
+            input,
+            random,
+            write,
+            pointer,
+            cell,
+            image: Image::new(program),
+        })
+    }
+
+    fn lower(&mut self, module: &mut JITModule, fb: &mut FunctionBuilder, nodes: &VecDeque<Node>) {
+        let start = fb.create_block();
+        fb.append_block_params_for_function_params(start);
+        fb.switch_to_block(start);
+        fb.seal_block(start);
+        let params = fb.block_params(start).to_vec();
+        let ptr = Variable::from_u32(0);
+        fb.declare_var(ptr, self.pointer);
+        let start_ptr = fb.ins().iconst(self.pointer, self.program.start_ptr as i64);
+        fb.def_var(ptr, start_ptr);
+        let fault = fb.create_block();
+        fb.append_block_param(fault, types::I64);
+        let frame = Frame {
+            base: params[0],
+            runtime: params[1],
+            ptr,
+            fault,
+        };
+
+        let mut levels = vec![(nodes.iter(), Close::Program)];
+        while let Some(&mut (ref mut nodes, ref close)) = levels.last_mut() {
+            let node = match nodes.next() {
+                Some(node) => node,
+                None => {
+                    match *close {
+                        Close::Program => {
+                            let ptr = fb.use_var(frame.ptr);
+                            let ptr = self.widen(fb, ptr);
+                            fb.ins().return_(&[ptr]);
+                        }
+                        Close::Loop { header, exit } => {
+                            fb.ins().jump(header, &[]);
+                            fb.seal_block(header);
+                            fb.switch_to_block(exit);
+                        }
+                        Close::If { exit } => {
+                            fb.ins().jump(exit, &[]);
+                            fb.seal_block(exit);
+                            fb.switch_to_block(exit);
+                        }
+                    }
+                    levels.pop();
+                    continue;
+                }
+            };
+            match *node {
+                Node::Loop(ref body, ref d) => {
+                    let header = fb.create_block();
+                    let enter = fb.create_block();
+                    let exit = fb.create_block();
+                    fb.ins().jump(header, &[]);
+                    fb.switch_to_block(header);
+                    let addr = self.cell_at(fb, &frame, 0, d);
+                    let value = fb.ins().load(self.cell, MemFlags::new(), addr, 0);
+                    fb.ins().brif(value, enter, &[], exit, &[]);
+                    fb.seal_block(enter);
+                    fb.seal_block(exit);
+                    fb.switch_to_block(enter);
+                    levels.push((body.iter(), Close::Loop { header, exit }));
+                }
+                Node::If(ref body, ref d) => {
+                    let enter = fb.create_block();
+                    let exit = fb.create_block();
+                    let addr = self.cell_at(fb, &frame, 0, d);
+                    let value = fb.ins().load(self.cell, MemFlags::new(), addr, 0);
+                    fb.ins().brif(value, enter, &[], exit, &[]);
+                    fb.seal_block(enter);
+                    fb.switch_to_block(enter);
+                    levels.push((body.iter(), Close::If { exit }));
+                }
+                _ => self.lower_node(module, fb, &frame, node),
+            }
+        }
+
+        fb.switch_to_block(fault);
+        fb.seal_block(fault);
+        let code = fb.block_params(fault)[0];
+        fb.ins().return_(&[code]);
+    }
+
+    /// Lowers a node without a body.
+    fn lower_node(
+        &mut self,
+        module: &mut JITModule,
+        fb: &mut FunctionBuilder,
+        frame: &Frame,
+        node: &Node,
+    ) {
+        let flags = MemFlags::new();
+        match *node {
+            Node::Move(v, _) => {
+                let ptr = fb.use_var(frame.ptr);
+                let moved = self.offset(fb, ptr, v);
+                fb.def_var(frame.ptr, moved);
+            }
+            Node::Add(offset, v, ref d) => {
+                let addr = self.cell_at(fb, frame, offset, d);
+                let value = fb.ins().load(self.cell, flags, addr, 0);
+                let sum = fb.ins().iadd_imm(value, i64::from(v));
+                fb.ins().store(flags, sum, addr, 0);
+            }
+            Node::Set(v, ref d) => {
+                let addr = self.cell_at(fb, frame, 0, d);
+                let value = fb.ins().iconst(self.cell, i64::from(v & self.mask()));
+                fb.ins().store(flags, value, addr, 0);
+            }
+            Node::MulAdd(offset, factor, ref d) => {
+                let addr = self.cell_at(fb, frame, 0, d);
+                let value = fb.ins().load(self.cell, flags, addr, 0);
+                let add = fb.create_block();
+                let done = fb.create_block();
+                fb.ins().brif(value, add, &[], done, &[]);
+                fb.seal_block(add);
+                fb.switch_to_block(add);
+                let target = self.cell_at(fb, frame, offset, d);
+                let old = fb.ins().load(self.cell, flags, target, 0);
+                let product = fb.ins().imul_imm(value, i64::from(factor));
+                let sum = fb.ins().iadd(old, product);
+                fb.ins().store(flags, sum, target, 0);
+                fb.ins().jump(done, &[]);
+                fb.seal_block(done);
+                fb.switch_to_block(done);
+            }
+            Node::Product(offset, source, factor, ref d) => {
+                let addr = self.cell_at(fb, frame, 0, d);
+                let value = fb.ins().load(self.cell, flags, addr, 0);
+                let add = fb.create_block();
+                let done = fb.create_block();
+                fb.ins().brif(value, add, &[], done, &[]);
+                fb.seal_block(add);
+                fb.switch_to_block(add);
+                let source = self.cell_at(fb, frame, source, d);
+                let source = fb.ins().load(self.cell, flags, source, 0);
+                let target = self.cell_at(fb, frame, offset, d);
+                let old = fb.ins().load(self.cell, flags, target, 0);
+                let product = fb.ins().imul(value, source);
+                let product = fb.ins().imul_imm(product, i64::from(factor));
+                let sum = fb.ins().iadd(old, product);
+                fb.ins().store(flags, sum, target, 0);
+                fb.ins().jump(done, &[]);
+                fb.seal_block(done);
+                fb.switch_to_block(done);
+            }
+            Node::Scan(step, ref d) => {
+                let header = fb.create_block();
+                let next = fb.create_block();
+                let exit = fb.create_block();
+                fb.ins().jump(header, &[]);
+                fb.switch_to_block(header);
+                let addr = self.cell_at(fb, frame, 0, d);
+                let value = fb.ins().load(self.cell, flags, addr, 0);
+                fb.ins().brif(value, next, &[], exit, &[]);
+                fb.seal_block(next);
+                fb.seal_block(exit);
+                fb.switch_to_block(next);
+                let ptr = fb.use_var(frame.ptr);
+                let moved = self.offset(fb, ptr, step);
+                fb.def_var(frame.ptr, moved);
+                fb.ins().jump(header, &[]);
+                fb.seal_block(header);
+                fb.switch_to_block(exit);
+            }
+            Node::Output(ref d) => {
+                let addr = self.cell_at(fb, frame, 0, d);
+                let value = fb.ins().load(self.cell, flags, addr, 0);
+                let value = self.widen_cell(fb, value);
+                let output = module.declare_func_in_func(self.output, fb.func);
+                fb.ins().call(output, &[frame.runtime, value]);
+            }
+            Node::Input(ref d) => {
+                let addr = self.cell_at(fb, frame, 0, d);
+                let input = module.declare_func_in_func(self.input, fb.func);
+                let call = fb.ins().call(input, &[frame.runtime]);
+                let byte = fb.inst_results(call)[0];
+                let eof = fb.ins().icmp_imm(IntCC::Equal, byte, -1);
+                let byte = self.narrow_to_cell(fb, byte);
+                let value = match self.program.eof {
+                    EofMode::Zero => {
+                        let zero = fb.ins().iconst(self.cell, 0);
+                        fb.ins().select(eof, zero, byte)
+                    }
+                    EofMode::MinusOne => {
+                        let all = fb.ins().iconst(self.cell, i64::from(self.mask()));
+                        fb.ins().select(eof, all, byte)
+                    }
+                    EofMode::Unchanged => {
+                        let old = fb.ins().load(self.cell, flags, addr, 0);
+                        fb.ins().select(eof, old, byte)
+                    }
+                };
+                fb.ins().store(flags, value, addr, 0);
+            }
+            Node::Random(ref d) => {
+                let addr = self.cell_at(fb, frame, 0, d);
+                let random = module.declare_func_in_func(self.random, fb.func);
+                let call = fb.ins().call(random, &[frame.runtime]);
+                let byte = fb.inst_results(call)[0];
+                let value = self.narrow_to_cell(fb, byte);
+                fb.ins().store(flags, value, addr, 0);
+            }
+            Node::Write(ref bytes, _) => {
+                let data = fb.ins().iconst(self.pointer, self.image.keep(bytes) as i64);
+                let len = fb.ins().iconst(self.pointer, bytes.len() as i64);
+                let write = module.declare_func_in_func(self.write, fb.func);
+                fb.ins().call(write, &[frame.runtime, data, len]);
+            }
+            // Like compiled programs, the JIT has nowhere to report `#` to.
+            Node::Debug(_) => (),
+            Node::PrintNumber(_) | Node::ReadNumber(_) => {
+                unreachable!("numeric I/O is rejected before lowering")
+            }
+            Node::Loop(..) | Node::If(..) => unreachable!("blocks are lowered by `lower`"),
+        }
+    }
+
+    fn mask(&self) -> u32 {
+        self.program.cell_size.mask()
+    }
+
+    /// The index `offset` cells from `ptr`, wrapped around a wrapping tape.
+    fn offset(&self, fb: &mut FunctionBuilder, ptr: Value, offset: isize) -> Value {
+        if self.program.tape_mode != TapeMode::Wrap {
+            return fb.ins().iadd_imm(ptr, offset as i64);
+        }
+        let len = self.program.mem_size as i64;
+        let offset = (offset as i64).rem_euclid(len);
+        if offset == 0 {
+            return ptr;
+        }
+        let sum = fb.ins().iadd_imm(ptr, offset);
+        let wrapped = fb.ins().iadd_imm(sum, -len);
+        let past = fb
+            .ins()
+            .icmp_imm(IntCC::UnsignedGreaterThanOrEqual, sum, len);
+        fb.ins().select(past, wrapped, sum)
+    }
+
+    /// The address of the cell `offset` cells from the pointer. On a fixed tape,
+    /// the function returns the fault code for `d` if it is off the tape.
+    fn cell_at(
+        &mut self,
+        fb: &mut FunctionBuilder,
+        frame: &Frame,
+        offset: isize,
+        d: &DebugInfo,
+    ) -> Value {
+        let ptr = fb.use_var(frame.ptr);
+        let index = self.offset(fb, ptr, offset);
+        if self.program.tape_mode == TapeMode::Fixed {
+            let off_tape = fb.ins().icmp_imm(
+                IntCC::UnsignedGreaterThanOrEqual,
+                index,
+                self.program.mem_size as i64,
+            );
+            let on_tape = fb.create_block();
+            let code = self.image.fault_code(d);
+            let code = fb.ins().iconst(types::I64, code);
+            fb.ins().brif(off_tape, frame.fault, &[code], on_tape, &[]);
+            fb.seal_block(on_tape);
+            fb.switch_to_block(on_tape);
+        }
+        let scaled = match self.program.cell_size.bytes() {
+            1 => index,
+            bytes => fb.ins().imul_imm(index, bytes as i64),
+        };
+        fb.ins().iadd(frame.base, scaled)
+    }
+
+    fn widen(&self, fb: &mut FunctionBuilder, value: Value) -> Value {
+        if self.pointer == types::I64 {
+            value
+        } else {
+            fb.ins().uextend(types::I64, value)
+        }
+    }
+
+    fn widen_cell(&self, fb: &mut FunctionBuilder, value: Value) -> Value {
+        if self.cell == types::I32 {
+            value
+        } else {
+            fb.ins().uextend(types::I32, value)
+        }
+    }
+
+    fn narrow_to_cell(&self, fb: &mut FunctionBuilder, value: Value) -> Value {
+        if self.cell == types::I32 {
+            value
+        } else {
+            fb.ins().ireduce(self.cell, value)
+        }
+    }
+}
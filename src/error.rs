@@ -0,0 +1,93 @@
+use alloc::string::String;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(feature = "std")]
+use std::io;
+#[cfg(feature = "std")]
+use std::path::PathBuf;
+
+use brainfuck::{ExecError, ParseError, Span};
+
+/// Any error produced while parsing, running or compiling a program. The
+/// underlying error is available through `source()`.
+#[derive(Debug)]
+pub enum BrainfuckError {
+    Parse(ParseError),
+    Exec(ExecError),
+    /// A `Backend` could not lower the program.
+    Backend(String),
+    /// An external compiler tool could not be started, or exited unsuccessfully.
+    #[cfg(feature = "std")]
+    Toolchain {
+        tool: &'static str,
+        /// Why the tool could not be started, or `None` if it ran and failed.
+        error: Option<io::Error>,
+    },
+    /// The program's source file could not be read.
+    #[cfg(feature = "std")]
+    Read {
+        path: PathBuf,
+        error: io::Error,
+    },
+}
+
+impl fmt::Display for BrainfuckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BrainfuckError::Parse(_) => write!(f, "could not parse program"),
+            BrainfuckError::Exec(_) => write!(f, "program raised an exception"),
+            BrainfuckError::Backend(ref message) => {
+                write!(f, "could not generate code: {}", message)
+            }
+            #[cfg(feature = "std")]
+            BrainfuckError::Toolchain { tool, .. } => write!(f, "failed to execute {}", tool),
+            #[cfg(feature = "std")]
+            BrainfuckError::Read { ref path, .. } => {
+                write!(f, "could not read {}", path.display())
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for BrainfuckError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            BrainfuckError::Parse(ref e) => Some(e),
+            BrainfuckError::Exec(ref e) => Some(e),
+            BrainfuckError::Backend(_) => None,
+            BrainfuckError::Toolchain { ref error, .. } => {
+                error.as_ref().map(|e| e as &(dyn Error + 'static))
+            }
+            BrainfuckError::Read { ref error, .. } => Some(error),
+        }
+    }
+}
+
+impl BrainfuckError {
+    /// Where in the source the error occurred, if it relates to the program itself.
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            BrainfuckError::Parse(ref e) => Some(e.span()),
+            BrainfuckError::Exec(ref e) => Some(e.span()),
+            BrainfuckError::Backend(_) => None,
+            #[cfg(feature = "std")]
+            BrainfuckError::Toolchain { .. } => None,
+            #[cfg(feature = "std")]
+            BrainfuckError::Read { .. } => None,
+        }
+    }
+}
+
+impl From<ParseError> for BrainfuckError {
+    fn from(e: ParseError) -> BrainfuckError {
+        BrainfuckError::Parse(e)
+    }
+}
+
+impl From<ExecError> for BrainfuckError {
+    fn from(e: ExecError) -> BrainfuckError {
+        BrainfuckError::Exec(e)
+    }
+}
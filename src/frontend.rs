@@ -0,0 +1,134 @@
+use alloc::vec::Vec;
+
+use brainfuck::Span;
+
+/// One command recognized in the source, independent of the dialect it was
+/// written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symbol {
+    IncPtr,
+    DecPtr,
+    Increment,
+    Decrement,
+    Output,
+    Input,
+    OpenBlock,
+    CloseBlock,
+    /// `#`, the debug dump extension.
+    Debug,
+    /// `?`, the random byte extension.
+    Random,
+    /// `:`, which prints the current cell in decimal.
+    PrintNumber,
+    /// `;`, which reads a decimal number into the current cell.
+    ReadNumber,
+}
+
+/// Optional commands beyond the standard eight, all disabled by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extensions {
+    /// `#` reports the pointer and nearby cells.
+    pub debug: bool,
+    /// `?` stores a random byte in the current cell.
+    pub random: bool,
+    /// `:` and `;` print and read cells as decimal numbers.
+    pub numeric_io: bool,
+}
+
+impl Extensions {
+    /// Whether `symbol` is a standard command or one of the enabled extensions.
+    pub fn allows(self, symbol: Symbol) -> bool {
+        match symbol {
+            Symbol::Debug => self.debug,
+            Symbol::Random => self.random,
+            Symbol::PrintNumber | Symbol::ReadNumber => self.numeric_io,
+            _ => true,
+        }
+    }
+
+    pub(crate) fn bits(self) -> u8 {
+        self.debug as u8 | (self.random as u8) << 1 | (self.numeric_io as u8) << 2
+    }
+}
+
+/// A tokenizer for one brainfuck dialect.
+pub trait Frontend {
+    /// The name used to select this frontend, such as `classic`.
+    fn name(&self) -> &'static str;
+
+    /// File extensions, without the leading dot, this frontend is chosen for.
+    fn extensions(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Splits `source` into commands, each with the span of source it came from.
+    fn tokenize(&self, source: &[u8]) -> Vec<(Symbol, Span)>;
+}
+
+/// The standard eight single-character commands, plus `#`, `?`, `:` and `;` when
+/// their extensions are enabled; every other byte is a comment.
+pub struct Classic;
+
+impl Frontend for Classic {
+    fn name(&self) -> &'static str {
+        "classic"
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        &["bf", "b"]
+    }
+
+    fn tokenize(&self, source: &[u8]) -> Vec<(Symbol, Span)> {
+        let mut symbols = Vec::new();
+        let mut line = 1;
+        let mut column = 1;
+        for (byte_offset, &byte) in source.iter().enumerate() {
+            let span = Span {
+                byte_offset,
+                len: 1,
+                line,
+                column,
+            };
+            match byte {
+                b'>' => symbols.push((Symbol::IncPtr, span)),
+                b'<' => symbols.push((Symbol::DecPtr, span)),
+                b'+' => symbols.push((Symbol::Increment, span)),
+                b'-' => symbols.push((Symbol::Decrement, span)),
+                b'.' => symbols.push((Symbol::Output, span)),
+                b',' => symbols.push((Symbol::Input, span)),
+                b'[' => symbols.push((Symbol::OpenBlock, span)),
+                b']' => symbols.push((Symbol::CloseBlock, span)),
+                b'#' => symbols.push((Symbol::Debug, span)),
+                b'?' => symbols.push((Symbol::Random, span)),
+                b':' => symbols.push((Symbol::PrintNumber, span)),
+                b';' => symbols.push((Symbol::ReadNumber, span)),
+                b'\n' => {
+                    line += 1;
+                    column = 0;
+                }
+                _ => (),
+            }
+            column += 1;
+        }
+        symbols
+    }
+}
+
+/// Every built-in frontend.
+pub static FRONTENDS: &[&(dyn Frontend + Sync)] = &[&Classic];
+
+/// Looks up a built-in frontend by name.
+pub fn find_frontend(name: &str) -> Option<&'static (dyn Frontend + Sync)> {
+    FRONTENDS
+        .iter()
+        .cloned()
+        .find(|frontend| frontend.name() == name)
+}
+
+/// Picks the built-in frontend registered for a file extension.
+pub fn frontend_for_extension(extension: &str) -> Option<&'static (dyn Frontend + Sync)> {
+    FRONTENDS
+        .iter()
+        .cloned()
+        .find(|frontend| frontend.extensions().contains(&extension))
+}
@@ -0,0 +1,402 @@
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+
+use brainfuck::{Node, PassContext};
+
+/// Rewrites `nodes` as short brainfuck. Each run of `+-<>` is replaced by the
+/// shortest equivalent found: the changes it makes, visited in the best order,
+/// or a loop multiplying a counter into them, trying at most `budget` counter
+/// cells and counts per run. Loops that cannot run are dropped. Returns `None`
+/// for nodes only the optimizer makes.
+pub(crate) fn golf(nodes: &VecDeque<Node>, context: &PassContext, budget: usize) -> Option<String> {
+    let mut golfer = Golfer {
+        context,
+        modulus: i64::from(context.cell_size.mask()) + 1,
+        budget,
+        out: String::new(),
+    };
+    // A wrapping tape could make a counter the same cell as a target.
+    let mut zeros = if context.wrap_len.is_some() {
+        Zeros::Nothing
+    } else if context.zeroed_tape {
+        Zeros::AllBut {
+            pos: 0,
+            nonzero: BTreeSet::new(),
+        }
+    } else if context.zeroed_start {
+        Zeros::Current
+    } else {
+        Zeros::Nothing
+    };
+    let mut segment = Segment::default();
+    let mut levels = vec![nodes.iter()];
+    while !levels.is_empty() {
+        let last = levels.len() - 1;
+        let node = match levels[last].next() {
+            Some(node) => node,
+            None => {
+                levels.pop();
+                if !levels.is_empty() {
+                    golfer.flush(mem::take(&mut segment), &mut zeros);
+                    golfer.out.push(']');
+                    zeros = Zeros::Current;
+                }
+                continue;
+            }
+        };
+        let symbol = match *node {
+            Node::Move(v, _) => {
+                segment.move_by(v);
+                continue;
+            }
+            Node::Add(offset, v, _) => {
+                segment.add(offset, v);
+                continue;
+            }
+            Node::Loop(_, _) if golfer.is_zero(&segment, &zeros, segment.pos) => continue,
+            Node::Loop(ref body, _) => {
+                golfer.flush(mem::take(&mut segment), &mut zeros);
+                golfer.out.push('[');
+                zeros = Zeros::Nothing;
+                levels.push(body.iter());
+                continue;
+            }
+            Node::Output(_) => '.',
+            Node::Debug(_) => '#',
+            Node::PrintNumber(_) => ':',
+            Node::Input(_) => ',',
+            Node::Random(_) => '?',
+            Node::ReadNumber(_) => ';',
+            Node::Set(..)
+            | Node::MulAdd(..)
+            | Node::Product(..)
+            | Node::Scan(..)
+            | Node::Write(..)
+            | Node::If(..) => return None,
+        };
+        golfer.flush(mem::take(&mut segment), &mut zeros);
+        golfer.out.push(symbol);
+        if let Node::Input(_) | Node::Random(_) | Node::ReadNumber(_) = *node {
+            zeros.clobber(0);
+        }
+    }
+    golfer.flush(segment, &mut zeros);
+    Some(golfer.out)
+}
+
+/// Which cells are known to be zero where a run of `+-<>` starts.
+enum Zeros {
+    /// Every cell but those in `nonzero`, before the program enters its first
+    /// loop. Cells are counted from the starting cell, and `pos` is the pointer.
+    AllBut {
+        pos: isize,
+        nonzero: BTreeSet<isize>,
+    },
+    /// Just the current cell.
+    Current,
+    Nothing,
+}
+
+impl Zeros {
+    /// Whether the cell `offset` cells from the pointer is zero.
+    fn contains(&self, offset: isize) -> bool {
+        match *self {
+            Zeros::AllBut { pos, ref nonzero } => !nonzero.contains(&(pos + offset)),
+            Zeros::Current => offset == 0,
+            Zeros::Nothing => false,
+        }
+    }
+
+    /// Forgets the cell at `offset`.
+    fn clobber(&mut self, offset: isize) {
+        match *self {
+            Zeros::AllBut {
+                pos,
+                ref mut nonzero,
+            } => {
+                nonzero.insert(pos + offset);
+            }
+            Zeros::Current if offset == 0 => *self = Zeros::Nothing,
+            _ => (),
+        }
+    }
+}
+
+/// A run of `+-<>`, as what it does to the cells and the pointer.
+#[derive(Default)]
+struct Segment {
+    /// Where the pointer ends, from where it started.
+    pos: isize,
+    /// How much is added to each cell.
+    deltas: BTreeMap<isize, i64>,
+    /// The leftmost and rightmost cells the run reaches. Rewrites stay between
+    /// them, so they cannot leave a tape the original stayed on.
+    lo: isize,
+    hi: isize,
+}
+
+impl Segment {
+    fn move_by(&mut self, v: isize) {
+        self.pos += v;
+        self.reach(self.pos);
+    }
+
+    fn add(&mut self, offset: isize, v: i32) {
+        let cell = self.pos + offset;
+        *self.deltas.entry(cell).or_insert(0) += i64::from(v);
+        self.reach(cell);
+    }
+
+    fn reach(&mut self, cell: isize) {
+        self.lo = self.lo.min(cell);
+        self.hi = self.hi.max(cell);
+    }
+
+    fn delta(&self, cell: isize) -> i64 {
+        self.deltas.get(&cell).map_or(0, |&d| d)
+    }
+}
+
+/// A loop the search found: a counter cell, how many times it runs, and what it
+/// adds to each cell on each run.
+struct CounterLoop {
+    counter: isize,
+    count: i64,
+    factors: Vec<(isize, i64)>,
+    /// What is left to add to each cell after the loop.
+    rest: Vec<(isize, i64)>,
+}
+
+struct Golfer<'c> {
+    context: &'c PassContext,
+    /// How many values a cell holds.
+    modulus: i64,
+    budget: usize,
+    out: String,
+}
+
+impl<'c> Golfer<'c> {
+    /// How many `+` or `-` it takes to add `delta`. Strict mode must not wrap,
+    /// so that cells overflow where they did.
+    fn cost(&self, delta: i64) -> i64 {
+        if self.context.strict {
+            return delta.abs();
+        }
+        let delta = delta.rem_euclid(self.modulus);
+        delta.min(self.modulus - delta)
+    }
+
+    /// Whether `cell` is zero after `segment`. Cells off the tape are not, as
+    /// reading them must still fail.
+    fn is_zero(&self, segment: &Segment, zeros: &Zeros, cell: isize) -> bool {
+        let on_tape = match *zeros {
+            Zeros::AllBut { pos, .. } => self.context.on_start_tape(pos + cell),
+            _ => true,
+        };
+        on_tape && zeros.contains(cell) && self.cost(segment.delta(cell)) == 0
+    }
+
+    fn emit_delta(&mut self, delta: i64) {
+        let delta = if self.context.strict {
+            delta
+        } else {
+            let delta = delta.rem_euclid(self.modulus);
+            if delta <= self.modulus - delta {
+                delta
+            } else {
+                delta - self.modulus
+            }
+        };
+        let symbol = if delta > 0 { '+' } else { '-' };
+        self.out.extend((0..delta.abs()).map(|_| symbol));
+    }
+
+    fn emit_move(&mut self, from: isize, to: isize) {
+        let symbol = if to > from { '>' } else { '<' };
+        self.out.extend((0..(to - from).abs()).map(|_| symbol));
+    }
+
+    /// What it costs to walk from `start` past every cell in `cells`, sorted, to
+    /// `end`, and whether to visit the leftmost first.
+    fn route(start: isize, cells: &[(isize, i64)], end: isize) -> (i64, bool) {
+        let (lo, hi) = match (cells.first(), cells.last()) {
+            (Some(&(lo, _)), Some(&(hi, _))) => (lo, hi),
+            _ => return ((end - start).abs() as i64, true),
+        };
+        let left = (start - lo).abs() + (hi - lo) + (end - hi).abs();
+        let right = (start - hi).abs() + (hi - lo) + (end - lo).abs();
+        (left.min(right) as i64, left <= right)
+    }
+
+    /// What it costs to add `cells`, sorted, on the way from `start` to `end`.
+    fn route_cost(&self, start: isize, cells: &[(isize, i64)], end: isize) -> i64 {
+        let changes: i64 = cells.iter().map(|&(_, d)| self.cost(d)).sum();
+        Golfer::route(start, cells, end).0 + changes
+    }
+
+    fn emit_route(&mut self, start: isize, cells: &[(isize, i64)], end: isize) {
+        let (_, left_first) = Golfer::route(start, cells, end);
+        let mut pos = start;
+        let mut visit = |golfer: &mut Golfer<'c>, &(cell, delta): &(isize, i64)| {
+            golfer.emit_move(pos, cell);
+            golfer.emit_delta(delta);
+            pos = cell;
+        };
+        if left_first {
+            cells.iter().for_each(|c| visit(self, c));
+        } else {
+            cells.iter().rev().for_each(|c| visit(self, c));
+        }
+        self.emit_move(pos, end);
+    }
+
+    /// Writes `segment` as briefly as the search can, and updates what is known
+    /// to be zero after it.
+    fn flush(&mut self, segment: Segment, zeros: &mut Zeros) {
+        let cells: Vec<(isize, i64)> = segment
+            .deltas
+            .iter()
+            .map(|(&cell, &delta)| (cell, delta))
+            .filter(|&(_, delta)| self.cost(delta) > 0)
+            .collect();
+        let plain = self.route_cost(0, &cells, segment.pos);
+        match self.search(&segment, zeros, &cells) {
+            Some((cost, found)) if cost < plain => self.emit_loop(found, segment.pos),
+            _ => self.emit_route(0, &cells, segment.pos),
+        }
+        *zeros = match *zeros {
+            Zeros::AllBut { pos, ref nonzero } => Zeros::AllBut {
+                pos: pos + segment.pos,
+                nonzero: nonzero
+                    .iter()
+                    .cloned()
+                    .chain(cells.iter().map(|&(cell, _)| pos + cell))
+                    .collect(),
+            },
+            Zeros::Current if segment.pos == 0 && self.cost(segment.delta(0)) == 0 => {
+                Zeros::Current
+            }
+            _ => Zeros::Nothing,
+        };
+    }
+
+    /// Finds the cheapest loop that adds `cells` using a counter that starts and
+    /// ends at zero, trying at most `budget` counters and counts.
+    fn search(
+        &self,
+        segment: &Segment,
+        zeros: &Zeros,
+        cells: &[(isize, i64)],
+    ) -> Option<(i64, CounterLoop)> {
+        // A loop only wraps cells into range if they are allowed to wrap.
+        if self.context.strict || cells.is_empty() {
+            return None;
+        }
+        // Where the pointer is known, the counter can be a little past what the
+        // run reaches, as long as it is on the tape.
+        let counters = match *zeros {
+            Zeros::AllBut { .. } => segment.lo - 2..=segment.hi + 2,
+            _ => segment.lo..=segment.hi,
+        };
+        let largest = cells.iter().map(|&(_, d)| self.cost(d)).max().unwrap_or(0);
+        let mut best: Option<(i64, CounterLoop)> = None;
+        let mut tries = 0;
+        for counter in counters {
+            if !self.is_zero(segment, zeros, counter) {
+                continue;
+            }
+            for count in 2..=largest.min(self.modulus / 2) {
+                if tries == self.budget {
+                    return best;
+                }
+                tries += 1;
+                let found = self.counter_loop(counter, count, cells);
+                if found.factors.is_empty() {
+                    continue;
+                }
+                let cost = self.loop_cost(&found, segment.pos);
+                match best {
+                    Some((least, _)) if least <= cost => (),
+                    _ => best = Some((cost, found)),
+                }
+            }
+        }
+        best
+    }
+
+    /// Splits `cells` into what a loop run `count` times adds, where that saves
+    /// anything, and what is left to add after it.
+    fn counter_loop(&self, counter: isize, count: i64, cells: &[(isize, i64)]) -> CounterLoop {
+        let mut found = CounterLoop {
+            counter,
+            count,
+            factors: Vec::new(),
+            rest: Vec::new(),
+        };
+        for &(cell, delta) in cells {
+            let up = delta.rem_euclid(self.modulus);
+            let down = self.modulus - up;
+            let factor = [
+                up / count,
+                up / count + 1,
+                -(down / count),
+                -(down / count + 1),
+            ]
+            .iter()
+            .cloned()
+            .min_by_key(|&f| f.abs() + self.cost(delta - count * f))
+            .expect("there are candidate factors");
+            let left = delta - count * factor;
+            if factor.abs() + self.cost(left) < self.cost(delta) {
+                found.factors.push((cell, factor));
+                if self.cost(left) > 0 {
+                    found.rest.push((cell, left));
+                }
+            } else {
+                found.rest.push((cell, delta));
+            }
+        }
+        found
+    }
+
+    fn loop_cost(&self, found: &CounterLoop, end: isize) -> i64 {
+        let counter = found.counter;
+        let lo = found
+            .factors
+            .first()
+            .map_or(counter, |&(cell, _)| cell.min(counter));
+        let hi = found
+            .factors
+            .last()
+            .map_or(counter, |&(cell, _)| cell.max(counter));
+        let body: i64 = found.factors.iter().map(|&(_, f)| f.abs()).sum();
+        // Reaching the counter, setting it, the brackets and the step.
+        let setup = counter.abs() as i64 + found.count + 3;
+        setup + 2 * (hi - lo) as i64 + body + self.route_cost(counter, &found.rest, end)
+    }
+
+    fn emit_loop(&mut self, found: CounterLoop, end: isize) {
+        let counter = found.counter;
+        self.emit_move(0, counter);
+        self.emit_delta(found.count);
+        self.out.push('[');
+        let mut pos = counter;
+        let lo = found
+            .factors
+            .first()
+            .map_or(counter, |&(cell, _)| cell.min(counter));
+        self.emit_move(pos, lo);
+        pos = lo;
+        for &(cell, factor) in &found.factors {
+            self.emit_move(pos, cell);
+            self.emit_delta(factor);
+            pos = cell;
+        }
+        self.emit_move(pos, counter);
+        self.out.push('-');
+        self.out.push(']');
+        self.emit_route(counter, &found.rest, end);
+    }
+}
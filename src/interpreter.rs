@@ -0,0 +1,1322 @@
+use alloc::collections::vec_deque::Iter;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::iter;
+use core::mem;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use std::error::Error;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+
+use brainfuck::{
+    balanced_span, next_random, rng_state, CellSize, DebugDump, DebugInfo, EofMode, ExecError, Io,
+    Node, Program, TapeMode,
+};
+
+/// Counters collected while a program runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExecStats {
+    /// Nodes executed, after optimization has merged repeated instructions.
+    pub instructions: u64,
+    /// Times a loop body was entered.
+    pub loop_iterations: u64,
+    /// Lowest cell index read or written.
+    pub lowest_cell: usize,
+    /// Highest cell index read or written.
+    pub highest_cell: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// How many cells on each side of the pointer `#` reports.
+const DEBUG_WINDOW: usize = 8;
+
+struct ProgramState {
+    ptr: usize,
+    mem: Vec<u32>,
+    tape_mode: TapeMode,
+    cell_size: CellSize,
+    signed_cells: bool,
+    eof: EofMode,
+    strict: bool,
+    /// Every cell value is kept within this mask, matching the cell width.
+    mask: u32,
+    /// The most cells a growing tape may extend to.
+    max_cells: usize,
+    rng: u64,
+    /// Progress through a `:` or `;` that has not finished yet.
+    pending: Option<Pending>,
+    stats: ExecStats,
+}
+
+/// `:` and `;` transfer one byte per step, so they can wait for input and report
+/// output like `,` and `.` do.
+enum Pending {
+    /// The bytes left to write, last first.
+    Output(Vec<u8>),
+    Input {
+        value: i64,
+        negative: bool,
+        digits: bool,
+    },
+}
+
+impl ProgramState {
+    fn new(program: &Program) -> ProgramState {
+        let start_ptr = program.start_ptr;
+        let mut mem = vec![0; program.mem_size];
+        let offset = program.tape_init_offset;
+        if program.tape_mode == TapeMode::Grow {
+            let end = offset + program.tape_init.len();
+            if end > mem.len() {
+                mem.resize(end, 0);
+            }
+        }
+        if let Some(cells) = mem.get_mut(offset..) {
+            for (cell, &b) in cells.iter_mut().zip(&program.tape_init) {
+                *cell = u32::from(b);
+            }
+        }
+        ProgramState {
+            ptr: start_ptr,
+            mem,
+            tape_mode: program.tape_mode,
+            cell_size: program.cell_size,
+            signed_cells: program.signed_cells,
+            eof: program.eof,
+            strict: program.strict,
+            mask: program.cell_size.mask(),
+            max_cells: program
+                .max_mem
+                .map_or(usize::MAX, |bytes| bytes / program.cell_size.bytes()),
+            rng: rng_state(program.seed.unwrap_or_else(fresh_seed)),
+            pending: None,
+            stats: ExecStats {
+                lowest_cell: start_ptr,
+                highest_cell: start_ptr,
+                ..ExecStats::default()
+            },
+        }
+    }
+
+    #[inline]
+    fn move_right(&mut self, v: usize) {
+        self.ptr = match self.tape_mode {
+            TapeMode::Wrap => (self.ptr + v % self.mem.len()) % self.mem.len(),
+            _ => self.ptr.wrapping_add(v),
+        };
+    }
+
+    fn move_left(&mut self, v: usize) {
+        self.ptr = match self.tape_mode {
+            TapeMode::Wrap => {
+                let len = self.mem.len();
+                (self.ptr + len - v % len) % len
+            }
+            // Prepend cells and shift every index, so cell 0 stays the leftmost.
+            // Past the memory limit the pointer is left off the tape, so the next
+            // access reports it.
+            TapeMode::Grow if v > self.ptr && v - self.ptr <= self.room() => {
+                let added = (((v - self.ptr) / Program::GROW_CHUNK + 1) * Program::GROW_CHUNK)
+                    .min(self.room());
+                self.mem.splice(0..0, iter::repeat_n(0, added));
+                self.stats.lowest_cell += added;
+                self.stats.highest_cell += added;
+                self.ptr + added - v
+            }
+            _ => self.ptr.wrapping_sub(v),
+        };
+    }
+
+    /// Moves the pointer by `step` until it reaches a zero cell, searching the
+    /// tape as a slice. Returns `false` if the search ran off the end of the
+    /// tape instead, leaving the pointer just past it for the next attempt.
+    fn scan<H: Hooks>(
+        &mut self,
+        step: isize,
+        d: &DebugInfo,
+        hooks: &mut H,
+    ) -> Result<bool, ExecError> {
+        let ptr = self.cell(d)?;
+        let stride = step.unsigned_abs();
+        let (found, visited) = if step > 0 {
+            let mut cells = self.mem[ptr..].iter().step_by(stride);
+            let visited = cells.len();
+            (cells.position(|&c| c == 0), visited)
+        } else {
+            let mut cells = self.mem[..=ptr].iter().rev().step_by(stride);
+            let visited = cells.len();
+            (cells.position(|&c| c == 0), visited)
+        };
+        let last = found.unwrap_or(visited - 1);
+        for i in 0..=last {
+            if step > 0 {
+                hooks.on_cell_read(ptr + i * stride);
+            } else {
+                hooks.on_cell_read(ptr - i * stride);
+            }
+        }
+        self.ptr = if step > 0 {
+            ptr + last * stride
+        } else {
+            ptr - last * stride
+        };
+        self.cell(d)?;
+        if found.is_none() {
+            if step > 0 {
+                self.move_right(stride);
+            } else {
+                self.move_left(stride);
+            }
+        }
+        Ok(found.is_some())
+    }
+
+    /// Moves the pointer `offset` cells, to the right if it is positive.
+    #[inline]
+    fn seek(&mut self, offset: isize) {
+        if offset < 0 {
+            self.move_left(offset.unsigned_abs());
+        } else {
+            self.move_right(offset as usize);
+        }
+    }
+
+    /// Whether every cell from `lo` to `hi` cells away from the pointer is on the
+    /// tape.
+    fn fits(&self, lo: isize, hi: isize) -> bool {
+        self.ptr.checked_add_signed(lo).is_some()
+            && self
+                .ptr
+                .checked_add_signed(hi)
+                .is_some_and(|end| end < self.mem.len())
+    }
+
+    /// The index `offset` cells from the pointer, which the caller knows is on
+    /// the tape.
+    fn cell_at(&mut self, offset: isize) -> usize {
+        let ptr = self.ptr.wrapping_add_signed(offset);
+        self.stats.lowest_cell = self.stats.lowest_cell.min(ptr);
+        self.stats.highest_cell = self.stats.highest_cell.max(ptr);
+        ptr
+    }
+
+    /// The index `offset` cells from the pointer. When `checked`, walks there and
+    /// back so tape growth and wrapping behave as they would for the moves the
+    /// optimizer folded away; a growing tape may shift earlier indices.
+    fn reach(&mut self, offset: isize, d: &DebugInfo, checked: bool) -> Result<usize, ExecError> {
+        if checked {
+            self.seek(offset);
+            let ptr = self.cell(d)?;
+            self.seek(offset.wrapping_neg());
+            Ok(ptr)
+        } else {
+            Ok(self.cell_at(offset))
+        }
+    }
+
+    /// How many more cells a growing tape may add.
+    fn room(&self) -> usize {
+        self.max_cells.saturating_sub(self.mem.len())
+    }
+
+    /// Counts the node about to run and reports it to `hooks`.
+    #[inline(always)]
+    fn begin<H: Hooks>(&mut self, node: Option<&Option<&Node>>, hooks: &mut H) {
+        if let Some(&Some(node)) = node {
+            let cell = self
+                .mem
+                .get(self.ptr)
+                .map(|&raw| self.cell_size.value(raw, self.signed_cells));
+            hooks.on_instruction(node, self.ptr, cell);
+        }
+        self.stats.instructions += 1;
+    }
+
+    #[inline(always)]
+    fn move_by(&mut self, v: isize, checked: bool) {
+        if checked {
+            self.seek(v);
+        } else {
+            self.ptr = self.ptr.wrapping_add_signed(v);
+        }
+    }
+
+    #[inline(always)]
+    fn add<H: Hooks>(
+        &mut self,
+        offset: isize,
+        v: i32,
+        d: &DebugInfo,
+        checked: bool,
+        hooks: &mut H,
+    ) -> Result<(), ExecError> {
+        let ptr = if offset == 0 {
+            self.cell(d)?
+        } else {
+            self.reach(offset, d, checked)?
+        };
+        if self.strict {
+            self.check_range(ptr, i64::from(v), d)?;
+        }
+        self.mem[ptr] = self.mem[ptr].wrapping_add(v as u32) & self.mask;
+        hooks.on_cell_read(ptr);
+        hooks.on_cell_write(ptr);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn set<H: Hooks>(&mut self, v: u32, d: &DebugInfo, hooks: &mut H) -> Result<(), ExecError> {
+        let ptr = self.cell(d)?;
+        self.mem[ptr] = v & self.mask;
+        hooks.on_cell_write(ptr);
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn mul_add<H: Hooks>(
+        &mut self,
+        offset: isize,
+        factor: u32,
+        d: &DebugInfo,
+        checked: bool,
+        hooks: &mut H,
+    ) -> Result<(), ExecError> {
+        let ptr = self.cell(d)?;
+        hooks.on_cell_read(ptr);
+        let value = self.mem[ptr];
+        if value != 0 {
+            let target = self.reach(offset, d, checked)?;
+            let sum = self.mem[target].wrapping_add(value.wrapping_mul(factor));
+            self.mem[target] = sum & self.mask;
+            hooks.on_cell_read(target);
+            hooks.on_cell_write(target);
+        }
+        Ok(())
+    }
+
+    /// Fails if adding `delta` to cell `ptr` would leave the range of its type.
+    fn check_range(&self, ptr: usize, delta: i64, d: &DebugInfo) -> Result<(), ExecError> {
+        let value = self.cell_size.value(self.mem[ptr], self.signed_cells) + delta;
+        if value > self.cell_size.max_value(self.signed_cells) {
+            Err(ExecError::Overflow(d.clone()))
+        } else if value < self.cell_size.min_value(self.signed_cells) {
+            Err(ExecError::Underflow(d.clone()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Stores what `,` or `;` leaves in cell `ptr` at end of input.
+    fn store_eof(&mut self, ptr: usize) {
+        match self.eof {
+            EofMode::Zero => self.mem[ptr] = 0,
+            EofMode::MinusOne => self.mem[ptr] = self.mask,
+            EofMode::Unchanged => (),
+        }
+    }
+
+    /// The cells around the pointer, clipped to the tape.
+    fn dump<'d>(&self, location: &'d DebugInfo) -> DebugDump<'d> {
+        let len = self.mem.len();
+        let first_cell = self.ptr.saturating_sub(DEBUG_WINDOW).min(len);
+        let end = self.ptr.saturating_add(DEBUG_WINDOW + 1).min(len);
+        DebugDump {
+            location,
+            ptr: self.ptr,
+            first_cell,
+            cells: self.mem[first_cell..end]
+                .iter()
+                .map(|&raw| self.cell_size.value(raw, self.signed_cells))
+                .collect(),
+        }
+    }
+
+    /// Returns the current cell index, or an error if it is off the tape.
+    #[inline]
+    fn cell(&mut self, d: &DebugInfo) -> Result<usize, ExecError> {
+        if self.ptr >= self.mem.len() {
+            self.extend(d)?;
+        }
+        self.stats.lowest_cell = self.stats.lowest_cell.min(self.ptr);
+        self.stats.highest_cell = self.stats.highest_cell.max(self.ptr);
+        Ok(self.ptr)
+    }
+
+    /// Grows the tape to reach the pointer, which is past its end.
+    #[cold]
+    fn extend(&mut self, d: &DebugInfo) -> Result<(), ExecError> {
+        if self.tape_mode != TapeMode::Grow {
+            return Err(ExecError::OutOfBounds(d.clone()));
+        }
+        if self.ptr >= self.max_cells {
+            return Err(ExecError::MemoryLimitExceeded(d.clone()));
+        }
+        let len = ((self.ptr / Program::GROW_CHUNK + 1) * Program::GROW_CHUNK).min(self.max_cells);
+        self.mem.resize(len, 0);
+        Ok(())
+    }
+}
+
+/// A seed for unseeded programs: the clock where there is one, otherwise fixed.
+pub(crate) fn fresh_seed() -> u64 {
+    #[cfg(feature = "std")]
+    {
+        use std::time::{SystemTime, UNIX_EPOCH};
+        if let Ok(elapsed) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            return elapsed.as_nanos() as u64;
+        }
+    }
+    0
+}
+
+/// A saved copy of an interpreter's tape, pointer and position in the program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct StateSnapshot {
+    pub ptr: usize,
+    pub mem: Vec<u32>,
+    /// The node index at each level of loop nesting, outermost first.
+    pub pc: Vec<usize>,
+    pub stats: ExecStats,
+}
+
+/// Returned when a snapshot's position does not exist in the program being restored.
+#[derive(Debug)]
+pub struct InvalidSnapshot;
+
+impl fmt::Display for InvalidSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "snapshot does not match this program")
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for InvalidSnapshot {}
+
+/// Callbacks invoked as a program runs, for building tracers, coverage tools and
+/// visualizers. Every method defaults to doing nothing.
+pub trait Hooks {
+    /// Called before each node executes, with the pointer and the value of the
+    /// cell it is on, or `None` if the pointer is off the tape.
+    fn on_instruction(&mut self, _node: &Node, _ptr: usize, _cell: Option<i64>) {}
+
+    fn on_output(&mut self, _value: u8, _d: &DebugInfo) {}
+
+    /// Called when execution reaches a loop, before its condition is first tested.
+    fn on_loop_enter(&mut self, _d: &DebugInfo) {}
+
+    /// Called when a loop's condition is found false and execution moves past it.
+    fn on_loop_exit(&mut self, _d: &DebugInfo) {}
+
+    /// Called whenever a cell's value is read, including by loop conditions.
+    fn on_cell_read(&mut self, _index: usize) {}
+
+    fn on_cell_write(&mut self, _index: usize) {}
+}
+
+/// Hooks that do nothing.
+pub struct NoHooks;
+
+impl Hooks for NoHooks {}
+
+impl<H: Hooks + ?Sized> Hooks for &mut H {
+    fn on_instruction(&mut self, node: &Node, ptr: usize, cell: Option<i64>) {
+        (**self).on_instruction(node, ptr, cell)
+    }
+
+    fn on_output(&mut self, value: u8, d: &DebugInfo) {
+        (**self).on_output(value, d)
+    }
+
+    fn on_loop_enter(&mut self, d: &DebugInfo) {
+        (**self).on_loop_enter(d)
+    }
+
+    fn on_loop_exit(&mut self, d: &DebugInfo) {
+        (**self).on_loop_exit(d)
+    }
+
+    fn on_cell_read(&mut self, index: usize) {
+        (**self).on_cell_read(index)
+    }
+
+    fn on_cell_write(&mut self, index: usize) {
+        (**self).on_cell_write(index)
+    }
+}
+
+/// Absent hooks do nothing.
+impl<H: Hooks> Hooks for Option<H> {
+    fn on_instruction(&mut self, node: &Node, ptr: usize, cell: Option<i64>) {
+        if let Some(hooks) = self {
+            hooks.on_instruction(node, ptr, cell)
+        }
+    }
+
+    fn on_output(&mut self, value: u8, d: &DebugInfo) {
+        if let Some(hooks) = self {
+            hooks.on_output(value, d)
+        }
+    }
+
+    fn on_loop_enter(&mut self, d: &DebugInfo) {
+        if let Some(hooks) = self {
+            hooks.on_loop_enter(d)
+        }
+    }
+
+    fn on_loop_exit(&mut self, d: &DebugInfo) {
+        if let Some(hooks) = self {
+            hooks.on_loop_exit(d)
+        }
+    }
+
+    fn on_cell_read(&mut self, index: usize) {
+        if let Some(hooks) = self {
+            hooks.on_cell_read(index)
+        }
+    }
+
+    fn on_cell_write(&mut self, index: usize) {
+        if let Some(hooks) = self {
+            hooks.on_cell_write(index)
+        }
+    }
+}
+
+/// Runs both sets of hooks, the first before the second.
+impl<A: Hooks, B: Hooks> Hooks for (A, B) {
+    fn on_instruction(&mut self, node: &Node, ptr: usize, cell: Option<i64>) {
+        self.0.on_instruction(node, ptr, cell);
+        self.1.on_instruction(node, ptr, cell);
+    }
+
+    fn on_output(&mut self, value: u8, d: &DebugInfo) {
+        self.0.on_output(value, d);
+        self.1.on_output(value, d);
+    }
+
+    fn on_loop_enter(&mut self, d: &DebugInfo) {
+        self.0.on_loop_enter(d);
+        self.1.on_loop_enter(d);
+    }
+
+    fn on_loop_exit(&mut self, d: &DebugInfo) {
+        self.0.on_loop_exit(d);
+        self.1.on_loop_exit(d);
+    }
+
+    fn on_cell_read(&mut self, index: usize) {
+        self.0.on_cell_read(index);
+        self.1.on_cell_read(index);
+    }
+
+    fn on_cell_write(&mut self, index: usize) {
+        self.0.on_cell_write(index);
+        self.1.on_cell_write(index);
+    }
+}
+
+/// Why `Interpreter::run_for` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunOutcome {
+    /// The fuel ran out before the program finished; call `run_for` again to continue.
+    Yielded,
+    /// The next `,` is waiting for input the `Io` does not have ready yet.
+    NeedsInput,
+    Halted,
+}
+
+/// Bounds on how much work `Interpreter::run_limited` may do.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// The most nodes to execute, counted like `ExecStats::instructions`.
+    pub max_instructions: Option<u64>,
+    /// The most bytes to write.
+    pub max_output: Option<u64>,
+    #[cfg(feature = "std")]
+    pub timeout: Option<Duration>,
+    /// Stops the run with `ExecError::Interrupted` once set, for example from a
+    /// signal handler. Checked periodically rather than every step.
+    pub interrupt: Option<Arc<AtomicBool>>,
+}
+
+/// Why `Interpreter::execute` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    Halted,
+    /// It ran all the steps it was given.
+    OutOfFuel,
+    /// The next node would pass the instruction limit.
+    InstructionLimit,
+    /// The next node would pass the output limit.
+    OutputLimit,
+}
+
+/// One instruction of the program flattened for execution, with each loop and
+/// `If` body between an `Enter` and its `End`. The nodes run most often get ops
+/// of their own, so running them takes a single dispatch.
+#[derive(Clone, Copy)]
+enum Op<'p> {
+    Move(isize),
+    Add(isize, i32, &'p DebugInfo),
+    Set(u32, &'p DebugInfo),
+    MulAdd(isize, u32, &'p DebugInfo),
+    Product(isize, isize, u32, &'p DebugInfo),
+    Scan(isize, &'p DebugInfo),
+    /// Superinstructions for the pairs of nodes that most often run one after
+    /// the other, each still counted and reported to hooks as two nodes. The
+    /// op for the second node follows, for when only the first half runs.
+    MoveAdd(isize, isize, i32, &'p DebugInfo),
+    AddMove(isize, i32, &'p DebugInfo, isize),
+    SetMove(u32, &'p DebugInfo, isize),
+    MoveMulAdd(isize, isize, u32, &'p DebugInfo),
+    MulAddSet(isize, u32, &'p DebugInfo, u32, &'p DebugInfo),
+    /// Runs the body of a loop, or of an `If` when `span` is `None`, if the
+    /// current cell is nonzero, and otherwise moves past the `End` at `end`.
+    /// `span` is the loop's `balanced_span`.
+    Enter {
+        end: usize,
+        span: Option<Option<(isize, isize)>>,
+        d: &'p DebugInfo,
+    },
+    /// Ends the body entered at `start`, testing a loop's condition again.
+    End {
+        start: usize,
+    },
+    /// Runs any other node.
+    Node(&'p Node),
+}
+
+/// The program flattened for execution.
+struct Code<'p> {
+    ops: Vec<Op<'p>>,
+    /// The node each op runs, or `None` for an `End`.
+    nodes: Vec<Option<&'p Node>>,
+}
+
+impl<'p> Code<'p> {
+    /// Flattens `nodes`, without recursing per level of nesting.
+    fn new(nodes: &'p VecDeque<Node>) -> Code<'p> {
+        let mut code = Code {
+            ops: Vec::new(),
+            nodes: Vec::new(),
+        };
+        // The rest of each enclosing level, with the index of the body's `Enter`.
+        let mut outer: Vec<(Iter<'p, Node>, usize)> = Vec::new();
+        let mut level = nodes.iter();
+        loop {
+            let node = match level.next() {
+                Some(node) => node,
+                None => match outer.pop() {
+                    Some((rest, start)) => {
+                        let end = code.ops.len();
+                        if let Op::Enter { end: ref mut e, .. } = code.ops[start] {
+                            *e = end;
+                        }
+                        code.ops.push(Op::End { start });
+                        code.nodes.push(None);
+                        level = rest;
+                        continue;
+                    }
+                    None => {
+                        code.fuse();
+                        return code;
+                    }
+                },
+            };
+            let op = match *node {
+                Node::Move(v, _) => Op::Move(v),
+                Node::Add(offset, v, ref d) => Op::Add(offset, v, d),
+                Node::Set(v, ref d) => Op::Set(v, d),
+                Node::MulAdd(offset, factor, ref d) => Op::MulAdd(offset, factor, d),
+                Node::Product(offset, source, factor, ref d) => {
+                    Op::Product(offset, source, factor, d)
+                }
+                Node::Scan(step, ref d) => Op::Scan(step, d),
+                Node::Loop(ref body, ref d) | Node::If(ref body, ref d) => {
+                    let span = match *node {
+                        Node::Loop(..) => Some(balanced_span(body)),
+                        _ => None,
+                    };
+                    outer.push((mem::replace(&mut level, body.iter()), code.ops.len()));
+                    Op::Enter { end: 0, span, d }
+                }
+                _ => Op::Node(node),
+            };
+            code.ops.push(op);
+            code.nodes.push(Some(node));
+        }
+    }
+
+    /// Replaces the first op of each fusable pair with a superinstruction.
+    fn fuse(&mut self) {
+        let mut i = 0;
+        while i + 1 < self.ops.len() {
+            let fused = match (self.ops[i], self.ops[i + 1]) {
+                (Op::Move(m), Op::Add(offset, v, d)) => Op::MoveAdd(m, offset, v, d),
+                (Op::Add(offset, v, d), Op::Move(m)) => Op::AddMove(offset, v, d, m),
+                (Op::Set(v, d), Op::Move(m)) => Op::SetMove(v, d, m),
+                (Op::Move(m), Op::MulAdd(offset, factor, d)) => {
+                    Op::MoveMulAdd(m, offset, factor, d)
+                }
+                (Op::MulAdd(offset, factor, d), Op::Set(v, set_d)) => {
+                    Op::MulAddSet(offset, factor, d, v, set_d)
+                }
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            self.ops[i] = fused;
+            i += 2;
+        }
+    }
+}
+
+/// Executes a program one node at a time, so callers can pause, inspect and
+/// resume it between steps.
+pub struct Interpreter<'p, I, H = NoHooks> {
+    code: Code<'p>,
+    /// The next op to run, past the end once the program has finished.
+    pc: usize,
+    /// Whether the pointer needs bounds checks. Balanced loops that fit on the
+    /// tape when entered skip them, as the pointer cannot leave it.
+    checked: bool,
+    state: ProgramState,
+    io: I,
+    hooks: H,
+}
+
+impl<'p, I: Io> Interpreter<'p, I> {
+    pub(crate) fn new(program: &'p Program, io: I) -> Interpreter<'p, I> {
+        Interpreter {
+            code: Code::new(&program.ast.nodes),
+            pc: 0,
+            checked: true,
+            state: ProgramState::new(program),
+            io,
+            hooks: NoHooks,
+        }
+    }
+}
+
+impl<'p, I: Io, H: Hooks> Interpreter<'p, I, H> {
+    /// Replaces the interpreter's hooks.
+    pub fn with_hooks<H2: Hooks>(self, hooks: H2) -> Interpreter<'p, I, H2> {
+        Interpreter {
+            code: self.code,
+            pc: self.pc,
+            checked: self.checked,
+            state: self.state,
+            io: self.io,
+            hooks,
+        }
+    }
+
+    /// Executes the next node, returning the byte written if it was an output.
+    /// Does nothing once the program has finished.
+    pub fn step(&mut self) -> Result<Option<u8>, ExecError> {
+        self.execute(1, u64::MAX, u64::MAX)
+            .map(|(_, output)| output)
+    }
+
+    /// Executes up to `fuel` steps without returning between them, stopping
+    /// early if the program finishes, or before a node that would take it past
+    /// `max_instructions` or write past `max_output` bytes. Each node is a step,
+    /// as is each byte of a `:` or `;` and each end of a loop or `If` body.
+    /// Also returns the last byte written, if any.
+    fn execute(
+        &mut self,
+        fuel: u64,
+        max_instructions: u64,
+        max_output: u64,
+    ) -> Result<(Stop, Option<u8>), ExecError> {
+        // Kept out of `self` while running, so it can live in a register.
+        let mut pc = self.pc;
+        let result = self.execute_at(&mut pc, fuel, max_instructions, max_output);
+        self.pc = pc;
+        result
+    }
+
+    #[inline(always)]
+    fn execute_at(
+        &mut self,
+        pc: &mut usize,
+        mut fuel: u64,
+        max_instructions: u64,
+        max_output: u64,
+    ) -> Result<(Stop, Option<u8>), ExecError> {
+        let mut output = None;
+        while let Some(&op) = self.code.ops.get(*pc) {
+            if fuel == 0 {
+                return Ok((Stop::OutOfFuel, output));
+            }
+            fuel -= 1;
+            if let Op::End { start } = op {
+                // An empty loop never changes its cell, so once entered it runs
+                // forever. Each pass counts as its node again, so instruction
+                // limits still stop it.
+                if start + 1 == *pc
+                    && matches!(self.code.ops[start], Op::Enter { span: Some(_), .. })
+                {
+                    if self.state.stats.instructions >= max_instructions {
+                        return Ok((Stop::InstructionLimit, output));
+                    }
+                    self.state
+                        .begin(self.code.nodes.get(start), &mut self.hooks);
+                }
+                self.end(pc, start)?;
+                continue;
+            }
+            let checked = self.checked;
+            let state = &mut self.state;
+            if state.pending.is_none() {
+                if state.stats.instructions >= max_instructions {
+                    return Ok((Stop::InstructionLimit, output));
+                }
+                state.begin(self.code.nodes.get(*pc), &mut self.hooks);
+            }
+            // The second half of a fused op runs in the same step if there is
+            // fuel and room under the limit left for it, and otherwise on the next.
+            macro_rules! then {
+                () => {{
+                    *pc += 1;
+                    if fuel == 0 || state.stats.instructions >= max_instructions {
+                        continue;
+                    }
+                    fuel -= 1;
+                    state.begin(self.code.nodes.get(*pc), &mut self.hooks);
+                }};
+            }
+            match op {
+                Op::Move(v) => state.move_by(v, checked),
+                Op::Add(offset, v, d) => state.add(offset, v, d, checked, &mut self.hooks)?,
+                Op::Set(v, d) => state.set(v, d, &mut self.hooks)?,
+                Op::MulAdd(offset, factor, d) => {
+                    state.mul_add(offset, factor, d, checked, &mut self.hooks)?
+                }
+                Op::MoveAdd(m, offset, v, d) => {
+                    state.move_by(m, checked);
+                    then!();
+                    state.add(offset, v, d, checked, &mut self.hooks)?;
+                }
+                Op::AddMove(offset, v, d, m) => {
+                    state.add(offset, v, d, checked, &mut self.hooks)?;
+                    then!();
+                    state.move_by(m, checked);
+                }
+                Op::SetMove(v, d, m) => {
+                    state.set(v, d, &mut self.hooks)?;
+                    then!();
+                    state.move_by(m, checked);
+                }
+                Op::MoveMulAdd(m, offset, factor, d) => {
+                    state.move_by(m, checked);
+                    then!();
+                    state.mul_add(offset, factor, d, checked, &mut self.hooks)?;
+                }
+                Op::MulAddSet(offset, factor, d, v, set_d) => {
+                    state.mul_add(offset, factor, d, checked, &mut self.hooks)?;
+                    then!();
+                    state.set(v, set_d, &mut self.hooks)?;
+                }
+                Op::Product(offset, source, factor, d) => {
+                    let ptr = state.cell(d)?;
+                    self.hooks.on_cell_read(ptr);
+                    let value = state.mem[ptr];
+                    if value != 0 {
+                        let source = state.reach(source, d, checked)?;
+                        self.hooks.on_cell_read(source);
+                        let value = value.wrapping_mul(state.mem[source]);
+                        let target = state.reach(offset, d, checked)?;
+                        let sum = state.mem[target].wrapping_add(value.wrapping_mul(factor));
+                        state.mem[target] = sum & state.mask;
+                        self.hooks.on_cell_read(target);
+                        self.hooks.on_cell_write(target);
+                    }
+                }
+                Op::Scan(step, d) => {
+                    // Scans that run off the tape resume on the next step, so each
+                    // step stays bounded even on a wrapping tape with no zero cell.
+                    if !state.scan(step, d, &mut self.hooks)? {
+                        continue;
+                    }
+                }
+                Op::Enter { end, span, d } => {
+                    if span.is_some() {
+                        self.hooks.on_loop_enter(d);
+                    }
+                    let ptr = state.cell(d)?;
+                    self.hooks.on_cell_read(ptr);
+                    if state.mem[ptr] != 0 {
+                        state.stats.loop_iterations += 1;
+                        // An `If` body is checked as the code around it is.
+                        if let Some(span) = span {
+                            self.checked = !span.is_some_and(|(lo, hi)| state.fits(lo, hi));
+                        }
+                    } else {
+                        if span.is_some() {
+                            self.hooks.on_loop_exit(d);
+                        }
+                        *pc = end + 1;
+                        continue;
+                    }
+                }
+                Op::End { .. } => unreachable!("handled before counting instructions"),
+                Op::Node(node) => {
+                    if state.stats.bytes_written >= max_output && node.writes_output() {
+                        return Ok((Stop::OutputLimit, output));
+                    }
+                    match *node {
+                        Node::Output(ref d) => {
+                            let ptr = state.cell(d)?;
+                            self.hooks.on_cell_read(ptr);
+                            state.stats.bytes_written += 1;
+                            // Wide cells write their low byte.
+                            let value = state.mem[ptr] as u8;
+                            self.io.output(value);
+                            self.hooks.on_output(value, d);
+                            output = Some(value);
+                        }
+                        Node::Input(ref d) => {
+                            let val = self.io.input();
+                            let ptr = state.cell(d)?;
+                            self.hooks.on_cell_write(ptr);
+                            match val {
+                                Some(val) => {
+                                    state.stats.bytes_read += 1;
+                                    state.mem[ptr] = u32::from(val);
+                                }
+                                None => state.store_eof(ptr),
+                            }
+                        }
+                        Node::PrintNumber(ref d) => {
+                            let ptr = state.cell(d)?;
+                            let mut bytes = match state.pending.take() {
+                                Some(Pending::Output(bytes)) => bytes,
+                                _ => {
+                                    self.hooks.on_cell_read(ptr);
+                                    let value =
+                                        state.cell_size.value(state.mem[ptr], state.signed_cells);
+                                    format!("{}\n", value).bytes().rev().collect()
+                                }
+                            };
+                            let value = bytes.pop().expect("a number ends with a newline");
+                            state.stats.bytes_written += 1;
+                            self.io.output(value);
+                            self.hooks.on_output(value, d);
+                            if !bytes.is_empty() {
+                                state.pending = Some(Pending::Output(bytes));
+                                output = Some(value);
+                                continue;
+                            }
+                            output = Some(value);
+                        }
+                        Node::ReadNumber(ref d) => {
+                            let byte = self.io.input();
+                            let ptr = state.cell(d)?;
+                            let (mut value, mut negative, mut digits) = match state.pending.take() {
+                                Some(Pending::Input {
+                                    value,
+                                    negative,
+                                    digits,
+                                }) => (value, negative, digits),
+                                _ => (0, false, false),
+                            };
+                            if byte.is_some() {
+                                state.stats.bytes_read += 1;
+                            }
+                            let started = digits || negative;
+                            let more = match byte {
+                                Some(b @ b'0'..=b'9') => {
+                                    value =
+                                        value.wrapping_mul(10).wrapping_add(i64::from(b - b'0'));
+                                    digits = true;
+                                    true
+                                }
+                                Some(b'-') if !started => {
+                                    negative = true;
+                                    true
+                                }
+                                Some(b) => b.is_ascii_whitespace() && !started,
+                                None => false,
+                            };
+                            if more {
+                                state.pending = Some(Pending::Input {
+                                    value,
+                                    negative,
+                                    digits,
+                                });
+                                continue;
+                            }
+                            match (digits, byte) {
+                                (true, _) => {
+                                    let value = if negative {
+                                        value.wrapping_neg()
+                                    } else {
+                                        value
+                                    };
+                                    state.mem[ptr] = value as u32 & state.mask;
+                                }
+                                (false, None) => state.store_eof(ptr),
+                                (false, Some(_)) => state.mem[ptr] = 0,
+                            }
+                            self.hooks.on_cell_write(ptr);
+                        }
+                        Node::Write(ref bytes, ref d) => {
+                            let mut bytes = match state.pending.take() {
+                                Some(Pending::Output(bytes)) => bytes,
+                                _ => bytes.iter().rev().cloned().collect(),
+                            };
+                            let value = bytes.pop().expect("a write is never empty");
+                            state.stats.bytes_written += 1;
+                            self.io.output(value);
+                            self.hooks.on_output(value, d);
+                            if !bytes.is_empty() {
+                                state.pending = Some(Pending::Output(bytes));
+                                output = Some(value);
+                                continue;
+                            }
+                            output = Some(value);
+                        }
+                        Node::Debug(ref d) => self.io.debug(&state.dump(d)),
+                        Node::Random(ref d) => {
+                            let ptr = state.cell(d)?;
+                            state.mem[ptr] = u32::from(next_random(&mut state.rng));
+                            self.hooks.on_cell_write(ptr);
+                        }
+                        _ => unreachable!("other nodes are flattened into their own ops"),
+                    }
+                }
+            }
+            *pc += 1;
+        }
+        Ok((Stop::Halted, output))
+    }
+
+    /// Runs the `End` of the body entered at `start`, running a loop body
+    /// again while the current cell is nonzero.
+    #[inline(always)]
+    fn end(&mut self, pc: &mut usize, start: usize) -> Result<(), ExecError> {
+        if let Op::Enter {
+            span: Some(_), d, ..
+        } = self.code.ops[start]
+        {
+            let ptr = self.state.cell(d)?;
+            self.hooks.on_cell_read(ptr);
+            if self.state.mem[ptr] != 0 {
+                self.state.stats.loop_iterations += 1;
+                *pc = start + 1;
+                return Ok(());
+            }
+            self.hooks.on_loop_exit(d);
+            // A balanced loop's body holds no other loops, so the code around
+            // it needs checks again.
+            self.checked = true;
+        }
+        *pc += 1;
+        Ok(())
+    }
+
+    /// Runs until the next output, returning the byte written, or `None` if the
+    /// program finished first.
+    pub fn run_until_output(&mut self) -> Result<Option<u8>, ExecError> {
+        while !self.is_finished() {
+            if let Some(value) = self.step()? {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs the program to completion.
+    pub fn run(&mut self) -> Result<&ExecStats, ExecError> {
+        self.execute(u64::MAX, u64::MAX, u64::MAX)?;
+        Ok(&self.state.stats)
+    }
+
+    /// Executes at most `fuel` steps, so a host can bound the work done per call.
+    pub fn run_for(&mut self, fuel: u64) -> Result<RunOutcome, ExecError> {
+        for _ in 0..fuel {
+            if self.is_finished() {
+                return Ok(RunOutcome::Halted);
+            }
+            if self.current_node().is_some_and(Node::reads_input) && !self.io.input_ready() {
+                return Ok(RunOutcome::NeedsInput);
+            }
+            self.step()?;
+        }
+
+        if self.is_finished() {
+            Ok(RunOutcome::Halted)
+        } else {
+            Ok(RunOutcome::Yielded)
+        }
+    }
+
+    /// Runs the program to completion, failing with `ExecError::LimitExceeded`
+    /// if it passes any of `limits`, or `ExecError::Interrupted` if interrupted.
+    pub fn run_limited(&mut self, limits: &Limits) -> Result<&ExecStats, ExecError> {
+        #[cfg(feature = "std")]
+        let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+        let max_instructions = limits.max_instructions.unwrap_or(u64::MAX);
+        let max_output = limits.max_output.unwrap_or(u64::MAX);
+        loop {
+            // Reading the clock every step would dominate the run time.
+            match self.execute(0x10000, max_instructions, max_output)?.0 {
+                Stop::Halted => return Ok(&self.state.stats),
+                Stop::InstructionLimit => {
+                    return Err(ExecError::LimitExceeded(self.location().clone()))
+                }
+                Stop::OutputLimit => {
+                    let node = self.current_node().expect("stopped before an output");
+                    return Err(ExecError::OutputLimitExceeded(node.debug_info().clone()));
+                }
+                Stop::OutOfFuel => (),
+            }
+            #[cfg(feature = "std")]
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(ExecError::LimitExceeded(self.location().clone()));
+            }
+            if limits
+                .interrupt
+                .as_ref()
+                .is_some_and(|flag| flag.load(Ordering::Relaxed))
+            {
+                return Err(ExecError::Interrupted(self.location().clone()));
+            }
+        }
+    }
+
+    /// The innermost loop being executed, or the next node outside of any loop.
+    fn location(&self) -> &'p DebugInfo {
+        match self.enclosing() {
+            Some(start) => self.code.nodes[start].expect("an `Enter` runs a node"),
+            None => self
+                .current_node()
+                .expect("only called with a node to execute"),
+        }
+        .debug_info()
+    }
+
+    /// The index of the `Enter` of the innermost body being executed, found by
+    /// walking back over the ops before it at the same level.
+    fn enclosing(&self) -> Option<usize> {
+        let mut i = self.pc;
+        while i > 0 {
+            i -= 1;
+            match self.code.ops[i] {
+                Op::Enter { .. } => return Some(i),
+                Op::End { start } => i = start,
+                _ => (),
+            }
+        }
+        None
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.pc >= self.code.ops.len()
+    }
+
+    /// The node the next `step` will execute.
+    pub fn current_node(&self) -> Option<&'p Node> {
+        *self.code.nodes.get(self.pc)?
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.state.ptr
+    }
+
+    pub fn tape(&self) -> &[u32] {
+        &self.state.mem
+    }
+
+    /// The number held in cell `index`, signed if the program uses signed cells.
+    pub fn cell_value(&self, index: usize) -> Option<i64> {
+        let raw = *self.state.mem.get(index)?;
+        Some(self.state.cell_size.value(raw, self.state.signed_cells))
+    }
+
+    pub fn stats(&self) -> &ExecStats {
+        &self.state.stats
+    }
+
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            ptr: self.state.ptr,
+            mem: self.state.mem.clone(),
+            pc: self.path(),
+            stats: self.state.stats.clone(),
+        }
+    }
+
+    /// The node index at each level of nesting down to the next op, where an
+    /// `End` is one past the last node of its body.
+    fn path(&self) -> Vec<usize> {
+        let mut path = Vec::new();
+        if self.is_finished() {
+            return path;
+        }
+        let (mut i, mut index) = (self.pc, 0);
+        while i > 0 {
+            i -= 1;
+            match self.code.ops[i] {
+                Op::Enter { .. } => {
+                    path.push(index);
+                    index = 0;
+                    continue;
+                }
+                Op::End { start } => i = start,
+                _ => (),
+            }
+            index += 1;
+        }
+        path.push(index);
+        path.reverse();
+        path
+    }
+
+    /// Resumes from `snapshot`, which must have been taken from the same program.
+    pub fn restore(&mut self, snapshot: &StateSnapshot) -> Result<(), InvalidSnapshot> {
+        let mut pc = 0;
+        for (depth, &index) in snapshot.pc.iter().enumerate() {
+            for _ in 0..index {
+                pc = match self.code.ops.get(pc) {
+                    Some(&Op::Enter { end, .. }) => end + 1,
+                    Some(&Op::End { .. }) | None => return Err(InvalidSnapshot),
+                    Some(_) => pc + 1,
+                };
+            }
+            if depth + 1 < snapshot.pc.len() {
+                match self.code.ops.get(pc) {
+                    Some(&Op::Enter { .. }) => pc += 1,
+                    _ => return Err(InvalidSnapshot),
+                }
+            }
+        }
+        if snapshot.pc.is_empty() {
+            pc = self.code.ops.len();
+        }
+
+        self.pc = pc;
+        self.checked = true;
+        self.state.ptr = snapshot.ptr;
+        self.state.mem = snapshot.mem.clone();
+        self.state.stats = snapshot.stats.clone();
+        self.state.pending = None;
+        Ok(())
+    }
+
+    pub fn io_mut(&mut self) -> &mut I {
+        &mut self.io
+    }
+
+    pub fn into_io(self) -> I {
+        self.io
+    }
+}
+
+/// Something observable that happened while running a program pulled through
+/// `Events`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecEvent {
+    Output(u8),
+    /// The next `,` is waiting for `Events::provide_input` or `Events::provide_eof`.
+    NeedsInput,
+    /// A loop body is about to run, once per iteration.
+    LoopEntered,
+    Halted,
+}
+
+/// Holds the byte supplied for the next `,` while running under `Events`.
+pub struct EventIo {
+    /// `Some(None)` once end of input has been provided.
+    input: Option<Option<u8>>,
+}
+
+impl EventIo {
+    pub(crate) fn new() -> EventIo {
+        EventIo { input: None }
+    }
+}
+
+impl Io for EventIo {
+    fn output(&mut self, _value: u8) {}
+
+    fn input(&mut self) -> Option<u8> {
+        self.input.take().and_then(|value| value)
+    }
+
+    fn input_ready(&mut self) -> bool {
+        self.input.is_some()
+    }
+}
+
+/// Runs a program by pulling `ExecEvent`s, without performing any I/O itself.
+pub struct Events<'p> {
+    interpreter: Interpreter<'p, EventIo>,
+    halted: bool,
+}
+
+impl<'p> Events<'p> {
+    pub(crate) fn new(interpreter: Interpreter<'p, EventIo>) -> Events<'p> {
+        Events {
+            interpreter,
+            halted: false,
+        }
+    }
+
+    /// Supplies the byte read by the pending `,`.
+    pub fn provide_input(&mut self, value: u8) {
+        self.interpreter.io.input = Some(Some(value));
+    }
+
+    /// Lets the pending `,` read end of input.
+    pub fn provide_eof(&mut self) {
+        self.interpreter.io.input = Some(None);
+    }
+
+    pub fn interpreter(&self) -> &Interpreter<'p, EventIo> {
+        &self.interpreter
+    }
+}
+
+impl<'p> Iterator for Events<'p> {
+    type Item = Result<ExecEvent, ExecError>;
+
+    fn next(&mut self) -> Option<Result<ExecEvent, ExecError>> {
+        while !self.interpreter.is_finished() {
+            if self
+                .interpreter
+                .current_node()
+                .is_some_and(Node::reads_input)
+                && !self.interpreter.io.input_ready()
+            {
+                return Some(Ok(ExecEvent::NeedsInput));
+            }
+
+            let iterations = self.interpreter.state.stats.loop_iterations;
+            match self.interpreter.step() {
+                Ok(Some(value)) => return Some(Ok(ExecEvent::Output(value))),
+                Ok(None) if self.interpreter.state.stats.loop_iterations > iterations => {
+                    return Some(Ok(ExecEvent::LoopEntered))
+                }
+                Ok(None) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        if self.halted {
+            None
+        } else {
+            self.halted = true;
+            Some(Ok(ExecEvent::Halted))
+        }
+    }
+}
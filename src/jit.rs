@@ -0,0 +1,143 @@
+use std::slice;
+
+use brainfuck::{next_random, rng_state, CellSize, DebugInfo, Io, Node, Program, TapeMode};
+use error::BrainfuckError;
+use interpreter::fresh_seed;
+use ExecError;
+
+/// Compiled code: given the tape and the runtime, it returns where the pointer
+/// ends, or a negative fault code.
+pub(crate) type Entry = unsafe extern "C" fn(*mut u8, *mut Runtime) -> i64;
+
+/// What compiled code calls back into while it runs.
+pub(crate) struct Runtime<'i> {
+    io: &'i mut dyn Io,
+    rng: u64,
+}
+
+pub(crate) extern "C" fn jit_output(runtime: *mut Runtime, value: u32) {
+    let runtime = unsafe { &mut *runtime };
+    runtime.io.output(value as u8);
+}
+
+/// The next input byte, or -1 at end of input.
+pub(crate) extern "C" fn jit_input(runtime: *mut Runtime) -> i32 {
+    let runtime = unsafe { &mut *runtime };
+    runtime.io.input().map_or(-1, i32::from)
+}
+
+pub(crate) extern "C" fn jit_random(runtime: *mut Runtime) -> u32 {
+    let runtime = unsafe { &mut *runtime };
+    u32::from(next_random(&mut runtime.rng))
+}
+
+pub(crate) extern "C" fn jit_write(runtime: *mut Runtime, bytes: *const u8, len: usize) {
+    let runtime = unsafe { &mut *runtime };
+    for &b in unsafe { slice::from_raw_parts(bytes, len) } {
+        runtime.io.output(b);
+    }
+}
+
+/// Fails for programs the JITs can't compile.
+pub(crate) fn check(program: &Program) -> Result<(), BrainfuckError> {
+    if program.strict {
+        return Err(BrainfuckError::Backend(
+            "strict overflow checking is only supported by the interpreter".into(),
+        ));
+    }
+    if program.tape_mode == TapeMode::Grow {
+        return Err(BrainfuckError::Backend(
+            "growing tapes are not supported by the JIT".into(),
+        ));
+    }
+    if program
+        .ast
+        .uses(|node| matches!(*node, Node::PrintNumber(_) | Node::ReadNumber(_)))
+    {
+        return Err(BrainfuckError::Backend(
+            "numeric I/O is only supported by the interpreter".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Everything compiled code needs besides itself to run.
+pub(crate) struct Image {
+    /// Where each failing access is in the source, by the code it returns.
+    pub faults: Vec<DebugInfo>,
+    /// The bytes of each `Write`, which the code points into.
+    pub writes: Vec<Box<[u8]>>,
+    mem_size: usize,
+    cell_size: CellSize,
+    tape_init: (usize, Vec<u8>),
+    seed: Option<u64>,
+}
+
+impl Image {
+    pub fn new(program: &Program) -> Image {
+        Image {
+            faults: Vec::new(),
+            writes: Vec::new(),
+            mem_size: program.mem_size,
+            cell_size: program.cell_size,
+            tape_init: (program.tape_init_offset, program.tape_init.clone()),
+            seed: program.seed,
+        }
+    }
+
+    /// The code returned when the access at `d` is off the tape.
+    pub fn fault_code(&mut self, d: &DebugInfo) -> i64 {
+        let same = |last: &DebugInfo| last.span == d.span && last.file == d.file;
+        if !self.faults.last().is_some_and(same) {
+            self.faults.push(d.clone());
+        }
+        -(self.faults.len() as i64)
+    }
+
+    /// Keeps `bytes` for as long as the code runs, returning where they are.
+    pub fn keep(&mut self, bytes: &[u8]) -> *const u8 {
+        let bytes: Box<[u8]> = bytes.into();
+        let at = bytes.as_ptr();
+        self.writes.push(bytes);
+        at
+    }
+
+    /// Runs `entry` on a fresh tape to completion, returning the value of the
+    /// cell the pointer ends on.
+    ///
+    /// `entry` must have been compiled for this image.
+    pub unsafe fn run(&self, entry: Entry, io: &mut dyn Io) -> Result<u32, ExecError> {
+        // Whole words, so wide cells are aligned.
+        let bytes = self.cell_size.bytes();
+        let mut tape = vec![0u64; (self.mem_size * bytes).div_ceil(8)];
+        let base = tape.as_mut_ptr().cast::<u8>();
+        let (offset, ref data) = self.tape_init;
+        for (index, &b) in (offset..self.mem_size).zip(data) {
+            let at = base.add(index * bytes);
+            match self.cell_size {
+                CellSize::U8 => *at = b,
+                CellSize::U16 => *at.cast::<u16>() = u16::from(b),
+                CellSize::U32 => *at.cast::<u32>() = u32::from(b),
+            }
+        }
+        let mut runtime = Runtime {
+            io,
+            rng: rng_state(self.seed.unwrap_or_else(fresh_seed)),
+        };
+        let ptr = entry(base, &mut runtime);
+        if ptr < 0 {
+            let fault = self.faults[(-ptr - 1) as usize].clone();
+            return Err(ExecError::OutOfBounds(fault));
+        }
+        let ptr = ptr as usize;
+        if ptr >= self.mem_size {
+            return Ok(0);
+        }
+        let at = base.add(ptr * bytes);
+        Ok(match self.cell_size {
+            CellSize::U8 => u32::from(*at),
+            CellSize::U16 => u32::from(*at.cast::<u16>()),
+            CellSize::U32 => *at.cast::<u32>(),
+        })
+    }
+}
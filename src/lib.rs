@@ -0,0 +1,78 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[macro_use]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate core;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_codegen;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_frontend;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_jit;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_module;
+#[cfg(feature = "cranelift")]
+extern crate cranelift_native;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+
+#[cfg(feature = "tokio")]
+mod async_io;
+mod backend;
+mod brainfuck;
+mod c;
+#[cfg(feature = "std")]
+mod cache;
+#[cfg(feature = "cranelift")]
+mod cranelift;
+mod error;
+mod frontend;
+mod golf;
+mod interpreter;
+#[cfg(any(
+    feature = "cranelift",
+    all(feature = "jit-x86", target_arch = "x86_64", target_os = "linux")
+))]
+mod jit;
+mod llvm;
+#[cfg(feature = "llvm")]
+mod native;
+mod rust;
+pub mod visit;
+mod wasm;
+#[cfg(feature = "jit-x86")]
+mod x86;
+
+#[cfg(feature = "tokio")]
+pub use async_io::ExecFuture;
+pub use backend::{find_backend, Artifact, Backend, BACKENDS};
+pub use brainfuck::opt_level_passes;
+pub use brainfuck::{
+    find_pass, Ast, Brainfuck, BrainfuckBuilder, CellSize, DebugDump, DebugInfo, EofMode,
+    ExecError, Io, Node, NullIo, ParseError, Pass, PassContext, Span, TapeMode, ValueRange,
+    ValueRanges, DEFAULT_PASSES, PASSES,
+};
+#[cfg(feature = "std")]
+pub use brainfuck::{OutputEncoding, ReadWriteIo, StdIo};
+pub use c::CSource;
+#[cfg(feature = "cranelift")]
+pub use cranelift::CraneliftJit;
+pub use error::BrainfuckError;
+pub use frontend::{
+    find_frontend, frontend_for_extension, Classic, Extensions, Frontend, Symbol, FRONTENDS,
+};
+pub use interpreter::{
+    EventIo, Events, ExecEvent, ExecStats, Hooks, Interpreter, InvalidSnapshot, Limits, NoHooks,
+    RunOutcome, StateSnapshot,
+};
+pub use llvm::{LlvmIr, Target};
+#[cfg(feature = "llvm")]
+pub use native::LlvmObject;
+pub use rust::RustSource;
+pub use wasm::{WasmModule, WasmText};
+#[cfg(feature = "jit-x86")]
+pub use x86::X86Jit;
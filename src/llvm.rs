@@ -0,0 +1,1385 @@
+use alloc::collections::vec_deque::Iter;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+
+use backend::{Artifact, Backend};
+use brainfuck::{balanced_span, rng_state, Brainfuck, CellSize, EofMode, Node, Program, TapeMode};
+use error::BrainfuckError;
+
+/// Generates textual LLVM IR for a freestanding Linux binary, which performs
+/// I/O with raw syscalls and exits from `_start`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LlvmIr {
+    pub target: Target,
+}
+
+impl Backend for LlvmIr {
+    fn name(&self) -> &'static str {
+        "llvm-ir"
+    }
+
+    fn lower(&self, program: &Brainfuck) -> Result<Artifact, BrainfuckError> {
+        if program.program.strict {
+            return Err(BrainfuckError::Backend(
+                "strict overflow checking is only supported by the interpreter".into(),
+            ));
+        }
+        if program
+            .program
+            .ast
+            .uses(|node| matches!(*node, Node::PrintNumber(_) | Node::ReadNumber(_)))
+        {
+            return Err(BrainfuckError::Backend(
+                "numeric I/O is only supported by the interpreter".into(),
+            ));
+        }
+        // Windows can't reserve address space to commit as it's touched without
+        // handling the faults itself.
+        if self.target.os() == Os::Windows && program.program.tape_mode == TapeMode::Grow {
+            return Err(BrainfuckError::Backend(
+                "growing tapes are not supported when targeting Windows".into(),
+            ));
+        }
+        Ok(Artifact::Text(gen_ir(&program.program, self.target)))
+    }
+}
+
+/// A platform compiled programs can be built for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Target {
+    #[default]
+    X86_64Linux,
+    Aarch64Linux,
+    /// RV64GC, the general purpose profile Linux distributions target.
+    Riscv64Linux,
+    /// Calls `kernel32.dll` for I/O, so it links against an import library.
+    X86_64Windows,
+    /// Calls libSystem, as macOS has no stable system call interface.
+    X86_64MacOs,
+    Aarch64MacOs,
+}
+
+/// How programs for a `Target` reach the operating system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Os {
+    Linux,
+    Windows,
+    Darwin,
+}
+
+impl Target {
+    /// Looks up a target by the name `--target` gives it: its architecture, and
+    /// its operating system unless that is Linux.
+    pub fn from_name(name: &str) -> Option<Target> {
+        match name {
+            "x86_64" => Some(Target::X86_64Linux),
+            "aarch64" => Some(Target::Aarch64Linux),
+            "riscv64" => Some(Target::Riscv64Linux),
+            "x86_64-windows" => Some(Target::X86_64Windows),
+            "x86_64-macos" => Some(Target::X86_64MacOs),
+            "aarch64-macos" => Some(Target::Aarch64MacOs),
+            _ => None,
+        }
+    }
+
+    /// The LLVM target triple.
+    pub fn triple(self) -> &'static str {
+        match self {
+            Target::X86_64Linux => "x86_64-unknown-linux-gnu",
+            Target::Aarch64Linux => "aarch64-unknown-linux-gnu",
+            Target::Riscv64Linux => "riscv64-unknown-linux-gnu",
+            Target::X86_64Windows => "x86_64-pc-windows-msvc",
+            // 11.0 is the first release on Apple silicon, and the oldest the
+            // linker is told the programs run on.
+            Target::X86_64MacOs => "x86_64-apple-macosx11.0.0",
+            Target::Aarch64MacOs => "arm64-apple-macosx11.0.0",
+        }
+    }
+
+    /// The LLVM target features code is generated with, beyond what the triple
+    /// implies.
+    pub fn features(self) -> &'static str {
+        match self {
+            // The triple alone only assumes the base integer instructions. The
+            // programs use no floating point, so the ABI can stay the default.
+            Target::Riscv64Linux => "+m,+a,+f,+d,+c",
+            _ => "",
+        }
+    }
+
+    /// The name the linking tools give this target's machine: the emulation for
+    /// `ld.lld -m`, the machine for `llvm-dlltool -m` or the arch for
+    /// `ld64.lld -arch`.
+    pub fn machine(self) -> &'static str {
+        match self {
+            Target::X86_64Linux => "elf_x86_64",
+            Target::Aarch64Linux => "aarch64linux",
+            Target::Riscv64Linux => "elf64lriscv",
+            Target::X86_64Windows => "i386:x86-64",
+            Target::X86_64MacOs => "x86_64",
+            Target::Aarch64MacOs => "arm64",
+        }
+    }
+
+    /// Describes the library functions programs for this target call, so they
+    /// link without the platform's SDK: a module definition `llvm-dlltool` makes
+    /// a Windows import library from, or a text stub of macOS's libSystem.
+    pub fn import_definition(self) -> Option<String> {
+        match self.os() {
+            Os::Linux => None,
+            Os::Windows => {
+                let mut def = String::from("LIBRARY kernel32.dll\nEXPORTS\n");
+                for &(name, _) in self.imports() {
+                    def.push_str(&format!("    {}\n", name));
+                }
+                Some(def)
+            }
+            Os::Darwin => {
+                let target = match self {
+                    Target::Aarch64MacOs => "arm64-macos",
+                    _ => "x86_64-macos",
+                };
+                // Lazily bound calls go through `dyld_stub_binder`.
+                let mut symbols = String::from("dyld_stub_binder");
+                for &(name, _) in self.imports() {
+                    symbols.push_str(&format!(", _{}", name));
+                }
+                Some(format!(
+                    "--- !tapi-tbd
+tbd-version: 4
+targets: [ {target} ]
+install-name: '/usr/lib/libSystem.B.dylib'
+exports:
+  - targets: [ {target} ]
+    symbols: [ {symbols} ]
+...
+",
+                    target = target,
+                    symbols = symbols
+                ))
+            }
+        }
+    }
+
+    fn os(self) -> Os {
+        match self {
+            Target::X86_64Linux | Target::Aarch64Linux | Target::Riscv64Linux => Os::Linux,
+            Target::X86_64Windows => Os::Windows,
+            Target::X86_64MacOs | Target::Aarch64MacOs => Os::Darwin,
+        }
+    }
+
+    /// The library functions programs call, with their declarations.
+    fn imports(self) -> &'static [(&'static str, &'static str)] {
+        match self.os() {
+            Os::Linux => &[],
+            Os::Windows => &KERNEL32,
+            Os::Darwin => &LIBSYSTEM,
+        }
+    }
+
+    fn syscall_number(self, call: Syscall) -> u32 {
+        match (self, call) {
+            (Target::X86_64Linux, Syscall::Read) => 0,
+            (Target::X86_64Linux, Syscall::Write) => 1,
+            (Target::X86_64Linux, Syscall::Mmap) => 9,
+            (Target::X86_64Linux, Syscall::Exit) => 60,
+            (Target::X86_64Linux, Syscall::Getrandom) => 318,
+            // Newer architectures share the kernel's generic numbering.
+            (Target::Aarch64Linux, call) | (Target::Riscv64Linux, call) => match call {
+                Syscall::Read => 63,
+                Syscall::Write => 64,
+                Syscall::Mmap => 222,
+                Syscall::Exit => 93,
+                Syscall::Getrandom => 278,
+            },
+            _ => unreachable!("only Linux programs make system calls themselves"),
+        }
+    }
+}
+
+/// The Linux system calls compiled programs make.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Syscall {
+    Read,
+    Write,
+    Mmap,
+    Exit,
+    Getrandom,
+}
+
+/// Calls `call` with `args`, each given with its type, as the right hand side
+/// of an instruction.
+fn syscall(target: Target, call: Syscall, args: &[&str]) -> String {
+    // The instruction, where the number and result go, the argument registers,
+    // and what else the kernel may clobber.
+    let (instruction, number, result, registers, clobbers): (_, _, _, &[&str], _) = match target {
+        Target::X86_64Linux => (
+            "syscall",
+            "rax",
+            "rax",
+            &["rdi", "rsi", "rdx", "r10", "r8", "r9"],
+            "~{rcx},~{r11},",
+        ),
+        Target::Aarch64Linux => (
+            "svc #0",
+            "x8",
+            "x0",
+            &["x0", "x1", "x2", "x3", "x4", "x5"],
+            "",
+        ),
+        Target::Riscv64Linux => (
+            "ecall",
+            "a7",
+            "a0",
+            &["a0", "a1", "a2", "a3", "a4", "a5"],
+            "",
+        ),
+        _ => unreachable!("only Linux programs make system calls themselves"),
+    };
+    let mut constraints = format!("={{{}}},{{{}}}", result, number);
+    let mut operands = format!("i64 {}", target.syscall_number(call));
+    for (register, arg) in registers.iter().zip(args) {
+        constraints.push_str(&format!(",{{{}}}", register));
+        operands.push_str(&format!(", {}", arg));
+    }
+    format!(
+        r#"call i64 asm sideeffect "{}", "{},{}~{{memory}}"({})"#,
+        instruction, constraints, clobbers, operands
+    )
+}
+
+/// The functions Windows programs import from `kernel32.dll`, with their
+/// declarations.
+const KERNEL32: [(&str, &str); 5] = [
+    ("GetStdHandle", "i8* @GetStdHandle(i32)"),
+    ("ReadFile", "i32 @ReadFile(i8*, i8*, i32, i32*, i8*)"),
+    ("WriteFile", "i32 @WriteFile(i8*, i8*, i32, i32*, i8*)"),
+    ("ExitProcess", "void @ExitProcess(i32) noreturn"),
+    (
+        "QueryPerformanceCounter",
+        "i32 @QueryPerformanceCounter(i64*)",
+    ),
+];
+
+/// The functions macOS programs call in libSystem, with their declarations.
+const LIBSYSTEM: [(&str, &str); 5] = [
+    ("read", "i64 @read(i32, i8*, i64)"),
+    ("write", "i64 @write(i32, i8*, i64)"),
+    ("exit", "void @exit(i32) noreturn"),
+    ("mmap", "i8* @mmap(i8*, i64, i32, i32, i32, i64)"),
+    ("arc4random_buf", "void @arc4random_buf(i8*, i64)"),
+];
+
+/// Where compiled programs write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Writes the `len` bytes at `data` to `stream`.
+fn gen_write(state: &mut IrState, stream: Stream, data: &str, len: usize) -> String {
+    let fd = match stream {
+        Stream::Stdout => 1,
+        Stream::Stderr => 2,
+    };
+    match state.target.os() {
+        Os::Linux => syscall(
+            state.target,
+            Syscall::Write,
+            &[
+                &format!("i64 {}", fd),
+                &format!("i8* {}", data),
+                &format!("i64 {}", len),
+            ],
+        ),
+        Os::Darwin => format!("call i64 @write(i32 {}, i8* {}, i64 {})", fd, data, len),
+        Os::Windows => {
+            // `_start` looks up the standard output handle once, up front.
+            let (lookup, handle) = match stream {
+                Stream::Stdout => (String::new(), "%stdout".into()),
+                Stream::Stderr => {
+                    let handle = state.ident();
+                    (
+                        format!("{} = call i8* @GetStdHandle(i32 -12)\n    ", handle),
+                        handle,
+                    )
+                }
+            };
+            format!(
+                "{}call i32 @WriteFile(i8* {}, i8* {}, i32 {}, i32* %io_count, i8* null)",
+                lookup, handle, data, len
+            )
+        }
+    }
+}
+
+/// Reads a byte into `data`, setting `read` to the number of bytes read, which
+/// is zero or less at end of input.
+fn gen_read(state: &mut IrState, read: &str, data: &str) -> String {
+    match state.target.os() {
+        Os::Linux => format!(
+            "{} = {}",
+            read,
+            syscall(
+                state.target,
+                Syscall::Read,
+                &["i64 0", &format!("i8* {}", data), "i64 1"]
+            )
+        ),
+        Os::Darwin => format!("{} = call i64 @read(i32 0, i8* {}, i64 1)", read, data),
+        Os::Windows => {
+            // Pipes report end of input as an error rather than a read of no bytes.
+            let ok = state.ident();
+            let succeeded = state.ident();
+            let count = state.ident();
+            let wide = state.ident();
+            format!(
+                r"{ok} = call i32 @ReadFile(i8* %stdin, i8* {data}, i32 1, i32* %io_count, i8* null)
+    {succeeded} = icmp ne i32 {ok}, 0
+    {count} = load i32, i32* %io_count
+    {wide} = zext i32 {count} to i64
+    {read} = select i1 {succeeded}, i64 {wide}, i64 0",
+                ok = ok,
+                data = data,
+                succeeded = succeeded,
+                count = count,
+                wide = wide,
+                read = read
+            )
+        }
+    }
+}
+
+/// Exits with `status`, an `i64`.
+fn gen_exit(state: &mut IrState, status: &str) -> String {
+    let exit = match state.target.os() {
+        Os::Linux => return syscall(state.target, Syscall::Exit, &[&format!("i64 {}", status)]),
+        Os::Windows => "ExitProcess",
+        Os::Darwin => "exit",
+    };
+    let code = state.ident();
+    format!(
+        "{code} = trunc i64 {status} to i32
+    call void @{exit}(i32 {code})",
+        code = code,
+        status = status,
+        exit = exit
+    )
+}
+
+/// Reserves `bytes` of zeroed memory for a growing tape, setting `%tape_addr`
+/// to its address.
+fn gen_reserve(target: Target, bytes: u64) -> String {
+    // Readable and writable, private and anonymous, and on Linux without
+    // reserving swap.
+    match target.os() {
+        Os::Linux => format!(
+            "%tape_addr = {}",
+            syscall(
+                target,
+                Syscall::Mmap,
+                &[
+                    "i64 0",
+                    &format!("i64 {}", bytes),
+                    "i64 3",
+                    "i64 16418",
+                    "i64 -1",
+                    "i64 0",
+                ],
+            )
+        ),
+        Os::Darwin => format!(
+            "%tape_map = call i8* @mmap(i8* null, i64 {}, i32 3, i32 4098, i32 -1, i64 0)
+    %tape_addr = ptrtoint i8* %tape_map to i64",
+            bytes
+        ),
+        Os::Windows => unreachable!("growing tapes are rejected for Windows"),
+    }
+}
+
+/// Address space reserved for a growing tape; 64 GiB.
+const GROW_RESERVE: u64 = 1 << 36;
+
+/// Points at the low byte of the cell at `mem_ptr`, which the I/O syscalls read
+/// and write, returning any instructions needed and the byte pointer. Before
+/// input the rest of a wide cell is cleared, so it holds exactly the byte read.
+fn low_byte(
+    program: &Program,
+    state: &mut IrState,
+    mem_ptr: &str,
+    clear: bool,
+) -> (String, String) {
+    let ty = program.cell_size.ir_type();
+    if program.cell_size == CellSize::U8 {
+        return (String::new(), mem_ptr.into());
+    }
+    let byte_ptr = state.ident();
+    let mut ir = String::new();
+    if clear {
+        ir.push_str(&format!(
+            "
+    store atomic volatile {ty} 0, {ty}* {mem_ptr} monotonic, align {align}",
+            ty = ty,
+            mem_ptr = mem_ptr,
+            align = program.cell_size.bytes()
+        ));
+    }
+    ir.push_str(&format!(
+        "
+    {byte_ptr} = bitcast {ty}* {mem_ptr} to i8*",
+        byte_ptr = byte_ptr,
+        ty = ty,
+        mem_ptr = mem_ptr
+    ));
+    (ir, byte_ptr)
+}
+
+/// The address of cell `ptr`, as the right hand side of an instruction.
+fn cell_address(program: &Program, ptr: &str) -> String {
+    match program.tape_mode {
+        TapeMode::Fixed | TapeMode::Wrap => format!(
+            "getelementptr [{size} x {ty}], [{size} x {ty}]* @mem, i64 0, i64 {ptr}",
+            size = program.mem_size,
+            ty = program.cell_size.ir_type(),
+            ptr = ptr
+        ),
+        TapeMode::Grow => format!(
+            "getelementptr {ty}, {ty}* %tape, i64 {ptr}",
+            ty = program.cell_size.ir_type(),
+            ptr = ptr
+        ),
+    }
+}
+
+/// Moves the pointer `offset` cells right, modulo the tape length.
+fn gen_wrapping_move(
+    program: &Program,
+    ir: &mut String,
+    state: &mut IrState,
+    offset: usize,
+    comment: &str,
+) {
+    let i0 = state.ident();
+    let i1 = state.ident();
+    let i2 = state.ident();
+    let r = format!(
+        r"
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; {comment}
+    {sum} = add i64 {ptr}, {offset}
+    {wrapped} = urem i64 {sum}, {mem_size}
+    store atomic i64 {wrapped}, i64* %ptr monotonic, align 1",
+        ptr = i0,
+        sum = i1,
+        wrapped = i2,
+        offset = offset,
+        mem_size = program.mem_size,
+        comment = comment
+    );
+    ir.push_str(&r);
+}
+
+/// Computes the index `offset` cells from `ptr` into `target`, modulo the tape
+/// length on a wrapping tape.
+fn gen_offset_index(
+    program: &Program,
+    state: &mut IrState,
+    ptr: &str,
+    offset: isize,
+    target: &str,
+) -> String {
+    if program.tape_mode == TapeMode::Wrap {
+        let moved = state.ident();
+        format!(
+            r"
+    {moved} = add i64 {ptr}, {offset}
+    {target} = urem i64 {moved}, {mem_size}",
+            moved = moved,
+            ptr = ptr,
+            offset = offset.rem_euclid(program.mem_size as isize),
+            target = target,
+            mem_size = program.mem_size
+        )
+    } else {
+        format!(
+            r"
+    {target} = add i64 {ptr}, {offset}",
+            target = target,
+            ptr = ptr,
+            offset = offset
+        )
+    }
+}
+
+struct IrState {
+    target: Target,
+    next_label: i32,
+    /// Constants emitted after the function, such as the bytes of each `Write`.
+    globals: String,
+    /// Set inside a balanced loop whose cells were checked when it was entered,
+    /// so `gen_mem_check` has nothing left to do.
+    checked: bool,
+}
+
+impl IrState {
+    fn new(target: Target) -> IrState {
+        IrState {
+            target,
+            next_label: 0,
+            globals: String::new(),
+            checked: false,
+        }
+    }
+
+    fn ident(&mut self) -> String {
+        self.next_label += 1;
+        format!("%i{}", self.next_label)
+    }
+
+    fn label(&mut self) -> String {
+        self.next_label += 1;
+        format!("l{}", self.next_label)
+    }
+}
+
+fn gen_ir(program: &Program, target: Target) -> String {
+    let mut ir = format!("target triple = \"{}\"\n", target.triple());
+    let mut ir_state = IrState::new(target);
+    let linkage = match target.os() {
+        Os::Windows => "dllimport ",
+        _ => "",
+    };
+    for &(_, declaration) in target.imports() {
+        ir.push_str(&format!("declare {}{}\n", linkage, declaration));
+    }
+    ir.push_str(&gen_tape_init_data(program));
+    if mem_limit(program).is_some() {
+        ir.push_str(&format!(
+            "\n@mem_limit_msg = private constant [{} x i8] c\"{}\\0A\"",
+            Program::MEM_LIMIT_MESSAGE.len(),
+            Program::MEM_LIMIT_MESSAGE.trim_end()
+        ));
+    }
+    let prelude = match program.tape_mode {
+        TapeMode::Fixed | TapeMode::Wrap => format!(
+            r"
+@mem = private global [{} x {}] zeroinitializer
+define void @_start() {{
+    %ptr = alloca i64
+    store atomic volatile i64 {}, i64* %ptr monotonic, align 1",
+            program.mem_size,
+            program.cell_size.ir_type(),
+            program.start_ptr
+        ),
+        // Reserve address space without committing it, so the kernel hands out
+        // zeroed pages only as the program touches them. The starting cell sits
+        // in the middle so the pointer can move far in both directions.
+        TapeMode::Grow => {
+            let bytes = program.cell_size.bytes() as u64;
+            let reserve = GROW_RESERVE
+                .max(2 * program.mem_size as u64 * bytes)
+                .max(2 * program.max_mem.unwrap_or(0) as u64);
+            format!(
+                r"
+define void @_start() {{
+    %ptr = alloca i64
+    {reserve}
+    %tape_base = inttoptr i64 %tape_addr to {ty}*
+    %tape = getelementptr {ty}, {ty}* %tape_base, i64 {origin}
+    store atomic volatile i64 {start_ptr}, i64* %ptr monotonic, align 1",
+                reserve = gen_reserve(target, reserve),
+                ty = program.cell_size.ir_type(),
+                origin = reserve / 2 / bytes - program.start_ptr as u64,
+                start_ptr = program.start_ptr
+            )
+        }
+    };
+    ir.push_str(&prelude);
+    if target.os() == Os::Windows {
+        ir.push_str(
+            r"
+    %io_count = alloca i32
+    %stdin = call i8* @GetStdHandle(i32 -10)
+    %stdout = call i8* @GetStdHandle(i32 -11)",
+        );
+    }
+    if let Some(cells) = mem_limit(program) {
+        ir.push_str(&format!(
+            r"
+    %tape_lo = alloca i64
+    %tape_hi = alloca i64
+    store i64 {lo}, i64* %tape_lo
+    store i64 {hi}, i64* %tape_hi",
+            lo = 0,
+            hi = (program.mem_size.max(program.start_ptr + 1) - 1).min(cells as usize - 1)
+        ));
+    }
+    ir.push_str(&gen_tape_init(program));
+    if program.ast.uses(|node| matches!(*node, Node::Random(_))) {
+        ir.push_str(&gen_rng_init(program, target));
+    }
+    gen_ir_nodes(program, &mut ir, &mut ir_state, &program.ast.nodes);
+    let status = if program.exit_cell {
+        ir.push_str(&format!(
+            r"
+    %exit_ptr = load atomic i64, i64* %ptr monotonic, align 1 ; Exit
+    %exit_cell = {cell}
+    %exit_val = load atomic volatile {ty}, {ty}* %exit_cell monotonic, align {align}
+    %exit_wide = zext {ty} %exit_val to i64
+    %exit_status = and i64 %exit_wide, 255",
+            cell = cell_address(program, "%exit_ptr"),
+            ty = program.cell_size.ir_type(),
+            align = program.cell_size.bytes()
+        ));
+        "%exit_status"
+    } else {
+        "0"
+    };
+    let epilogue = format!(
+        r"
+    {}
+    ret void",
+        gen_exit(&mut ir_state, status)
+    );
+    ir.push_str(&epilogue);
+    if mem_limit(program).is_some() {
+        ir.push_str(&gen_mem_limit_exit(&mut ir_state));
+    }
+    ir.push_str("\n}");
+    ir.push_str(&ir_state.globals);
+    ir
+}
+
+/// Copies the program's `tape_init` bytes into their cells, from a constant
+/// declared ahead of `_start`.
+fn gen_tape_init(program: &Program) -> String {
+    let data = program.tape_init_data();
+    if data.is_empty() {
+        return String::new();
+    }
+    let ty = program.cell_size.ir_type();
+    let (extend, value) = if program.cell_size == CellSize::U8 {
+        (String::new(), "%init_byte")
+    } else {
+        (
+            format!(
+                "
+    %init_val = zext i8 %init_byte to {}",
+                ty
+            ),
+            "%init_val",
+        )
+    };
+    format!(
+        r"
+    %init_i = alloca i64
+    store i64 0, i64* %init_i
+    br label %init_loop
+init_loop:
+    %init_n = load i64, i64* %init_i
+    %init_src = getelementptr [{len} x i8], [{len} x i8]* @tape_init, i64 0, i64 %init_n
+    %init_byte = load i8, i8* %init_src{extend}
+    %init_idx = add i64 %init_n, {offset}
+    %init_dst = {cell}
+    store volatile {ty} {value}, {ty}* %init_dst, align {align}
+    %init_next = add i64 %init_n, 1
+    store i64 %init_next, i64* %init_i
+    %init_done = icmp eq i64 %init_next, {len}
+    br i1 %init_done, label %init_end, label %init_loop
+init_end:",
+        len = data.len(),
+        extend = extend,
+        offset = program.tape_init_offset,
+        cell = cell_address(program, "%init_idx"),
+        ty = ty,
+        value = value,
+        align = program.cell_size.bytes()
+    )
+}
+
+/// The `@tape_init` constant read by `gen_tape_init`, if the program has one.
+fn gen_tape_init_data(program: &Program) -> String {
+    let data = program.tape_init_data();
+    if data.is_empty() {
+        return String::new();
+    }
+    format!(
+        "\n@tape_init = private constant [{} x i8] {}",
+        data.len(),
+        ir_bytes(data)
+    )
+}
+
+/// A string constant holding `data`.
+fn ir_bytes(data: &[u8]) -> String {
+    let mut ir = String::from("c\"");
+    for &b in data {
+        ir.push_str(&format!("\\{:02X}", b));
+    }
+    ir.push('"');
+    ir
+}
+
+/// The most cells a growing tape may span, if the program limits its memory.
+fn mem_limit(program: &Program) -> Option<u64> {
+    match (program.tape_mode, program.max_mem) {
+        (TapeMode::Grow, Some(bytes)) => Some((bytes / program.cell_size.bytes()).max(1) as u64),
+        _ => None,
+    }
+}
+
+/// Widens the range of cells the pointer has visited to include `ptr`, exiting
+/// through `mem_limit` if the range no longer fits the memory limit.
+fn gen_mem_check(program: &Program, state: &mut IrState, ptr: &str) -> String {
+    let cells = match mem_limit(program) {
+        Some(cells) if !state.checked => cells,
+        _ => return String::new(),
+    };
+    let lo = state.ident();
+    let hi = state.ident();
+    let below = state.ident();
+    let new_lo = state.ident();
+    let above = state.ident();
+    let new_hi = state.ident();
+    let span = state.ident();
+    let over = state.ident();
+    let ok = state.label();
+    format!(
+        r"
+    {lo} = load i64, i64* %tape_lo
+    {hi} = load i64, i64* %tape_hi
+    {below} = icmp slt i64 {ptr}, {lo}
+    {new_lo} = select i1 {below}, i64 {ptr}, i64 {lo}
+    {above} = icmp sgt i64 {ptr}, {hi}
+    {new_hi} = select i1 {above}, i64 {ptr}, i64 {hi}
+    store i64 {new_lo}, i64* %tape_lo
+    store i64 {new_hi}, i64* %tape_hi
+    {span} = sub i64 {new_hi}, {new_lo}
+    {over} = icmp uge i64 {span}, {cells}
+    br i1 {over}, label %mem_limit, label %{ok}
+{ok}:",
+        lo = lo,
+        hi = hi,
+        below = below,
+        new_lo = new_lo,
+        above = above,
+        new_hi = new_hi,
+        span = span,
+        over = over,
+        ptr = ptr,
+        cells = cells,
+        ok = ok
+    )
+}
+
+/// The block `gen_mem_check` branches to, which reports the error on stderr and
+/// exits with status 1.
+fn gen_mem_limit_exit(state: &mut IrState) -> String {
+    let len = Program::MEM_LIMIT_MESSAGE.len();
+    let write = gen_write(state, Stream::Stderr, "%mem_limit_msg", len);
+    let exit = gen_exit(state, "1");
+    format!(
+        r"
+mem_limit:
+    %mem_limit_msg = getelementptr [{len} x i8], [{len} x i8]* @mem_limit_msg, i64 0, i64 0
+    {write}
+    {exit}
+    unreachable",
+        len = len,
+        write = write,
+        exit = exit
+    )
+}
+
+/// Sets up `%rng` for the `?` extension, asking the kernel for a seed if the
+/// program was not given one. Windows programs seed from the performance
+/// counter instead.
+fn gen_rng_init(program: &Program, target: Target) -> String {
+    match program.seed {
+        Some(seed) => format!(
+            r"
+    %rng = alloca i64
+    store i64 {}, i64* %rng",
+            rng_state(seed) as i64
+        ),
+        // Spread the counter's changing low bits over the state as `rng_state`
+        // does.
+        None if target.os() == Os::Windows => format!(
+            r"
+    %rng = alloca i64
+    call i32 @QueryPerformanceCounter(i64* %rng)
+    %rng_raw = load i64, i64* %rng
+    %rng_mixed = mul i64 %rng_raw, {}
+    %rng_seed = or i64 %rng_mixed, 1
+    store i64 %rng_seed, i64* %rng",
+            0x9e37_79b9_7f4a_7c15_u64 as i64
+        ),
+        None => format!(
+            r"
+    %rng = alloca i64
+    %rng_bytes = bitcast i64* %rng to i8*
+    {}
+    %rng_raw = load i64, i64* %rng
+    %rng_seed = or i64 %rng_raw, 1
+    store i64 %rng_seed, i64* %rng",
+            match target.os() {
+                Os::Darwin => "call void @arc4random_buf(i8* %rng_bytes, i64 8)".into(),
+                _ => syscall(
+                    target,
+                    Syscall::Getrandom,
+                    &["i8* %rng_bytes", "i64 8", "i64 0"],
+                ),
+            }
+        ),
+    }
+}
+
+/// How to end a loop or `If` body once its last node is generated.
+enum Close {
+    If {
+        end: String,
+    },
+    Loop {
+        header: String,
+        end: String,
+        /// Whether the code around the loop needed no memory limit checks.
+        outer_checked: bool,
+    },
+}
+
+/// Generates `nodes`, walking loop bodies with an explicit stack so nesting
+/// depth is limited by memory rather than the call stack.
+fn gen_ir_nodes(program: &Program, ir: &mut String, state: &mut IrState, nodes: &VecDeque<Node>) {
+    let mut level = nodes.iter();
+    // The rest of each enclosing level, and how to end the body entered.
+    let mut outer: Vec<(Iter<'_, Node>, Close)> = Vec::new();
+    loop {
+        let node = match level.next() {
+            Some(node) => node,
+            None => match outer.pop() {
+                Some((rest, Close::If { end })) => {
+                    ir.push_str(&format!(
+                        r"
+    br label %{end}
+{end}:",
+                        end = end
+                    ));
+                    level = rest;
+                    continue;
+                }
+                Some((
+                    rest,
+                    Close::Loop {
+                        header,
+                        end,
+                        outer_checked,
+                    },
+                )) => {
+                    state.checked = outer_checked;
+                    let r = format!(
+                        r"
+    br label %{header}
+{end}:",
+                        header = header,
+                        end = end
+                    );
+                    ir.push_str(&r);
+                    level = rest;
+                    continue;
+                }
+                None => break,
+            },
+        };
+        match *node {
+            Node::Move(v, _) if program.tape_mode == TapeMode::Wrap => {
+                gen_wrapping_move(
+                    program,
+                    ir,
+                    state,
+                    v.rem_euclid(program.mem_size as isize) as usize,
+                    "Move Pointer",
+                );
+            }
+            Node::Move(v, _) => {
+                let i0 = state.ident();
+                let i1 = state.ident();
+                let r = format!(
+                    r"
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Move Pointer
+    {mem_ptr} = add i64 {ptr}, {value}
+    store atomic i64 {mem_ptr}, i64* %ptr monotonic, align 1",
+                    ptr = i0,
+                    mem_ptr = i1,
+                    value = v
+                );
+                ir.push_str(&r);
+                ir.push_str(&gen_mem_check(program, state, &i1));
+            }
+            Node::Output(_) => {
+                let i0 = state.ident();
+                let i1 = state.ident();
+                let (bytes, byte_ptr) = low_byte(program, state, &i1, false);
+                let r = format!(
+                    r"
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Output
+    {mem_ptr} = {cell}{bytes}
+    {write}",
+                    cell = cell_address(program, &i0),
+                    bytes = bytes,
+                    ptr = i0,
+                    mem_ptr = i1,
+                    write = gen_write(state, Stream::Stdout, &byte_ptr, 1),
+                );
+                ir.push_str(&r);
+            }
+            Node::Input(_) => {
+                let i0 = state.ident();
+                let i1 = state.ident();
+                let i2 = state.ident();
+                let i3 = state.ident();
+                let i4 = state.ident();
+                let at_eof = state.label();
+                let done = state.label();
+                let ty = program.cell_size.ir_type();
+                let align = program.cell_size.bytes();
+                // Keep the old value around in case end of input leaves it unchanged.
+                let (old, eof_value) = match program.eof {
+                    EofMode::Zero => (String::new(), "0".into()),
+                    EofMode::MinusOne => (String::new(), "-1".into()),
+                    EofMode::Unchanged => (
+                        format!(
+                            "
+    {old} = load atomic volatile {ty}, {ty}* {mem_ptr} monotonic, align {align}",
+                            old = i2,
+                            ty = ty,
+                            mem_ptr = i1,
+                            align = align
+                        ),
+                        i2,
+                    ),
+                };
+                let (bytes, byte_ptr) = low_byte(program, state, &i1, true);
+                let r = format!(
+                    r"
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Input
+    {mem_ptr} = {cell}{old}{bytes}
+    {read_call}
+    {is_eof} = icmp sle i64 {read}, 0
+    br i1 {is_eof}, label %{at_eof}, label %{done}
+{at_eof}:
+    store atomic volatile {ty} {eof_value}, {ty}* {mem_ptr} monotonic, align {align}
+    br label %{done}
+{done}:",
+                    cell = cell_address(program, &i0),
+                    old = old,
+                    bytes = bytes,
+                    ptr = i0,
+                    mem_ptr = i1,
+                    read_call = gen_read(state, &i3, &byte_ptr),
+                    read = i3,
+                    is_eof = i4,
+                    at_eof = at_eof,
+                    done = done,
+                    ty = ty,
+                    align = align,
+                    eof_value = eof_value,
+                );
+                ir.push_str(&r);
+            }
+            Node::Write(ref bytes, _) => {
+                let data = format!("@{}", state.label());
+                let data_ptr = state.ident();
+                state.globals.push_str(&format!(
+                    "\n{} = private constant [{} x i8] {}",
+                    data,
+                    bytes.len(),
+                    ir_bytes(bytes)
+                ));
+                let r = format!(
+                    r"
+    {data_ptr} = getelementptr [{len} x i8], [{len} x i8]* {data}, i64 0, i64 0 ; Write
+    {write}",
+                    write = gen_write(state, Stream::Stdout, &data_ptr, bytes.len()),
+                    data_ptr = data_ptr,
+                    data = data,
+                    len = bytes.len()
+                );
+                ir.push_str(&r);
+            }
+            // Compiled programs have nowhere to report to, so `#` is ignored.
+            Node::Debug(_) => (),
+            Node::Set(v, _) => {
+                let i0 = state.ident();
+                let i1 = state.ident();
+                let r = format!(
+                    r"
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Set
+    {mem_ptr} = {cell}
+    store atomic volatile {ty} {value}, {ty}* {mem_ptr} monotonic, align {align}",
+                    cell = cell_address(program, &i0),
+                    ptr = i0,
+                    mem_ptr = i1,
+                    value = v & program.cell_size.mask(),
+                    ty = program.cell_size.ir_type(),
+                    align = program.cell_size.bytes()
+                );
+                ir.push_str(&r);
+            }
+            Node::MulAdd(offset, factor, _) => {
+                let ptr = state.ident();
+                let src_ptr = state.ident();
+                let src = state.ident();
+                let nonzero = state.ident();
+                let target = state.ident();
+                let dst_ptr = state.ident();
+                let dst = state.ident();
+                let product = state.ident();
+                let sum = state.ident();
+                let add = state.label();
+                let done = state.label();
+                let target_index = gen_offset_index(program, state, &ptr, offset, &target);
+                let r = format!(
+                    r"
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Multiply and Add
+    {src_ptr} = {src_cell}
+    {src} = load atomic volatile {ty}, {ty}* {src_ptr} monotonic, align {align}
+    {nonzero} = icmp ne {ty} {src}, 0
+    br i1 {nonzero}, label %{add}, label %{done}
+{add}:{target_index}
+    {dst_ptr} = {dst_cell}
+    {dst} = load atomic volatile {ty}, {ty}* {dst_ptr} monotonic, align {align}
+    {product} = mul {ty} {src}, {factor}
+    {sum} = add {ty} {dst}, {product}
+    store atomic volatile {ty} {sum}, {ty}* {dst_ptr} monotonic, align {align}
+    br label %{done}
+{done}:",
+                    src_cell = cell_address(program, &ptr),
+                    dst_cell = cell_address(program, &target),
+                    ptr = ptr,
+                    src_ptr = src_ptr,
+                    src = src,
+                    nonzero = nonzero,
+                    add = add,
+                    done = done,
+                    target_index = target_index,
+                    dst_ptr = dst_ptr,
+                    dst = dst,
+                    product = product,
+                    sum = sum,
+                    factor = factor & program.cell_size.mask(),
+                    ty = program.cell_size.ir_type(),
+                    align = program.cell_size.bytes()
+                );
+                ir.push_str(&r);
+            }
+            Node::Product(offset, source, factor, _) => {
+                let ptr = state.ident();
+                let cur_ptr = state.ident();
+                let cur = state.ident();
+                let nonzero = state.ident();
+                let source_index = state.ident();
+                let src_ptr = state.ident();
+                let src = state.ident();
+                let target = state.ident();
+                let dst_ptr = state.ident();
+                let dst = state.ident();
+                let scaled = state.ident();
+                let product = state.ident();
+                let sum = state.ident();
+                let add = state.label();
+                let done = state.label();
+                let mut checks = gen_offset_index(program, state, &ptr, source, &source_index);
+                checks.push_str(&gen_mem_check(program, state, &source_index));
+                checks.push_str(&gen_offset_index(program, state, &ptr, offset, &target));
+                checks.push_str(&gen_mem_check(program, state, &target));
+                let r = format!(
+                    r"
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Product
+    {cur_ptr} = {cur_cell}
+    {cur} = load atomic volatile {ty}, {ty}* {cur_ptr} monotonic, align {align}
+    {nonzero} = icmp ne {ty} {cur}, 0
+    br i1 {nonzero}, label %{add}, label %{done}
+{add}:{checks}
+    {src_ptr} = {src_cell}
+    {src} = load atomic volatile {ty}, {ty}* {src_ptr} monotonic, align {align}
+    {dst_ptr} = {dst_cell}
+    {dst} = load atomic volatile {ty}, {ty}* {dst_ptr} monotonic, align {align}
+    {scaled} = mul {ty} {cur}, {src}
+    {product} = mul {ty} {scaled}, {factor}
+    {sum} = add {ty} {dst}, {product}
+    store atomic volatile {ty} {sum}, {ty}* {dst_ptr} monotonic, align {align}
+    br label %{done}
+{done}:",
+                    cur_cell = cell_address(program, &ptr),
+                    src_cell = cell_address(program, &source_index),
+                    dst_cell = cell_address(program, &target),
+                    ptr = ptr,
+                    cur_ptr = cur_ptr,
+                    cur = cur,
+                    nonzero = nonzero,
+                    add = add,
+                    done = done,
+                    checks = checks,
+                    src_ptr = src_ptr,
+                    src = src,
+                    dst_ptr = dst_ptr,
+                    dst = dst,
+                    scaled = scaled,
+                    product = product,
+                    sum = sum,
+                    factor = factor & program.cell_size.mask(),
+                    ty = program.cell_size.ir_type(),
+                    align = program.cell_size.bytes()
+                );
+                ir.push_str(&r);
+            }
+            Node::Scan(step, _) => {
+                let ptr = state.ident();
+                let mem_ptr = state.ident();
+                let mem_val = state.ident();
+                let comp = state.ident();
+                let header = state.label();
+                let body = state.label();
+                let end = state.label();
+                let r = format!(
+                    r"
+    br label %{header} ; Scan
+{header}:
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1
+    {mem_ptr} = {cell}
+    {mem_val} = load atomic volatile {ty}, {ty}* {mem_ptr} monotonic, align {align}
+    {comp} = icmp eq {ty} 0, {mem_val}
+    br i1 {comp}, label %{end}, label %{body}
+{body}:",
+                    ptr = ptr,
+                    cell = cell_address(program, &ptr),
+                    mem_ptr = mem_ptr,
+                    mem_val = mem_val,
+                    comp = comp,
+                    ty = program.cell_size.ir_type(),
+                    align = program.cell_size.bytes(),
+                    header = header,
+                    body = body,
+                    end = end
+                );
+                ir.push_str(&r);
+                let next = state.ident();
+                ir.push_str(&gen_offset_index(program, state, &ptr, step, &next));
+                ir.push_str(&format!(
+                    r"
+    store atomic i64 {next}, i64* %ptr monotonic, align 1",
+                    next = next
+                ));
+                ir.push_str(&gen_mem_check(program, state, &next));
+                ir.push_str(&format!(
+                    r"
+    br label %{header}
+{end}:",
+                    header = header,
+                    end = end
+                ));
+            }
+            Node::Add(offset, v, _) => {
+                let ptr = state.ident();
+                ir.push_str(&format!(
+                    r"
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Add",
+                    ptr = ptr
+                ));
+                let target = if offset == 0 {
+                    ptr
+                } else {
+                    let target = state.ident();
+                    ir.push_str(&gen_offset_index(program, state, &ptr, offset, &target));
+                    ir.push_str(&gen_mem_check(program, state, &target));
+                    target
+                };
+                let mem_ptr = state.ident();
+                let mem_val = state.ident();
+                let new_mem_val = state.ident();
+                let r = format!(
+                    r"
+    {mem_ptr} = {cell}
+    {mem_val} = load atomic volatile {ty}, {ty}* {mem_ptr} monotonic, align {align}
+    {new_mem_val} = add {ty} {mem_val}, {value}
+    store atomic volatile {ty} {new_mem_val}, {ty}* {mem_ptr} monotonic, align {align}",
+                    cell = cell_address(program, &target),
+                    mem_ptr = mem_ptr,
+                    mem_val = mem_val,
+                    new_mem_val = new_mem_val,
+                    value = v as u32 & program.cell_size.mask(),
+                    ty = program.cell_size.ir_type(),
+                    align = program.cell_size.bytes()
+                );
+                ir.push_str(&r);
+            }
+            Node::PrintNumber(_) | Node::ReadNumber(_) => {
+                unreachable!("numeric I/O is rejected by `LlvmIr::lower`")
+            }
+            // One xorshift64 step, keeping the top byte.
+            Node::Random(_) => {
+                let i0 = state.ident();
+                let i1 = state.ident();
+                let x0 = state.ident();
+                let s0 = state.ident();
+                let x1 = state.ident();
+                let s1 = state.ident();
+                let x2 = state.ident();
+                let s2 = state.ident();
+                let x3 = state.ident();
+                let top = state.ident();
+                let value = state.ident();
+                let r = format!(
+                    r"
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Random
+    {mem_ptr} = {cell}
+    {x0} = load i64, i64* %rng
+    {s0} = shl i64 {x0}, 13
+    {x1} = xor i64 {x0}, {s0}
+    {s1} = lshr i64 {x1}, 7
+    {x2} = xor i64 {x1}, {s1}
+    {s2} = shl i64 {x2}, 17
+    {x3} = xor i64 {x2}, {s2}
+    store i64 {x3}, i64* %rng
+    {top} = lshr i64 {x3}, 56
+    {value} = trunc i64 {top} to {ty}
+    store atomic volatile {ty} {value}, {ty}* {mem_ptr} monotonic, align {align}",
+                    cell = cell_address(program, &i0),
+                    ptr = i0,
+                    mem_ptr = i1,
+                    x0 = x0,
+                    s0 = s0,
+                    x1 = x1,
+                    s1 = s1,
+                    x2 = x2,
+                    s2 = s2,
+                    x3 = x3,
+                    top = top,
+                    value = value,
+                    ty = program.cell_size.ir_type(),
+                    align = program.cell_size.bytes()
+                );
+                ir.push_str(&r);
+            }
+            Node::If(ref nodes, _) => {
+                let ptr = state.ident();
+                let mem_ptr = state.ident();
+                let mem_val = state.ident();
+                let comp = state.ident();
+                let body = state.label();
+                let end = state.label();
+                let r = format!(
+                    r"
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; If
+    {mem_ptr} = {cell}
+    {mem_val} = load atomic volatile {ty}, {ty}* {mem_ptr} monotonic, align {align}
+    {comp} = icmp eq {ty} 0, {mem_val}
+    br i1 {comp}, label %{end}, label %{body}
+{body}:",
+                    ptr = ptr,
+                    cell = cell_address(program, &ptr),
+                    mem_ptr = mem_ptr,
+                    mem_val = mem_val,
+                    comp = comp,
+                    ty = program.cell_size.ir_type(),
+                    align = program.cell_size.bytes(),
+                    body = body,
+                    end = end
+                );
+                ir.push_str(&r);
+                outer.push((mem::replace(&mut level, nodes.iter()), Close::If { end }));
+            }
+            Node::Loop(ref nodes, _) => {
+                let i0 = state.ident();
+                let i1 = state.ident();
+                let i2 = state.ident();
+                let i3 = state.ident();
+                let header = state.label();
+                let body = state.label();
+                let end = state.label();
+                let outer_checked = state.checked;
+                if mem_limit(program).is_some() && !state.checked {
+                    if let Some((lo, hi)) = balanced_span(nodes) {
+                        ir.push_str(&gen_loop_bounds_check(program, state, lo, hi, &end));
+                        state.checked = true;
+                    }
+                }
+                let r = format!(
+                    r"
+    br label %{header} ; Loop
+{header}:
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1
+    {mem_ptr} = {cell}
+    {mem_val} = load atomic volatile {ty}, {ty}* {mem_ptr} monotonic, align {align}
+    {comp} = icmp eq {ty} 0, {mem_val}
+    br i1 {comp}, label %{end}, label %{body}
+{body}:",
+                    ptr = i0,
+                    cell = cell_address(program, &i0),
+                    mem_ptr = i1,
+                    mem_val = i2,
+                    comp = i3,
+                    ty = program.cell_size.ir_type(),
+                    align = program.cell_size.bytes(),
+                    header = header,
+                    body = body,
+                    end = end
+                );
+                ir.push_str(&r);
+                let close = Close::Loop {
+                    header,
+                    end,
+                    outer_checked,
+                };
+                outer.push((mem::replace(&mut level, nodes.iter()), close));
+            }
+        }
+    }
+}
+
+/// Checks the cells from `lo` to `hi` away from the pointer against the memory
+/// limit before a balanced loop runs, skipping to `end` if it would not run at
+/// all. Its body then needs no checks of its own.
+fn gen_loop_bounds_check(
+    program: &Program,
+    state: &mut IrState,
+    lo: isize,
+    hi: isize,
+    end: &str,
+) -> String {
+    let ptr = state.ident();
+    let mem_ptr = state.ident();
+    let mem_val = state.ident();
+    let comp = state.ident();
+    let enter = state.label();
+    let mut ir = format!(
+        r"
+    {ptr} = load atomic i64, i64* %ptr monotonic, align 1 ; Loop Bounds
+    {mem_ptr} = {cell}
+    {mem_val} = load atomic volatile {ty}, {ty}* {mem_ptr} monotonic, align {align}
+    {comp} = icmp eq {ty} 0, {mem_val}
+    br i1 {comp}, label %{end}, label %{enter}
+{enter}:",
+        ptr = ptr,
+        cell = cell_address(program, &ptr),
+        mem_ptr = mem_ptr,
+        mem_val = mem_val,
+        comp = comp,
+        ty = program.cell_size.ir_type(),
+        align = program.cell_size.bytes(),
+        end = end,
+        enter = enter
+    );
+    // The current cell was checked when the pointer moved to it.
+    for offset in [lo, hi] {
+        if offset != 0 {
+            let target = state.ident();
+            ir.push_str(&gen_offset_index(program, state, &ptr, offset, &target));
+            ir.push_str(&gen_mem_check(program, state, &target));
+        }
+    }
+    ir
+}
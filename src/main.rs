@@ -1,107 +1,2204 @@
 use std::borrow::{Borrow, Cow};
-use std::fs::File;
-use std::io::{BufReader, Write};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, IsTerminal, Read, Write};
 use std::path::Path;
-use std::process::Command;
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::vec;
 
+extern crate brainfuck;
 extern crate clap;
-use clap::{App, Arg};
+extern crate ctrlc;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
-mod brainfuck;
-use brainfuck::{Brainfuck, ExecError, ParseError};
+#[cfg(feature = "cranelift")]
+use brainfuck::CraneliftJit;
+#[cfg(feature = "llvm")]
+use brainfuck::LlvmObject;
+#[cfg(feature = "jit-x86")]
+use brainfuck::X86Jit;
+use brainfuck::{
+    Ast, Backend, Brainfuck, BrainfuckBuilder, BrainfuckError, CSource, CellSize, DebugInfo,
+    EofMode, ExecError, ExecStats, Extensions, Hooks, Interpreter, Io, Limits, Node, NullIo,
+    OutputEncoding, Pass, RustSource, StateSnapshot, StdIo, TapeMode, Target, ValueRanges,
+    WasmModule, WasmText, DEFAULT_PASSES, FRONTENDS, PASSES,
+};
 
 fn main() {
+    let mut program = Vec::new();
+    let e = match run(&mut program) {
+        Ok(0) => return,
+        Ok(status) => process::exit(i32::from(status)),
+        Err(e) => e,
+    };
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message.push_str(&format!(": {}", e));
+        source = e.source();
+    }
+    eprintln!("error: {}", message);
+    if let Some(span) = e.span() {
+        eprint!("{}", span.render(&program));
+    }
+    if let BrainfuckError::Exec(ExecError::Interrupted(_)) = e {
+        process::exit(130);
+    }
+    process::exit(1);
+}
+
+/// Runs the command line, reading the program's source into `source`, and
+/// returns the exit status, which is 1 after reporting an invalid argument.
+fn run(source: &mut Vec<u8>) -> Result<u8, BrainfuckError> {
     let matches = App::new("Brainfuck")
         .version("0.0.1")
         .author("Nick Massey <nickmass@nickmass.com>")
         .about("Parses brainfuck and interprets or compiles it")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .setting(AppSettings::VersionlessSubcommands)
         .arg(
             Arg::with_name("emit-ir")
                 .short("S")
                 .long("emit-ir")
                 .help("Outputs llvm-ir to stdout"),
-        ).arg(
+        )
+        .arg(
             Arg::with_name("compile")
                 .short("c")
                 .long("compile")
                 .help("Compile to binary with llvm"),
-        ).arg(
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .takes_value(true)
+                .value_name("TARGET")
+                .possible_values(&["x86_64", "aarch64", "riscv64", "x86_64-windows", "x86_64-macos", "aarch64-macos", "wasm32"])
+                .help("Sets what --compile and --emit-ir build for; bare architectures mean Linux, and wasm32 writes a .wasm module [default: x86_64]"),
+        )
+        .arg(
+            Arg::with_name("emit-c")
+                .long("emit-c")
+                .conflicts_with_all(&["emit-ir", "compile"])
+                .help("Outputs portable C to stdout, for compiling without llvm"),
+        )
+        .arg(
+            Arg::with_name("emit-rust")
+                .long("emit-rust")
+                .conflicts_with_all(&["emit-ir", "emit-c", "compile"])
+                .help("Outputs a standalone main.rs in safe Rust to stdout, for building with rustc"),
+        )
+        .arg(
+            Arg::with_name("emit-wat")
+                .long("emit-wat")
+                .conflicts_with_all(&["emit-ir", "emit-c", "emit-rust", "compile"])
+                .help("Outputs the WebAssembly text format to stdout, for the module --target wasm32 writes"),
+        )
+        .arg(
+            Arg::with_name("precompute")
+                .long("precompute")
+                .help("Runs programs that never read input while compiling, so the result just writes their output"),
+        )
+        .arg(
+            Arg::with_name("jit")
+                .long("jit")
+                .conflicts_with_all(&[
+                    "emit-ir",
+                    "emit-c",
+                    "emit-rust",
+                    "emit-wat",
+                    "compile",
+                    "trace",
+                    "trace-file",
+                    "heatmap",
+                    "warn-uninit",
+                    "digest",
+                    "stats",
+                    "checkpoint",
+                    "resume",
+                    "tape-dump",
+                    "max-instructions",
+                    "max-output",
+                    "timeout",
+                    "catch-interrupt",
+                ])
+                .help("Runs the program as native code, interpreting it where there is no JIT"),
+        )
+        .arg(
+            Arg::with_name("no-io")
+                .long("no-io")
+                .help("Discards output and reads EOF for input, for benchmarking"),
+        )
+        .arg(
+            Arg::with_name("input-file")
+                .long("input")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with("no-io")
+                .help("Reads program input from FILE instead of stdin"),
+        )
+        .arg(
+            Arg::with_name("input-str")
+                .long("input-str")
+                .takes_value(true)
+                .value_name("TEXT")
+                .conflicts_with_all(&["no-io", "input-file"])
+                .help("Uses TEXT as program input, with escapes like \\n and \\x00"),
+        )
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with("no-io")
+                .help("Saves every byte of program input to FILE, for --replay"),
+        )
+        .arg(
+            Arg::with_name("record-timing")
+                .long("record-timing")
+                .requires("record")
+                .help("Stores when each byte was read in the --record file"),
+        )
+        .arg(
+            Arg::with_name("replay")
+                .long("replay")
+                .takes_value(true)
+                .value_name("FILE")
+                .conflicts_with_all(&["no-io", "input-file", "input-str"])
+                .help("Feeds a --record file back as program input, at its original pace if timed"),
+        )
+        .arg(
+            Arg::with_name("bang-input")
+                .long("bang-input")
+                .conflicts_with_all(&["no-io", "input-file", "input-str", "replay"])
+                .help("Uses everything after the first `!` outside a loop as program input"),
+        )
+        .arg(
+            Arg::with_name("raw-input")
+                .long("raw-input")
+                .conflicts_with("no-io")
+                .help("Reads each keypress immediately, without echo, when input is a terminal"),
+        )
+        .arg(
+            Arg::with_name("unbuffered")
+                .long("unbuffered")
+                .conflicts_with("no-io")
+                .help("Writes each output byte immediately instead of buffering"),
+        )
+        .arg(
+            Arg::with_name("utf8-out")
+                .long("utf8-out")
+                .conflicts_with("no-io")
+                .help("Decodes output bytes as UTF-8 instead of writing each as a character"),
+        )
+        .arg(
+            Arg::with_name("raw-out")
+                .long("raw-out")
+                .conflicts_with_all(&["no-io", "utf8-out"])
+                .help("Writes output bytes exactly, the default when stdout is not a terminal"),
+        )
+        .arg(
+            Arg::with_name("output-format")
+                .long("output-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["text", "hex", "decimal"])
+                .conflicts_with("no-io")
+                .help("Writes output bytes as text or as space-separated numbers [default: text]"),
+        )
+        .arg(
+            Arg::with_name("output-file")
+                .long("output-file")
+                .takes_value(true)
+                .value_name("PATH")
+                .conflicts_with("no-io")
+                .help("Writes program output to PATH instead of stdout"),
+        )
+        .arg(
+            Arg::with_name("tee")
+                .long("tee")
+                .requires("output-file")
+                .help("Also writes program output to stdout when using --output-file"),
+        )
+        .arg(
+            Arg::with_name("warn-uninit")
+                .long("warn-uninit")
+                .help("Warns once per instruction that reads a cell the program never wrote, skipping the passes that would hide reads"),
+        )
+        .arg(
+            Arg::with_name("catch-interrupt")
+                .long("catch-interrupt")
+                .help("Reports where the program was when Ctrl-C stops it; press again to exit at once"),
+        )
+        .arg(
+            Arg::with_name("digest")
+                .long("digest")
+                .help("Prints a hash of the output, final tape and pointer to stderr"),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .help("Prints execution statistics to stderr"),
+        )
+        .arg(
+            Arg::with_name("opt-stats")
+                .long("opt-stats")
+                .help("Prints how each optimization pass changed the program to stderr"),
+        )
+        .arg(
+            Arg::with_name("max-mem")
+                .long("max-mem")
+                .takes_value(true)
+                .value_name("BYTES")
+                .requires("grow-tape")
+                .help("Stops a growing tape from taking more than BYTES bytes"),
+        )
+        .arg(
+            Arg::with_name("tape-dump")
+                .long("tape-dump")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Writes each cell of the tape to FILE, little-endian, when the program halts or fails"),
+        )
+        .arg(
+            Arg::with_name("tape-dump-touched")
+                .long("tape-dump-touched")
+                .requires("tape-dump")
+                .help("Limits --tape-dump to the cells the program read or wrote"),
+        )
+        .arg(
+            Arg::with_name("eof")
+                .long("eof")
+                .takes_value(true)
+                .value_name("MODE")
+                .possible_values(&["zero", "minus-one", "unchanged"])
+                .help("Sets what `,` stores at end of input [default: minus-one]"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .takes_value(true)
+                .value_name("N")
+                .help("Seeds the random numbers `?` produces, for repeatable runs"),
+        )
+        .arg(
+            Arg::with_name("trace").long("trace").help(
+                "Logs each executed instruction with its location, pointer and cell to stderr",
+            ),
+        )
+        .arg(
+            Arg::with_name("trace-file")
+                .long("trace-file")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Writes the --trace log to FILE instead of stderr"),
+        )
+        .arg(
+            Arg::with_name("heatmap")
+                .long("heatmap")
+                .help("Prints how often each region of the tape was read and written to stderr"),
+        )
+        .arg(
+            Arg::with_name("max-instructions")
+                .long("max-instructions")
+                .takes_value(true)
+                .value_name("N")
+                .help("Stops the program after it executes N instructions"),
+        )
+        .arg(
+            Arg::with_name("max-output")
+                .long("max-output")
+                .takes_value(true)
+                .value_name("BYTES")
+                .help("Stops the program if it writes more than BYTES bytes"),
+        )
+        .arg(
+            Arg::with_name("checkpoint")
+                .long("checkpoint")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Periodically saves the program's state to FILE, for --resume"),
+        )
+        .arg(
+            Arg::with_name("checkpoint-interval")
+                .long("checkpoint-interval")
+                .takes_value(true)
+                .value_name("N")
+                .requires("checkpoint")
+                .help("Saves a checkpoint every N instructions [default: 1000000000]"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Continues from a --checkpoint file; input and output before it are not replayed"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .takes_value(true)
+                .value_name("SECS")
+                .help("Stops the program after it runs for SECS seconds"),
+        )
+        .arg(
+            Arg::with_name("exit-cell")
+                .long("exit-cell")
+                .help("Exits with the value of the current cell when the program halts"),
+        )
+        .arg(
+            Arg::with_name("passes")
+                .long("passes")
+                .takes_value(true)
+                .use_delimiter(true)
+                .value_name("PASS,...")
+                .help("Runs exactly the named optimization passes, in order"),
+        )
+        .arg(
+            Arg::with_name("dump-ast")
+                .long("dump-ast")
+                .help("Prints the program tree before optimization and exits"),
+        )
+        .arg(
+            Arg::with_name("dump-opt")
+                .long("dump-opt")
+                .help("Prints the program tree after optimization and exits"),
+        )
+        .arg(
+            Arg::with_name("analyze")
+                .long("analyze")
+                .help("Prints the optimized program tree with the values each cell may hold and exits"),
+        )
+        .arg(
+            Arg::with_name("explain")
+                .long("explain")
+                .help("Prints each loop idiom the optimizer recognizes with its source and exits"),
+        )
+        .arg(
+            Arg::with_name("disable-passes")
+                .long("disable-passes")
+                .takes_value(true)
+                .use_delimiter(true)
+                .value_name("PASS,...")
+                .help("Skips the named optimization passes"),
+        )
+        .arg(
+            Arg::with_name("opt-level")
+                .short("O")
+                .takes_value(true)
+                .value_name("LEVEL")
+                .possible_values(&["0", "1", "2", "3"])
+                .conflicts_with("passes")
+                .help("Picks the passes to run by level; 3 also precomputes compiled programs"),
+        )
+        .arg(
+            Arg::with_name("cache")
+                .long("cache")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Reuses the optimized program stored in FILE, refreshing it if stale"),
+        )
+        .arg(
             Arg::with_name("INPUT")
                 .help("Sets the brainfuck file to parse")
                 .required(true)
                 .index(1),
-        ).get_matches();
+        )
+        .arg(
+            Arg::with_name("ARGS")
+                .help("Feeds each argument, followed by a newline, to the program before its input")
+                .multiple(true)
+                .last(true),
+        )
+        .args(&program_args())
+        .subcommand(
+            SubCommand::with_name("golf")
+                .about("Prints a shorter program that does the same")
+                .args(&program_args())
+                .arg(
+                    Arg::with_name("budget")
+                        .long("budget")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Tries at most N loops for each run of `+-<>` [default: 10000]"),
+                )
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the brainfuck file to golf")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .get_matches();
+    if let Some(matches) = matches.subcommand_matches("golf") {
+        return golf(matches, source);
+    }
 
     let compile_ir = matches.is_present("compile");
     let gen_ir = matches.is_present("emit-ir") || compile_ir;
-    let input_file = matches.value_of("INPUT").unwrap();
-
-    let input_path = Path::new(&input_file);
-    let file_name = input_path
-        .file_name()
-        .expect("No source file specified")
-        .to_string_lossy();
-    let directory = input_path
-        .parent()
-        .map(|x| x.to_string_lossy())
-        .unwrap_or(Cow::Borrowed(""));
-    let source = File::open(input_path).expect("Could not open source file.");
-    let reader = BufReader::new(source);
+    let source_backend: Option<&dyn Backend> = if matches.is_present("emit-c") {
+        Some(&CSource)
+    } else if matches.is_present("emit-rust") {
+        Some(&RustSource)
+    } else if matches.is_present("emit-wat") {
+        Some(&WasmText)
+    } else {
+        None
+    };
+    let no_io = matches.is_present("no-io");
+    let print_stats = matches.is_present("stats");
+    let cache_file = matches.value_of("cache");
+    let input_path = Path::new(matches.value_of("INPUT").unwrap());
 
-    let bf = Brainfuck::parse(reader, file_name.borrow(), directory.borrow());
+    let mut limits = Limits::default();
+    if let Some(max) = matches.value_of("max-instructions") {
+        match max.parse() {
+            Ok(max) => limits.max_instructions = Some(max),
+            Err(_) => {
+                eprintln!("error: invalid instruction limit `{}`", max);
+                return Ok(1);
+            }
+        }
+    }
+    if let Some(max) = matches.value_of("max-output") {
+        match max.parse() {
+            Ok(max) => limits.max_output = Some(max),
+            Err(_) => {
+                eprintln!("error: invalid output limit `{}`", max);
+                return Ok(1);
+            }
+        }
+    }
+    if let Some(secs) = matches.value_of("timeout") {
+        match secs
+            .parse()
+            .ok()
+            .and_then(|s| Duration::try_from_secs_f64(s).ok())
+        {
+            Some(timeout) => limits.timeout = Some(timeout),
+            None => {
+                eprintln!("error: invalid timeout `{}`", secs);
+                return Ok(1);
+            }
+        }
+    }
 
-    let bf = match bf {
-        Ok(bf) => bf,
-        Err(ParseError::UnmatchedLoop(d)) => {
-            println!(
-                "error: unmatached loop --> {}:{}:{}",
-                d.file, d.line, d.column
-            );
-            return;
+    let mut builder = match configure(&matches, input_path) {
+        Some(builder) => builder,
+        None => return Ok(1),
+    };
+    if let Some(seed) = matches.value_of("seed") {
+        match seed.parse() {
+            Ok(seed) => builder = builder.seed(seed),
+            Err(_) => {
+                eprintln!("error: invalid seed `{}`", seed);
+                return Ok(1);
+            }
+        }
+    }
+    if matches.is_present("exit-cell") {
+        builder = builder.exit_cell(true);
+    }
+    match matches.value_of("eof") {
+        Some("zero") => builder = builder.eof(EofMode::Zero),
+        Some("unchanged") => builder = builder.eof(EofMode::Unchanged),
+        _ => (),
+    }
+    if let Some(max) = matches.value_of("max-mem") {
+        match max.parse() {
+            Ok(max) => builder = builder.max_mem(max),
+            Err(_) => {
+                eprintln!("error: invalid memory limit `{}`", max);
+                return Ok(1);
+            }
         }
+    }
+    let opt_level = matches.value_of("opt-level").and_then(|l| l.parse().ok());
+    let mut passes = match opt_level.and_then(brainfuck::opt_level_passes) {
+        Some(passes) => passes,
+        None => DEFAULT_PASSES.to_vec(),
+    };
+    if let Some(names) = matches.values_of("passes") {
+        passes = match find_passes(names) {
+            Some(named) => named,
+            None => return Ok(1),
+        };
+    }
+    if let Some(names) = matches.values_of("disable-passes") {
+        let disabled = match find_passes(names) {
+            Some(disabled) => disabled,
+            None => return Ok(1),
+        };
+        passes.retain(|pass| !disabled.iter().any(|d| d.name() == pass.name()));
+    }
+    if matches.is_present("warn-uninit") {
+        passes.retain(|pass| !UNINIT_HIDING_PASSES.contains(&pass.name()));
+    }
+    builder = builder.passes(&passes);
+
+    let (file_name, directory) = read_source(input_path, source)?;
+    let bang_input = if matches.is_present("bang-input") {
+        bang_position(source).map(|pos| {
+            let input = source.split_off(pos + 1);
+            source.truncate(pos);
+            input
+        })
+    } else {
+        None
+    };
+
+    let fingerprint = builder.fingerprint(source);
+    let cached = cache_file.and_then(|f| File::open(f).ok()).and_then(|f| {
+        Brainfuck::read_cache(
+            BufReader::new(f),
+            file_name.borrow(),
+            directory.borrow(),
+            fingerprint,
+        )
+    });
+    let is_cached = cached.is_some();
+
+    let bf = match cached {
+        Some(bf) => bf,
+        None => builder.parse(&source[..], file_name.borrow(), directory.borrow())?,
     };
 
-    if gen_ir {
-        let ir = bf.gen_ir();
+    let tape_bytes = bf.mem_size() * bf.cell_size().bytes();
+    if bf.max_mem().is_some_and(|max| max < tape_bytes) {
+        eprintln!(
+            "error: memory limit is smaller than the initial tape of {} bytes",
+            tape_bytes
+        );
+        return Ok(1);
+    }
+    let (offset, data) = bf.tape_init();
+    if bf.tape_mode() != TapeMode::Grow && offset + data.len() > bf.mem_size() {
+        eprintln!(
+            "error: {} bytes of tape data starting at cell {} do not fit on a tape of {} cells",
+            data.len(),
+            offset,
+            bf.mem_size()
+        );
+        return Ok(1);
+    }
+
+    if let (Some(cache_file), false) = (cache_file, is_cached) {
+        let written =
+            File::create(cache_file).and_then(|f| bf.write_cache(BufWriter::new(f), fingerprint));
+        if written.is_err() {
+            eprintln!("warning: could not write cache file {}", cache_file);
+        }
+    }
+
+    if matches.is_present("opt-stats") {
+        let parsed = builder.clone().passes(&[]).parse(
+            &source[..],
+            file_name.borrow(),
+            directory.borrow(),
+        )?;
+        opt_stats(&builder, &passes, parsed.ast().clone());
+    }
+
+    if matches.is_present("explain") {
+        let passes: Vec<_> = EXPLAIN_PASSES
+            .iter()
+            .filter_map(|&name| brainfuck::find_pass(name))
+            .collect();
+        let recognized = builder.clone().passes(&passes).parse(
+            &source[..],
+            file_name.borrow(),
+            directory.borrow(),
+        )?;
+        let stdout = io::stdout();
+        let mut out = BufWriter::new(stdout.lock());
+        let _ = explain(&mut out, recognized.ast(), bf.cell_size(), source);
+        let _ = out.flush();
+        return Ok(0);
+    }
+
+    if matches.is_present("dump-ast")
+        || matches.is_present("dump-opt")
+        || matches.is_present("analyze")
+    {
+        let stdout = io::stdout();
+        let mut out = BufWriter::new(stdout.lock());
+        let ranges = builder.analyze(bf.ast());
+        if matches.is_present("dump-ast") {
+            let parsed =
+                builder
+                    .passes(&[])
+                    .parse(&source[..], file_name.borrow(), directory.borrow())?;
+            // Like `println!`, but stopping quietly if stdout is closed early.
+            let _ = dump_ast(&mut out, parsed.ast(), None);
+        }
+        if matches.is_present("dump-opt") {
+            let _ = dump_ast(&mut out, bf.ast(), None);
+        }
+        if matches.is_present("analyze") {
+            let _ = dump_ast(&mut out, bf.ast(), Some(&ranges));
+        }
+        let _ = out.flush();
+        return Ok(0);
+    }
+
+    if matches.is_present("precompute") && !gen_ir && source_backend.is_none() {
+        eprintln!("error: --precompute requires --emit-ir, --emit-c, --emit-rust, --emit-wat or --compile");
+        return Ok(1);
+    }
+
+    let target = matches.value_of("target").unwrap_or("x86_64");
+    if matches.is_present("target") && !gen_ir {
+        eprintln!("error: --target requires --compile or --emit-ir");
+        return Ok(1);
+    }
+    if target == "wasm32" && !compile_ir {
+        eprintln!("error: --target wasm32 has no llvm-ir; use --emit-wat to see the module");
+        return Ok(1);
+    }
+
+    if gen_ir || source_backend.is_some() {
+        let precomputed = if matches.is_present("precompute") || opt_level == Some(3) {
+            precompute(&bf, &limits)
+        } else {
+            None
+        };
+        let program = precomputed.as_ref().unwrap_or(&bf);
+        if let Some(backend) = source_backend {
+            let source = backend.lower(program)?;
+            let _ = io::stdout().write_all(source.as_bytes());
+            return Ok(0);
+        }
+        if target == "wasm32" {
+            let file_name: &str = file_name.borrow();
+            let output_name = Path::new(file_name).file_stem().unwrap().to_string_lossy();
+            let module = WasmModule.lower(program)?;
+            fs::write(format!("{}.wasm", output_name), module.as_bytes())
+                .expect("Could not write module file.");
+            return Ok(0);
+        }
+        let target = Target::from_name(target).expect("--target only accepts known targets");
+        let ir = program.gen_ir_for(target)?;
         if compile_ir {
             let file_name: &str = file_name.borrow();
             let output_name = Path::new(file_name).file_stem().unwrap().to_string_lossy();
-            let ir_file_name = format!("{}.ll", output_name);
-            let bc_file_name = format!("{}.bc", output_name);
             let o_file_name = format!("{}.o", output_name);
+            compile_object(program, target, &ir, &output_name, &o_file_name)?;
+            link(target, &o_file_name, &output_name)?;
+        } else {
+            println!("{}", ir);
+        }
+        return Ok(0);
+    }
 
-            let mut ir_file = File::create(&ir_file_name).unwrap();
-            let _ = ir_file.write_all(ir.as_bytes()).unwrap();
+    let jit = if matches.is_present("jit") {
+        match compile_jit(&bf) {
+            Ok(Some(jit)) => Some(jit),
+            Ok(None) => {
+                eprintln!("warning: no JIT in this build for this host, interpreting instead");
+                None
+            }
+            Err(e) => {
+                eprintln!("warning: {}, interpreting instead", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
 
-            let opt = Command::new("opt")
-                .arg("-O3")
-                .arg("-strip-debug")
-                .arg(&ir_file_name)
-                .arg("-o")
-                .arg(&bc_file_name)
-                .status()
-                .expect("failed to execute opt");
+    let mut io: Box<dyn Io> = if no_io {
+        Box::new(NullIo)
+    } else {
+        let input: Box<dyn Read> = if let Some(path) = matches.value_of("input-file") {
+            match File::open(path) {
+                Ok(f) => Box::new(BufReader::new(f)),
+                Err(e) => {
+                    eprintln!("error: could not open input file `{}`: {}", path, e);
+                    return Ok(1);
+                }
+            }
+        } else if let Some(input) = bang_input {
+            Box::new(Cursor::new(input))
+        } else if let Some(text) = matches.value_of("input-str") {
+            match unescape(text) {
+                Ok(input) => Box::new(Cursor::new(input)),
+                Err(escape) => {
+                    eprintln!("error: invalid escape `{}` in input string", escape);
+                    return Ok(1);
+                }
+            }
+        } else if let Some(path) = matches.value_of("replay") {
+            match fs::read(path).map(replay) {
+                Ok(Some(replay)) => replay,
+                Ok(None) => {
+                    eprintln!("error: `{}` is not a valid timed recording", path);
+                    return Ok(1);
+                }
+                Err(e) => {
+                    eprintln!("error: could not open recording `{}`: {}", path, e);
+                    return Ok(1);
+                }
+            }
+        } else {
+            Box::new(io::stdin())
+        };
+        let input: Box<dyn Read> = match matches.value_of("record") {
+            Some(path) => match File::create(path) {
+                Ok(f) => {
+                    let mut log = BufWriter::new(f);
+                    let timed = matches.is_present("record-timing");
+                    if timed {
+                        let _ = log.write_all(TIMED_RECORDING);
+                    }
+                    Box::new(Recorder {
+                        inner: input,
+                        log,
+                        start: if timed { Some(Instant::now()) } else { None },
+                    })
+                }
+                Err(e) => {
+                    eprintln!("error: could not create recording `{}`: {}", path, e);
+                    return Ok(1);
+                }
+            },
+            None => input,
+        };
+        let mut args = Vec::new();
+        for arg in matches.values_of("ARGS").into_iter().flatten() {
+            args.extend_from_slice(arg.as_bytes());
+            args.push(b'\n');
+        }
+        let mut output = if matches.is_present("unbuffered") {
+            StdIo::unbuffered()
+        } else {
+            StdIo::new()
+        };
+        if matches.is_present("utf8-out") {
+            output = output.encoding(OutputEncoding::Utf8);
+        } else if matches.is_present("raw-out") {
+            output = output.encoding(OutputEncoding::Raw);
+        }
+        let file = match matches.value_of("output-file") {
+            Some(path) => match File::create(path) {
+                Ok(f) => Some(BufWriter::new(f)),
+                Err(e) => {
+                    eprintln!("error: could not create output file `{}`: {}", path, e);
+                    return Ok(1);
+                }
+            },
+            None => None,
+        };
+        let output = if file.is_none() || matches.is_present("tee") {
+            Some(output)
+        } else {
+            None
+        };
+        let format = match matches.value_of("output-format") {
+            Some("hex") => OutputFormat::Hex,
+            Some("decimal") => OutputFormat::Decimal,
+            _ => OutputFormat::Text,
+        };
+        Box::new(InputIo {
+            input: Cursor::new(args).chain(input),
+            output,
+            file,
+            format,
+            written: false,
+        })
+    };
+    let trace: Option<Box<dyn Write>> = match matches.value_of("trace-file") {
+        Some(path) => match File::create(path) {
+            Ok(f) => Some(Box::new(f)),
+            Err(e) => {
+                eprintln!("error: could not create trace file `{}`: {}", path, e);
+                return Ok(1);
+            }
+        },
+        None if matches.is_present("trace") => Some(Box::new(io::stderr())),
+        None => None,
+    };
+
+    let mut tracer = trace.map(|out| Tracer {
+        out: BufWriter::new(out),
+    });
+    let mut heatmap = if matches.is_present("heatmap") {
+        Some(Heatmap::default())
+    } else {
+        None
+    };
+
+    let mut uninit = if matches.is_present("warn-uninit") {
+        let (offset, data) = bf.tape_init();
+        let mut uninit = UninitReads::default();
+        for index in offset..offset + data.len() {
+            uninit.on_cell_write(index);
+        }
+        Some(uninit)
+    } else {
+        None
+    };
+
+    if matches.is_present("catch-interrupt") {
+        let flag = Arc::new(AtomicBool::new(false));
+        limits.interrupt = Some(flag.clone());
+        let handler = ctrlc::set_handler(move || {
+            if flag.swap(true, Ordering::Relaxed) {
+                process::exit(130);
+            }
+        });
+        if let Err(e) = handler {
+            eprintln!("warning: could not catch interrupts: {}", e);
+        }
+    }
 
-            if !opt.success() {
-                println!("failed to execute opt");
+    let raw_terminal = if matches.is_present("raw-input") {
+        RawTerminal::enable()
+    } else {
+        None
+    };
+    if let Some(jit) = jit {
+        let cell = jit.run(&mut *io)?;
+        drop(io);
+        drop(raw_terminal);
+        // Exit statuses are a byte wide, as when interpreting.
+        return Ok(if bf.exit_cell() { cell as u8 } else { 0 });
+    }
+    let checkpoint = match matches.value_of("checkpoint") {
+        Some(path) => {
+            let interval = matches
+                .value_of("checkpoint-interval")
+                .unwrap_or("1000000000");
+            match interval.parse() {
+                Ok(interval) if interval > 0 => Some(Checkpoint {
+                    path: path.to_string(),
+                    interval,
+                    fingerprint,
+                }),
+                _ => {
+                    eprintln!("error: invalid checkpoint interval `{}`", interval);
+                    return Ok(1);
+                }
+            }
+        }
+        None => None,
+    };
+    let resume = match matches.value_of("resume") {
+        Some(path) => match File::open(path) {
+            Ok(f) => match StateSnapshot::read_checkpoint(BufReader::new(f), fingerprint) {
+                Some(snapshot) => Some(snapshot),
+                None => {
+                    eprintln!(
+                        "error: checkpoint `{}` was not saved by this program and configuration",
+                        path
+                    );
+                    return Ok(1);
+                }
+            },
+            Err(e) => {
+                eprintln!("error: could not open checkpoint `{}`: {}", path, e);
+                return Ok(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut tape_dump = match matches.value_of("tape-dump") {
+        Some(path) => match File::create(path) {
+            Ok(f) => Some(TapeDump {
+                out: BufWriter::new(f),
+                cell_size: bf.cell_size(),
+                touched: matches.is_present("tape-dump-touched"),
+            }),
+            Err(e) => {
+                eprintln!("error: could not create tape dump `{}`: {}", path, e);
+                return Ok(1);
+            }
+        },
+        None => None,
+    };
+
+    let mut digest = if matches.is_present("digest") {
+        Some(Digest::new())
+    } else {
+        None
+    };
+    let wants_digest = digest.is_some();
+
+    let (stats, cell, end_state) = {
+        let mut interpreter = bf.interpreter(&mut *io);
+        if let Some(ref snapshot) = resume {
+            if let Err(e) = interpreter.restore(snapshot) {
+                eprintln!("error: could not resume: {}", e);
+                return Ok(1);
+            }
+        }
+        // Only pay for hooks when something is listening.
+        if tracer.is_some() || heatmap.is_some() || uninit.is_some() || wants_digest {
+            let mut interpreter =
+                interpreter.with_hooks((((&mut tracer, &mut heatmap), &mut uninit), &mut digest));
+            let (stats, cell) = finish(
+                &mut interpreter,
+                &limits,
+                checkpoint.as_ref(),
+                tape_dump.as_mut(),
+            )?;
+            let end_state = if wants_digest {
+                Some(Digest::end_state(interpreter.tape(), interpreter.ptr()))
+            } else {
+                None
+            };
+            (stats, cell, end_state)
+        } else {
+            let (stats, cell) = finish(
+                &mut interpreter,
+                &limits,
+                checkpoint.as_ref(),
+                tape_dump.as_mut(),
+            )?;
+            (stats, cell, None)
+        }
+    };
+    // Flush the program's output and restore the terminal before reporting.
+    drop(io);
+    drop(raw_terminal);
+    if let Some(heatmap) = heatmap {
+        heatmap.report();
+    }
+    if let (Some(mut digest), Some(end_state)) = (digest, end_state) {
+        digest.feed(&stats.bytes_written.to_le_bytes());
+        digest.feed(&end_state.to_le_bytes());
+        eprintln!("digest: {:016x}", digest.hash);
+    }
+    if print_stats {
+        eprintln!("instructions:    {}", stats.instructions);
+        eprintln!("loop iterations: {}", stats.loop_iterations);
+        eprintln!(
+            "cells touched:   {}..={}",
+            stats.lowest_cell, stats.highest_cell
+        );
+        eprintln!("bytes read:      {}", stats.bytes_read);
+        eprintln!("bytes written:   {}", stats.bytes_written);
+    }
+    // Exit statuses are a byte wide, like the compiled program's.
+    let status = match cell {
+        Some(raw) if bf.exit_cell() => raw as u8,
+        _ => 0,
+    };
+    Ok(status)
+}
+
+/// A program compiled by one of the JITs this build has.
+trait Jit {
+    /// Runs the program, returning the value of the cell the pointer ends on.
+    fn run(&self, io: &mut dyn Io) -> Result<u32, ExecError>;
+}
+
+#[cfg(feature = "jit-x86")]
+impl Jit for X86Jit {
+    fn run(&self, io: &mut dyn Io) -> Result<u32, ExecError> {
+        X86Jit::run(self, io)
+    }
+}
+
+#[cfg(feature = "cranelift")]
+impl Jit for CraneliftJit {
+    fn run(&self, io: &mut dyn Io) -> Result<u32, ExecError> {
+        CraneliftJit::run(self, io)
+    }
+}
+
+/// Compiles `bf` with the first JIT that emits native code on this host, or
+/// returns `None` if there is none.
+#[cfg(any(feature = "jit-x86", feature = "cranelift"))]
+fn compile_jit(bf: &Brainfuck) -> Result<Option<Box<dyn Jit>>, BrainfuckError> {
+    #[cfg(feature = "jit-x86")]
+    {
+        let jit = X86Jit::compile(bf)?;
+        if jit.is_native() {
+            return Ok(Some(Box::new(jit)));
+        }
+    }
+    #[cfg(feature = "cranelift")]
+    let jit = CraneliftJit::compile(bf).map(|jit| Some(Box::new(jit) as Box<dyn Jit>));
+    #[cfg(not(feature = "cranelift"))]
+    let jit = Ok(None);
+    jit
+}
+
+#[cfg(not(any(feature = "jit-x86", feature = "cranelift")))]
+fn compile_jit(_bf: &Brainfuck) -> Result<Option<Box<dyn Jit>>, BrainfuckError> {
+    Ok(None)
+}
+
+/// How `InputIo` writes each output byte.
+#[derive(Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Text,
+    Hex,
+    Decimal,
+}
+
+/// Runs `interpreter` within `limits`, returning its statistics and the value of
+/// the cell under the pointer. The tape is dumped whether or not the run fails.
+fn finish<I: Io, H: Hooks>(
+    interpreter: &mut Interpreter<I, H>,
+    limits: &Limits,
+    checkpoint: Option<&Checkpoint>,
+    tape_dump: Option<&mut TapeDump>,
+) -> Result<(ExecStats, Option<u32>), ExecError> {
+    let result = match checkpoint {
+        Some(checkpoint) => checkpoint.run(interpreter, limits),
+        None => interpreter.run_limited(limits).cloned(),
+    };
+    if let Err(ExecError::Interrupted(_)) = result {
+        eprintln!(
+            "interrupted: ptr {} after {} instructions",
+            interpreter.ptr(),
+            interpreter.stats().instructions
+        );
+    }
+    if let Some(tape_dump) = tape_dump {
+        tape_dump.write(interpreter.tape(), interpreter.stats());
+    }
+    Ok((result?, interpreter.tape().get(interpreter.ptr()).cloned()))
+}
+
+/// Where and how often `--checkpoint` saves the interpreter's state.
+struct Checkpoint {
+    path: String,
+    /// Instructions between checkpoints.
+    interval: u64,
+    fingerprint: u64,
+}
+
+impl Checkpoint {
+    /// Runs within `limits`, stopping every `interval` instructions to save a
+    /// checkpoint. An interrupted run is saved too, so it can be resumed.
+    fn run<I: Io, H: Hooks>(
+        &self,
+        interpreter: &mut Interpreter<I, H>,
+        limits: &Limits,
+    ) -> Result<ExecStats, ExecError> {
+        let start = Instant::now();
+        let mut chunk = limits.clone();
+        loop {
+            let next = interpreter
+                .stats()
+                .instructions
+                .saturating_add(self.interval);
+            chunk.max_instructions =
+                Some(limits.max_instructions.map_or(next, |max| max.min(next)));
+            chunk.timeout = limits
+                .timeout
+                .map(|timeout| timeout.saturating_sub(start.elapsed()));
+            match interpreter.run_limited(&chunk).cloned() {
+                Err(ExecError::LimitExceeded(_))
+                    if interpreter.stats().instructions >= next
+                        && limits
+                            .max_instructions
+                            .is_none_or(|max| interpreter.stats().instructions < max) =>
+                {
+                    self.save(interpreter);
+                }
+                Err(ExecError::Interrupted(d)) => {
+                    self.save(interpreter);
+                    return Err(ExecError::Interrupted(d));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Writes to a temporary file first, so a crash mid-write leaves the previous
+    /// checkpoint intact.
+    fn save<I: Io, H: Hooks>(&self, interpreter: &Interpreter<I, H>) {
+        let temp = format!("{}.tmp", self.path);
+        let saved = File::create(&temp)
+            .and_then(|f| {
+                let mut out = BufWriter::new(f);
+                interpreter
+                    .snapshot()
+                    .write_checkpoint(&mut out, self.fingerprint)?;
+                out.into_inner()?.sync_all()
+            })
+            .and_then(|_| fs::rename(&temp, &self.path));
+        if let Err(e) = saved {
+            eprintln!("warning: could not save checkpoint `{}`: {}", self.path, e);
+        }
+    }
+}
+
+/// Where `--tape-dump` writes the final tape.
+struct TapeDump {
+    out: BufWriter<File>,
+    cell_size: CellSize,
+    /// Whether to write only the cells the program accessed.
+    touched: bool,
+}
+
+impl TapeDump {
+    /// Writes each cell as little-endian bytes, as many as the cell size has.
+    fn write(&mut self, tape: &[u32], stats: &ExecStats) {
+        let cells = if self.touched {
+            let end = (stats.highest_cell + 1).min(tape.len());
+            &tape[stats.lowest_cell.min(end)..end]
+        } else {
+            tape
+        };
+        let width = self.cell_size.bytes();
+        let bytes: Vec<u8> = cells
+            .iter()
+            .flat_map(|&cell| cell.to_le_bytes()[..width].to_vec())
+            .collect();
+        let written = self.out.write_all(&bytes).and_then(|_| self.out.flush());
+        if let Err(e) = written {
+            eprintln!("warning: could not write tape dump: {}", e);
+        }
+    }
+}
+
+/// Reads program input from `input` while writing output through `StdIo`, to
+/// `file`, or both.
+struct InputIo<R> {
+    input: R,
+    output: Option<StdIo>,
+    file: Option<BufWriter<File>>,
+    format: OutputFormat,
+    /// Whether a numeric value has been written, and so needs a separator.
+    written: bool,
+}
+
+impl<R> InputIo<R> {
+    fn write(&mut self, bytes: &[u8]) {
+        if let Some(ref mut output) = self.output {
+            for &b in bytes {
+                output.output(b);
+            }
+        }
+        if let Some(ref mut file) = self.file {
+            let _ = file.write_all(bytes);
+        }
+    }
+}
+
+impl<R> Drop for InputIo<R> {
+    /// Ends a line of numbers.
+    fn drop(&mut self) {
+        if self.written {
+            self.write(b"\n");
+        }
+    }
+}
+
+impl<R: Read> Io for InputIo<R> {
+    fn output(&mut self, value: u8) {
+        let text = match self.format {
+            OutputFormat::Text => return self.write(&[value]),
+            OutputFormat::Hex => format!("{:02x}", value),
+            OutputFormat::Decimal => value.to_string(),
+        };
+        if self.written {
+            self.write(b" ");
+        }
+        self.written = true;
+        self.write(text.as_bytes());
+    }
+
+    fn input(&mut self) -> Option<u8> {
+        if let Some(ref mut output) = self.output {
+            output.flush();
+        }
+        if let Some(ref mut file) = self.file {
+            let _ = file.flush();
+        }
+        let mut buf = [0];
+        match self.input.read(&mut buf) {
+            Ok(1) => Some(buf[0]),
+            _ => None,
+        }
+    }
+}
+
+/// Switches the terminal to non-canonical, no-echo input with `stty`, restoring
+/// its previous settings when dropped.
+struct RawTerminal {
+    saved: String,
+}
+
+impl RawTerminal {
+    /// Returns `None`, leaving the terminal alone, if stdin is not a terminal.
+    fn enable() -> Option<RawTerminal> {
+        if !io::stdin().is_terminal() {
+            return None;
+        }
+        let saved = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok());
+        let changed = Command::new("stty")
+            .args(["-icanon", "-echo", "min", "1"])
+            .status()
+            .is_ok_and(|status| status.success());
+        match saved {
+            Some(saved) if changed => Some(RawTerminal {
+                saved: saved.trim().to_string(),
+            }),
+            _ => {
+                eprintln!("warning: could not switch the terminal to raw input");
+                None
+            }
+        }
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = Command::new("stty").arg(&self.saved).status();
+    }
+}
+
+/// Logs each instruction as it executes, for `--trace`.
+struct Tracer<W> {
+    out: W,
+}
+
+impl<W: Write> Hooks for Tracer<W> {
+    fn on_instruction(&mut self, node: &Node, ptr: usize, cell: Option<i64>) {
+        let cell = cell.map_or("-".to_string(), |value| value.to_string());
+        let _ = writeln!(
+            self.out,
+            "{}: {} ptr {} cell {}",
+            node.debug_info(),
+            describe(node),
+            ptr,
+            cell
+        );
+    }
+}
+
+/// A short form of `node` in brainfuck-like notation, such as `+3` or `>2`, for
+/// `--trace` and the AST dumps.
+fn describe(node: &Node) -> String {
+    match *node {
+        Node::Loop(..) => "[".to_string(),
+        Node::Move(v, _) if v < 0 => format!("<{}", v.unsigned_abs()),
+        Node::Move(v, _) => format!(">{}", v),
+        Node::Add(0, v, _) => format!("{:+}", v),
+        Node::Add(offset, v, _) => format!("{:+}@{}", v, offset),
+        Node::Output(_) => ".".to_string(),
+        Node::Input(_) => ",".to_string(),
+        Node::Debug(_) => "#".to_string(),
+        Node::Random(_) => "?".to_string(),
+        Node::PrintNumber(_) => ":".to_string(),
+        Node::ReadNumber(_) => ";".to_string(),
+        Node::Set(v, _) => format!("={}", v),
+        Node::MulAdd(offset, factor, _) => format!("*{}@{}", factor, offset),
+        Node::Product(offset, source, factor, _) => {
+            format!("*{}@{}*@{}", factor, offset, source)
+        }
+        Node::Scan(step, _) => format!("[{:+}]", step),
+        Node::Write(ref bytes, _) => format!(".{:?}", String::from_utf8_lossy(bytes)),
+        Node::If(..) => "if".to_string(),
+    }
+}
+
+/// Prints a program tree for `--dump-ast` and `--dump-opt`, one node per line
+/// with its source location, indenting loop bodies. Runs of identical nodes
+/// share a line, with a count such as `x3`. For `--analyze`, each node is
+/// instead shown with what the current cell may hold before it runs.
+fn dump_ast<W: Write>(out: &mut W, ast: &Ast, ranges: Option<&ValueRanges>) -> io::Result<()> {
+    // An iterator over each level entered, and what closes it.
+    let mut levels = vec![(ast.nodes.iter().peekable(), "")];
+    let mut index = 0;
+    while !levels.is_empty() {
+        let depth = levels.len() - 1;
+        let (ref mut nodes, close) = levels[depth];
+        let node = match nodes.next() {
+            Some(node) => node,
+            None => {
+                levels.pop();
+                if depth > 0 {
+                    writeln!(out, "{}{}", "  ".repeat(depth - 1), close)?;
+                }
+                continue;
+            }
+        };
+        let op = describe(node);
+        let mut count = 1;
+        if ranges.is_none() && !matches!(*node, Node::Loop(..) | Node::If(..)) {
+            while nodes.next_if(|next| describe(next) == op).is_some() {
+                count += 1;
+            }
+        }
+        let count = match ranges {
+            Some(ranges) => {
+                let once = if ranges.runs_once(index) {
+                    " (runs once)"
+                } else {
+                    ""
+                };
+                format!("  cell {}{}", ranges.cell(index), once)
+            }
+            None if count > 1 => format!(" x{}", count),
+            None => String::new(),
+        };
+        index += 1;
+        writeln!(
+            out,
+            "{}{}{}  --> {}",
+            "  ".repeat(depth),
+            op,
+            count,
+            node.debug_info()
+        )?;
+        match *node {
+            Node::Loop(ref body, _) => levels.push((body.iter().peekable(), "]")),
+            Node::If(ref body, _) => levels.push((body.iter().peekable(), "end")),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// The passes `--explain` runs: those that rewrite loops into the nodes it
+/// describes, and `coalesce`, which they rely on.
+const EXPLAIN_PASSES: &[&str] = &[
+    "coalesce",
+    "clear-loops",
+    "mul-loops",
+    "nested-mul",
+    "scan-loops",
+];
+
+/// The passes `--warn-uninit` skips, as they drop reads of the tape by working
+/// out the value read, or report them from a node other than the one in the
+/// source.
+const UNINIT_HIDING_PASSES: &[&str] = &[
+    "clear-loops",
+    "mul-loops",
+    "scan-loops",
+    "const-fold",
+    "unroll",
+    "dead-code",
+];
+
+/// Prints each loop in `ast` that a pass rewrote, for `--explain`, with what it
+/// does and the loop's source underlined, in source order.
+fn explain<W: Write>(out: &mut W, ast: &Ast, cell_size: CellSize, source: &[u8]) -> io::Result<()> {
+    // An iterator over each level entered, and the loop it was rewritten from,
+    // whose nodes are described with it.
+    let mut levels = vec![(ast.nodes.iter().peekable(), None)];
+    while let Some(&mut (ref mut nodes, rewritten)) = levels.last_mut() {
+        let node = match nodes.next() {
+            Some(node) => node,
+            None => {
+                levels.pop();
+                continue;
+            }
+        };
+        let d = node.debug_info();
+        if rewritten == Some(d.span) {
+            continue;
+        }
+        let what = match *node {
+            Node::Loop(ref body, _) => {
+                levels.push((body.iter().peekable(), None));
+                continue;
+            }
+            Node::If(ref body, _) => {
+                // The first run of a nested multiplication, then the rest.
+                let rest = body.iter().filter(|node| node.debug_info().span == d.span);
+                let what = idiom(rest, cell_size);
+                levels.push((body.iter().peekable(), Some(d.span)));
+                what
+            }
+            Node::Scan(step, _) if step < 0 => scan("left", step),
+            Node::Scan(step, _) => scan("right", step),
+            Node::Set(..) | Node::MulAdd(..) => {
+                let mut run = vec![node];
+                while let Some(next) = nodes.next_if(|next| next.debug_info().span == d.span) {
+                    run.push(next);
+                }
+                idiom(run.into_iter(), cell_size)
+            }
+            _ => continue,
+        };
+        write!(out, "{}  --> {}\n{}", what, d, d.span.render(source))?;
+    }
+    Ok(())
+}
+
+/// Describes a `Scan` by `step` in `direction`, for `--explain`.
+fn scan(direction: &str, step: isize) -> String {
+    match step.unsigned_abs() {
+        1 => format!("scan {}", direction),
+        n => format!("scan {} by {}", direction, n),
+    }
+}
+
+/// Describes the nodes a loop was rewritten into, for `--explain`, such as
+/// `copy x2 to +1 and +4`.
+fn idiom<'n, I: Iterator<Item = &'n Node>>(nodes: I, cell_size: CellSize) -> String {
+    // The offsets each factor is added to, in order, and what else is done.
+    let mut adds: Vec<(i64, Vec<isize>)> = Vec::new();
+    let mut parts = Vec::new();
+    for node in nodes {
+        match *node {
+            Node::MulAdd(offset, factor, _) => {
+                let factor = cell_size.value(factor, true);
+                match adds.iter_mut().find(|&&mut (f, _)| f == factor) {
+                    Some(&mut (_, ref mut offsets)) => offsets.push(offset),
+                    None => adds.push((factor, vec![offset])),
+                }
+            }
+            Node::Product(offset, source, factor, _) => {
+                let factor = match cell_size.value(factor, true) {
+                    1 => String::new(),
+                    factor => format!(" x{}", factor),
+                };
+                parts.push(format!(
+                    "multiply by {:+}{} into {:+}",
+                    source, factor, offset
+                ));
+            }
+            Node::Set(0, _) if adds.is_empty() && parts.is_empty() => {
+                parts.push("clear loop".to_string())
+            }
+            _ => (),
+        }
+    }
+    // A single add of the cell, once, moves it.
+    let moves = adds.len() == 1 && adds[0].1.len() == 1;
+    let adds: Vec<_> = adds
+        .into_iter()
+        .map(|(factor, offsets)| {
+            let offsets: Vec<_> = offsets.iter().map(|o| format!("{:+}", o)).collect();
+            let offsets = offsets.join(" and ");
+            match factor {
+                1 if moves => format!("move to {}", offsets),
+                1 => format!("copy to {}", offsets),
+                -1 => format!("subtract from {}", offsets),
+                f if f < 0 => format!("subtract x{} from {}", -f, offsets),
+                f => format!("copy x{} to {}", f, offsets),
+            }
+        })
+        .collect();
+    parts.splice(0..0, adds);
+    parts.join(", ")
+}
+
+/// How many of each kind of node a program tree holds, for `--opt-stats`.
+#[derive(Default, Clone, Copy)]
+struct NodeCounts {
+    nodes: usize,
+    loops: usize,
+    sets: usize,
+    mul_adds: usize,
+    scans: usize,
+}
+
+impl NodeCounts {
+    fn of(ast: &Ast) -> NodeCounts {
+        let mut counts = NodeCounts::default();
+        let mut levels = vec![&ast.nodes];
+        while let Some(nodes) = levels.pop() {
+            counts.nodes += nodes.len();
+            for node in nodes {
+                match *node {
+                    Node::Loop(ref body, _) => {
+                        counts.loops += 1;
+                        levels.push(body);
+                    }
+                    Node::If(ref body, _) => levels.push(body),
+                    Node::Set(..) => counts.sets += 1,
+                    Node::MulAdd(..) | Node::Product(..) => counts.mul_adds += 1,
+                    Node::Scan(..) => counts.scans += 1,
+                    _ => (),
+                }
+            }
+        }
+        counts
+    }
+
+    /// Prints one row of the `--opt-stats` table, with the change in nodes since
+    /// `before`.
+    fn report(&self, name: &str, before: &NodeCounts) {
+        let change = match self.nodes as i64 - before.nodes as i64 {
+            0 => String::new(),
+            change => format!("{:+}", change),
+        };
+        eprintln!(
+            "{:<12} {:>8} {:>8} {:>7} {:>7} {:>7} {:>7}",
+            name, self.nodes, change, self.loops, self.sets, self.mul_adds, self.scans
+        );
+    }
+}
+
+/// Runs `passes` over the unoptimized `ast` one at a time for `--opt-stats`,
+/// reporting the nodes and loops left after each, and how many loops have been
+/// lowered to `Set`, `MulAdd` and `Scan` nodes.
+fn opt_stats(builder: &BrainfuckBuilder, passes: &[&(dyn Pass + Sync)], mut ast: Ast) {
+    eprintln!(
+        "{:<12} {:>8} {:>8} {:>7} {:>7} {:>7} {:>7}",
+        "pass", "nodes", "change", "loops", "set", "mul-add", "scan"
+    );
+    let parsed = NodeCounts::of(&ast);
+    parsed.report("parsed", &parsed);
+    let mut before = parsed;
+    for &pass in passes {
+        builder.run_pass(&mut ast, pass);
+        let after = NodeCounts::of(&ast);
+        after.report(pass.name(), &before);
+        before = after;
+    }
+    let eliminated = parsed.nodes.saturating_sub(before.nodes);
+    eprintln!(
+        "eliminated {} of {} nodes ({:.1}%)",
+        eliminated,
+        parsed.nodes,
+        100.0 * eliminated as f64 / parsed.nodes.max(1) as f64
+    );
+}
+
+/// Counts reads and writes of each cell, for `--heatmap`.
+#[derive(Default)]
+struct Heatmap {
+    /// Reads and writes, indexed by cell.
+    counts: Vec<(u64, u64)>,
+}
+
+impl Heatmap {
+    /// The most rows printed; larger tapes are grouped into buckets of cells.
+    const ROWS: usize = 32;
+    const BAR_WIDTH: u64 = 40;
+
+    fn count(&mut self, index: usize) -> &mut (u64, u64) {
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, (0, 0));
+        }
+        &mut self.counts[index]
+    }
+
+    fn report(&self) {
+        let touched = |&(reads, writes): &(u64, u64)| reads + writes > 0;
+        let (first, last) = match (
+            self.counts.iter().position(touched),
+            self.counts.iter().rposition(touched),
+        ) {
+            (Some(first), Some(last)) => (first, last),
+            _ => {
+                eprintln!("heatmap: no cells accessed");
                 return;
             }
+        };
+        let bucket = (last - first) / Heatmap::ROWS + 1;
+        let rows: Vec<(usize, u64, u64)> = (first..=last)
+            .step_by(bucket)
+            .map(|start| {
+                let end = (start + bucket).min(last + 1);
+                let (reads, writes) = self.counts[start..end]
+                    .iter()
+                    .fold((0, 0), |(r, w), &(reads, writes)| (r + reads, w + writes));
+                (start, reads, writes)
+            })
+            .collect();
+        let busiest = rows.iter().map(|&(_, r, w)| r + w).max().unwrap_or(1);
 
-            let llc = Command::new("llc")
-                .arg("-O3")
-                .arg("-filetype=obj")
-                .arg(&bc_file_name)
-                .arg("-o")
-                .arg(&o_file_name)
-                .status()
-                .expect("failed to execute llc");
+        eprintln!("heatmap: cells {}..={}, {} per row", first, last, bucket);
+        eprintln!("{:>12} {:>12} {:>12}", "cell", "reads", "writes");
+        for (start, reads, writes) in rows {
+            let total = reads + writes;
+            let bar = (total * Heatmap::BAR_WIDTH).div_ceil(busiest);
+            eprintln!(
+                "{:>12} {:>12} {:>12}  {}",
+                start,
+                reads,
+                writes,
+                "#".repeat(bar as usize)
+            );
+        }
+    }
+}
+
+impl Hooks for Heatmap {
+    fn on_cell_read(&mut self, index: usize) {
+        self.count(index).0 += 1;
+    }
+
+    fn on_cell_write(&mut self, index: usize) {
+        self.count(index).1 += 1;
+    }
+}
 
-            if !llc.success() {
-                println!("failed to execute llc");
+/// Hashes everything the program wrote, then its final tape and pointer, for
+/// `--digest`.
+struct Digest {
+    hash: u64,
+}
+
+impl Digest {
+    fn new() -> Digest {
+        Digest {
+            hash: 0xcbf2_9ce4_8422_2325,
+        }
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.hash ^= u64::from(b);
+            self.hash = self.hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    /// The hash of a tape and pointer, fed in after the output.
+    fn end_state(tape: &[u32], ptr: usize) -> u64 {
+        let mut digest = Digest::new();
+        digest.feed(&(tape.len() as u64).to_le_bytes());
+        for cell in tape {
+            digest.feed(&cell.to_le_bytes());
+        }
+        digest.feed(&(ptr as u64).to_le_bytes());
+        digest.hash
+    }
+}
+
+impl Hooks for Digest {
+    fn on_output(&mut self, value: u8, _d: &DebugInfo) {
+        self.feed(&[value]);
+    }
+}
+
+/// Reports reads of cells that were never written, for `--warn-uninit`.
+#[derive(Default)]
+struct UninitReads {
+    written: Vec<bool>,
+    /// The byte offsets of instructions already warned about.
+    warned: HashSet<usize>,
+    /// The instruction last executed.
+    current: Option<DebugInfo>,
+    /// Whether `current` is `+` or `-`, which may start from the implicit 0.
+    arithmetic: bool,
+    /// Whether the next read belongs to `current`; later reads are loop tests.
+    own_read: bool,
+    loops: Vec<DebugInfo>,
+}
+
+impl Hooks for UninitReads {
+    fn on_instruction(&mut self, node: &Node, _ptr: usize, _cell: Option<i64>) {
+        self.current = Some(node.debug_info().clone());
+        self.arithmetic = matches!(*node, Node::Add(..));
+        self.own_read = true;
+    }
+
+    fn on_loop_enter(&mut self, d: &DebugInfo) {
+        self.loops.push(d.clone());
+    }
+
+    fn on_loop_exit(&mut self, _d: &DebugInfo) {
+        self.loops.pop();
+    }
+
+    fn on_cell_read(&mut self, index: usize) {
+        let location = if self.own_read {
+            self.own_read = false;
+            if self.arithmetic {
                 return;
             }
+            self.current.as_ref()
+        } else {
+            self.loops.last()
+        };
+        if self.written.get(index).cloned().unwrap_or(false) {
+            return;
+        }
+        if let Some(location) = location {
+            if self.warned.insert(location.span.byte_offset) {
+                eprintln!(
+                    "warning: read of never-written cell {} --> {}",
+                    index, location
+                );
+            }
+        }
+    }
+
+    fn on_cell_write(&mut self, index: usize) {
+        if index >= self.written.len() {
+            self.written.resize(index + 1, false);
+        }
+        self.written[index] = true;
+    }
+}
+
+/// The arguments that shape how the program behaves, shared by running it and
+/// `golf`.
+fn program_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("mem-size")
+            .long("mem-size")
+            .takes_value(true)
+            .value_name("N")
+            .help("Sets the number of cells on the tape [default: 100000]"),
+        Arg::with_name("tape-init")
+            .long("tape-init")
+            .takes_value(true)
+            .value_name("FILE[:OFFSET]")
+            .help("Loads FILE into the tape, one byte per cell, from cell OFFSET or the starting cell"),
+        Arg::with_name("cell-size")
+            .long("cell-size")
+            .takes_value(true)
+            .value_name("BITS")
+            .possible_values(&["8", "16", "32"])
+            .help("Sets the width of each cell [default: 8]"),
+        Arg::with_name("signed-cells")
+            .long("signed-cells")
+            .help("Treats cell values as signed numbers"),
+        Arg::with_name("debug-ext")
+            .long("debug-ext")
+            .help("Makes `#` print the pointer and nearby cells to stderr"),
+        Arg::with_name("random-ext")
+            .long("random-ext")
+            .help("Makes `?` store a random byte in the current cell"),
+        Arg::with_name("numeric-ext")
+            .long("numeric-ext")
+            .help("Makes `:` print the current cell in decimal and `;` read a decimal number"),
+        Arg::with_name("strict")
+            .long("strict")
+            .help("Reports cells that overflow or underflow instead of wrapping"),
+        Arg::with_name("grow-tape")
+            .long("grow-tape")
+            .help("Extends the tape when the pointer moves past either end"),
+        Arg::with_name("wrap-tape")
+            .long("wrap-tape")
+            .conflicts_with("grow-tape")
+            .help("Wraps the pointer around the ends of the tape"),
+        Arg::with_name("frontend")
+            .long("frontend")
+            .takes_value(true)
+            .value_name("NAME")
+            .help(
+                "Reads the source in the named dialect instead of guessing from its extension",
+            ),
+    ]
+}
+
+/// Builds the program's settings from the arguments `program_args` adds,
+/// picking the frontend from `input_path`'s extension unless one is named.
+/// Returns `None` after reporting an invalid argument.
+fn configure(matches: &ArgMatches, input_path: &Path) -> Option<BrainfuckBuilder> {
+    let mut builder = Brainfuck::builder();
+    if let Some(size) = matches.value_of("mem-size") {
+        match size.parse() {
+            Ok(size) if size > 0 => builder = builder.mem_size(size),
+            _ => {
+                eprintln!("error: invalid tape size `{}`", size);
+                return None;
+            }
+        }
+    }
+    if let Some(bits) = matches.value_of("cell-size") {
+        let bits = bits.parse().expect("validated by possible_values");
+        builder = builder.cell_size(CellSize::from_bits(bits).unwrap());
+    }
+    builder = builder.extensions(Extensions {
+        debug: matches.is_present("debug-ext"),
+        random: matches.is_present("random-ext"),
+        numeric_io: matches.is_present("numeric-ext"),
+    });
+    if matches.is_present("signed-cells") {
+        builder = builder.signed_cells(true);
+    }
+    if matches.is_present("strict") {
+        builder = builder.strict(true);
+    }
+    if matches.is_present("grow-tape") {
+        builder = builder.tape_mode(TapeMode::Grow);
+    }
+    if matches.is_present("wrap-tape") {
+        builder = builder.tape_mode(TapeMode::Wrap);
+    }
+    if let Some(arg) = matches.value_of("tape-init") {
+        let (path, offset) = match arg.rfind(':') {
+            Some(i) => match arg[i + 1..].parse() {
+                Ok(offset) => (&arg[..i], Some(offset)),
+                Err(_) => (arg, None),
+            },
+            None => (arg, None),
+        };
+        match fs::read(path) {
+            Ok(data) => builder = builder.tape_init(&data),
+            Err(e) => {
+                eprintln!("error: could not read tape file `{}`: {}", path, e);
+                return None;
+            }
+        }
+        if let Some(offset) = offset {
+            builder = builder.tape_init_offset(offset);
+        }
+    }
+    let frontend = match matches.value_of("frontend") {
+        Some(name) => match brainfuck::find_frontend(name) {
+            Some(frontend) => Some(frontend),
+            None => {
+                let known: Vec<&str> = FRONTENDS.iter().map(|f| f.name()).collect();
+                eprintln!(
+                    "error: unknown frontend `{}`, expected one of: {}",
+                    name,
+                    known.join(", ")
+                );
+                return None;
+            }
+        },
+        None => input_path
+            .extension()
+            .and_then(|e| brainfuck::frontend_for_extension(&e.to_string_lossy())),
+    };
+    if let Some(frontend) = frontend {
+        builder = builder.frontend(frontend);
+    }
+    Some(builder)
+}
+
+/// Reads the program at `path` into `source`, returning the file name and
+/// directory it is parsed with.
+fn read_source<'p>(
+    path: &'p Path,
+    source: &mut Vec<u8>,
+) -> Result<(Cow<'p, str>, Cow<'p, str>), BrainfuckError> {
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(source))
+        .map_err(|error| BrainfuckError::Read {
+            path: path.to_path_buf(),
+            error,
+        })?;
+    let file_name = path
+        .file_name()
+        .expect("No source file specified")
+        .to_string_lossy();
+    let directory = path
+        .parent()
+        .map(|x| x.to_string_lossy())
+        .unwrap_or(Cow::Borrowed(""));
+    Ok((file_name, directory))
+}
+
+/// Runs the `golf` subcommand, printing a shorter version of the program.
+fn golf(matches: &ArgMatches, source: &mut Vec<u8>) -> Result<u8, BrainfuckError> {
+    let input_path = Path::new(matches.value_of("INPUT").unwrap());
+    let builder = match configure(matches, input_path) {
+        Some(builder) => builder,
+        None => return Ok(1),
+    };
+    let budget = matches.value_of("budget").unwrap_or("10000");
+    let budget = match budget.parse() {
+        Ok(budget) => budget,
+        Err(_) => {
+            eprintln!("error: invalid golf budget `{}`", budget);
+            return Ok(1);
+        }
+    };
+    let (file_name, directory) = read_source(input_path, source)?;
+    let parsed =
+        builder
+            .clone()
+            .passes(&[])
+            .parse(&source[..], file_name.borrow(), directory.borrow())?;
+    let golfed = builder
+        .golf(parsed.ast(), budget)
+        .expect("unoptimized trees can be golfed");
+    println!("{}", golfed);
+    Ok(0)
+}
+
+/// How many instructions `--precompute` runs without `--max-instructions` or
+/// `--timeout`.
+const PRECOMPUTE_BUDGET: u64 = 100_000_000;
+
+/// Looks up the passes named by `--passes` or `--disable-passes`, reporting the
+/// first unknown name.
+fn find_passes<'a, I>(names: I) -> Option<Vec<&'static (dyn Pass + Sync)>>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let mut passes = Vec::new();
+    for name in names.filter(|n| !n.is_empty()) {
+        match brainfuck::find_pass(name) {
+            Some(pass) => passes.push(pass),
+            None => {
+                let known: Vec<&str> = PASSES.iter().map(|p| p.name()).collect();
+                eprintln!(
+                    "error: unknown pass `{}`, expected one of: {}",
+                    name,
+                    known.join(", ")
+                );
+                return None;
+            }
+        }
+    }
+    Some(passes)
+}
+
+/// Runs `bf` for `--precompute`, warning and returning `None` if it cannot be
+/// precomputed within `limits`.
+fn precompute(bf: &Brainfuck, limits: &Limits) -> Option<Brainfuck> {
+    let mut budget = limits.clone();
+    if budget.max_instructions.is_none() && budget.timeout.is_none() {
+        budget.max_instructions = Some(PRECOMPUTE_BUDGET);
+    }
+    match bf.precompute(&budget) {
+        Ok(Some(precomputed)) => Some(precomputed),
+        Ok(None) => {
+            eprintln!("warning: not precomputing a program that reads input or uses unseeded `?`");
+            None
+        }
+        Err(e) => {
+            eprintln!("warning: could not precompute the program: {}", e);
+            None
+        }
+    }
+}
+
+/// Starts a recording made with `--record-timing`, which stores each byte after
+/// the microseconds since the run started.
+const TIMED_RECORDING: &[u8] = b"BFREC\x01";
+
+/// Copies each byte of input to `log`, for `--record`.
+struct Recorder<R> {
+    inner: R,
+    log: BufWriter<File>,
+    /// When the run started, if the recording is timed.
+    start: Option<Instant>,
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        for &b in &buf[..len] {
+            if let Some(start) = self.start {
+                let micros = start.elapsed().as_micros() as u64;
+                self.log.write_all(&micros.to_le_bytes())?;
+            }
+            self.log.write_all(&[b])?;
+        }
+        Ok(len)
+    }
+}
+
+/// Reads back a `--record` file; `None` if a timed recording is truncated.
+fn replay(recording: Vec<u8>) -> Option<Box<dyn Read>> {
+    let records = match recording.strip_prefix(TIMED_RECORDING) {
+        Some(records) => records,
+        None => return Some(Box::new(Cursor::new(recording))),
+    };
+    if records.len() % 9 != 0 {
+        return None;
+    }
+    let records = records
+        .chunks(9)
+        .map(|record| {
+            let mut micros = [0; 8];
+            micros.copy_from_slice(&record[..8]);
+            (u64::from_le_bytes(micros), record[8])
+        })
+        .collect::<Vec<_>>();
+    Some(Box::new(TimedReplay {
+        records: records.into_iter(),
+        start: Instant::now(),
+    }))
+}
+
+/// Feeds back a timed recording, holding each byte until its original time.
+struct TimedReplay {
+    records: vec::IntoIter<(u64, u8)>,
+    start: Instant,
+}
+
+impl Read for TimedReplay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.records.next() {
+            Some((micros, b)) => {
+                let due = self.start + Duration::from_micros(micros);
+                let now = Instant::now();
+                if due > now {
+                    thread::sleep(due - now);
+                }
+                buf[0] = b;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+/// Finds the `!` separating a program from its input, skipping any inside loops.
+fn bang_position(source: &[u8]) -> Option<usize> {
+    let mut depth = 0usize;
+    for (pos, &b) in source.iter().enumerate() {
+        match b {
+            b'[' => depth += 1,
+            b']' => depth = depth.saturating_sub(1),
+            b'!' if depth == 0 => return Some(pos),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Decodes `\\n`, `\\r`, `\\t`, `\\0`, `\\\\` and `\\xHH` escapes, returning the first
+/// invalid escape on failure.
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) if hex.len() == 2 && !hex.starts_with('+') => bytes.push(b),
+                    _ => return Err(format!("\\x{}", hex)),
+                }
+            }
+            Some(c) => return Err(format!("\\{}", c)),
+            None => return Err("\\".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Compiles `program`, whose IR is `ir`, to the object file `o_file_name` for
+/// `target`: in process with the `llvm` feature, and otherwise with `opt` and
+/// `llc`, leaving their inputs beside it.
+#[cfg(feature = "llvm")]
+fn compile_object(
+    program: &Brainfuck,
+    target: Target,
+    _: &str,
+    _: &str,
+    o_file_name: &str,
+) -> Result<(), BrainfuckError> {
+    let object = LlvmObject { target }.lower(program)?;
+    fs::write(o_file_name, object.as_bytes()).expect("Could not write object file.");
+    Ok(())
+}
+
+#[cfg(not(feature = "llvm"))]
+fn compile_object(
+    _: &Brainfuck,
+    target: Target,
+    ir: &str,
+    output_name: &str,
+    o_file_name: &str,
+) -> Result<(), BrainfuckError> {
+    let ir_file_name = format!("{}.ll", output_name);
+    let bc_file_name = format!("{}.bc", output_name);
+
+    let mut ir_file = File::create(&ir_file_name).unwrap();
+    ir_file.write_all(ir.as_bytes()).unwrap();
+
+    run_tool(
+        "opt",
+        Command::new("opt")
+            .arg("-O3")
+            .arg("-strip-debug")
+            .arg(&ir_file_name)
+            .arg("-o")
+            .arg(&bc_file_name),
+    )?;
 
-            let lld = Command::new("ld.lld")
+    run_tool(
+        "llc",
+        Command::new("llc")
+            .arg("-O3")
+            .arg(format!("-mtriple={}", target.triple()))
+            .arg(format!("-mattr={}", target.features()))
+            .arg("-filetype=obj")
+            .arg(&bc_file_name)
+            .arg("-o")
+            .arg(o_file_name),
+    )
+}
+
+/// Links the object file `o_file_name` into a standalone executable named after
+/// `output_name`: with `ld.lld` for Linux, for Windows with `lld-link` and an
+/// import library `llvm-dlltool` builds beside it, and for macOS with `ld64.lld`
+/// and a stub of libSystem written beside it.
+fn link(target: Target, o_file_name: &str, output_name: &str) -> Result<(), BrainfuckError> {
+    match target {
+        Target::X86_64Windows => {
+            let def_file_name = format!("{}.def", output_name);
+            let lib_file_name = format!("{}.lib", output_name);
+            write_import_definition(target, &def_file_name);
+            run_tool(
+                "llvm-dlltool",
+                Command::new("llvm-dlltool")
+                    .arg("-m")
+                    .arg(target.machine())
+                    .arg("-d")
+                    .arg(&def_file_name)
+                    .arg("-l")
+                    .arg(&lib_file_name),
+            )?;
+            run_tool(
+                "lld-link",
+                Command::new("lld-link")
+                    .arg("/entry:_start")
+                    .arg("/subsystem:console")
+                    .arg("/nodefaultlib")
+                    .arg("/opt:ref")
+                    .arg(o_file_name)
+                    .arg(&lib_file_name)
+                    .arg(format!("/out:{}.exe", output_name)),
+            )
+        }
+        Target::X86_64MacOs | Target::Aarch64MacOs => {
+            let tbd_file_name = format!("{}.tbd", output_name);
+            write_import_definition(target, &tbd_file_name);
+            // The version matches the minimum in the target triple; arm64
+            // binaries are ad-hoc signed by the linker, as macOS requires.
+            run_tool(
+                "ld64.lld",
+                Command::new("ld64.lld")
+                    .arg("-arch")
+                    .arg(target.machine())
+                    .arg("-platform_version")
+                    .arg("macos")
+                    .arg("11.0")
+                    .arg("11.0")
+                    .arg("-e")
+                    .arg("__start")
+                    .arg("-dead_strip")
+                    .arg(o_file_name)
+                    .arg(&tbd_file_name)
+                    .arg("-o")
+                    .arg(output_name),
+            )
+        }
+        _ => run_tool(
+            "ld.lld",
+            Command::new("ld.lld")
+                .arg("-m")
+                .arg(target.machine())
                 .arg("-static")
                 .arg("-nostdlib")
                 .arg("--gc-sections")
@@ -110,30 +2207,28 @@ fn main() {
                 .arg("norelro")
                 .arg("--hash-style=gnu")
                 .arg("--build-id=none")
-                .arg(&o_file_name)
+                .arg(o_file_name)
                 .arg("-o")
-                .arg(output_name.to_string())
-                .status()
-                .expect("failed to execute lld");
-
-            if !lld.success() {
-                println!("failed to execute lld");
-                return;
-            }
-        } else {
-            println!("{}", ir);
-        }
-        return;
+                .arg(output_name),
+        ),
     }
+}
 
-    match bf.exec() {
-        Ok(_) => (),
-        Err(ExecError::OutOfBounds(d)) => {
-            println!(
-                "exception: out of bounds access --> {}:{}:{}",
-                d.file, d.line, d.column
-            );
-            return;
-        }
+fn write_import_definition(target: Target, file_name: &str) {
+    let definition = target
+        .import_definition()
+        .expect("targets linking against libraries describe them");
+    fs::write(file_name, definition).expect("Could not write import definition file.");
+}
+
+/// Runs an external compiler tool, failing if it cannot start or exits unsuccessfully.
+fn run_tool(tool: &'static str, command: &mut Command) -> Result<(), BrainfuckError> {
+    match command.status() {
+        Ok(status) if status.success() => Ok(()),
+        Ok(_) => Err(BrainfuckError::Toolchain { tool, error: None }),
+        Err(e) => Err(BrainfuckError::Toolchain {
+            tool,
+            error: Some(e),
+        }),
     }
 }
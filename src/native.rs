@@ -0,0 +1,260 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use std::slice;
+use std::sync::Once;
+
+use backend::{Artifact, Backend};
+use brainfuck::Brainfuck;
+use error::BrainfuckError;
+use llvm::{self, LlvmIr};
+
+enum Context {}
+enum Module {}
+enum MemoryBuffer {}
+enum Target {}
+enum TargetMachine {}
+enum PassBuilderOptions {}
+enum OpaqueError {}
+
+/// `LLVMCodeGenLevelAggressive`, as `llc -O3` uses.
+const CODEGEN_LEVEL_AGGRESSIVE: c_int = 3;
+const RELOC_DEFAULT: c_int = 0;
+const CODE_MODEL_DEFAULT: c_int = 0;
+const OBJECT_FILE: c_int = 1;
+
+// The parts of the LLVM C API that `compile` uses, from the LLVM shared library
+// `build.rs` links. The few used are declared here, as in the LLVM 14 headers,
+// rather than pulling in `llvm-sys`; `build.rs` refuses other LLVM versions, and
+// `tests/differential.rs` runs the objects they emit.
+extern "C" {
+    fn LLVMContextCreate() -> *mut Context;
+    fn LLVMContextDispose(context: *mut Context);
+    fn LLVMCreateMemoryBufferWithMemoryRangeCopy(
+        data: *const c_char,
+        len: usize,
+        name: *const c_char,
+    ) -> *mut MemoryBuffer;
+    fn LLVMGetBufferStart(buffer: *mut MemoryBuffer) -> *const c_char;
+    fn LLVMGetBufferSize(buffer: *mut MemoryBuffer) -> usize;
+    fn LLVMDisposeMemoryBuffer(buffer: *mut MemoryBuffer);
+    fn LLVMParseIRInContext(
+        context: *mut Context,
+        buffer: *mut MemoryBuffer,
+        module: *mut *mut Module,
+        message: *mut *mut c_char,
+    ) -> c_int;
+    fn LLVMSetTarget(module: *mut Module, triple: *const c_char);
+    fn LLVMStripModuleDebugInfo(module: *mut Module) -> c_int;
+    fn LLVMDisposeModule(module: *mut Module);
+    fn LLVMDisposeMessage(message: *mut c_char);
+
+    fn LLVMInitializeX86TargetInfo();
+    fn LLVMInitializeX86Target();
+    fn LLVMInitializeX86TargetMC();
+    fn LLVMInitializeX86AsmPrinter();
+    fn LLVMInitializeX86AsmParser();
+    fn LLVMInitializeAArch64TargetInfo();
+    fn LLVMInitializeAArch64Target();
+    fn LLVMInitializeAArch64TargetMC();
+    fn LLVMInitializeAArch64AsmPrinter();
+    fn LLVMInitializeAArch64AsmParser();
+    fn LLVMInitializeRISCVTargetInfo();
+    fn LLVMInitializeRISCVTarget();
+    fn LLVMInitializeRISCVTargetMC();
+    fn LLVMInitializeRISCVAsmPrinter();
+    fn LLVMInitializeRISCVAsmParser();
+    fn LLVMGetTargetFromTriple(
+        triple: *const c_char,
+        target: *mut *mut Target,
+        message: *mut *mut c_char,
+    ) -> c_int;
+    fn LLVMCreateTargetMachine(
+        target: *mut Target,
+        triple: *const c_char,
+        cpu: *const c_char,
+        features: *const c_char,
+        level: c_int,
+        reloc: c_int,
+        code_model: c_int,
+    ) -> *mut TargetMachine;
+    fn LLVMDisposeTargetMachine(machine: *mut TargetMachine);
+    fn LLVMTargetMachineEmitToMemoryBuffer(
+        machine: *mut TargetMachine,
+        module: *mut Module,
+        codegen: c_int,
+        message: *mut *mut c_char,
+        buffer: *mut *mut MemoryBuffer,
+    ) -> c_int;
+
+    fn LLVMCreatePassBuilderOptions() -> *mut PassBuilderOptions;
+    fn LLVMDisposePassBuilderOptions(options: *mut PassBuilderOptions);
+    fn LLVMRunPasses(
+        module: *mut Module,
+        passes: *const c_char,
+        machine: *mut TargetMachine,
+        options: *mut PassBuilderOptions,
+    ) -> *mut OpaqueError;
+    fn LLVMGetErrorMessage(error: *mut OpaqueError) -> *mut c_char;
+    fn LLVMDisposeErrorMessage(message: *mut c_char);
+}
+
+/// Lowers a program to an object file for its target in-process through the
+/// LLVM C API, optimizing it like `opt -O3` and `llc -O3` would. The object
+/// still needs linking.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LlvmObject {
+    pub target: llvm::Target,
+}
+
+impl Backend for LlvmObject {
+    fn name(&self) -> &'static str {
+        "llvm-obj"
+    }
+
+    fn lower(&self, program: &Brainfuck) -> Result<Artifact, BrainfuckError> {
+        let target = self.target;
+        let ir = match (LlvmIr { target }).lower(program)? {
+            Artifact::Text(ir) => ir,
+            Artifact::Binary(_) => unreachable!("the LLVM IR backend always produces text"),
+        };
+        compile(&ir, target).map(Artifact::Binary)
+    }
+}
+
+/// Takes ownership of an LLVM message and turns it into a backend error.
+unsafe fn take_message(message: *mut c_char, what: &str) -> BrainfuckError {
+    if message.is_null() {
+        return BrainfuckError::Backend(what.into());
+    }
+    let text = CStr::from_ptr(message).to_string_lossy().into_owned();
+    LLVMDisposeMessage(message);
+    BrainfuckError::Backend(format!("{}: {}", what, text))
+}
+
+/// The LLVM objects `compile` creates, disposed of in reverse order however it
+/// returns.
+struct Session {
+    context: *mut Context,
+    module: *mut Module,
+    machine: *mut TargetMachine,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.machine.is_null() {
+                LLVMDisposeTargetMachine(self.machine);
+            }
+            if !self.module.is_null() {
+                LLVMDisposeModule(self.module);
+            }
+            LLVMContextDispose(self.context);
+        }
+    }
+}
+
+/// Registers the LLVM target that generates code for `target`, once.
+fn init(target: llvm::Target) {
+    static X86_64: Once = Once::new();
+    static AARCH64: Once = Once::new();
+    static RISCV64: Once = Once::new();
+    // The asm parsers are needed to assemble the inline system calls.
+    match target {
+        llvm::Target::X86_64Linux | llvm::Target::X86_64Windows | llvm::Target::X86_64MacOs => {
+            X86_64.call_once(|| unsafe {
+                LLVMInitializeX86TargetInfo();
+                LLVMInitializeX86Target();
+                LLVMInitializeX86TargetMC();
+                LLVMInitializeX86AsmPrinter();
+                LLVMInitializeX86AsmParser();
+            })
+        }
+        llvm::Target::Aarch64Linux | llvm::Target::Aarch64MacOs => AARCH64.call_once(|| unsafe {
+            LLVMInitializeAArch64TargetInfo();
+            LLVMInitializeAArch64Target();
+            LLVMInitializeAArch64TargetMC();
+            LLVMInitializeAArch64AsmPrinter();
+            LLVMInitializeAArch64AsmParser();
+        }),
+        llvm::Target::Riscv64Linux => RISCV64.call_once(|| unsafe {
+            LLVMInitializeRISCVTargetInfo();
+            LLVMInitializeRISCVTarget();
+            LLVMInitializeRISCVTargetMC();
+            LLVMInitializeRISCVAsmPrinter();
+            LLVMInitializeRISCVAsmParser();
+        }),
+    }
+}
+
+fn compile(ir: &str, target: llvm::Target) -> Result<Vec<u8>, BrainfuckError> {
+    init(target);
+    let triple = CString::new(target.triple()).expect("the triple has no NUL bytes");
+    let features = CString::new(target.features()).expect("the features have no NUL bytes");
+    let empty = CString::default();
+    let name = CString::new("program").expect("the name has no NUL bytes");
+    unsafe {
+        let mut session = Session {
+            context: LLVMContextCreate(),
+            module: ptr::null_mut(),
+            machine: ptr::null_mut(),
+        };
+        // Parsing takes ownership of the buffer, whether or not it succeeds.
+        let buffer =
+            LLVMCreateMemoryBufferWithMemoryRangeCopy(ir.as_ptr().cast(), ir.len(), name.as_ptr());
+        let mut message = ptr::null_mut();
+        if LLVMParseIRInContext(session.context, buffer, &mut session.module, &mut message) != 0 {
+            session.module = ptr::null_mut();
+            return Err(take_message(message, "invalid LLVM IR"));
+        }
+        LLVMSetTarget(session.module, triple.as_ptr());
+        LLVMStripModuleDebugInfo(session.module);
+
+        let mut llvm_target = ptr::null_mut();
+        if LLVMGetTargetFromTriple(triple.as_ptr(), &mut llvm_target, &mut message) != 0 {
+            return Err(take_message(message, "unsupported target"));
+        }
+        session.machine = LLVMCreateTargetMachine(
+            llvm_target,
+            triple.as_ptr(),
+            empty.as_ptr(),
+            features.as_ptr(),
+            CODEGEN_LEVEL_AGGRESSIVE,
+            RELOC_DEFAULT,
+            CODE_MODEL_DEFAULT,
+        );
+
+        let passes = CString::new("default<O3>").expect("the pipeline has no NUL bytes");
+        let options = LLVMCreatePassBuilderOptions();
+        let error = LLVMRunPasses(session.module, passes.as_ptr(), session.machine, options);
+        LLVMDisposePassBuilderOptions(options);
+        if !error.is_null() {
+            let message = LLVMGetErrorMessage(error);
+            let text = CStr::from_ptr(message).to_string_lossy().into_owned();
+            LLVMDisposeErrorMessage(message);
+            return Err(BrainfuckError::Backend(format!(
+                "could not optimize: {}",
+                text
+            )));
+        }
+
+        let mut object = ptr::null_mut();
+        if LLVMTargetMachineEmitToMemoryBuffer(
+            session.machine,
+            session.module,
+            OBJECT_FILE,
+            &mut message,
+            &mut object,
+        ) != 0
+        {
+            return Err(take_message(message, "could not emit an object file"));
+        }
+        let bytes = slice::from_raw_parts(
+            LLVMGetBufferStart(object).cast::<u8>(),
+            LLVMGetBufferSize(object),
+        )
+        .to_vec();
+        LLVMDisposeMemoryBuffer(object);
+        Ok(bytes)
+    }
+}
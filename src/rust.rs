@@ -0,0 +1,467 @@
+use alloc::collections::vec_deque::Iter;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use backend::{Artifact, Backend, MAX_INDENT};
+use brainfuck::{self, rng_state, Brainfuck, CellSize, EofMode, Node, Program, TapeMode};
+use error::BrainfuckError;
+
+/// Generates a standalone `main.rs` in safe Rust, with the tape in a `Vec`
+/// and I/O through `std::io`, for building with `rustc` alone.
+pub struct RustSource;
+
+impl Backend for RustSource {
+    fn name(&self) -> &'static str {
+        "rust"
+    }
+
+    fn lower(&self, program: &Brainfuck) -> Result<Artifact, BrainfuckError> {
+        if program.program.strict {
+            return Err(BrainfuckError::Backend(
+                "strict overflow checking is only supported by the interpreter".into(),
+            ));
+        }
+        if program
+            .program
+            .ast
+            .uses(|node| matches!(*node, Node::PrintNumber(_) | Node::ReadNumber(_)))
+        {
+            return Err(BrainfuckError::Backend(
+                "numeric I/O is only supported by the interpreter".into(),
+            ));
+        }
+        Ok(Artifact::Text(gen_rust(&program.program)))
+    }
+}
+
+/// A tape that grows from either end, reallocating to take in the index
+/// reached and `CHUNK` cells beyond.
+const GROW_TAPE: &str = r#"
+struct Tape {
+    cells: Vec<Cell>,
+    /// The index of the first cell.
+    lo: i64,
+}
+
+impl Tape {
+    fn at(&mut self, i: i64) -> io::Result<&mut Cell> {
+        let hi = self.lo + self.cells.len() as i64;
+        if i < self.lo || i >= hi {
+            let new_lo = if i < self.lo { i - CHUNK } else { self.lo };
+            let new_hi = if i >= hi { i + 1 + CHUNK } else { hi };
+LIMIT_CHECK            let mut cells = vec![0; (new_hi - new_lo) as usize];
+            let start = (self.lo - new_lo) as usize;
+            cells[start..start + self.cells.len()].copy_from_slice(&self.cells);
+            self.cells = cells;
+            self.lo = new_lo;
+        }
+        Ok(&mut self.cells[(i - self.lo) as usize])
+    }
+}
+"#;
+
+/// Keeps a growing tape within `LIMIT` cells.
+const LIMIT_CHECK: &str = r#"            if (if i < self.lo { hi - i } else { i + 1 - self.lo }) > LIMIT {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "tape memory limit exceeded",
+                ));
+            }
+            let (new_lo, new_hi) = if new_hi - new_lo <= LIMIT {
+                (new_lo, new_hi)
+            } else if i < self.lo {
+                (hi - LIMIT, new_hi)
+            } else {
+                (new_lo, self.lo + LIMIT)
+            };
+"#;
+
+/// One xorshift64 step, keeping the top byte, as the interpreter does.
+const NEXT_RANDOM: &str = r"
+fn next_random(rng: &mut u64) -> Cell {
+    *rng ^= *rng << 13;
+    *rng ^= *rng >> 7;
+    *rng ^= *rng << 17;
+    Cell::from((*rng >> 56) as u8)
+}
+";
+
+/// Seeds `next_random` from the clock, for unseeded programs.
+const SEED_FROM_TIME: &str = r"    let mut rng = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(1, |d| d.as_nanos() as u64)
+        .wrapping_mul(0x9e3779b97f4a7c15)
+        | 1;
+";
+
+/// Flushes output, so prompts show, before reading the next input byte.
+const READ: &str = r"
+fn read(
+    out: &mut impl Write,
+    input: &mut impl Iterator<Item = io::Result<u8>>,
+) -> io::Result<Option<u8>> {
+    out.flush()?;
+    input.next().transpose()
+}
+";
+
+/// Reports errors from `run`, after its output is flushed.
+const MAIN: &str = r#"
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+"#;
+
+/// Like `MAIN`, exiting with the cell `run` ends on.
+const MAIN_EXIT_CELL: &str = r#"
+fn main() {
+    match run() {
+        Ok(code) => process::exit(i32::from(code)),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    }
+}
+"#;
+
+fn rust_type(cell_size: CellSize) -> &'static str {
+    match cell_size {
+        CellSize::U8 => "u8",
+        CellSize::U16 => "u16",
+        CellSize::U32 => "u32",
+    }
+}
+
+fn gen_rust(program: &Program) -> String {
+    let grow = program.tape_mode == TapeMode::Grow;
+    let random = program.ast.uses(|node| matches!(*node, Node::Random(_)));
+    let input = program.ast.uses(|node| matches!(*node, Node::Input(_)));
+    let data = program.tape_init_data();
+    // Moves after the last access to the tape do nothing, and rustc warns
+    // about them.
+    let nodes = &program.ast.nodes;
+    let last_access = if program.exit_cell {
+        Some(nodes.len())
+    } else {
+        nodes
+            .iter()
+            .rposition(|node| !matches!(*node, Node::Move(..) | Node::Write(..) | Node::Debug(_)))
+    };
+    let live = |&(i, node): &(usize, &Node)| {
+        !matches!(*node, Node::Move(..)) || last_access.is_some_and(|last| i < last)
+    };
+    let moves = nodes.iter().enumerate().filter(live).any(|(_, node)| {
+        uses(node, &|node| match *node {
+            Node::Move(v, _) | Node::Scan(v, _) => !seek(program, v).is_empty(),
+            _ => false,
+        })
+    });
+    let writes = !data.is_empty()
+        || program.ast.uses(|node| {
+            matches!(
+                *node,
+                Node::Add(..)
+                    | Node::Set(..)
+                    | Node::MulAdd(..)
+                    | Node::Product(..)
+                    | Node::Input(_)
+                    | Node::Random(_)
+            )
+        });
+    // Programs reduced to writing their output never look at the tape.
+    let tape = last_access.is_some() || !data.is_empty();
+
+    let mut rs = String::new();
+    if input {
+        rs.push_str("use std::io::{self, Read, Write};\n");
+    } else {
+        rs.push_str("use std::io::{self, Write};\n");
+    }
+    rs.push_str("use std::process;\n");
+    if random && program.seed.is_none() {
+        rs.push_str("use std::time::{SystemTime, UNIX_EPOCH};\n");
+    }
+    if tape || random {
+        rs.push_str(&format!(
+            "\ntype Cell = {};\n",
+            rust_type(program.cell_size)
+        ));
+    }
+    if grow && tape {
+        let limit_check = match program.max_mem {
+            Some(bytes) => LIMIT_CHECK.replace(
+                "LIMIT",
+                &format!("{}", (bytes / program.cell_size.bytes()).max(1)),
+            ),
+            None => String::new(),
+        };
+        rs.push_str(
+            &GROW_TAPE
+                .replace("CHUNK", &format!("{}", Program::GROW_CHUNK))
+                .replace("LIMIT_CHECK", &limit_check),
+        );
+    }
+    if !data.is_empty() {
+        rs.push_str("\nconst TAPE_INIT: &[u8] = &[");
+        for (i, b) in data.iter().enumerate() {
+            rs.push_str(if i % 12 == 0 { "\n    " } else { " " });
+            rs.push_str(&format!("{},", b));
+        }
+        rs.push_str("\n];\n");
+    }
+    if random {
+        rs.push_str(NEXT_RANDOM);
+    }
+    if input {
+        rs.push_str(READ);
+    }
+
+    if program.exit_cell {
+        rs.push_str(MAIN_EXIT_CELL);
+        rs.push_str("\nfn run() -> io::Result<u8> {\n");
+    } else {
+        rs.push_str(MAIN);
+        rs.push_str("\nfn run() -> io::Result<()> {\n");
+    }
+    rs.push_str(
+        "    let stdout = io::stdout();\n    let mut out = io::BufWriter::new(stdout.lock());\n",
+    );
+    if input {
+        rs.push_str("    let stdin = io::stdin();\n    let mut input = stdin.lock().bytes();\n");
+    }
+    if tape {
+        let p = if moves { "let mut p" } else { "let p" };
+        if grow {
+            rs.push_str(&format!(
+                "    let mut tape = Tape {{\n        cells: vec![0; {}],\n        lo: 0,\n    }};\n    {}: i64 = {};\n",
+                program.mem_size, p, program.start_ptr
+            ));
+        } else {
+            rs.push_str(&format!(
+                "    {}: Vec<Cell> = vec![0; {}];\n    {}: usize = {};\n",
+                if writes { "let mut mem" } else { "let mem" },
+                program.mem_size,
+                p,
+                program.start_ptr
+            ));
+        }
+    }
+    if !data.is_empty() {
+        let index = if grow {
+            format!("{} + i as i64", program.tape_init_offset)
+        } else {
+            format!("{} + i", program.tape_init_offset)
+        };
+        rs.push_str(&format!(
+            "    for (i, &b) in TAPE_INIT.iter().enumerate() {{\n        {} = Cell::from(b);\n    }}\n",
+            place_at(program, &index)
+        ));
+    }
+    if random {
+        match program.seed {
+            Some(seed) => rs.push_str(&format!("    let mut rng: u64 = {};\n", rng_state(seed))),
+            None => rs.push_str(SEED_FROM_TIME),
+        }
+    }
+    let live = nodes.iter().enumerate().filter(live).map(|(_, node)| node);
+    gen_rust_nodes(program, &mut rs, live);
+    rs.push_str("    out.flush()?;\n");
+    if program.exit_cell {
+        rs.push_str(&format!("    Ok({} as u8)\n}}\n", value(program, 0)));
+    } else {
+        rs.push_str("    Ok(())\n}\n");
+    }
+    rs
+}
+
+/// Whether `node` or any node in its body is `wanted`.
+fn uses(node: &Node, wanted: &dyn Fn(&Node) -> bool) -> bool {
+    match *node {
+        Node::Loop(ref body, _) | Node::If(ref body, _) => brainfuck::uses(body, wanted),
+        _ => wanted(node),
+    }
+}
+
+/// The cell at index `index`, as a place to assign to.
+fn place_at(program: &Program, index: &str) -> String {
+    match program.tape_mode {
+        TapeMode::Fixed | TapeMode::Wrap => format!("mem[{}]", index),
+        TapeMode::Grow => format!("*tape.at({})?", index),
+    }
+}
+
+/// `p` plus `offset`, modulo the tape length on a wrapping tape.
+fn index(program: &Program, offset: isize) -> String {
+    if program.tape_mode == TapeMode::Wrap {
+        let offset = offset.rem_euclid(program.mem_size as isize);
+        if offset == 0 {
+            return "p".into();
+        }
+        return format!("(p + {}) % {}", offset, program.mem_size);
+    }
+    match offset {
+        0 => "p".into(),
+        offset if offset < 0 => format!("p - {}", offset.unsigned_abs()),
+        offset => format!("p + {}", offset),
+    }
+}
+
+/// The cell `offset` cells from the pointer, as a place to assign to.
+fn place(program: &Program, offset: isize) -> String {
+    place_at(program, &index(program, offset))
+}
+
+/// The value of the cell `offset` cells from the pointer.
+fn value(program: &Program, offset: isize) -> String {
+    match program.tape_mode {
+        TapeMode::Fixed | TapeMode::Wrap => place(program, offset),
+        TapeMode::Grow => format!("(*tape.at({})?)", index(program, offset)),
+    }
+}
+
+/// Moves the pointer `offset` cells, or nothing for going all the way around
+/// a wrapping tape.
+fn seek(program: &Program, offset: isize) -> String {
+    if program.tape_mode == TapeMode::Wrap {
+        return match index(program, offset) {
+            ref p if p == "p" => String::new(),
+            index => format!("p = {};", index),
+        };
+    }
+    if offset < 0 {
+        format!("p -= {};", offset.unsigned_abs())
+    } else {
+        format!("p += {};", offset)
+    }
+}
+
+/// A byte string literal holding `data`, escaping everything but printable
+/// ASCII.
+fn byte_string(data: &[u8]) -> String {
+    let mut rs = String::from("b\"");
+    for &b in data {
+        match b {
+            b'"' | b'\\' => rs.push_str(&format!("\\x{:02x}", b)),
+            b' '..=b'~' => rs.push(b as char),
+            _ => rs.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    rs.push('"');
+    rs
+}
+
+/// Emits `nodes` inside `main`, walking loop bodies with an explicit stack so
+/// nesting depth is limited by memory rather than the call stack.
+fn gen_rust_nodes<'a, I: IntoIterator<Item = &'a Node>>(
+    program: &Program,
+    rs: &mut String,
+    nodes: I,
+) {
+    let mask = program.cell_size.mask();
+    let mut nodes = nodes.into_iter();
+    // An iterator over each body entered, each indented a step further.
+    let mut levels: Vec<Iter<'a, Node>> = Vec::new();
+    loop {
+        let next = match levels.last_mut() {
+            Some(body) => body.next(),
+            None => nodes.next(),
+        };
+        let node = match next {
+            Some(node) => node,
+            None if levels.is_empty() => break,
+            None => {
+                levels.pop();
+                indent(rs, levels.len() + 1);
+                rs.push_str("}\n");
+                continue;
+            }
+        };
+        if let Node::Move(v, _) = *node {
+            if seek(program, v).is_empty() {
+                continue;
+            }
+        }
+        indent(rs, levels.len() + 1);
+        match *node {
+            Node::Move(v, _) => rs.push_str(&seek(program, v)),
+            Node::Add(offset, v, _) => rs.push_str(&format!(
+                "{} = {}.wrapping_add({});",
+                place(program, offset),
+                value(program, offset),
+                v as u32 & mask
+            )),
+            Node::Set(v, _) => rs.push_str(&format!("{} = {};", place(program, 0), v & mask)),
+            Node::MulAdd(offset, factor, _) => rs.push_str(&format!(
+                "if {cur} != 0 {{ {target} = {target_value}.wrapping_add({cur}.wrapping_mul({factor})); }}",
+                cur = value(program, 0),
+                target = place(program, offset),
+                target_value = value(program, offset),
+                factor = factor & mask
+            )),
+            Node::Product(offset, source, factor, _) => rs.push_str(&format!(
+                "if {cur} != 0 {{ {target} = {target_value}.wrapping_add({cur}.wrapping_mul({source}).wrapping_mul({factor})); }}",
+                cur = value(program, 0),
+                target = place(program, offset),
+                target_value = value(program, offset),
+                source = value(program, source),
+                factor = factor & mask
+            )),
+            Node::Scan(step, _) => match seek(program, step) {
+                ref seek if seek.is_empty() => {
+                    rs.push_str(&format!("while {} != 0 {{}}", value(program, 0)))
+                }
+                seek => rs.push_str(&format!("while {} != 0 {{ {} }}", value(program, 0), seek)),
+            },
+            Node::Output(_) => rs.push_str(&format!(
+                "out.write_all(&[{} as u8])?;",
+                value(program, 0)
+            )),
+            Node::Input(_) => match program.eof {
+                EofMode::Zero => rs.push_str(&format!(
+                    "{} = read(&mut out, &mut input)?.map_or(0, Cell::from);",
+                    place(program, 0)
+                )),
+                EofMode::MinusOne => rs.push_str(&format!(
+                    "{} = read(&mut out, &mut input)?.map_or(Cell::MAX, Cell::from);",
+                    place(program, 0)
+                )),
+                EofMode::Unchanged => rs.push_str(&format!(
+                    "if let Some(b) = read(&mut out, &mut input)? {{ {} = Cell::from(b); }}",
+                    place(program, 0)
+                )),
+            },
+            Node::Write(ref bytes, _) => {
+                rs.push_str(&format!("out.write_all({})?;", byte_string(bytes)))
+            }
+            Node::Random(_) => rs.push_str(&format!(
+                "{} = next_random(&mut rng);",
+                place(program, 0)
+            )),
+            // Compiled programs have nowhere to report to, so `#` is ignored.
+            Node::Debug(_) => rs.push_str("// #"),
+            Node::PrintNumber(_) | Node::ReadNumber(_) => {
+                unreachable!("numeric I/O is rejected by `RustSource::lower`")
+            }
+            Node::Loop(ref body, _) | Node::If(ref body, _) => {
+                let keyword = match *node {
+                    Node::Loop(..) => "while",
+                    _ => "if",
+                };
+                rs.push_str(&format!("{} {} != 0 {{\n", keyword, value(program, 0)));
+                levels.push(body.iter());
+                continue;
+            }
+        }
+        rs.push('\n');
+    }
+}
+
+fn indent(rs: &mut String, depth: usize) {
+    for _ in 0..depth.min(MAX_INDENT) {
+        rs.push_str("    ");
+    }
+}
@@ -0,0 +1,50 @@
+use alloc::collections::VecDeque;
+
+use brainfuck::Node;
+
+/// Walks a program tree. Override the methods of interest and call the matching
+/// `walk_*` function to continue into loop bodies.
+pub trait Visitor {
+    fn visit_nodes(&mut self, nodes: &VecDeque<Node>) {
+        walk_nodes(self, nodes)
+    }
+
+    fn visit_node(&mut self, node: &Node) {
+        walk_node(self, node)
+    }
+}
+
+pub fn walk_nodes<V: Visitor + ?Sized>(visitor: &mut V, nodes: &VecDeque<Node>) {
+    for node in nodes {
+        visitor.visit_node(node);
+    }
+}
+
+pub fn walk_node<V: Visitor + ?Sized>(visitor: &mut V, node: &Node) {
+    if let Node::Loop(ref body, _) | Node::If(ref body, _) = *node {
+        visitor.visit_nodes(body);
+    }
+}
+
+/// Like `Visitor`, but with mutable access so nodes can be rewritten in place.
+pub trait VisitorMut {
+    fn visit_nodes_mut(&mut self, nodes: &mut VecDeque<Node>) {
+        walk_nodes_mut(self, nodes)
+    }
+
+    fn visit_node_mut(&mut self, node: &mut Node) {
+        walk_node_mut(self, node)
+    }
+}
+
+pub fn walk_nodes_mut<V: VisitorMut + ?Sized>(visitor: &mut V, nodes: &mut VecDeque<Node>) {
+    for node in nodes {
+        visitor.visit_node_mut(node);
+    }
+}
+
+pub fn walk_node_mut<V: VisitorMut + ?Sized>(visitor: &mut V, node: &mut Node) {
+    if let Node::Loop(ref mut body, _) | Node::If(ref mut body, _) = *node {
+        visitor.visit_nodes_mut(body);
+    }
+}
@@ -0,0 +1,964 @@
+use alloc::collections::vec_deque::Iter;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem;
+
+use backend::{Artifact, Backend, MAX_INDENT};
+use brainfuck::{rng_state, Brainfuck, CellSize, EofMode, Node, Program, TapeMode};
+use error::BrainfuckError;
+use interpreter::fresh_seed;
+
+/// Generates a WebAssembly module that exports its memory and `run`, which
+/// returns the exit status, and imports `read_byte`, returning -1 at end of
+/// input, and `write_byte` from `env`.
+///
+/// The host has no randomness to offer, so without a seed `?` is seeded when
+/// the module is generated.
+pub struct WasmModule;
+
+impl Backend for WasmModule {
+    fn name(&self) -> &'static str {
+        "wasm"
+    }
+
+    fn lower(&self, program: &Brainfuck) -> Result<Artifact, BrainfuckError> {
+        Ok(Artifact::Binary(Module::new(&program.program)?.encode()))
+    }
+}
+
+/// Prints the module `WasmModule` generates in the WebAssembly text format.
+pub struct WasmText;
+
+impl Backend for WasmText {
+    fn name(&self) -> &'static str {
+        "wat"
+    }
+
+    fn lower(&self, program: &Brainfuck) -> Result<Artifact, BrainfuckError> {
+        Ok(Artifact::Text(Module::new(&program.program)?.print()))
+    }
+}
+
+const PAGE_SIZE: u64 = 64 * 1024;
+
+/// Pages in the 4 GiB a wasm32 memory can address.
+const MAX_PAGES: u64 = 1 << 16;
+
+/// A cell or byte load or store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Width {
+    U8,
+    U16,
+    U32,
+}
+
+impl Width {
+    fn of(cell_size: CellSize) -> Width {
+        match cell_size {
+            CellSize::U8 => Width::U8,
+            CellSize::U16 => Width::U16,
+            CellSize::U32 => Width::U32,
+        }
+    }
+
+    /// The alignment hint, as a power of two.
+    fn align(self) -> u32 {
+        match self {
+            Width::U8 => 0,
+            Width::U16 => 1,
+            Width::U32 => 2,
+        }
+    }
+}
+
+/// The few instructions the generated code uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Instr {
+    Block,
+    Loop,
+    If,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Call(u32),
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+    /// Loads, zero extended, from the address on the stack plus an offset.
+    Load(Width, u32),
+    /// Stores the low bits of a value to the address below it plus an offset.
+    Store(Width, u32),
+    I32Const(i32),
+    I64Const(i64),
+    I32Eqz,
+    I32GeS,
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I32RemU,
+    I32And,
+    I32Shl,
+    I64Xor,
+    I64Shl,
+    I64ShrU,
+    I32WrapI64,
+}
+
+impl Instr {
+    fn encode(self, out: &mut Vec<u8>) {
+        // Blocks produce no values.
+        const EMPTY: u8 = 0x40;
+        match self {
+            Instr::Block => out.extend_from_slice(&[0x02, EMPTY]),
+            Instr::Loop => out.extend_from_slice(&[0x03, EMPTY]),
+            Instr::If => out.extend_from_slice(&[0x04, EMPTY]),
+            Instr::End => out.push(0x0b),
+            Instr::Br(depth) => {
+                out.push(0x0c);
+                uleb(out, depth.into());
+            }
+            Instr::BrIf(depth) => {
+                out.push(0x0d);
+                uleb(out, depth.into());
+            }
+            Instr::Call(func) => {
+                out.push(0x10);
+                uleb(out, func.into());
+            }
+            Instr::Select => out.push(0x1b),
+            Instr::LocalGet(local) => {
+                out.push(0x20);
+                uleb(out, local.into());
+            }
+            Instr::LocalSet(local) => {
+                out.push(0x21);
+                uleb(out, local.into());
+            }
+            Instr::LocalTee(local) => {
+                out.push(0x22);
+                uleb(out, local.into());
+            }
+            Instr::GlobalGet(global) => {
+                out.push(0x23);
+                uleb(out, global.into());
+            }
+            Instr::GlobalSet(global) => {
+                out.push(0x24);
+                uleb(out, global.into());
+            }
+            Instr::Load(width, offset) => {
+                out.push(match width {
+                    Width::U8 => 0x2d,
+                    Width::U16 => 0x2f,
+                    Width::U32 => 0x28,
+                });
+                uleb(out, width.align().into());
+                uleb(out, offset.into());
+            }
+            Instr::Store(width, offset) => {
+                out.push(match width {
+                    Width::U8 => 0x3a,
+                    Width::U16 => 0x3b,
+                    Width::U32 => 0x36,
+                });
+                uleb(out, width.align().into());
+                uleb(out, offset.into());
+            }
+            Instr::I32Const(v) => {
+                out.push(0x41);
+                sleb(out, v.into());
+            }
+            Instr::I64Const(v) => {
+                out.push(0x42);
+                sleb(out, v);
+            }
+            Instr::I32Eqz => out.push(0x45),
+            Instr::I32GeS => out.push(0x4e),
+            Instr::I32Add => out.push(0x6a),
+            Instr::I32Sub => out.push(0x6b),
+            Instr::I32Mul => out.push(0x6c),
+            Instr::I32RemU => out.push(0x70),
+            Instr::I32And => out.push(0x71),
+            Instr::I32Shl => out.push(0x74),
+            Instr::I64Xor => out.push(0x85),
+            Instr::I64Shl => out.push(0x86),
+            Instr::I64ShrU => out.push(0x88),
+            Instr::I32WrapI64 => out.push(0xa7),
+        }
+    }
+
+    /// The instruction in the text format, naming what it refers to.
+    fn print(self, module: &Module, func: &Func) -> String {
+        let local = |index: u32| {
+            func.params
+                .iter()
+                .chain(func.locals)
+                .nth(index as usize)
+                .expect("locals are named")
+        };
+        match self {
+            Instr::Block => "block".into(),
+            Instr::Loop => "loop".into(),
+            Instr::If => "if".into(),
+            Instr::End => "end".into(),
+            Instr::Br(depth) => format!("br {}", depth),
+            Instr::BrIf(depth) => format!("br_if {}", depth),
+            Instr::Call(index) => format!("call ${}", module.func_name(index)),
+            Instr::Select => "select".into(),
+            Instr::LocalGet(index) => format!("local.get ${}", local(index)),
+            Instr::LocalSet(index) => format!("local.set ${}", local(index)),
+            Instr::LocalTee(index) => format!("local.tee ${}", local(index)),
+            // The only global is `RNG`.
+            Instr::GlobalGet(_) => "global.get $rng".into(),
+            Instr::GlobalSet(_) => "global.set $rng".into(),
+            Instr::Load(width, offset) | Instr::Store(width, offset) => {
+                let op = match (self, width) {
+                    (Instr::Load(..), Width::U8) => "i32.load8_u",
+                    (Instr::Load(..), Width::U16) => "i32.load16_u",
+                    (Instr::Load(..), Width::U32) => "i32.load",
+                    (_, Width::U8) => "i32.store8",
+                    (_, Width::U16) => "i32.store16",
+                    (_, Width::U32) => "i32.store",
+                };
+                if offset == 0 {
+                    op.into()
+                } else {
+                    format!("{} offset={}", op, offset)
+                }
+            }
+            Instr::I32Const(v) => format!("i32.const {}", v),
+            Instr::I64Const(v) => format!("i64.const {}", v),
+            Instr::I32Eqz => "i32.eqz".into(),
+            Instr::I32GeS => "i32.ge_s".into(),
+            Instr::I32Add => "i32.add".into(),
+            Instr::I32Sub => "i32.sub".into(),
+            Instr::I32Mul => "i32.mul".into(),
+            Instr::I32RemU => "i32.rem_u".into(),
+            Instr::I32And => "i32.and".into(),
+            Instr::I32Shl => "i32.shl".into(),
+            Instr::I64Xor => "i64.xor".into(),
+            Instr::I64Shl => "i64.shl".into(),
+            Instr::I64ShrU => "i64.shr_u".into(),
+            Instr::I32WrapI64 => "i32.wrap_i64".into(),
+        }
+    }
+}
+
+fn uleb(out: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut v: i64) {
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if (v == 0 && byte & 0x40 == 0) || (v == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn section(out: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    out.push(id);
+    uleb(out, contents.len() as u64);
+    out.extend_from_slice(contents);
+}
+
+/// The signatures functions are declared with, by their index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Type {
+    /// `() -> i32`, for `read_byte`, `run` and `random`.
+    Nullary,
+    /// `(i32)`, for `write_byte`.
+    Byte,
+    /// `(i32, i32)`, for `write`.
+    Span,
+}
+
+const TYPES: [Type; 3] = [Type::Nullary, Type::Byte, Type::Span];
+
+impl Type {
+    fn encode(self, out: &mut Vec<u8>) {
+        const FUNC: u8 = 0x60;
+        const I32: u8 = 0x7f;
+        match self {
+            Type::Nullary => out.extend_from_slice(&[FUNC, 0, 1, I32]),
+            Type::Byte => out.extend_from_slice(&[FUNC, 1, I32, 0]),
+            Type::Span => out.extend_from_slice(&[FUNC, 2, I32, I32, 0]),
+        }
+    }
+
+    fn print(self) -> &'static str {
+        match self {
+            Type::Nullary => "(func (result i32))",
+            Type::Byte => "(func (param i32))",
+            Type::Span => "(func (param i32 i32))",
+        }
+    }
+
+    fn index(self) -> u32 {
+        TYPES.iter().position(|&ty| ty == self).unwrap() as u32
+    }
+}
+
+/// The host functions, which come first in the function index space.
+const IMPORTS: [(&str, Type); 2] = [("read_byte", Type::Nullary), ("write_byte", Type::Byte)];
+const READ_BYTE: u32 = 0;
+const WRITE_BYTE: u32 = 1;
+
+/// The global holding the random number generator's state.
+const RNG: u32 = 0;
+
+/// A function defined by the module.
+struct Func {
+    name: &'static str,
+    ty: Type,
+    /// The names of its parameters, then of its other `i32` locals.
+    params: &'static [&'static str],
+    locals: &'static [&'static str],
+    body: Vec<Instr>,
+    exported: bool,
+}
+
+/// Everything in a module besides the parts every module shares.
+struct Module {
+    funcs: Vec<Func>,
+    pages: u64,
+    /// Active data segments, as their address and bytes.
+    data: Vec<(u32, Vec<u8>)>,
+    /// The starting state of the random number generator, if `?` is used.
+    rng: Option<u64>,
+}
+
+impl Module {
+    fn new(program: &Program) -> Result<Module, BrainfuckError> {
+        if program.strict {
+            return Err(BrainfuckError::Backend(
+                "strict overflow checking is only supported by the interpreter".into(),
+            ));
+        }
+        if program.tape_mode == TapeMode::Grow {
+            return Err(BrainfuckError::Backend(
+                "growing tapes are not supported by the wasm backend".into(),
+            ));
+        }
+        if program
+            .ast
+            .uses(|node| matches!(*node, Node::PrintNumber(_) | Node::ReadNumber(_)))
+        {
+            return Err(BrainfuckError::Backend(
+                "numeric I/O is only supported by the interpreter".into(),
+            ));
+        }
+
+        // The tape starts at address 0, and the bytes of each `Write` follow it.
+        let bytes = program.cell_size.bytes() as u64;
+        let tape_end = program.mem_size as u64 * bytes;
+        let mut writes = Vec::new();
+        collect_writes(&program.ast.nodes, &mut writes);
+        let pages = (tape_end + writes.len() as u64).div_ceil(PAGE_SIZE).max(1);
+        // Indices on a wrapping tape are summed before they're wrapped.
+        let wraps_too_far =
+            program.tape_mode == TapeMode::Wrap && program.mem_size > i32::MAX as usize;
+        if pages > MAX_PAGES || wraps_too_far {
+            return Err(BrainfuckError::Backend(
+                "the tape is too large for a wasm32 memory".into(),
+            ));
+        }
+
+        let mut data = Vec::new();
+        let init = program.tape_init_data();
+        if !init.is_empty() {
+            let mut cells = Vec::with_capacity(init.len() * bytes as usize);
+            for &b in init {
+                cells.push(b);
+                cells.resize(cells.len() + bytes as usize - 1, 0);
+            }
+            data.push(((program.tape_init_offset as u64 * bytes) as u32, cells));
+        }
+        let write = !writes.is_empty();
+        if write {
+            data.push((tape_end as u32, writes));
+        }
+
+        let random = program.ast.uses(|node| matches!(*node, Node::Random(_)));
+        let mut funcs = Vec::new();
+        let first = IMPORTS.len() as u32 + 1;
+        let mut lowering = Lowering {
+            program,
+            width: Width::of(program.cell_size),
+            code: Vec::new(),
+            write: if write { Some(first) } else { None },
+            random: if random {
+                Some(first + write as u32)
+            } else {
+                None
+            },
+            writes: tape_end as u32,
+        };
+        lowering.run();
+        funcs.push(Func {
+            name: "run",
+            ty: Type::Nullary,
+            params: &[],
+            locals: &["p", "address", "value"],
+            body: lowering.code,
+            exported: true,
+        });
+        if write {
+            funcs.push(Func {
+                name: "write",
+                ty: Type::Span,
+                params: &["address", "len"],
+                locals: &[],
+                body: write_body(),
+                exported: false,
+            });
+        }
+        if random {
+            funcs.push(Func {
+                name: "random",
+                ty: Type::Nullary,
+                params: &[],
+                locals: &[],
+                body: random_body(),
+                exported: false,
+            });
+        }
+        Ok(Module {
+            funcs,
+            pages,
+            data,
+            rng: if random {
+                Some(rng_state(program.seed.unwrap_or_else(fresh_seed)))
+            } else {
+                None
+            },
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = b"\0asm\x01\0\0\0".to_vec();
+
+        let mut types = Vec::new();
+        uleb(&mut types, TYPES.len() as u64);
+        for ty in &TYPES {
+            ty.encode(&mut types);
+        }
+        section(&mut out, 1, &types);
+
+        let mut imports = Vec::new();
+        uleb(&mut imports, IMPORTS.len() as u64);
+        for &(func, ty) in &IMPORTS {
+            name(&mut imports, "env");
+            name(&mut imports, func);
+            imports.push(0x00);
+            uleb(&mut imports, ty.index().into());
+        }
+        section(&mut out, 2, &imports);
+
+        let mut funcs = Vec::new();
+        uleb(&mut funcs, self.funcs.len() as u64);
+        for func in &self.funcs {
+            uleb(&mut funcs, func.ty.index().into());
+        }
+        section(&mut out, 3, &funcs);
+
+        let mut memory = Vec::new();
+        uleb(&mut memory, 1);
+        memory.push(0x00);
+        uleb(&mut memory, self.pages);
+        section(&mut out, 5, &memory);
+
+        if let Some(state) = self.rng {
+            let mut globals = Vec::new();
+            uleb(&mut globals, 1);
+            // A mutable i64, initialized by a constant expression.
+            globals.extend_from_slice(&[0x7e, 0x01]);
+            Instr::I64Const(state as i64).encode(&mut globals);
+            Instr::End.encode(&mut globals);
+            section(&mut out, 6, &globals);
+        }
+
+        let mut exports = Vec::new();
+        let exported = self.funcs.iter().filter(|func| func.exported).count();
+        uleb(&mut exports, exported as u64 + 1);
+        name(&mut exports, "memory");
+        exports.push(0x02);
+        uleb(&mut exports, 0);
+        for (index, func) in self.funcs.iter().enumerate() {
+            if func.exported {
+                name(&mut exports, func.name);
+                exports.push(0x00);
+                uleb(&mut exports, (IMPORTS.len() + index) as u64);
+            }
+        }
+        section(&mut out, 7, &exports);
+
+        let mut code = Vec::new();
+        uleb(&mut code, self.funcs.len() as u64);
+        for func in &self.funcs {
+            let mut body = Vec::new();
+            if func.locals.is_empty() {
+                uleb(&mut body, 0);
+            } else {
+                uleb(&mut body, 1);
+                uleb(&mut body, func.locals.len() as u64);
+                body.push(0x7f);
+            }
+            for &instr in &func.body {
+                instr.encode(&mut body);
+            }
+            Instr::End.encode(&mut body);
+            uleb(&mut code, body.len() as u64);
+            code.extend_from_slice(&body);
+        }
+        section(&mut out, 10, &code);
+
+        if !self.data.is_empty() {
+            let mut data = Vec::new();
+            uleb(&mut data, self.data.len() as u64);
+            for &(address, ref bytes) in &self.data {
+                uleb(&mut data, 0);
+                Instr::I32Const(address as i32).encode(&mut data);
+                Instr::End.encode(&mut data);
+                uleb(&mut data, bytes.len() as u64);
+                data.extend_from_slice(bytes);
+            }
+            section(&mut out, 11, &data);
+        }
+        out
+    }
+
+    fn func_name(&self, index: u32) -> &'static str {
+        let index = index as usize;
+        match IMPORTS.get(index) {
+            Some(&(name, _)) => name,
+            None => self.funcs[index - IMPORTS.len()].name,
+        }
+    }
+
+    /// The same module as `encode`, in the text format.
+    fn print(&self) -> String {
+        let mut wat = String::from("(module\n");
+        for (index, ty) in TYPES.iter().enumerate() {
+            wat.push_str(&format!("  (type (;{};) {})\n", index, ty.print()));
+        }
+        for &(func, ty) in &IMPORTS {
+            wat.push_str(&format!(
+                "  (import \"env\" \"{0}\" (func ${0} (type {1})))\n",
+                func,
+                ty.index()
+            ));
+        }
+        wat.push_str(&format!("  (memory (export \"memory\") {})\n", self.pages));
+        if let Some(state) = self.rng {
+            wat.push_str(&format!(
+                "  (global $rng (mut i64) (i64.const {}))\n",
+                state as i64
+            ));
+        }
+
+        for func in &self.funcs {
+            wat.push_str(&format!("\n  (func ${}", func.name));
+            if func.exported {
+                wat.push_str(&format!(" (export \"{}\")", func.name));
+            }
+            wat.push_str(&format!(" (type {})", func.ty.index()));
+            for param in func.params {
+                wat.push_str(&format!(" (param ${} i32)", param));
+            }
+            if func.ty == Type::Nullary {
+                wat.push_str(" (result i32)");
+            }
+            wat.push('\n');
+            for local in func.locals {
+                wat.push_str(&format!("    (local ${} i32)\n", local));
+            }
+            let mut depth = 2;
+            for &instr in &func.body {
+                if instr == Instr::End {
+                    depth -= 1;
+                }
+                for _ in 0..depth.min(MAX_INDENT) {
+                    wat.push_str("  ");
+                }
+                wat.push_str(&instr.print(self, func));
+                wat.push('\n');
+                if let Instr::Block | Instr::Loop | Instr::If = instr {
+                    depth += 1;
+                }
+            }
+            wat.push_str("  )\n");
+        }
+
+        if !self.data.is_empty() {
+            wat.push('\n');
+        }
+        for &(address, ref bytes) in &self.data {
+            wat.push_str(&format!("  (data (i32.const {})", address));
+            for line in bytes.chunks(32) {
+                wat.push_str("\n    \"");
+                for &b in line {
+                    match b {
+                        b'"' | b'\\' => wat.push_str(&format!("\\{:02x}", b)),
+                        0x20..=0x7e => wat.push(b as char),
+                        _ => wat.push_str(&format!("\\{:02x}", b)),
+                    }
+                }
+                wat.push('"');
+            }
+            wat.push_str(")\n");
+        }
+        wat.push_str(")\n");
+        wat
+    }
+}
+
+/// Appends the bytes of every `Write`, in the order `Lowering` reaches them.
+fn collect_writes(nodes: &VecDeque<Node>, writes: &mut Vec<u8>) {
+    let mut levels = vec![nodes.iter()];
+    while let Some(nodes) = levels.last_mut() {
+        match nodes.next() {
+            Some(Node::Write(bytes, _)) => writes.extend_from_slice(bytes),
+            Some(&Node::Loop(ref body, _)) | Some(&Node::If(ref body, _)) => {
+                levels.push(body.iter())
+            }
+            Some(_) => {}
+            None => {
+                levels.pop();
+            }
+        }
+    }
+}
+
+/// Writes `len` bytes from `address`, its two parameters.
+fn write_body() -> Vec<Instr> {
+    const ADDRESS: u32 = 0;
+    const LEN: u32 = 1;
+    vec![
+        Instr::Block,
+        Instr::Loop,
+        Instr::LocalGet(LEN),
+        Instr::I32Eqz,
+        Instr::BrIf(1),
+        Instr::LocalGet(ADDRESS),
+        Instr::Load(Width::U8, 0),
+        Instr::Call(WRITE_BYTE),
+        Instr::LocalGet(ADDRESS),
+        Instr::I32Const(1),
+        Instr::I32Add,
+        Instr::LocalSet(ADDRESS),
+        Instr::LocalGet(LEN),
+        Instr::I32Const(1),
+        Instr::I32Sub,
+        Instr::LocalSet(LEN),
+        Instr::Br(0),
+        Instr::End,
+        Instr::End,
+    ]
+}
+
+/// One xorshift64 step, returning the top byte, as the interpreter does.
+fn random_body() -> Vec<Instr> {
+    let mut body = Vec::new();
+    for &(shift, op) in &[
+        (13, Instr::I64Shl),
+        (7, Instr::I64ShrU),
+        (17, Instr::I64Shl),
+    ] {
+        body.extend_from_slice(&[
+            Instr::GlobalGet(RNG),
+            Instr::GlobalGet(RNG),
+            Instr::I64Const(shift),
+            op,
+            Instr::I64Xor,
+            Instr::GlobalSet(RNG),
+        ]);
+    }
+    body.extend_from_slice(&[
+        Instr::GlobalGet(RNG),
+        Instr::I64Const(56),
+        Instr::I64ShrU,
+        Instr::I32WrapI64,
+    ]);
+    body
+}
+
+/// Locals of `run`: the pointer, a spare address, and a spare value.
+const P: u32 = 0;
+const ADDRESS: u32 = 1;
+const VALUE: u32 = 2;
+
+/// Lowers the program into the body of `run`.
+///
+/// On a fixed tape `P` holds the address of the current cell, so offsets fold
+/// into loads and stores. On a wrapping tape it holds the cell's index.
+struct Lowering<'a> {
+    program: &'a Program,
+    width: Width,
+    code: Vec<Instr>,
+    /// The `write` and `random` functions, if the program needs them.
+    write: Option<u32>,
+    random: Option<u32>,
+    /// Where the bytes of the next `Write` are.
+    writes: u32,
+}
+
+impl<'a> Lowering<'a> {
+    fn run(&mut self) {
+        let start = match self.program.tape_mode {
+            TapeMode::Wrap => self.program.start_ptr,
+            _ => self.program.start_ptr * self.program.cell_size.bytes(),
+        };
+        self.code
+            .extend_from_slice(&[Instr::I32Const(start as i32), Instr::LocalSet(P)]);
+        let program = self.program;
+        self.nodes(&program.ast.nodes);
+        if self.program.exit_cell {
+            self.load(0);
+            self.code
+                .extend_from_slice(&[Instr::I32Const(255), Instr::I32And]);
+        } else {
+            self.code.push(Instr::I32Const(0));
+        }
+    }
+
+    /// Pushes the address of the cell `offset` cells from the pointer, less
+    /// the offset to load or store it at, which is returned.
+    fn address(&mut self, offset: isize) -> u32 {
+        let bytes = self.program.cell_size.bytes() as isize;
+        self.code.push(Instr::LocalGet(P));
+        if self.program.tape_mode == TapeMode::Wrap {
+            let offset = offset.rem_euclid(self.program.mem_size as isize);
+            if offset != 0 {
+                self.code.extend_from_slice(&[
+                    Instr::I32Const(offset as i32),
+                    Instr::I32Add,
+                    Instr::I32Const(self.program.mem_size as i32),
+                    Instr::I32RemU,
+                ]);
+            }
+            if bytes > 1 {
+                self.code.extend_from_slice(&[
+                    Instr::I32Const(bytes.trailing_zeros() as i32),
+                    Instr::I32Shl,
+                ]);
+            }
+            return 0;
+        }
+        if offset >= 0 {
+            return (offset * bytes) as u32;
+        }
+        self.code
+            .extend_from_slice(&[Instr::I32Const((-offset * bytes) as i32), Instr::I32Sub]);
+        0
+    }
+
+    fn load(&mut self, offset: isize) {
+        let at = self.address(offset);
+        self.code.push(Instr::Load(self.width, at));
+    }
+
+    /// Moves the pointer `offset` cells.
+    fn seek(&mut self, offset: isize) {
+        let code = &mut self.code;
+        if self.program.tape_mode == TapeMode::Wrap {
+            let offset = offset.rem_euclid(self.program.mem_size as isize);
+            if offset != 0 {
+                code.extend_from_slice(&[
+                    Instr::LocalGet(P),
+                    Instr::I32Const(offset as i32),
+                    Instr::I32Add,
+                    Instr::I32Const(self.program.mem_size as i32),
+                    Instr::I32RemU,
+                    Instr::LocalSet(P),
+                ]);
+            }
+            return;
+        }
+        let bytes = self.program.cell_size.bytes() as isize;
+        code.extend_from_slice(&[
+            Instr::LocalGet(P),
+            Instr::I32Const((offset * bytes) as i32),
+            Instr::I32Add,
+            Instr::LocalSet(P),
+        ]);
+    }
+
+    /// Adds the value on the stack to the cell `offset` cells from the pointer.
+    fn add_to(&mut self, offset: isize) {
+        self.code.push(Instr::LocalSet(VALUE));
+        let at = self.address(offset);
+        self.code.extend_from_slice(&[
+            Instr::LocalTee(ADDRESS),
+            Instr::LocalGet(ADDRESS),
+            Instr::Load(self.width, at),
+            Instr::LocalGet(VALUE),
+            Instr::I32Add,
+            Instr::Store(self.width, at),
+        ]);
+    }
+
+    /// Lowers `nodes`, walking loop bodies with an explicit stack so nesting
+    /// depth is limited by memory rather than the call stack.
+    fn nodes(&mut self, nodes: &VecDeque<Node>) {
+        let mask = self.program.cell_size.mask();
+        let width = self.width;
+        let mut nodes = nodes.iter();
+        // The rest of each enclosing level, and whether the body entered repeats.
+        let mut outer: Vec<(Iter<'_, Node>, bool)> = Vec::new();
+        loop {
+            let node = match nodes.next() {
+                Some(node) => node,
+                None => match outer.pop() {
+                    Some((rest, repeat)) => {
+                        if repeat {
+                            self.load(0);
+                            self.code.extend_from_slice(&[Instr::BrIf(0), Instr::End]);
+                        }
+                        self.code.push(Instr::End);
+                        nodes = rest;
+                        continue;
+                    }
+                    None => break,
+                },
+            };
+            match *node {
+                Node::Move(v, _) => self.seek(v),
+                Node::Add(offset, v, _) => {
+                    self.code.push(Instr::I32Const(v));
+                    self.add_to(offset);
+                }
+                Node::Set(v, _) => {
+                    let at = self.address(0);
+                    self.code.extend_from_slice(&[
+                        Instr::I32Const((v & mask) as i32),
+                        Instr::Store(width, at),
+                    ]);
+                }
+                Node::MulAdd(offset, factor, _) | Node::Product(offset, _, factor, _) => {
+                    self.load(0);
+                    self.code.push(Instr::If);
+                    self.load(0);
+                    if let Node::Product(_, source, _, _) = *node {
+                        self.load(source);
+                        self.code.push(Instr::I32Mul);
+                    }
+                    self.code.extend_from_slice(&[
+                        Instr::I32Const((factor & mask) as i32),
+                        Instr::I32Mul,
+                    ]);
+                    self.add_to(offset);
+                    self.code.push(Instr::End);
+                }
+                Node::Scan(step, _) => {
+                    self.code.extend_from_slice(&[Instr::Block, Instr::Loop]);
+                    self.load(0);
+                    self.code
+                        .extend_from_slice(&[Instr::I32Eqz, Instr::BrIf(1)]);
+                    self.seek(step);
+                    self.code
+                        .extend_from_slice(&[Instr::Br(0), Instr::End, Instr::End]);
+                }
+                Node::Output(_) => {
+                    self.load(0);
+                    if width != Width::U8 {
+                        self.code
+                            .extend_from_slice(&[Instr::I32Const(255), Instr::I32And]);
+                    }
+                    self.code.push(Instr::Call(WRITE_BYTE));
+                }
+                Node::Input(_) => match self.program.eof {
+                    // -1 stores as every bit set.
+                    EofMode::MinusOne => {
+                        let at = self.address(0);
+                        self.code
+                            .extend_from_slice(&[Instr::Call(READ_BYTE), Instr::Store(width, at)]);
+                    }
+                    EofMode::Zero => {
+                        let at = self.address(0);
+                        self.code.extend_from_slice(&[
+                            Instr::Call(READ_BYTE),
+                            Instr::LocalTee(VALUE),
+                            Instr::I32Const(0),
+                            Instr::LocalGet(VALUE),
+                            Instr::I32Const(0),
+                            Instr::I32GeS,
+                            Instr::Select,
+                            Instr::Store(width, at),
+                        ]);
+                    }
+                    EofMode::Unchanged => {
+                        self.code.extend_from_slice(&[
+                            Instr::Call(READ_BYTE),
+                            Instr::LocalTee(VALUE),
+                            Instr::I32Const(0),
+                            Instr::I32GeS,
+                            Instr::If,
+                        ]);
+                        let at = self.address(0);
+                        self.code.extend_from_slice(&[
+                            Instr::LocalGet(VALUE),
+                            Instr::Store(width, at),
+                            Instr::End,
+                        ]);
+                    }
+                },
+                Node::Write(ref bytes, _) => {
+                    let write = self
+                        .write
+                        .expect("`write` is defined for programs with `Write`");
+                    self.code.extend_from_slice(&[
+                        Instr::I32Const(self.writes as i32),
+                        Instr::I32Const(bytes.len() as i32),
+                        Instr::Call(write),
+                    ]);
+                    self.writes += bytes.len() as u32;
+                }
+                Node::Random(_) => {
+                    let random = self
+                        .random
+                        .expect("`random` is defined for programs with `?`");
+                    let at = self.address(0);
+                    self.code
+                        .extend_from_slice(&[Instr::Call(random), Instr::Store(width, at)]);
+                }
+                // Compiled programs have nowhere to report to, so `#` is ignored.
+                Node::Debug(_) => {}
+                Node::PrintNumber(_) | Node::ReadNumber(_) => {
+                    unreachable!("numeric I/O is rejected by `Module::new`")
+                }
+                // Runs the body while, or if, the current cell is nonzero.
+                Node::Loop(ref body, _) | Node::If(ref body, _) => {
+                    let repeat = matches!(*node, Node::Loop(..));
+                    self.load(0);
+                    self.code.push(Instr::If);
+                    if repeat {
+                        self.code.push(Instr::Loop);
+                    }
+                    outer.push((mem::replace(&mut nodes, body.iter()), repeat));
+                }
+            }
+        }
+    }
+}
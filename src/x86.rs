@@ -0,0 +1,575 @@
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+pub use self::interpreted::X86Jit;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+pub use self::native::X86Jit;
+
+/// Where there is no x86-64 Linux host to emit code for, the JIT interprets.
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+mod interpreted {
+    use brainfuck::{Brainfuck, Io};
+    use error::BrainfuckError;
+    use ExecError;
+
+    pub struct X86Jit {
+        program: Brainfuck,
+    }
+
+    impl X86Jit {
+        pub fn compile(program: &Brainfuck) -> Result<X86Jit, BrainfuckError> {
+            Ok(X86Jit {
+                program: program.clone(),
+            })
+        }
+
+        /// Whether the program runs as native code rather than being interpreted.
+        pub fn is_native(&self) -> bool {
+            false
+        }
+
+        /// Runs the program to completion, returning the value of the cell the
+        /// pointer ends on.
+        pub fn run(&self, io: &mut dyn Io) -> Result<u32, ExecError> {
+            let mut interpreter = self.program.interpreter(io);
+            interpreter.run()?;
+            Ok(interpreter
+                .tape()
+                .get(interpreter.ptr())
+                .cloned()
+                .unwrap_or(0))
+        }
+    }
+}
+
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod native {
+    use std::collections::VecDeque;
+    use std::convert::TryFrom;
+    use std::io;
+    use std::mem;
+    use std::os::raw::{c_int, c_void};
+    use std::ptr;
+
+    use brainfuck::{Brainfuck, CellSize, DebugInfo, EofMode, Io, Node, Program, TapeMode};
+    use error::BrainfuckError;
+    use jit::{self, jit_input, jit_output, jit_random, jit_write, Entry, Image};
+    use ExecError;
+
+    const PROT_READ: c_int = 1;
+    const PROT_WRITE: c_int = 2;
+    const PROT_EXEC: c_int = 4;
+    const MAP_PRIVATE: c_int = 2;
+    const MAP_ANONYMOUS: c_int = 0x20;
+
+    extern "C" {
+        fn mmap(
+            addr: *mut c_void,
+            len: usize,
+            prot: c_int,
+            flags: c_int,
+            fd: c_int,
+            offset: i64,
+        ) -> *mut c_void;
+        fn mprotect(addr: *mut c_void, len: usize, prot: c_int) -> c_int;
+        fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    }
+
+    /// A program compiled straight to x86-64 machine code, with no code
+    /// generator behind it. It supports fixed and wrapping tapes, and ignores
+    /// `#` as compiled programs do.
+    pub struct X86Jit {
+        code: *mut c_void,
+        len: usize,
+        image: Image,
+    }
+
+    impl X86Jit {
+        pub fn compile(program: &Brainfuck) -> Result<X86Jit, BrainfuckError> {
+            let program = &program.program;
+            jit::check(program)?;
+            let mut emitter = Emitter::new(program);
+            emitter.program(&program.ast.nodes);
+            let Emitter { asm, image, .. } = emitter;
+            let code = asm.finish();
+
+            let mapping_failed =
+                |what| BrainfuckError::Backend(format!("{}: {}", what, io::Error::last_os_error()));
+            let len = code.len();
+            unsafe {
+                let at = mmap(
+                    ptr::null_mut(),
+                    len,
+                    PROT_READ | PROT_WRITE,
+                    MAP_PRIVATE | MAP_ANONYMOUS,
+                    -1,
+                    0,
+                );
+                if at as isize == -1 {
+                    return Err(mapping_failed("could not map memory for code"));
+                }
+                ptr::copy_nonoverlapping(code.as_ptr(), at.cast::<u8>(), len);
+                if mprotect(at, len, PROT_READ | PROT_EXEC) != 0 {
+                    munmap(at, len);
+                    return Err(mapping_failed("could not make code executable"));
+                }
+                Ok(X86Jit {
+                    code: at,
+                    len,
+                    image,
+                })
+            }
+        }
+
+        /// Whether the program runs as native code rather than being interpreted.
+        pub fn is_native(&self) -> bool {
+            true
+        }
+
+        /// Runs the program to completion, returning the value of the cell the
+        /// pointer ends on.
+        pub fn run(&self, io: &mut dyn Io) -> Result<u32, ExecError> {
+            unsafe {
+                let entry = mem::transmute::<*mut c_void, Entry>(self.code);
+                self.image.run(entry, io)
+            }
+        }
+    }
+
+    impl Drop for X86Jit {
+        fn drop(&mut self) {
+            unsafe { munmap(self.code, self.len) };
+        }
+    }
+
+    type Label = usize;
+
+    /// Machine code with forward and backward jumps to labels.
+    struct Assembler {
+        code: Vec<u8>,
+        labels: Vec<Option<usize>>,
+        /// Where each jump's 32-bit displacement is, and the label it is to.
+        fixups: Vec<(usize, Label)>,
+    }
+
+    impl Assembler {
+        fn emit(&mut self, bytes: &[u8]) {
+            self.code.extend_from_slice(bytes);
+        }
+
+        fn imm32(&mut self, v: i32) {
+            self.emit(&v.to_le_bytes());
+        }
+
+        fn imm64(&mut self, v: i64) {
+            self.emit(&v.to_le_bytes());
+        }
+
+        fn label(&mut self) -> Label {
+            self.labels.push(None);
+            self.labels.len() - 1
+        }
+
+        fn bind(&mut self, label: Label) {
+            self.labels[label] = Some(self.code.len());
+        }
+
+        /// Emits a jump, `opcode` followed by a displacement to `label`.
+        fn jump(&mut self, opcode: &[u8], label: Label) {
+            self.emit(opcode);
+            self.fixups.push((self.code.len(), label));
+            self.imm32(0);
+        }
+
+        fn finish(mut self) -> Vec<u8> {
+            for &(at, label) in &self.fixups {
+                let target = self.labels[label].expect("every label is bound");
+                let displacement = target as i64 - (at as i64 + 4);
+                self.code[at..at + 4].copy_from_slice(&(displacement as i32).to_le_bytes());
+            }
+            self.code
+        }
+    }
+
+    const JMP: &[u8] = &[0xe9];
+    const JE: &[u8] = &[0x0f, 0x84];
+    const JNE: &[u8] = &[0x0f, 0x85];
+    const JAE: &[u8] = &[0x0f, 0x83];
+
+    const ECX: u8 = 1;
+    const EDX: u8 = 2;
+
+    /// A block being emitted, and how to leave it once its nodes are done.
+    enum Close {
+        Program,
+        Loop { header: Label, exit: Label },
+        If { exit: Label },
+    }
+
+    /// Emits a program as one function. While it runs, `rbx` holds the tape,
+    /// `r12` the pointer as a cell index, `r13` the runtime and `r14` the
+    /// length of the tape; cell addresses are computed into `rax`.
+    struct Emitter<'p> {
+        program: &'p Program,
+        asm: Assembler,
+        image: Image,
+        /// The stub returning each fault code, by the code.
+        faults: Vec<Label>,
+    }
+
+    impl<'p> Emitter<'p> {
+        fn new(program: &'p Program) -> Emitter<'p> {
+            Emitter {
+                program,
+                asm: Assembler {
+                    code: Vec::new(),
+                    labels: Vec::new(),
+                    fixups: Vec::new(),
+                },
+                image: Image::new(program),
+                faults: Vec::new(),
+            }
+        }
+
+        fn program(&mut self, nodes: &VecDeque<Node>) {
+            // push rbx, r12, r13, r14 and r15, leaving the stack aligned for calls
+            self.asm
+                .emit(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+            // mov rbx, rdi; mov r13, rsi
+            self.asm.emit(&[0x48, 0x89, 0xfb, 0x49, 0x89, 0xf5]);
+            // movabs r12, start_ptr; movabs r14, mem_size
+            self.asm.emit(&[0x49, 0xbc]);
+            self.asm.imm64(self.program.start_ptr as i64);
+            self.asm.emit(&[0x49, 0xbe]);
+            self.asm.imm64(self.program.mem_size as i64);
+
+            let mut levels = vec![(nodes.iter(), Close::Program)];
+            while let Some(&mut (ref mut nodes, ref close)) = levels.last_mut() {
+                let node = match nodes.next() {
+                    Some(node) => node,
+                    None => {
+                        match *close {
+                            Close::Program => (),
+                            Close::Loop { header, exit } => {
+                                self.asm.jump(JMP, header);
+                                self.asm.bind(exit);
+                            }
+                            Close::If { exit } => self.asm.bind(exit),
+                        }
+                        levels.pop();
+                        continue;
+                    }
+                };
+                match *node {
+                    Node::Loop(ref body, ref d) => {
+                        let header = self.asm.label();
+                        let exit = self.asm.label();
+                        self.asm.bind(header);
+                        self.index(0, d);
+                        self.compare_zero();
+                        self.asm.jump(JE, exit);
+                        levels.push((body.iter(), Close::Loop { header, exit }));
+                    }
+                    Node::If(ref body, ref d) => {
+                        let exit = self.asm.label();
+                        self.index(0, d);
+                        self.compare_zero();
+                        self.asm.jump(JE, exit);
+                        levels.push((body.iter(), Close::If { exit }));
+                    }
+                    _ => self.node(node),
+                }
+            }
+
+            // mov rax, r12
+            self.asm.emit(&[0x4c, 0x89, 0xe0]);
+            let leave = self.asm.label();
+            self.asm.bind(leave);
+            // pop r15, r14, r13, r12 and rbx; ret
+            self.asm
+                .emit(&[0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d, 0x41, 0x5c, 0x5b, 0xc3]);
+            for (index, &stub) in self.faults.iter().enumerate() {
+                self.asm.bind(stub);
+                // mov rax, code
+                self.asm.emit(&[0x48, 0xc7, 0xc0]);
+                self.asm.imm32(-(index as i32) - 1);
+                self.asm.jump(JMP, leave);
+            }
+        }
+
+        /// Emits a node without a body.
+        fn node(&mut self, node: &Node) {
+            match *node {
+                Node::Move(v, _) => self.seek(v),
+                Node::Add(offset, v, ref d) => {
+                    self.index(offset, d);
+                    self.add_immediate(v as u32);
+                }
+                Node::Set(v, ref d) => {
+                    self.index(0, d);
+                    self.set_immediate(v);
+                }
+                Node::MulAdd(offset, factor, ref d) => {
+                    let done = self.asm.label();
+                    self.index(0, d);
+                    self.load(ECX);
+                    // test ecx, ecx
+                    self.asm.emit(&[0x85, 0xc9]);
+                    self.asm.jump(JE, done);
+                    self.multiply(factor);
+                    self.index(offset, d);
+                    self.add_ecx();
+                    self.asm.bind(done);
+                }
+                Node::Product(offset, source, factor, ref d) => {
+                    let done = self.asm.label();
+                    self.index(0, d);
+                    self.load(ECX);
+                    // test ecx, ecx
+                    self.asm.emit(&[0x85, 0xc9]);
+                    self.asm.jump(JE, done);
+                    self.index(source, d);
+                    self.load(EDX);
+                    // imul ecx, edx
+                    self.asm.emit(&[0x0f, 0xaf, 0xca]);
+                    self.multiply(factor);
+                    self.index(offset, d);
+                    self.add_ecx();
+                    self.asm.bind(done);
+                }
+                Node::Scan(step, ref d) => {
+                    let header = self.asm.label();
+                    let exit = self.asm.label();
+                    self.asm.bind(header);
+                    self.index(0, d);
+                    self.compare_zero();
+                    self.asm.jump(JE, exit);
+                    self.seek(step);
+                    self.asm.jump(JMP, header);
+                    self.asm.bind(exit);
+                }
+                Node::Output(ref d) => {
+                    self.index(0, d);
+                    self.load(ECX);
+                    // mov esi, ecx
+                    self.asm.emit(&[0x89, 0xce]);
+                    self.call(jit_output as *const u8);
+                }
+                Node::Input(ref d) => {
+                    let done = self.asm.label();
+                    let store = self.asm.label();
+                    // Fails before reading, as the interpreter does.
+                    self.index(0, d);
+                    self.call(jit_input as *const u8);
+                    // cmp eax, -1
+                    self.asm.emit(&[0x83, 0xf8, 0xff]);
+                    let eof = match self.program.eof {
+                        EofMode::Zero => Some(0),
+                        EofMode::MinusOne => Some(self.program.cell_size.mask()),
+                        EofMode::Unchanged => None,
+                    };
+                    match eof {
+                        Some(v) => {
+                            self.asm.jump(JNE, store);
+                            // mov eax, v
+                            self.asm.emit(&[0xb8]);
+                            self.asm.imm32(v as i32);
+                        }
+                        None => self.asm.jump(JE, done),
+                    }
+                    self.asm.bind(store);
+                    // mov ecx, eax
+                    self.asm.emit(&[0x89, 0xc1]);
+                    self.index(0, d);
+                    self.store(ECX);
+                    self.asm.bind(done);
+                }
+                Node::Random(ref d) => {
+                    self.index(0, d);
+                    self.call(jit_random as *const u8);
+                    // mov ecx, eax
+                    self.asm.emit(&[0x89, 0xc1]);
+                    self.index(0, d);
+                    self.store(ECX);
+                }
+                Node::Write(ref bytes, _) => {
+                    let data = self.image.keep(bytes);
+                    // movabs rsi, data; movabs rdx, len
+                    self.asm.emit(&[0x48, 0xbe]);
+                    self.asm.imm64(data as i64);
+                    self.asm.emit(&[0x48, 0xba]);
+                    self.asm.imm64(bytes.len() as i64);
+                    self.call(jit_write as *const u8);
+                }
+                // Like compiled programs, the JIT has nowhere to report `#` to.
+                Node::Debug(_) => (),
+                Node::PrintNumber(_) | Node::ReadNumber(_) => {
+                    unreachable!("numeric I/O is rejected before emitting")
+                }
+                Node::Loop(..) | Node::If(..) => unreachable!("blocks are emitted by `program`"),
+            }
+        }
+
+        /// Calls `function` with the runtime as its first argument.
+        fn call(&mut self, function: *const u8) {
+            // mov rdi, r13; movabs rax, function; call rax
+            self.asm.emit(&[0x4c, 0x89, 0xef, 0x48, 0xb8]);
+            self.asm.imm64(function as i64);
+            self.asm.emit(&[0xff, 0xd0]);
+        }
+
+        /// Adds `v` to `rax`.
+        fn add_rax(&mut self, v: i64) {
+            match i32::try_from(v) {
+                Ok(v) => {
+                    // add rax, v
+                    self.asm.emit(&[0x48, 0x05]);
+                    self.asm.imm32(v);
+                }
+                Err(_) => {
+                    // movabs rdx, v; add rax, rdx
+                    self.asm.emit(&[0x48, 0xba]);
+                    self.asm.imm64(v);
+                    self.asm.emit(&[0x48, 0x01, 0xd0]);
+                }
+            }
+        }
+
+        /// Puts the index of the cell `offset` cells from the pointer in `rax`,
+        /// wrapped around a wrapping tape.
+        fn offset(&mut self, offset: isize) {
+            // mov rax, r12
+            self.asm.emit(&[0x4c, 0x89, 0xe0]);
+            if self.program.tape_mode != TapeMode::Wrap {
+                if offset != 0 {
+                    self.add_rax(offset as i64);
+                }
+                return;
+            }
+            let offset = (offset as i64).rem_euclid(self.program.mem_size as i64);
+            if offset != 0 {
+                self.add_rax(offset);
+                // mov rsi, rax; sub rsi, r14; cmovae rax, rsi
+                self.asm
+                    .emit(&[0x48, 0x89, 0xc6, 0x4c, 0x29, 0xf6, 0x48, 0x0f, 0x43, 0xc6]);
+            }
+        }
+
+        /// Moves the pointer `v` cells.
+        fn seek(&mut self, v: isize) {
+            if self.program.tape_mode == TapeMode::Wrap {
+                self.offset(v);
+                // mov r12, rax
+                self.asm.emit(&[0x49, 0x89, 0xc4]);
+                return;
+            }
+            match i32::try_from(v) {
+                Ok(v) => {
+                    // add r12, v
+                    self.asm.emit(&[0x49, 0x81, 0xc4]);
+                    self.asm.imm32(v);
+                }
+                Err(_) => {
+                    // movabs rdx, v; add r12, rdx
+                    self.asm.emit(&[0x48, 0xba]);
+                    self.asm.imm64(v as i64);
+                    self.asm.emit(&[0x49, 0x01, 0xd4]);
+                }
+            }
+        }
+
+        /// Puts the index of the cell `offset` cells from the pointer in `rax`.
+        /// On a fixed tape, the function returns the fault code for `d` if it is
+        /// off the tape.
+        fn index(&mut self, offset: isize, d: &DebugInfo) {
+            self.offset(offset);
+            if self.program.tape_mode == TapeMode::Fixed {
+                let code = -self.image.fault_code(d) as usize;
+                if code > self.faults.len() {
+                    let stub = self.asm.label();
+                    self.faults.push(stub);
+                }
+                // cmp rax, r14; jae stub
+                self.asm.emit(&[0x4c, 0x39, 0xf0]);
+                self.asm.jump(JAE, self.faults[code - 1]);
+            }
+        }
+
+        /// Emits the operand for the cell at `rax`, with `reg` in the ModRM byte.
+        fn cell(&mut self, reg: u8) {
+            let scale = match self.program.cell_size {
+                CellSize::U8 => 0,
+                CellSize::U16 => 1,
+                CellSize::U32 => 2,
+            };
+            // [rbx + rax * scale]
+            self.asm.emit(&[reg << 3 | 0b100, scale << 6 | 0b011]);
+        }
+
+        /// Emits `opcode` for the width of a cell, with the operand size prefix
+        /// for 16-bit cells.
+        fn sized(&mut self, byte: u8, wide: u8) {
+            match self.program.cell_size {
+                CellSize::U8 => self.asm.emit(&[byte]),
+                CellSize::U16 => self.asm.emit(&[0x66, wide]),
+                CellSize::U32 => self.asm.emit(&[wide]),
+            }
+        }
+
+        /// Emits an immediate the width of a cell.
+        fn immediate(&mut self, v: u32) {
+            match self.program.cell_size {
+                CellSize::U8 => self.asm.emit(&[v as u8]),
+                CellSize::U16 => self.asm.emit(&(v as u16).to_le_bytes()),
+                CellSize::U32 => self.asm.emit(&v.to_le_bytes()),
+            }
+        }
+
+        fn compare_zero(&mut self) {
+            // cmp cell, 0
+            self.sized(0x80, 0x81);
+            self.cell(7);
+            self.immediate(0);
+        }
+
+        fn add_immediate(&mut self, v: u32) {
+            // add cell, v
+            self.sized(0x80, 0x81);
+            self.cell(0);
+            self.immediate(v);
+        }
+
+        fn set_immediate(&mut self, v: u32) {
+            // mov cell, v
+            self.sized(0xc6, 0xc7);
+            self.cell(0);
+            self.immediate(v);
+        }
+
+        /// Loads the cell at `rax` into `reg`, zero-extended.
+        fn load(&mut self, reg: u8) {
+            match self.program.cell_size {
+                CellSize::U8 => self.asm.emit(&[0x0f, 0xb6]),
+                CellSize::U16 => self.asm.emit(&[0x0f, 0xb7]),
+                CellSize::U32 => self.asm.emit(&[0x8b]),
+            }
+            self.cell(reg);
+        }
+
+        fn store(&mut self, reg: u8) {
+            // mov cell, reg
+            self.sized(0x88, 0x89);
+            self.cell(reg);
+        }
+
+        fn add_ecx(&mut self) {
+            // add cell, ecx
+            self.sized(0x00, 0x01);
+            self.cell(ECX);
+        }
+
+        fn multiply(&mut self, factor: u32) {
+            // imul ecx, ecx, factor
+            self.asm.emit(&[0x69, 0xc9]);
+            self.asm.imm32(factor as i32);
+        }
+    }
+}
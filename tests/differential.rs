@@ -0,0 +1,356 @@
+//! Runs the programs in `programs/` and small generated ones through every
+//! optimization level and the source backends, checking each behaves like the
+//! unoptimized interpreter.
+
+extern crate brainfuck;
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs::{self, File};
+use std::mem::{self, Discriminant};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+
+use brainfuck::{
+    find_backend, opt_level_passes, Brainfuck, BrainfuckBuilder, CellSize, DebugDump, ExecError,
+    Io, Limits, TapeMode,
+};
+
+/// The most instructions a program may run unoptimized; longer programs, like
+/// `mandelbrot.bf`, are left out so debug builds finish quickly.
+const MAX_INSTRUCTIONS: u64 = 200_000;
+
+/// How many times more instructions optimized runs may take. They usually take
+/// fewer, but a loop that never runs is one instruction and the `MulAdd`s and
+/// `Set` replacing it are several.
+const OPTIMIZED_SLACK: u64 = 10;
+
+/// What programs that read input are given.
+const INPUT: &[u8] = b"Hello, differential!\n";
+
+/// How many programs `generated` makes.
+const GENERATED: usize = 300;
+
+/// How many of the generated programs are compiled with each backend.
+const GENERATED_COMPILED: usize = 12;
+
+/// Reads from `INPUT` and collects output.
+struct TestIo {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+impl Io for TestIo {
+    fn output(&mut self, value: u8) {
+        self.output.push(value);
+    }
+
+    fn input(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+
+    fn debug(&mut self, _dump: &DebugDump) {}
+}
+
+/// The output of a run, and the kind of error that stopped it, if any.
+#[derive(Debug, PartialEq)]
+struct Outcome {
+    output: Vec<u8>,
+    error: Option<Discriminant<ExecError>>,
+}
+
+/// Runs `source` with the passes of `level`, or gives `None` if it runs past
+/// `max_instructions`.
+fn interpret(
+    builder: &BrainfuckBuilder,
+    level: u32,
+    source: &str,
+    max_instructions: u64,
+) -> Option<Outcome> {
+    let passes = opt_level_passes(level).expect("levels 0 to 3 exist");
+    let program = builder.clone().passes(&passes).parse_str(source).unwrap();
+    let limits = Limits {
+        max_instructions: Some(max_instructions),
+        ..Limits::default()
+    };
+    let mut io = TestIo {
+        input: INPUT.iter().cloned().collect(),
+        output: Vec::new(),
+    };
+    let error = match program.interpreter(&mut io).run_limited(&limits) {
+        Ok(_) => None,
+        Err(ExecError::LimitExceeded(_)) => return None,
+        Err(e) => Some(mem::discriminant(&e)),
+    };
+    Some(Outcome {
+        output: io.output,
+        error,
+    })
+}
+
+/// The unoptimized run of `source` the others are checked against, or `None` if
+/// it does not parse or runs too long.
+fn reference(builder: &BrainfuckBuilder, source: &str) -> Option<Outcome> {
+    builder.clone().passes(&[]).parse_str(source).ok()?;
+    interpret(builder, 0, source, MAX_INSTRUCTIONS)
+}
+
+/// A xorshift64 generator, so generated programs are the same on every run.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
+
+/// Snippets the passes look for, mixed into generated programs.
+const SNIPPETS: &[&str] = &[
+    "[-]",
+    "[+]",
+    "[->+<]",
+    "[->++>+++<<]",
+    "[>]",
+    "[<<]",
+    ">[-<+>]<",
+    "[>[->+>+<<]>>[-<<+>>]<<<-]",
+    "[>+<[-]]",
+];
+
+/// A random program of up to `len` commands and snippets, with loops nested
+/// at most `depth` deep. Loops mostly count down, so many of them end.
+fn generate(rng: &mut Rng, len: usize, depth: usize) -> String {
+    let mut source = String::new();
+    for _ in 0..rng.below(len) + 1 {
+        match rng.below(12) {
+            0..=2 => source.push('+'),
+            3 => source.push('-'),
+            4 | 5 => source.push('>'),
+            6 => source.push('<'),
+            7 => source.push('.'),
+            8 => source.push(','),
+            9 => source.push_str(SNIPPETS[rng.below(SNIPPETS.len())]),
+            _ if depth > 0 => {
+                source.push_str("[-");
+                source.push_str(&generate(rng, len, depth - 1));
+                source.push(']');
+            }
+            _ => source.push('+'),
+        }
+    }
+    source
+}
+
+fn generated() -> Vec<String> {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    (0..GENERATED).map(|_| generate(&mut rng, 10, 3)).collect()
+}
+
+fn program_files() -> Vec<String> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("programs");
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bf"))
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .map(|path| fs::read_to_string(path).unwrap())
+        .collect()
+}
+
+#[test]
+fn optimization_levels_match_unoptimized() {
+    let default = Brainfuck::builder();
+    let builders = [
+        default.clone(),
+        default.clone().cell_size(CellSize::U16),
+        default.clone().tape_mode(TapeMode::Wrap).mem_size(8),
+        default.clone().strict(true),
+        default.clone().strict(true).signed_cells(true),
+    ];
+    let sources: Vec<String> = program_files().into_iter().chain(generated()).collect();
+    for builder in &builders {
+        for source in &sources {
+            let expected = match reference(builder, source) {
+                Some(outcome) => outcome,
+                None => continue,
+            };
+            for level in 1..=3 {
+                let max_instructions = MAX_INSTRUCTIONS * OPTIMIZED_SLACK;
+                let outcome = interpret(builder, level, source, max_instructions);
+                assert_eq!(
+                    outcome.as_ref(),
+                    Some(&expected),
+                    "-O{} of {}",
+                    level,
+                    source
+                );
+            }
+        }
+    }
+}
+
+/// Compiles `source` at each level with `backend`, runs it on `INPUT` and
+/// checks it writes what the unoptimized interpreter does. `build` turns the
+/// backend's output, written to `file`, into the command that runs it, and is
+/// only called if every tool it needs is installed.
+fn check_backend<F>(backend: &str, file: &str, tools: &[&str], build: F)
+where
+    F: Fn(&Path) -> Command,
+{
+    let missing: Vec<&&str> = tools.iter().filter(|tool| !installed(tool)).collect();
+    if !missing.is_empty() {
+        eprintln!("skipping the {} backend: {:?} not found", backend, missing);
+        return;
+    }
+    let dir = env::temp_dir().join(format!("brainfuck-{}-{}", backend, process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // Programs may exit before reading all of it, so input comes from a file
+    // rather than a pipe.
+    let input = dir.join("input");
+    fs::write(&input, INPUT).unwrap();
+    let backend = find_backend(backend).unwrap();
+    let builder = Brainfuck::builder();
+    let sources = program_files()
+        .into_iter()
+        .chain(generated().into_iter().take(GENERATED_COMPILED));
+    for source in sources {
+        let expected = match reference(&builder, &source) {
+            Some(Outcome {
+                output,
+                error: None,
+            }) => output,
+            _ => continue,
+        };
+        for level in 0..=3 {
+            let passes = opt_level_passes(level).unwrap();
+            let program = builder.clone().passes(&passes).parse_str(&source).unwrap();
+            let artifact = backend.lower(&program).unwrap();
+            let path = dir.join(file);
+            fs::write(&path, artifact.as_bytes()).unwrap();
+            let run = build(&path)
+                .stdin(File::open(&input).unwrap())
+                .stderr(Stdio::inherit())
+                .output()
+                .unwrap();
+            assert!(run.status.success(), "-O{} of {}", level, source);
+            assert_eq!(run.stdout, expected, "-O{} of {}", level, source);
+        }
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn installed(tool: &str) -> bool {
+    Command::new(tool)
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Runs `command`, panicking with its errors if it fails.
+fn run_tool(command: &mut Command) {
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        command,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn c_source_matches_unoptimized() {
+    check_backend("c", "main.c", &["cc"], |path| {
+        let exe = path.with_extension("");
+        run_tool(Command::new("cc").arg(path).arg("-o").arg(&exe));
+        Command::new(exe)
+    });
+}
+
+#[test]
+fn rust_source_matches_unoptimized() {
+    check_backend("rust", "main.rs", &["rustc"], |path| {
+        let exe = path.with_extension("");
+        run_tool(Command::new("rustc").arg(path).arg("-o").arg(&exe));
+        Command::new(exe)
+    });
+}
+
+#[test]
+fn llvm_ir_matches_unoptimized() {
+    // Built as the CLI does, with `cc` linking in place of `ld.lld`.
+    check_backend("llvm-ir", "main.ll", &["opt", "llc", "cc"], |path| {
+        let bc = path.with_extension("bc");
+        let object = path.with_extension("o");
+        let exe = path.with_extension("");
+        run_tool(Command::new("opt").arg("-O3").arg(path).arg("-o").arg(&bc));
+        run_tool(
+            Command::new("llc")
+                .arg("-O3")
+                .arg("-filetype=obj")
+                .arg(&bc)
+                .arg("-o")
+                .arg(&object),
+        );
+        run_tool(
+            Command::new("cc")
+                .arg("-static")
+                .arg("-nostdlib")
+                .arg(&object)
+                .arg("-o")
+                .arg(&exe),
+        );
+        Command::new(exe)
+    });
+}
+
+#[cfg(feature = "llvm")]
+#[test]
+fn llvm_object_matches_unoptimized() {
+    check_backend("llvm-obj", "main.o", &["cc"], |path| {
+        let exe = path.with_extension("");
+        run_tool(
+            Command::new("cc")
+                .arg("-static")
+                .arg("-nostdlib")
+                .arg(path)
+                .arg("-o")
+                .arg(&exe),
+        );
+        Command::new(exe)
+    });
+}
+
+/// Runs the module at `process.argv[2]`, reading stdin and writing stdout.
+const WASM_HOST: &str = r"
+const fs = require('fs');
+const input = fs.readFileSync(0);
+const output = [];
+let at = 0;
+const env = {
+    read_byte: () => (at < input.length ? input[at++] : -1),
+    write_byte: (byte) => output.push(byte & 0xff),
+};
+WebAssembly.instantiate(fs.readFileSync(process.argv[2]), { env }).then(({ instance }) => {
+    const status = instance.exports.run();
+    fs.writeSync(1, Buffer.from(output));
+    process.exitCode = status;
+});
+";
+
+#[test]
+fn wasm_module_matches_unoptimized() {
+    // The `wat` backend prints this same module as text.
+    check_backend("wasm", "main.wasm", &["node"], |path| {
+        let host = path.with_extension("js");
+        fs::write(&host, WASM_HOST).unwrap();
+        let mut command = Command::new("node");
+        command.arg(host).arg(path);
+        command
+    });
+}